@@ -0,0 +1,27 @@
+use clap::Args;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf_diff::diff_gguf_files;
+
+#[derive(Args, Debug)]
+pub struct GGUFDiffArgs {
+    /// The GGUF file on the left side
+    a: String,
+
+    /// The GGUF file on the right side
+    b: String,
+}
+
+pub fn run_gguf_diff(args: &GGUFDiffArgs) -> Result<()> {
+    let gl_a = GGUFFileLoader::new(&args.a, false)?;
+    let gf_a = gl_a.open()?;
+    let gl_b = GGUFFileLoader::new(&args.b, false)?;
+    let gf_b = gl_b.open()?;
+
+    let report = diff_gguf_files(&gf_a, &gf_b)?;
+    print!("{}", report);
+    if report.is_identical() {
+        println!("identical");
+    }
+    Ok(())
+}
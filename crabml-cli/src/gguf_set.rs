@@ -0,0 +1,281 @@
+use clap::Args;
+use crabml::bail;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileBuilder;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadata;
+use crabml::gguf::GGUFMetadataArray;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFMetadataValueType;
+
+#[derive(Args, Debug)]
+pub struct GGUFSetArgs {
+    /// The GGUF file to edit
+    input: String,
+
+    /// The path to write the edited GGUF file, it must be different from the input
+    output: String,
+
+    /// Set the metadata like `general.name=foo` or `tokenizer.ggml.eos_token_id:u32=2`. The
+    /// type can be omitted on replacing an existing key, it's one of u8, i8, u16, i16, u32,
+    /// i32, u64, i64, f32, f64, bool and string. The arrays are comma separated with the
+    /// element type, like `a.b:array:i32=1,2,3`.
+    #[arg(long = "set")]
+    sets: Vec<String>,
+
+    /// Remove the metadata key
+    #[arg(long = "remove")]
+    removes: Vec<String>,
+}
+
+fn parse_value_type(s: &str) -> Result<GGUFMetadataValueType> {
+    let typ = match s {
+        "u8" => GGUFMetadataValueType::U8,
+        "i8" => GGUFMetadataValueType::I8,
+        "u16" => GGUFMetadataValueType::U16,
+        "i16" => GGUFMetadataValueType::I16,
+        "u32" => GGUFMetadataValueType::U32,
+        "i32" => GGUFMetadataValueType::I32,
+        "u64" => GGUFMetadataValueType::U64,
+        "i64" => GGUFMetadataValueType::I64,
+        "f32" => GGUFMetadataValueType::F32,
+        "f64" => GGUFMetadataValueType::F64,
+        "bool" => GGUFMetadataValueType::Bool,
+        "str" | "string" => GGUFMetadataValueType::String,
+        _ => bail!(ErrorKind::BadInput, "unsupported metadata type: {}", s),
+    };
+    Ok(typ)
+}
+
+fn parse<T: std::str::FromStr>(s: &str) -> Result<T>
+where T::Err: std::error::Error + Send + Sync + 'static {
+    s.parse::<T>().map_err(|err| Error {
+        kind: ErrorKind::BadInput,
+        message: format!("invalid metadata value: {}", s),
+        cause: Some(std::sync::Arc::new(err)),
+    })
+}
+
+fn parse_value(typ: GGUFMetadataValueType, s: &str) -> Result<GGUFMetadataValue<'static>> {
+    let value = match typ {
+        GGUFMetadataValueType::U8 => GGUFMetadataValue::U8(parse(s)?),
+        GGUFMetadataValueType::I8 => GGUFMetadataValue::I8(parse(s)?),
+        GGUFMetadataValueType::U16 => GGUFMetadataValue::U16(parse(s)?),
+        GGUFMetadataValueType::I16 => GGUFMetadataValue::I16(parse(s)?),
+        GGUFMetadataValueType::U32 => GGUFMetadataValue::U32(parse(s)?),
+        GGUFMetadataValueType::I32 => GGUFMetadataValue::I32(parse(s)?),
+        GGUFMetadataValueType::U64 => GGUFMetadataValue::U64(parse(s)?),
+        GGUFMetadataValueType::I64 => GGUFMetadataValue::I64(parse(s)?),
+        GGUFMetadataValueType::F32 => GGUFMetadataValue::F32(parse(s)?),
+        GGUFMetadataValueType::F64 => GGUFMetadataValue::F64(parse(s)?),
+        GGUFMetadataValueType::Bool => GGUFMetadataValue::Bool(parse::<bool>(s)? as u8),
+        GGUFMetadataValueType::String => GGUFMetadataValue::String(s.to_string().into()),
+        GGUFMetadataValueType::Array => {
+            bail!(
+                ErrorKind::BadInput,
+                "the element type of the array must be specified, like array:i32"
+            )
+        }
+    };
+    Ok(value)
+}
+
+/// parse the comma separated elements into an array. the string arrays borrow the strings
+/// from the file, they can not be set yet.
+fn parse_array(typ: GGUFMetadataValueType, s: &str) -> Result<GGUFMetadataValue<'static>> {
+    fn parse_items<T: std::str::FromStr>(items: &[&str]) -> Result<Vec<T>>
+    where T::Err: std::error::Error + Send + Sync + 'static {
+        items.iter().map(|s| parse(s)).collect()
+    }
+
+    let items = match s.trim() {
+        "" => vec![],
+        s => s.split(',').map(str::trim).collect::<Vec<_>>(),
+    };
+    let arr = match typ {
+        GGUFMetadataValueType::U8 => GGUFMetadataArray::U8Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::I8 => GGUFMetadataArray::I8Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::U16 => GGUFMetadataArray::U16Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::I16 => GGUFMetadataArray::I16Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::U32 => GGUFMetadataArray::U32Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::I32 => GGUFMetadataArray::I32Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::U64 => GGUFMetadataArray::U64Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::I64 => GGUFMetadataArray::I64Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::F32 => GGUFMetadataArray::F32Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::F64 => GGUFMetadataArray::F64Array(parse_items(&items)?.into()),
+        GGUFMetadataValueType::Bool => GGUFMetadataArray::BoolArray(
+            parse_items::<bool>(&items)?
+                .into_iter()
+                .map(|v| v as u8)
+                .collect::<Vec<_>>()
+                .into(),
+        ),
+        GGUFMetadataValueType::String | GGUFMetadataValueType::Array => bail!(
+            ErrorKind::BadInput,
+            "setting {:?} array metadata is not supported",
+            typ
+        ),
+    };
+    Ok(GGUFMetadataValue::Array(arr))
+}
+
+/// parse the value on the type like `u32` or `array:u32`.
+fn parse_typed_value(typ: &str, s: &str) -> Result<GGUFMetadataValue<'static>> {
+    match typ.strip_prefix("array:") {
+        Some(elem_typ) => parse_array(parse_value_type(elem_typ)?, s),
+        None => parse_value(parse_value_type(typ)?, s),
+    }
+}
+
+/// parse the assignment like `KEY[:TYPE]=VALUE`, the type of the existing value is used if
+/// the type is omitted.
+fn parse_assignment<'a>(
+    s: &'a str,
+    metadata: &GGUFMetadata,
+) -> Result<(&'a str, GGUFMetadataValue<'static>)> {
+    let (key, value) = match s.split_once('=') {
+        Some(v) => v,
+        None => bail!(
+            ErrorKind::BadInput,
+            "invalid assignment: {}, expected KEY[:TYPE]=VALUE",
+            s
+        ),
+    };
+    // the type may contain a colon like array:i32, but the keys never do
+    let (key, value) = match key.split_once(':') {
+        Some((key, typ)) => (key, parse_typed_value(typ, value)?),
+        None => match metadata.as_hashmap().get(key) {
+            Some(GGUFMetadataValue::Array(arr)) => (key, parse_array(arr.typ(), value)?),
+            Some(existing) => (key, parse_value(existing.typ(), value)?),
+            None => bail!(
+                ErrorKind::BadInput,
+                "the type of the new key {} must be specified, like {}:string={}",
+                key,
+                key,
+                value
+            ),
+        },
+    };
+    Ok((key, value))
+}
+
+pub fn run_gguf_set(args: &GGUFSetArgs) -> Result<()> {
+    let same_file = match (
+        std::fs::canonicalize(&args.input),
+        std::fs::canonicalize(&args.output),
+    ) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => false,
+    };
+    if same_file {
+        bail!(
+            ErrorKind::BadInput,
+            "the output file must be different from the input file {}",
+            args.input
+        );
+    }
+
+    let gl = GGUFFileLoader::new(&args.input, false)?;
+    let mut gf = gl.open()?;
+    for s in args.sets.iter() {
+        let (key, value) = parse_assignment(s, gf.metadata())?;
+        match gf.set_metadata(key, value.clone())? {
+            Some(old) => eprintln!("replaced {}: {:?} -> {:?}", key, old, value),
+            None => eprintln!("inserted {}: {:?}", key, value),
+        }
+    }
+    for key in args.removes.iter() {
+        match gf.remove_metadata(key)? {
+            Some(old) => eprintln!("removed {}: {:?}", key, old),
+            None => bail!(ErrorKind::BadInput, "metadata key {} not found", key),
+        }
+    }
+
+    GGUFFileBuilder::from_gguf_file(&gf).write_to_file(&args.output)?;
+    eprintln!("edited model written to {}", args.output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGUFFile;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_assignment() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let metadata = gf.metadata();
+
+        let tests = vec![
+            (
+                "general.name=foo=bar",
+                "general.name",
+                "String(\"foo=bar\")",
+            ),
+            (
+                "tokenizer.ggml.eos_token_id=3",
+                "tokenizer.ggml.eos_token_id",
+                "U32(3)",
+            ),
+            ("a.b:i16=-3", "a.b", "I16(-3)"),
+            ("a.b:bool=true", "a.b", "Bool(1)"),
+            ("a.b:f32=0.5", "a.b", "F32(0.5)"),
+            ("a.b:string=", "a.b", "String(\"\")"),
+            (
+                "a.b:array:i32=1, -2,3",
+                "a.b",
+                "Array(I32Array([1, -2, 3]))",
+            ),
+            (
+                "a.b:array:bool=true,false",
+                "a.b",
+                "Array(BoolArray([1, 0]))",
+            ),
+            ("a.b:array:u8=", "a.b", "Array(U8Array([]))"),
+            (
+                "tokenizer.ggml.scores=0.5,-1",
+                "tokenizer.ggml.scores",
+                "Array(F32Array([0.5, -1.0]))",
+            ),
+        ];
+        for (s, key, value) in tests {
+            let (k, v) = parse_assignment(s, metadata)?;
+            assert_eq!(k, key);
+            assert_eq!(format!("{:?}", v), value);
+        }
+
+        assert!(parse_assignment("a.b=1", metadata).is_err());
+        assert!(parse_assignment("a.b:u8=256", metadata).is_err());
+        assert!(parse_assignment("a.b:list=1", metadata).is_err());
+        assert!(parse_assignment("tokenizer.ggml.eos_token_id=x", metadata).is_err());
+        assert!(parse_assignment("a.b:array=1", metadata).is_err());
+        assert!(parse_assignment("a.b:array:u8=1,256", metadata).is_err());
+        assert!(parse_assignment("a.b:array:string=a,b", metadata).is_err());
+        assert!(parse_assignment("tokenizer.ggml.tokens=a,b", metadata).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_array_round_trip() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let mut gf = gl.open()?;
+        for s in ["a.b:array:i32=1,-2,3", "tokenizer.ggml.scores=0.5,-1"] {
+            let (key, value) = parse_assignment(s, gf.metadata())?;
+            gf.set_metadata(key, value)?;
+        }
+
+        let mut buf = vec![];
+        GGUFFileBuilder::from_gguf_file(&gf).write(&mut buf)?;
+        let gf2 = GGUFFile::from_bytes(&buf)?;
+        assert_eq!(gf2.metadata().get_i32_array("a.b"), Some(&[1, -2, 3][..]));
+        assert_eq!(
+            gf2.metadata().get_f32_array("tokenizer.ggml.scores"),
+            Some(&[0.5, -1.0][..])
+        );
+        Ok(())
+    }
+}
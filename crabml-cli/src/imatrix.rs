@@ -0,0 +1,174 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use clap::Args;
+use crabml::bail;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::imatrix::IMatrix;
+use crabml::tensor::Tensor;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlamaModelLoader;
+
+#[derive(Args, Debug)]
+pub struct IMatrixArgs {
+    /// The GGUF model to run the calibration text with, the F32/F16/BF16 models are preferred
+    #[arg(short, long)]
+    model: String,
+
+    /// The calibration text file
+    #[arg(short = 'f', long)]
+    file: String,
+
+    /// The path to write the importance matrix
+    #[arg(short, long, default_value_t = format!("imatrix.dat"))]
+    output: String,
+
+    /// The number of tokens in each chunk, the kv cache is cleared between the chunks
+    #[arg(long, default_value_t = 512)]
+    ctx: usize,
+
+    /// The maximum number of chunks to process, all the chunks of the file by default
+    #[arg(long)]
+    chunks: Option<usize>,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// mlock the mmaped file, it can help run faster without swapping
+    #[arg(long, default_value_t = false)]
+    mlock: bool,
+}
+
+/// run the calibration text through the model in chunks of `ctx` tokens, and collect the squared
+/// input activations of every matmul weight. the runner is expected to be created with
+/// `with_imatrix_collector()`.
+pub fn compute_imatrix<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    text: &str,
+    dataset: &str,
+    ctx: usize,
+    max_chunks: Option<usize>,
+) -> Result<IMatrix> {
+    if ctx < 2 {
+        bail!(ErrorKind::BadInput, "the context size should be at least 2");
+    }
+    let tokens = runner.tokenizer().encode(text, false, false, false)?;
+    let mut n_chunks = tokens.len() / ctx;
+    if let Some(max_chunks) = max_chunks {
+        n_chunks = n_chunks.min(max_chunks);
+    }
+    if n_chunks == 0 {
+        bail!(
+            ErrorKind::BadInput,
+            "the calibration text has {} tokens, at least {} tokens are required",
+            tokens.len(),
+            ctx
+        );
+    }
+
+    let bos_token = runner.tokenizer().bos_token();
+    for (i, chunk) in tokens.chunks_exact(ctx).take(n_chunks).enumerate() {
+        let started_at = Instant::now();
+        runner.reset_kv_cache()?;
+        // every chunk starts with a bos token like what llama.cpp does
+        for (pos, token) in chunk.iter().enumerate() {
+            let token = if pos == 0 { bos_token } else { *token };
+            runner.forward_token(token, pos)?;
+        }
+        eprintln!(
+            "[{}/{}] {} tokens in {:.2}s",
+            i + 1,
+            n_chunks,
+            ctx,
+            started_at.elapsed().as_secs_f64()
+        );
+    }
+
+    let collector = match runner.take_imatrix_collector() {
+        Some(collector) => collector,
+        None => bail!(
+            ErrorKind::Unexpected,
+            "the runner is not created with the imatrix collector"
+        ),
+    };
+    Ok(collector.to_imatrix(n_chunks as i32, dataset))
+}
+
+pub fn run_imatrix(args: &IMatrixArgs) -> Result<()> {
+    let text = std::fs::read_to_string(&args.file).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to read the calibration file: {}", args.file),
+        cause: Some(Arc::new(err)),
+    })?;
+
+    let mut thread_num = args.threads;
+    if thread_num == 0 {
+        thread_num = num_cpus::get();
+    }
+    let gl = GGUFFileLoader::new(&args.model, args.mlock)?;
+    let gf = gl.open()?;
+    let model = CpuLlamaModelLoader::new()
+        .with_thread_num(thread_num)
+        .load(&gf)?;
+    if args.ctx > model.conf.seq_len {
+        bail!(
+            ErrorKind::BadInput,
+            "the context size {} exceeds the max sequence length {} of the model",
+            args.ctx,
+            model.conf.seq_len
+        );
+    }
+
+    let mut runner = Llama2Runner::new(&model, args.ctx, false)?.with_imatrix_collector();
+    let imatrix = compute_imatrix(&mut runner, &text, &args.file, args.ctx, args.chunks)?;
+    imatrix.write_to_file(&args.output)?;
+    eprintln!(
+        "imatrix with {} entries written to {}",
+        imatrix.entries().len(),
+        args.output
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_imatrix() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let model = CpuLlamaModelLoader::new().load(&gf)?;
+        let conf = model.conf.clone();
+        let text =
+            "Lily is a cat. She likes to play with yarn. One day, she saw a big red ball in \
+             the garden and ran to it. The ball rolled away and Lily chased it.";
+
+        let mut runner = Llama2Runner::new(&model, 16, false)?;
+        assert!(compute_imatrix(&mut runner, text, "test", 16, None).is_err());
+
+        let mut runner = Llama2Runner::new(&model, 16, false)?.with_imatrix_collector();
+        let imatrix = compute_imatrix(&mut runner, text, "test", 16, Some(2))?;
+        assert_eq!(imatrix.last_call(), 2);
+        assert_eq!(imatrix.dataset(), "test");
+        assert_eq!(imatrix.entries().len(), conf.n_layers * 7);
+
+        let entry = imatrix.get_entry("blk.0.attn_q.weight").unwrap();
+        assert_eq!(entry.ncall, 32);
+        assert_eq!(entry.values.len(), conf.embedding_dim);
+        let entry = imatrix.get_entry("blk.5.ffn_down.weight").unwrap();
+        assert_eq!(entry.values.len(), conf.hidden_dim);
+        assert!(entry.values.iter().all(|v| *v >= 0.0));
+        assert!(entry.values.iter().any(|v| *v > 0.0));
+
+        // the inputs of wq, wk and wv are the same
+        assert_eq!(
+            imatrix.importance("blk.1.attn_q.weight"),
+            imatrix.importance("blk.1.attn_v.weight")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,543 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::sync::Arc;
+
+use crabml::bail;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+use crabml_llama2::llama2::Llama2Runner;
+
+use crate::perplexity::evaluate_chunks;
+use crate::perplexity::PerplexityStats;
+
+const BASE_LOGITS_MAGIC: &[u8; 4] = b"CKLD";
+const BASE_LOGITS_VERSION: u32 = 1;
+
+/// the top-k log probabilities of the baseline model on every evaluated position, together with
+/// the tokens of the text, so the same text can be replayed through another model. the file
+/// layout is little endian:
+///
+/// ```text
+/// [u8; 4] magic, u32 version, u32 n_vocab, u32 ctx, u32 top_k, u32 n_tokens, [u32; n_tokens] tokens
+/// u32 n_positions, n_positions * { f32 true_logprob, top_k * { u32 token, f32 logprob } }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct BaseLogits {
+    pub n_vocab: usize,
+    pub ctx: usize,
+    pub top_k: usize,
+    pub tokens: Vec<usize>,
+    pub positions: Vec<PositionLogits>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionLogits {
+    /// the log probability of the true next token
+    pub true_logprob: f32,
+    /// the top-k tokens and their log probabilities, sorted by the probability descending
+    pub top: Vec<(usize, f32)>,
+}
+
+impl BaseLogits {
+    pub fn load(path: &str) -> Result<Self> {
+        let buf = std::fs::read(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the base logits file: {}", path),
+            cause: Some(Arc::new(err)),
+        })?;
+        Self::from_bytes(&buf)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 4 || &buf[..4] != BASE_LOGITS_MAGIC {
+            bail!(ErrorKind::FormatError, "not a base logits file");
+        }
+        let mut r = BaseLogitsReader { buf, pos: 4 };
+        let version = r.read_u32()?;
+        if version != BASE_LOGITS_VERSION as usize {
+            bail!(
+                ErrorKind::FormatError,
+                "unsupported base logits version: {}",
+                version
+            );
+        }
+        let n_vocab = r.read_u32()?;
+        let ctx = r.read_u32()?;
+        let top_k = r.read_u32()?;
+        let n_tokens = r.read_u32()?;
+        let tokens = (0..n_tokens)
+            .map(|_| r.read_token(n_vocab))
+            .collect::<Result<Vec<_>>>()?;
+        let n_positions = r.read_u32()?;
+        let mut positions = Vec::with_capacity(n_positions.min(1 << 20));
+        for _ in 0..n_positions {
+            let true_logprob = r.read_f32()?;
+            let top = (0..top_k)
+                .map(|_| Ok((r.read_token(n_vocab)?, r.read_f32()?)))
+                .collect::<Result<Vec<_>>>()?;
+            positions.push(PositionLogits { true_logprob, top });
+        }
+        Ok(Self {
+            n_vocab,
+            ctx,
+            top_k,
+            tokens,
+            positions,
+        })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = BASE_LOGITS_MAGIC.to_vec();
+        for v in [
+            BASE_LOGITS_VERSION as usize,
+            self.n_vocab,
+            self.ctx,
+            self.top_k,
+            self.tokens.len(),
+        ] {
+            buf.extend((v as u32).to_le_bytes());
+        }
+        buf.extend(self.tokens.iter().flat_map(|t| (*t as u32).to_le_bytes()));
+        buf.extend((self.positions.len() as u32).to_le_bytes());
+        for pos in self.positions.iter() {
+            buf.extend(pos.true_logprob.to_le_bytes());
+            for (token, logprob) in pos.top.iter() {
+                buf.extend((*token as u32).to_le_bytes());
+                buf.extend(logprob.to_le_bytes());
+            }
+        }
+        w.write_all(&buf).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: "failed to write the base logits".to_string(),
+            cause: Some(Arc::new(err)),
+        })
+    }
+
+    pub fn write_to_file(&self, path: &str) -> Result<()> {
+        let file = File::create(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to create the file: {}", path),
+            cause: Some(Arc::new(err)),
+        })?;
+        let mut w = BufWriter::new(file);
+        self.write(&mut w)?;
+        w.flush().map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to flush the file: {}", path),
+            cause: Some(Arc::new(err)),
+        })
+    }
+}
+
+struct BaseLogitsReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl BaseLogitsReader<'_> {
+    fn read_bytes(&mut self) -> Result<[u8; 4]> {
+        if self.buf.len() - self.pos < 4 {
+            bail!(
+                ErrorKind::FormatError,
+                "unexpected end of the base logits at offset {}",
+                self.pos
+            );
+        }
+        let b = &self.buf[self.pos..self.pos + 4];
+        self.pos += 4;
+        Ok([b[0], b[1], b[2], b[3]])
+    }
+
+    fn read_u32(&mut self) -> Result<usize> {
+        Ok(u32::from_le_bytes(self.read_bytes()?) as usize)
+    }
+
+    fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.read_bytes()?))
+    }
+
+    fn read_token(&mut self, n_vocab: usize) -> Result<usize> {
+        let token = self.read_u32()?;
+        if token >= n_vocab {
+            bail!(
+                ErrorKind::FormatError,
+                "invalid token {} in the base logits, the vocab size is {}",
+                token,
+                n_vocab
+            );
+        }
+        Ok(token)
+    }
+}
+
+/// the log-softmax of all the logits.
+pub fn log_softmax_all(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    let log_sum_exp = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|l| l - log_sum_exp).collect()
+}
+
+/// the k tokens with the highest log probabilities, sorted descending.
+pub fn top_k_logprobs(logprobs: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let mut top = logprobs.iter().copied().enumerate().collect::<Vec<_>>();
+    let k = k.min(top.len());
+    if k < top.len() {
+        top.select_nth_unstable_by(k, |a, b| b.1.total_cmp(&a.1));
+        top.truncate(k);
+    }
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+    top
+}
+
+/// the KL divergence from the base distribution to the log probabilities of the model. only the
+/// top-k tokens of the base are known, the rest of the probability mass on both sides is
+/// compared as a single bucket.
+pub fn kl_divergence(base: &PositionLogits, logprobs: &[f32]) -> f32 {
+    let mut kld = 0.0;
+    let mut base_sum = 0.0;
+    let mut sum = 0.0;
+    for (token, base_logprob) in base.top.iter() {
+        let p = base_logprob.exp();
+        kld += p * (base_logprob - logprobs[*token]);
+        base_sum += p;
+        sum += logprobs[*token].exp();
+    }
+    let base_rest = 1.0 - base_sum;
+    if base_rest > 1e-6 {
+        let rest = (1.0 - sum).max(1e-6);
+        kld += base_rest * (base_rest.ln() - rest.ln());
+    }
+    kld.max(0.0)
+}
+
+/// the statistics of a model against the base logits, like llama.cpp's `--kl-divergence`.
+#[derive(Debug, Clone, Default)]
+pub struct KLDivergenceStats {
+    pub base_ppl: PerplexityStats,
+    pub ppl: PerplexityStats,
+    /// the KL divergence on every position
+    pub kld: Vec<f32>,
+    /// the probability of the true token minus the one of the base on every position
+    pub delta_p: Vec<f32>,
+    /// the number of the positions where the most probable token is the same as the base
+    pub same_top: usize,
+}
+
+impl KLDivergenceStats {
+    pub fn add(&mut self, base: &PositionLogits, logprobs: &[f32], true_token: usize) {
+        self.base_ppl.add(base.true_logprob);
+        self.ppl.add(logprobs[true_token]);
+        self.kld.push(kl_divergence(base, logprobs));
+        self.delta_p
+            .push(logprobs[true_token].exp() - base.true_logprob.exp());
+
+        let top1 = top_k_logprobs(logprobs, 1)[0].0;
+        if base.top.first().map(|t| t.0) == Some(top1) {
+            self.same_top += 1;
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.kld.len()
+    }
+
+    pub fn same_top_ratio(&self) -> f64 {
+        self.same_top as f64 / self.count().max(1) as f64
+    }
+
+    pub fn print(&self) {
+        println!("====== Perplexity statistics ======");
+        println!(
+            "PPL(base)        : {:.4} +/- {:.4}",
+            self.base_ppl.ppl(),
+            self.base_ppl.ppl_error()
+        );
+        println!(
+            "PPL(Q)           : {:.4} +/- {:.4}",
+            self.ppl.ppl(),
+            self.ppl.ppl_error()
+        );
+        println!(
+            "ln(PPL(Q)/PPL(base)): {:.6}",
+            (self.ppl.ppl() / self.base_ppl.ppl()).ln()
+        );
+
+        println!();
+        println!("====== KL divergence statistics ======");
+        let (mean, err) = mean_and_error(&self.kld);
+        println!("Mean    KLD: {:.6} +/- {:.6}", mean, err);
+        print_percentiles("KLD", &self.kld, 1.0, 6, "");
+
+        println!();
+        println!("====== Token probability statistics ======");
+        let (mean, err) = mean_and_error(&self.delta_p);
+        println!("Mean    Δp: {:.3} +/- {:.3} %", mean * 100.0, err * 100.0);
+        print_percentiles("Δp", &self.delta_p, 100.0, 3, " %");
+        let rms =
+            (self.delta_p.iter().map(|v| v * v).sum::<f32>() / self.count().max(1) as f32).sqrt();
+        println!("RMS Δp    : {:.3} %", rms * 100.0);
+        let ratio = self.same_top_ratio();
+        let err = (ratio * (1.0 - ratio) / (self.count().max(2) - 1) as f64).sqrt();
+        println!("Same top p: {:.3} +/- {:.3} %", ratio * 100.0, err * 100.0);
+    }
+}
+
+fn mean_and_error(values: &[f32]) -> (f64, f64) {
+    let n = values.len().max(1) as f64;
+    let mean = values.iter().map(|v| *v as f64).sum::<f64>() / n;
+    if values.len() < 2 {
+        return (mean, 0.0);
+    }
+    let var = values
+        .iter()
+        .map(|v| (*v as f64 - mean) * (*v as f64 - mean))
+        .sum::<f64>()
+        / (n - 1.0);
+    (mean, (var / n).sqrt())
+}
+
+/// the value at the percentile p in [0, 1] of the sorted values.
+fn percentile(sorted: &[f32], p: f64) -> f32 {
+    let idx = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[idx]
+}
+
+fn print_percentiles(name: &str, values: &[f32], scale: f32, precision: usize, unit: &str) {
+    if values.is_empty() {
+        return;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    for (label, p) in [
+        ("Maximum", 1.0),
+        ("99.9%", 0.999),
+        ("99.0%", 0.99),
+        ("90.0%", 0.9),
+        ("Median", 0.5),
+        ("10.0%", 0.1),
+        ("5.0%", 0.05),
+        ("1.0%", 0.01),
+        ("0.1%", 0.001),
+        ("Minimum", 0.0),
+    ] {
+        println!(
+            "{:<7} {}: {:.*}{}",
+            label,
+            name,
+            precision,
+            percentile(&sorted, p) * scale,
+            unit
+        );
+    }
+}
+
+/// evaluate the tokens like the perplexity does, and save the top-k log probabilities of every
+/// evaluated position.
+pub fn save_base_logits<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    tokens: &[usize],
+    ctx: usize,
+    max_chunks: Option<usize>,
+    top_k: usize,
+) -> Result<(BaseLogits, PerplexityStats)> {
+    let mut positions = vec![];
+    let mut stats = PerplexityStats::default();
+    let n_chunks = evaluate_chunks(runner, tokens, ctx, max_chunks, |_, logits, token| {
+        let logprobs = log_softmax_all(logits);
+        stats.add(logprobs[token]);
+        positions.push(PositionLogits {
+            true_logprob: logprobs[token],
+            top: top_k_logprobs(&logprobs, top_k),
+        });
+        Ok(())
+    })?;
+    let base = BaseLogits {
+        n_vocab: runner.conf().vocab_size,
+        ctx,
+        top_k,
+        tokens: tokens[..n_chunks * ctx].to_vec(),
+        positions,
+    };
+    Ok((base, stats))
+}
+
+/// replay the tokens of the base logits through the model, and compare the log probabilities
+/// on every evaluated position.
+pub fn compare_base_logits<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    base: &BaseLogits,
+    max_chunks: Option<usize>,
+) -> Result<KLDivergenceStats> {
+    if base.n_vocab != runner.conf().vocab_size {
+        bail!(
+            ErrorKind::BadInput,
+            "the vocab size {} of the model does not match the base logits {}",
+            runner.conf().vocab_size,
+            base.n_vocab
+        );
+    }
+
+    let mut stats = KLDivergenceStats::default();
+    let mut positions = base.positions.iter();
+    let n_chunks = evaluate_chunks(
+        runner,
+        &base.tokens,
+        base.ctx,
+        max_chunks,
+        |_, logits, token| {
+            let base_pos = match positions.next() {
+                Some(pos) => pos,
+                None => bail!(
+                    ErrorKind::FormatError,
+                    "the base logits file has less positions than the tokens"
+                ),
+            };
+            stats.add(base_pos, &log_softmax_all(logits), token);
+            Ok(())
+        },
+    )?;
+    if n_chunks == base.tokens.len() / base.ctx && positions.next().is_some() {
+        bail!(
+            ErrorKind::FormatError,
+            "the base logits file has more positions than the tokens"
+        );
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml_llama2::model::CpuLlamaModelLoader;
+
+    use super::*;
+
+    #[test]
+    fn test_kl_divergence() {
+        let logprobs = log_softmax_all(&[1.0, 3.0, 2.0, -1.0]);
+        assert_relative_eq!(
+            logprobs.iter().map(|v| v.exp()).sum::<f32>(),
+            1.0,
+            epsilon = 1e-6
+        );
+        let top = top_k_logprobs(&logprobs, 2);
+        assert_eq!(top.iter().map(|t| t.0).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(top_k_logprobs(&logprobs, 10).len(), 4);
+
+        let base = PositionLogits {
+            true_logprob: logprobs[2],
+            top,
+        };
+        assert_relative_eq!(kl_divergence(&base, &logprobs), 0.0, epsilon = 1e-6);
+
+        let other = log_softmax_all(&[3.0, 1.0, 2.0, -1.0]);
+        let kld = kl_divergence(&base, &other);
+        assert!(kld > 0.1, "{}", kld);
+
+        let mut stats = KLDivergenceStats::default();
+        stats.add(&base, &logprobs, 2);
+        stats.add(&base, &other, 2);
+        assert_eq!(stats.count(), 2);
+        assert_eq!(stats.same_top, 1);
+        assert_eq!(stats.delta_p[0], 0.0);
+        assert_relative_eq!(stats.kld[1], kld);
+    }
+
+    #[test]
+    fn test_base_logits_roundtrip() -> Result<()> {
+        let base = BaseLogits {
+            n_vocab: 4,
+            ctx: 2,
+            top_k: 2,
+            tokens: vec![1, 3, 2, 0],
+            positions: vec![
+                PositionLogits {
+                    true_logprob: -0.5,
+                    top: vec![(3, -0.1), (1, -2.5)],
+                },
+                PositionLogits {
+                    true_logprob: -1.5,
+                    top: vec![(0, -0.3), (2, -1.5)],
+                },
+            ],
+        };
+        let mut buf = vec![];
+        base.write(&mut buf)?;
+        assert_eq!(BaseLogits::from_bytes(&buf)?, base);
+        assert!(BaseLogits::from_bytes(&buf[..buf.len() - 2]).is_err());
+        assert!(BaseLogits::from_bytes(b"GGUF").is_err());
+
+        // the token ids must be in the vocab
+        for invalid in [
+            BaseLogits {
+                tokens: vec![1, 4, 2, 0],
+                ..base.clone()
+            },
+            BaseLogits {
+                positions: vec![PositionLogits {
+                    true_logprob: -0.5,
+                    top: vec![(3, -0.1), (7, -2.5)],
+                }],
+                ..base.clone()
+            },
+        ] {
+            let mut buf = vec![];
+            invalid.write(&mut buf)?;
+            let err = BaseLogits::from_bytes(&buf).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::FormatError);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_base_logits() -> Result<()> {
+        let text = "Once upon a time, there was a little girl named Lily. She loved to play \
+                    outside in the park with her friends. One day, she saw a big dog.";
+
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let model = CpuLlamaModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&model, 16, false)?;
+        let tokens = runner.tokenizer().encode(text, false, false, false)?;
+        let (base, base_ppl) = save_base_logits(&mut runner, &tokens, 16, Some(2), 8)?;
+        assert_eq!(base.tokens.len(), 32);
+        assert_eq!(base.positions.len(), 14);
+
+        // compare with the model itself
+        let mut runner = Llama2Runner::new(&model, 16, false)?;
+        let stats = compare_base_logits(&mut runner, &base, None)?;
+        assert_eq!(stats.count(), 14);
+        assert_eq!(stats.same_top, 14);
+        assert!(stats.kld.iter().all(|v| *v < 1e-4));
+        assert_relative_eq!(stats.ppl.ppl(), base_ppl.ppl(), epsilon = 1e-3);
+
+        // the positions are checked against the tokens on both sides
+        let mut truncated = base.clone();
+        truncated.positions.pop();
+        let err = compare_base_logits(&mut runner, &truncated, None).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        let mut extended = base.clone();
+        extended.positions.push(base.positions[0].clone());
+        let err = compare_base_logits(&mut runner, &extended, None).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert_eq!(
+            compare_base_logits(&mut runner, &extended, Some(1))?.count(),
+            7
+        );
+
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q4_0.gguf", false)?;
+        let gf = gl.open()?;
+        let model = CpuLlamaModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&model, 16, false)?;
+        let stats = compare_base_logits(&mut runner, &base, None)?;
+        assert_eq!(stats.count(), 14);
+        let mean_kld = stats.kld.iter().sum::<f32>() / 14.0;
+        assert!(mean_kld > 1e-4 && mean_kld < 1.0, "{}", mean_kld);
+        assert!(stats.same_top_ratio() > 0.5);
+        Ok(())
+    }
+}
@@ -1,26 +1,56 @@
 #[cfg(not(target_env = "msvc"))]
 extern crate jemallocator;
 
+mod gguf_diff;
+mod gguf_set;
+mod imatrix;
+mod kl_divergence;
+mod perplexity;
+mod quantize;
+mod tokenize;
+
 use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Instant;
 
 use clap::Parser;
+use clap::Subcommand;
 use clap::ValueEnum;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGUFFile;
 use crabml::gguf::GGUFFileLoader;
 use crabml::gguf::GGUFMetadataValueType;
+use crabml::safetensors::SafeTensorsFileLoader;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
 use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::llama2c::Llama2cFileLoader;
 use crabml_llama2::model::CpuLlamaModelLoader;
 use crabml_llama2::GpuLlamaModel;
 use crabml_llama2::Llama2Chat;
 use crabml_wgpu::WgpuTensor;
 use crabml_wgpu::WgpuTensorDevice;
 use crabml_wgpu::WgpuTensorDeviceOptions;
+use gguf_diff::run_gguf_diff;
+use gguf_diff::GGUFDiffArgs;
+use gguf_set::run_gguf_set;
+use gguf_set::GGUFSetArgs;
+use imatrix::run_imatrix;
+use imatrix::IMatrixArgs;
+use perplexity::run_perplexity;
+use perplexity::PerplexityArgs;
+use quantize::run_quantize;
+use quantize::QuantizeArgs;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use tokenize::run_detokenize;
+use tokenize::run_tokenize;
+use tokenize::DetokenizeArgs;
+use tokenize::TokenizeArgs;
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
@@ -28,10 +58,19 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 #[derive(Parser, Debug)]
 struct CommandArgs {
-    /// The checkpoint file to load
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The checkpoint file to load, it can also be a llama2.c `.bin` checkpoint, or a Hugging
+    /// Face model directory with the safetensors checkpoint, config.json and tokenizer.json
     #[arg(short, long, default_value_t = format!("./testdata/tinyllamas-stories-15m-f32.gguf"))]
     model: String,
 
+    /// The tokenizer.bin of a llama2.c checkpoint, defaults to the tokenizer.bin in the same
+    /// directory of the checkpoint
+    #[arg(long)]
+    tokenizer: Option<String>,
+
     // The number of tokens to generate
     #[arg(short, long, default_value_t = 300)]
     steps: usize,
@@ -63,6 +102,31 @@ struct CommandArgs {
     device: DeviceType,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Quantize the weights of a F32/F16/BF16 GGUF model into a new GGUF file
+    Quantize(QuantizeArgs),
+
+    /// Set or remove the metadata of a GGUF model, the tensor data are copied unchanged
+    GgufSet(GGUFSetArgs),
+
+    /// Compare the metadata and the tensors of two GGUF models
+    GgufDiff(GGUFDiffArgs),
+
+    /// Compute the importance matrix of a model over a calibration text file
+    Imatrix(IMatrixArgs),
+
+    /// Evaluate the perplexity of a model over a text file
+    Perplexity(PerplexityArgs),
+
+    /// Tokenize a text with the tokenizer of a GGUF model, and print the ids, the pieces and the
+    /// byte spans of the tokens
+    Tokenize(TokenizeArgs),
+
+    /// Decode the token ids into text with the tokenizer of a GGUF model
+    Detokenize(DetokenizeArgs),
+}
+
 #[derive(Clone, Debug, ValueEnum)]
 enum DeviceType {
     Cpu,
@@ -137,7 +201,8 @@ fn run_generate<U: Tensor>(runner: &mut Llama2Runner<U>, args: &CommandArgs) ->
     let metrics = runner.metrics.clone();
     let prefill_started_at = Instant::now();
     let prompt = args.prompt.clone().unwrap_or("".to_string());
-    let (prefill_pos, _prev_token, token) = runner.prefill(&prompt, true, false)?;
+    // the prompt on the command line is trusted, its special tokens are parsed like llama.cpp
+    let (prefill_pos, _prev_token, token) = runner.prefill(&prompt, true, false, true)?;
     let prefill_elapsed = prefill_started_at.elapsed();
     if args.verbose {
         dump_metrics(&runner.metrics);
@@ -220,8 +285,27 @@ fn dump_gguf_metadata(gf: &GGUFFile) {
     }
 }
 
+fn read_to_string(path: PathBuf) -> Result<String> {
+    std::fs::read_to_string(&path).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to read the file: {}", path.display()),
+        cause: Some(Arc::new(err)),
+    })
+}
+
 fn main() -> Result<()> {
     let args = CommandArgs::parse();
+    match &args.command {
+        Some(Command::Quantize(args)) => return run_quantize(args),
+        Some(Command::GgufSet(args)) => return run_gguf_set(args),
+        Some(Command::GgufDiff(args)) => return run_gguf_diff(args),
+        Some(Command::Imatrix(args)) => return run_imatrix(args),
+        Some(Command::Perplexity(args)) => return run_perplexity(args),
+        Some(Command::Tokenize(args)) => return run_tokenize(args),
+        Some(Command::Detokenize(args)) => return run_detokenize(args),
+        None => {}
+    }
+
     let start_time = Instant::now();
 
     let mut thread_num = args.threads;
@@ -231,18 +315,35 @@ fn main() -> Result<()> {
 
     // it may takes a while to open the file if mlock is enabled
     eprintln!("loading model...");
-    let gl = GGUFFileLoader::new(&args.model, args.mlock)?;
-    let gf = gl.open()?;
-
-    if args.verbose {
-        dump_gguf_metadata(&gf);
-    }
-
-    let model_cpu = CpuLlamaModelLoader::new()
+    let loader = CpuLlamaModelLoader::new()
         .with_thread_num(thread_num)
         .with_temperature(args.temperature)
-        .with_probability(args.probability)
-        .load(&gf)?;
+        .with_probability(args.probability);
+
+    // a Hugging Face model directory is loaded from the safetensors checkpoint
+    let (gl, gf, sl, st, ll);
+    let model_cpu = if Path::new(&args.model).is_dir() {
+        let dir = Path::new(&args.model);
+        let config_json = read_to_string(dir.join("config.json"))?;
+        let tokenizer_json = read_to_string(dir.join("tokenizer.json"))?;
+        sl = SafeTensorsFileLoader::new(&args.model, args.mlock)?;
+        st = sl.open()?;
+        loader.load_safetensors(&st, &config_json, &tokenizer_json)?
+    } else if args.model.ends_with(".bin") {
+        let tokenizer_path = match &args.tokenizer {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&args.model).with_file_name("tokenizer.bin"),
+        };
+        ll = Llama2cFileLoader::new(&args.model, &tokenizer_path.to_string_lossy())?;
+        loader.load_llama2c(ll.checkpoint(), ll.tokenizer())?
+    } else {
+        gl = GGUFFileLoader::new(&args.model, args.mlock)?;
+        gf = gl.open()?;
+        if args.verbose {
+            dump_gguf_metadata(&gf);
+        }
+        loader.load(&gf)?
+    };
     let conf = model_cpu.conf.clone();
 
     match args.device {
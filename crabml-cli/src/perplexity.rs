@@ -0,0 +1,324 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use clap::Args;
+use crabml::bail;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tensor::Tensor;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlamaModelLoader;
+use crabml_llama2::GpuLlamaModel;
+use crabml_wgpu::WgpuTensor;
+use crabml_wgpu::WgpuTensorDevice;
+use crabml_wgpu::WgpuTensorDeviceOptions;
+
+use crate::kl_divergence::compare_base_logits;
+use crate::kl_divergence::save_base_logits;
+use crate::kl_divergence::BaseLogits;
+use crate::DeviceType;
+
+#[derive(Args, Debug)]
+pub struct PerplexityArgs {
+    /// The GGUF model to evaluate
+    #[arg(short, long)]
+    model: String,
+
+    /// The text file to evaluate the perplexity on, it's not needed with `--kl-divergence` as
+    /// the tokens are read from the base logits file
+    #[arg(short = 'f', long)]
+    file: Option<String>,
+
+    /// The number of tokens in each chunk, the kv cache is cleared between the chunks
+    #[arg(long, default_value_t = 512)]
+    ctx: usize,
+
+    /// The maximum number of chunks to evaluate, all the chunks of the file by default
+    #[arg(long)]
+    chunks: Option<usize>,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// mlock the mmaped file, it can help run faster without swapping
+    #[arg(long, default_value_t = false)]
+    mlock: bool,
+
+    #[arg(short = 'D', long, default_value_t = DeviceType::Cpu)]
+    device: DeviceType,
+
+    /// The file to save the top-k log probabilities of the baseline model into, or to read them
+    /// from with `--kl-divergence`
+    #[arg(long)]
+    kl_divergence_base: Option<String>,
+
+    /// Compare the model against the base logits saved by `--kl-divergence-base`
+    #[arg(long, default_value_t = false)]
+    kl_divergence: bool,
+
+    /// The number of the most probable tokens to save on each position of the base logits
+    #[arg(long, default_value_t = 32)]
+    kl_top_k: usize,
+}
+
+/// the negative log likelihoods of the predicted tokens, which gives the perplexity and its
+/// standard error the same way as llama.cpp.
+#[derive(Debug, Clone, Default)]
+pub struct PerplexityStats {
+    nll: f64,
+    nll2: f64,
+    count: usize,
+}
+
+impl PerplexityStats {
+    pub fn add(&mut self, logprob: f32) {
+        let nll = -logprob as f64;
+        self.nll += nll;
+        self.nll2 += nll * nll;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn ppl(&self) -> f64 {
+        (self.nll / self.count.max(1) as f64).exp()
+    }
+
+    /// the standard error of the perplexity, it's 0 until there are at least 2 tokens.
+    pub fn ppl_error(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        let mean = self.nll / self.count as f64;
+        let var = (self.nll2 / self.count as f64 - mean * mean).max(0.0);
+        self.ppl() * (var / (self.count - 1) as f64).sqrt()
+    }
+}
+
+/// the log-softmax of the logits at the token.
+pub fn log_softmax(logits: &[f32], token: usize) -> f32 {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    let sum_exp = logits.iter().map(|l| (l - max).exp()).sum::<f32>();
+    logits[token] - max - sum_exp.ln()
+}
+
+/// split the tokens into chunks of `ctx` tokens, every chunk is evaluated from an empty kv cache
+/// and starts with the bos token like what llama.cpp does. only the predictions on the second half
+/// of each chunk are passed to `f`, as the tokens in the first half have too little context.
+/// `f` is called with the index of the chunk, the logits and the true next token.
+pub fn evaluate_chunks<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    tokens: &[usize],
+    ctx: usize,
+    max_chunks: Option<usize>,
+    mut f: impl FnMut(usize, &[f32], usize) -> Result<()>,
+) -> Result<usize> {
+    if ctx < 2 {
+        bail!(ErrorKind::BadInput, "the context size should be at least 2");
+    }
+    let mut n_chunks = tokens.len() / ctx;
+    if let Some(max_chunks) = max_chunks {
+        n_chunks = n_chunks.min(max_chunks);
+    }
+    if n_chunks == 0 {
+        bail!(
+            ErrorKind::BadInput,
+            "the text has {} tokens, at least {} tokens are required",
+            tokens.len(),
+            ctx
+        );
+    }
+
+    let bos_token = runner.tokenizer().bos_token();
+    let first = ctx / 2;
+    for (i, chunk) in tokens.chunks_exact(ctx).take(n_chunks).enumerate() {
+        runner.reset_kv_cache()?;
+        for pos in 0..ctx - 1 {
+            let token = if pos == 0 { bos_token } else { chunk[pos] };
+            let logits = runner.forward_token(token, pos)?;
+            if pos >= first {
+                f(i, logits, chunk[pos + 1])?;
+            }
+        }
+    }
+    Ok(n_chunks)
+}
+
+pub fn compute_perplexity<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    text: &str,
+    ctx: usize,
+    max_chunks: Option<usize>,
+) -> Result<PerplexityStats> {
+    let tokens = runner.tokenizer().encode(text, false, false, false)?;
+    let mut stats = PerplexityStats::default();
+    let mut current_chunk = 0;
+    evaluate_chunks(runner, &tokens, ctx, max_chunks, |chunk, logits, token| {
+        if chunk != current_chunk {
+            print_chunk_ppl(current_chunk, &stats);
+            current_chunk = chunk;
+        }
+        stats.add(log_softmax(logits, token));
+        Ok(())
+    })?;
+    print_chunk_ppl(current_chunk, &stats);
+    Ok(stats)
+}
+
+// the running perplexity over the chunks evaluated so far
+fn print_chunk_ppl(chunk: usize, stats: &PerplexityStats) {
+    println!(
+        "[{}] PPL = {:.4} +/- {:.4}",
+        chunk + 1,
+        stats.ppl(),
+        stats.ppl_error()
+    );
+}
+
+fn run_with_runner<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    args: &PerplexityArgs,
+    base: Option<&BaseLogits>,
+) -> Result<()> {
+    let started_at = Instant::now();
+    if let Some(base) = base {
+        let stats = compare_base_logits(runner, base, args.chunks)?;
+        stats.print();
+        println!(
+            "{} tokens in {:.2}s",
+            stats.count(),
+            started_at.elapsed().as_secs_f64()
+        );
+        return Ok(());
+    }
+
+    let text = match &args.file {
+        Some(file) => std::fs::read_to_string(file).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the file: {}", file),
+            cause: Some(Arc::new(err)),
+        })?,
+        None => bail!(ErrorKind::BadInput, "the text file is required by -f"),
+    };
+    let stats = match &args.kl_divergence_base {
+        Some(path) => {
+            let tokens = runner.tokenizer().encode(&text, false, false, false)?;
+            let (base, stats) =
+                save_base_logits(runner, &tokens, args.ctx, args.chunks, args.kl_top_k)?;
+            base.write_to_file(path)?;
+            eprintln!(
+                "base logits of {} positions written to {}",
+                base.positions.len(),
+                path
+            );
+            stats
+        }
+        None => compute_perplexity(runner, &text, args.ctx, args.chunks)?,
+    };
+    println!(
+        "Final estimate: PPL = {:.4} +/- {:.4} over {} tokens, {:.2}s",
+        stats.ppl(),
+        stats.ppl_error(),
+        stats.count(),
+        started_at.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+pub fn run_perplexity(args: &PerplexityArgs) -> Result<()> {
+    let mut thread_num = args.threads;
+    if thread_num == 0 {
+        thread_num = num_cpus::get();
+    }
+    let gl = GGUFFileLoader::new(&args.model, args.mlock)?;
+    let gf = gl.open()?;
+    let model_cpu = CpuLlamaModelLoader::new()
+        .with_thread_num(thread_num)
+        .load(&gf)?;
+    let conf = model_cpu.conf.clone();
+
+    // the base logits are compared in the context size they were saved with
+    let base = match (args.kl_divergence, &args.kl_divergence_base) {
+        (true, Some(path)) => Some(BaseLogits::load(path)?),
+        (true, None) => bail!(
+            ErrorKind::BadInput,
+            "--kl-divergence requires the --kl-divergence-base file"
+        ),
+        (false, _) => None,
+    };
+    let ctx = base.as_ref().map(|b| b.ctx).unwrap_or(args.ctx);
+    if ctx > conf.seq_len {
+        bail!(
+            ErrorKind::BadInput,
+            "the context size {} exceeds the max sequence length {} of the model",
+            ctx,
+            conf.seq_len
+        );
+    }
+
+    match args.device {
+        DeviceType::Cpu => {
+            let mut runner = Llama2Runner::new(&model_cpu, ctx, false)?;
+            run_with_runner(&mut runner, args, base.as_ref())
+        }
+        DeviceType::Wgpu => {
+            let device_wgpu = WgpuTensorDevice::new(
+                WgpuTensorDeviceOptions::new().with_staging_buf_bytes(conf.vocab_size * 4),
+            );
+            let model_wgpu = GpuLlamaModel::<WgpuTensor>::from_cpu(&model_cpu, device_wgpu)?;
+            let mut runner = Llama2Runner::new(&model_wgpu, ctx, false)?;
+            run_with_runner(&mut runner, args, base.as_ref())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_perplexity_stats() {
+        let logits = vec![0.5; 8];
+        assert_relative_eq!(log_softmax(&logits, 3), -(8.0f32.ln()), epsilon = 1e-6);
+        assert_relative_eq!(log_softmax(&[1.0, 3.0], 1), -(1.0 + (-2.0f32).exp()).ln());
+
+        let mut stats = PerplexityStats::default();
+        assert_eq!(stats.ppl_error(), 0.0);
+        for _ in 0..4 {
+            stats.add(log_softmax(&logits, 0));
+        }
+        assert_eq!(stats.count(), 4);
+        assert_relative_eq!(stats.ppl(), 8.0, epsilon = 1e-5);
+        assert_relative_eq!(stats.ppl_error(), 0.0, epsilon = 1e-5);
+
+        stats.add(0.0);
+        stats.add(-4.0);
+        assert!(stats.ppl_error() > 0.0);
+    }
+
+    #[test]
+    fn test_compute_perplexity() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let model = CpuLlamaModelLoader::new().load(&gf)?;
+        let text = "Once upon a time, there was a little girl named Lily. She loved to play \
+                    outside in the park with her friends. One day, she saw a big dog.";
+
+        let mut runner = Llama2Runner::new(&model, 16, false)?;
+        let stats = compute_perplexity(&mut runner, text, 16, Some(2))?;
+        // 7 predictions on the second half of each chunk
+        assert_eq!(stats.count(), 14);
+        assert!(stats.ppl() > 1.0 && stats.ppl() < 100.0, "{}", stats.ppl());
+        assert!(stats.ppl_error() > 0.0);
+
+        let mut runner = Llama2Runner::new(&model, 16, false)?;
+        assert!(compute_perplexity(&mut runner, "Lily", 16, None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+use std::borrow::Cow;
+
+use clap::Args;
+use crabml::bail;
+use crabml::cpu::CpuTensorBuf;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFFileBuilder;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFTensorInfo;
+use crabml::gguf::KEY_GENERAL_FILE_TYPE;
+use crabml::gguf::KEY_GENERAL_QUANTIZATION_VERSION;
+use crabml::imatrix::IMatrix;
+use regex::Regex;
+
+// the quantization version of ggml, bumped on the breaking changes of the quantized formats.
+const GGML_QUANTIZATION_VERSION: u32 = 2;
+
+/// the `llama_ftype` of llama.cpp on the target type. the k-quants take the `_S` variants, as
+/// all the weights are quantized into the same type unless being overridden.
+fn llama_file_type(typ: GGMLType) -> Option<u32> {
+    let ftype = match typ {
+        GGMLType::F32 => 0,
+        GGMLType::F16 => 1,
+        GGMLType::Q4_0 => 2,
+        GGMLType::Q4_1 => 3,
+        GGMLType::Q8_0 => 7,
+        GGMLType::Q5_0 => 8,
+        GGMLType::Q5_1 => 9,
+        GGMLType::Q2K => 10,
+        GGMLType::Q3K => 11,
+        GGMLType::Q4K => 14,
+        GGMLType::Q5K => 16,
+        GGMLType::Q6K => 18,
+        GGMLType::IQ2XXS => 19,
+        GGMLType::IQ4NL => 25,
+        GGMLType::IQ3S => 26,
+        GGMLType::IQ4XS => 30,
+        GGMLType::BF16 => 32,
+        _ => return None,
+    };
+    Some(ftype)
+}
+
+#[derive(Args, Debug)]
+pub struct QuantizeArgs {
+    /// The F32/F16/BF16 GGUF file to quantize
+    input: String,
+
+    /// The path to write the quantized GGUF file
+    output: String,
+
+    /// The type to quantize the weights into, like Q8_0, Q4_0, Q4_K
+    #[arg(short = 't', long = "type", default_value_t = GGMLType::Q8_0)]
+    typ: GGMLType,
+
+    /// Override the type on the tensors whose name matches the pattern, like
+    /// `output\.weight=Q6_K`. It can be specified multiple times, the first match wins.
+    #[arg(long = "tensor-type", value_parser = parse_tensor_type_override)]
+    tensor_types: Vec<(Regex, GGMLType)>,
+
+    /// Allow quantizing the tensors which are already quantized, it may hurt the quality
+    #[arg(long, default_value_t = false)]
+    allow_requantize: bool,
+
+    /// The importance matrix file computed by `crabml-cli imatrix` or llama.cpp, it improves
+    /// the quality of Q2_K, Q3_K and Q4_K
+    #[arg(long)]
+    imatrix: Option<String>,
+}
+
+fn parse_tensor_type_override(s: &str) -> Result<(Regex, GGMLType)> {
+    let (pattern, typ) = match s.rsplit_once('=') {
+        Some(v) => v,
+        None => bail!(
+            ErrorKind::BadInput,
+            "invalid tensor type override: {}, expected PATTERN=TYPE",
+            s
+        ),
+    };
+    let pattern = Regex::new(pattern).map_err(|err| Error {
+        kind: ErrorKind::BadInput,
+        message: format!("invalid tensor name pattern: {}", pattern),
+        cause: Some(std::sync::Arc::new(err)),
+    })?;
+    Ok((pattern, typ.parse()?))
+}
+
+pub struct Quantizer {
+    typ: GGMLType,
+    tensor_types: Vec<(Regex, GGMLType)>,
+    allow_requantize: bool,
+    imatrix: Option<IMatrix>,
+}
+
+impl Quantizer {
+    pub fn new(typ: GGMLType) -> Self {
+        Self {
+            typ,
+            tensor_types: vec![],
+            allow_requantize: false,
+            imatrix: None,
+        }
+    }
+
+    pub fn with_tensor_type(mut self, pattern: Regex, typ: GGMLType) -> Self {
+        self.tensor_types.push((pattern, typ));
+        self
+    }
+
+    pub fn with_allow_requantize(mut self, allow_requantize: bool) -> Self {
+        self.allow_requantize = allow_requantize;
+        self
+    }
+
+    pub fn with_imatrix(mut self, imatrix: IMatrix) -> Self {
+        self.imatrix = Some(imatrix);
+        self
+    }
+
+    /// choose the target type of the tensor. the norm weights, the router weights of the
+    /// experts and other 1-D tensors are kept in F32 unless being overridden. if the row size
+    /// is not a multiple of the block size of the target type, falls back to a type with a
+    /// smaller block.
+    pub fn tensor_type(&self, name: &str, dimensions: &[usize]) -> GGMLType {
+        let overridden = self
+            .tensor_types
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(name))
+            .map(|(_, typ)| *typ);
+        let mut typ = match overridden {
+            Some(typ) => typ,
+            None if dimensions.len() <= 1 || name.contains("ffn_gate_inp") => {
+                return GGMLType::F32;
+            }
+            None => self.typ,
+        };
+
+        // the first dimension in GGUF is the row size
+        let row_size = dimensions.first().copied().unwrap_or(0);
+        while row_size % typ.block_size() != 0 {
+            typ = match typ {
+                GGMLType::Q2K | GGMLType::Q3K => GGMLType::Q4_0,
+                GGMLType::Q4K => GGMLType::Q5_0,
+                GGMLType::Q5K => GGMLType::Q5_1,
+                GGMLType::Q6K | GGMLType::Q8K => GGMLType::Q8_0,
+                _ => GGMLType::F16,
+            };
+        }
+        typ
+    }
+
+    pub fn quantize_tensor(&self, tensor_info: &GGUFTensorInfo, typ: GGMLType) -> Result<Vec<u8>> {
+        let src_typ = tensor_info.typ();
+        if src_typ == typ {
+            return Ok(tensor_info.data().to_vec());
+        }
+        let is_float = matches!(src_typ, GGMLType::F32 | GGMLType::F16 | GGMLType::BF16);
+        if !is_float && !self.allow_requantize {
+            bail!(
+                ErrorKind::BadInput,
+                "tensor {} is already quantized as {}, requantizing is not allowed",
+                tensor_info.name(),
+                src_typ
+            );
+        }
+
+        let buf =
+            CpuTensorBuf::from_raw_bytes(tensor_info.data(), src_typ)?.dequantize(GGMLType::F32)?;
+        let importance = self
+            .imatrix
+            .as_ref()
+            .and_then(|imatrix| imatrix.importance(tensor_info.name()));
+        let dims = tensor_info.dimensions();
+        let buf = match importance {
+            // the stacked experts have the importance of every expert in turn
+            Some(importance) if dims.len() == 3 && importance.len() == dims[0] * dims[2] => {
+                let expert_len = buf.len() / dims[2];
+                let mut bytes = vec![];
+                for (data, importance) in buf
+                    .as_f32_ref()
+                    .chunks(expert_len)
+                    .zip(importance.chunks(dims[0]))
+                {
+                    let expert = CpuTensorBuf::F32(Cow::Borrowed(data));
+                    bytes.extend(expert.quantize_with_imatrix(typ, importance)?.as_bytes());
+                }
+                return Ok(bytes);
+            }
+            Some(importance) => buf.quantize_with_imatrix(typ, &importance)?,
+            None => buf.quantize(typ)?,
+        };
+        Ok(buf.as_bytes().to_vec())
+    }
+
+    /// quantize all the tensors in the file, the metadata are copied as is except the file type.
+    pub fn quantize_file(&self, gf: &GGUFFile, output: &str) -> Result<()> {
+        let tensor_count = gf.tensor_infos().len();
+        let mut tensors = Vec::with_capacity(tensor_count);
+        for (i, tensor_info) in gf.tensor_infos().iter().enumerate() {
+            let typ = self.tensor_type(tensor_info.name(), tensor_info.dimensions());
+            let data = self.quantize_tensor(tensor_info, typ)?;
+            eprintln!(
+                "[{:>4}/{:>4}] {:<32} - {:?}, {} -> {}, {:.2} MiB -> {:.2} MiB",
+                i + 1,
+                tensor_count,
+                tensor_info.name(),
+                tensor_info.dimensions(),
+                tensor_info.typ(),
+                typ,
+                tensor_info.data().len() as f64 / 1024.0 / 1024.0,
+                data.len() as f64 / 1024.0 / 1024.0,
+            );
+            tensors.push((tensor_info, typ, data));
+        }
+
+        let mut builder = GGUFFileBuilder::new();
+        let mut metadata = gf.metadata().as_hashmap().iter().collect::<Vec<_>>();
+        metadata.sort_by_key(|(k, _)| *k);
+        for (key, value) in metadata {
+            if key != KEY_GENERAL_FILE_TYPE {
+                builder.add_metadata(key.clone(), value.clone());
+            }
+        }
+        if let Some(ftype) = llama_file_type(self.typ) {
+            builder.add_metadata(KEY_GENERAL_FILE_TYPE, GGUFMetadataValue::U32(ftype));
+        }
+        builder.add_metadata(
+            KEY_GENERAL_QUANTIZATION_VERSION,
+            GGUFMetadataValue::U32(GGML_QUANTIZATION_VERSION),
+        );
+        for (tensor_info, typ, data) in tensors.iter() {
+            builder.add_tensor(GGUFTensorInfo::new(
+                tensor_info.name().to_string(),
+                tensor_info.dimensions().to_vec(),
+                *typ,
+                data,
+            ))?;
+        }
+        builder.write_to_file(output)
+    }
+}
+
+pub fn run_quantize(args: &QuantizeArgs) -> Result<()> {
+    let mut quantizer = args.tensor_types.iter().fold(
+        Quantizer::new(args.typ).with_allow_requantize(args.allow_requantize),
+        |q, (pattern, typ)| q.with_tensor_type(pattern.clone(), *typ),
+    );
+    if let Some(path) = &args.imatrix {
+        let imatrix = IMatrix::load(path)?;
+        eprintln!(
+            "loaded imatrix with {} entries computed on {} chunks of {}",
+            imatrix.entries().len(),
+            imatrix.last_call(),
+            imatrix.dataset()
+        );
+        quantizer = quantizer.with_imatrix(imatrix);
+    }
+
+    let gl = GGUFFileLoader::new(&args.input, false)?;
+    let gf = gl.open()?;
+    quantizer.quantize_file(&gf, &args.output)?;
+    eprintln!("quantized model written to {}", args.output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml_llama2::model::CpuLlamaModelLoader;
+
+    use super::*;
+
+    #[test]
+    fn test_tensor_type() -> Result<()> {
+        let q = Quantizer::new(GGMLType::Q4K)
+            .with_tensor_type(Regex::new(r"^output\.weight$").unwrap(), GGMLType::Q6K)
+            .with_tensor_type(Regex::new(r"ffn_norm").unwrap(), GGMLType::F16);
+
+        assert_eq!(
+            q.tensor_type("blk.0.attn_q.weight", &[4096, 4096]),
+            GGMLType::Q4K
+        );
+        assert_eq!(
+            q.tensor_type("blk.0.attn_norm.weight", &[4096]),
+            GGMLType::F32
+        );
+        assert_eq!(
+            q.tensor_type("blk.0.ffn_norm.weight", &[4096]),
+            GGMLType::F16
+        );
+        assert_eq!(
+            q.tensor_type("blk.0.ffn_gate_inp.weight", &[4096, 8]),
+            GGMLType::F32
+        );
+        assert_eq!(
+            q.tensor_type("blk.0.ffn_gate_exps.weight", &[4096, 14336, 8]),
+            GGMLType::Q4K
+        );
+        assert_eq!(
+            q.tensor_type("output.weight", &[4096, 32000]),
+            GGMLType::Q6K
+        );
+        assert_eq!(
+            q.tensor_type("blk.0.attn_q.weight", &[64, 64]),
+            GGMLType::Q5_0
+        );
+        assert_eq!(q.tensor_type("output.weight", &[64, 512]), GGMLType::Q8_0);
+        assert_eq!(
+            q.tensor_type("blk.0.ffn_down.weight", &[172, 64]),
+            GGMLType::F16
+        );
+
+        assert_eq!(llama_file_type(GGMLType::Q4K), Some(14));
+        assert_eq!(llama_file_type(GGMLType::Q8K), None);
+
+        assert_eq!(parse_tensor_type_override("a.b=q8_0")?.1, GGMLType::Q8_0);
+        assert!(parse_tensor_type_override("a.b").is_err());
+        assert!(parse_tensor_type_override("a.b=Q9").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantize_file() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        let output = std::env::temp_dir().join("crabml-test-quantize-q8_0.gguf");
+        let output = output.to_str().unwrap();
+        Quantizer::new(GGMLType::Q8_0).quantize_file(&gf, output)?;
+
+        let gl2 = GGUFFileLoader::new(output, false)?;
+        let gf2 = gl2.open()?;
+        assert_eq!(
+            gf2.metadata().get_u32(KEY_GENERAL_QUANTIZATION_VERSION),
+            Some(2)
+        );
+        // the file type is the MOSTLY_Q8_0 of llama.cpp
+        assert_eq!(gf2.metadata().get_u32(KEY_GENERAL_FILE_TYPE), Some(7));
+        let typ = |name: &str| gf2.get_tensor_info(name).unwrap().typ();
+        assert_eq!(typ("blk.0.attn_q.weight"), GGMLType::Q8_0);
+        assert_eq!(typ("blk.0.attn_norm.weight"), GGMLType::F32);
+        // the row size 172 can not be divided by 32
+        assert_eq!(typ("blk.0.ffn_down.weight"), GGMLType::F16);
+
+        let lm = CpuLlamaModelLoader::new().load(&gf2)?;
+        assert_eq!(lm.conf.n_layers, 5);
+        std::fs::remove_file(output).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantize_tensor_with_imatrix() -> Result<()> {
+        let data = (0..512)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let bytes = data
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let info = GGUFTensorInfo::new(
+            "blk.0.attn_q.weight".to_string(),
+            vec![256, 2],
+            GGMLType::F32,
+            &bytes,
+        );
+
+        let importance = (0..256)
+            .map(|i| if i % 4 == 0 { 100.0 } else { 0.01 })
+            .collect::<Vec<_>>();
+        let mut imatrix = IMatrix::new(1, "test");
+        imatrix.add_entry(
+            "blk.0.attn_q.weight",
+            2,
+            importance.iter().map(|v| v * 2.0).collect(),
+        );
+
+        let plain = Quantizer::new(GGMLType::Q4K).quantize_tensor(&info, GGMLType::Q4K)?;
+        let weighted = Quantizer::new(GGMLType::Q4K)
+            .with_imatrix(imatrix)
+            .quantize_tensor(&info, GGMLType::Q4K)?;
+        assert_eq!(plain.len(), weighted.len());
+
+        let error = |buf: &[u8]| -> Result<f32> {
+            let buf =
+                CpuTensorBuf::from_raw_bytes(buf, GGMLType::Q4K)?.dequantize(GGMLType::F32)?;
+            Ok(buf
+                .as_f32_ref()
+                .iter()
+                .zip(data.iter())
+                .enumerate()
+                .map(|(i, (a, b))| importance[i % 256] * (a - b) * (a - b))
+                .sum())
+        };
+        assert!(error(&weighted)? < error(&plain)?);
+
+        // every stacked expert is quantized with its own importance
+        let info = GGUFTensorInfo::new(
+            "blk.0.ffn_up_exps.weight".to_string(),
+            vec![256, 1, 2],
+            GGMLType::F32,
+            &bytes,
+        );
+        let mut imatrix = IMatrix::new(1, "test");
+        imatrix.add_entry(
+            "blk.0.ffn_up_exps.weight",
+            1,
+            [vec![1.0; 256], importance.clone()].concat(),
+        );
+        let experts = Quantizer::new(GGMLType::Q4K)
+            .with_imatrix(imatrix)
+            .quantize_tensor(&info, GGMLType::Q4K)?;
+        assert_eq!(experts.len(), weighted.len());
+        let half = experts.len() / 2;
+        assert_eq!(experts[half..], weighted[half..]);
+        let first = CpuTensorBuf::F32(Cow::Borrowed(&data[..256]))
+            .quantize_with_imatrix(GGMLType::Q4K, &[1.0; 256])?;
+        assert_eq!(experts[..half], *first.as_bytes());
+        Ok(())
+    }
+}
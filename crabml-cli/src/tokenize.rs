@@ -0,0 +1,218 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use clap::Args;
+use clap::ValueEnum;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tokenizer::TokenID;
+use crabml::tokenizer::Tokenizer;
+use crabml::tokenizer::Utf8Buf;
+use crabml_llama2::model::CpuLlamaModelLoader;
+use serde_json::json;
+
+#[derive(Clone, Debug, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct TokenizeArgs {
+    /// The GGUF model to load the tokenizer from, the weights are not loaded
+    #[arg(short, long)]
+    model: String,
+
+    /// The text to tokenize, it's read from `--file` or the stdin if not given
+    #[arg(short, long)]
+    prompt: Option<String>,
+
+    /// The file to tokenize
+    #[arg(short = 'f', long)]
+    file: Option<String>,
+
+    /// Do not prepend the bos token, it's prepended if the model asks for it by default
+    #[arg(long, default_value_t = false)]
+    no_bos: bool,
+
+    /// Take the control tokens like `<|im_start|>` in the text as plain text
+    #[arg(long, default_value_t = false)]
+    no_special: bool,
+
+    /// Print a line of the id, the piece and the byte span in the text of every token, or a
+    /// JSON array of them
+    #[arg(short, long, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+#[derive(Args, Debug)]
+pub struct DetokenizeArgs {
+    /// The GGUF model to load the tokenizer from, the weights are not loaded
+    #[arg(short, long)]
+    model: String,
+
+    /// The token ids to decode, they're read from the stdin if not given, the numbers in the
+    /// input are taken as the ids so a JSON array also works
+    ids: Vec<TokenID>,
+
+    /// Render the control tokens like `<s>` which are skipped by default
+    #[arg(long, default_value_t = false)]
+    special: bool,
+
+    /// Print the decoded text, or a JSON object of the text and the pieces of the tokens
+    #[arg(short, long, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+fn load_tokenizer(model: &str) -> Result<Tokenizer> {
+    let gl = GGUFFileLoader::new(model, false)?;
+    let gf = gl.open()?;
+    CpuLlamaModelLoader::new().load_tokenizer_only(&gf)
+}
+
+fn read_input(file: Option<&str>) -> Result<String> {
+    let input = match file {
+        Some(file) => std::fs::read_to_string(file),
+        None => std::io::read_to_string(std::io::stdin()),
+    };
+    input.map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to read {}", file.unwrap_or("the stdin")),
+        cause: Some(Arc::new(err)),
+    })
+}
+
+fn format_tokens(
+    tokenizer: &Tokenizer,
+    tokens: &[(TokenID, Range<usize>)],
+    output: &OutputFormat,
+) -> String {
+    match output {
+        OutputFormat::Text => tokens
+            .iter()
+            .map(|(id, span)| {
+                let piece = format!("{:?}", tokenizer.token(*id));
+                format!("{:>6} {:<20} {}..{}", id, piece, span.start, span.end)
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputFormat::Json => {
+            let tokens = tokens
+                .iter()
+                .map(|(id, span)| {
+                    json!({
+                        "id": id,
+                        "piece": tokenizer.token(*id),
+                        "start": span.start,
+                        "end": span.end,
+                    })
+                })
+                .collect::<Vec<_>>();
+            serde_json::Value::from(tokens).to_string()
+        }
+    }
+}
+
+fn decode_tokens(tokenizer: &Tokenizer, ids: &[TokenID], special: bool) -> Result<String> {
+    let mut buf = Utf8Buf::new();
+    let mut text = String::new();
+    for id in ids {
+        if *id >= tokenizer.vocab().len() {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!("token {} is out of the vocab", id),
+                cause: None,
+            });
+        }
+        text += &match special {
+            true => tokenizer.decode_special(*id, &mut buf)?,
+            false => tokenizer.decode(*id, &mut buf)?,
+        };
+    }
+    Ok(text)
+}
+
+/// the numbers in the input are taken as the ids, like `1 2 3` or `[1, 2, 3]`.
+fn parse_ids(input: &str) -> Result<Vec<TokenID>> {
+    input
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse().map_err(|err| Error {
+                kind: ErrorKind::BadInput,
+                message: format!("invalid token id: {}", s),
+                cause: Some(Arc::new(err)),
+            })
+        })
+        .collect()
+}
+
+pub fn run_tokenize(args: &TokenizeArgs) -> Result<()> {
+    let tokenizer = load_tokenizer(&args.model)?;
+    let text = match &args.prompt {
+        Some(prompt) => prompt.clone(),
+        None => read_input(args.file.as_deref())?,
+    };
+    let bos = !args.no_bos && tokenizer.add_bos_token();
+    let tokens = tokenizer.encode_with_offsets(&text, bos, false, !args.no_special)?;
+    println!("{}", format_tokens(&tokenizer, &tokens, &args.output));
+    Ok(())
+}
+
+pub fn run_detokenize(args: &DetokenizeArgs) -> Result<()> {
+    let tokenizer = load_tokenizer(&args.model)?;
+    let ids = if args.ids.is_empty() {
+        parse_ids(&read_input(None)?)?
+    } else {
+        args.ids.clone()
+    };
+    let text = decode_tokens(&tokenizer, &ids, args.special)?;
+    match args.output {
+        OutputFormat::Text => println!("{}", text),
+        OutputFormat::Json => {
+            let pieces = ids
+                .iter()
+                .map(|id| json!({"id": id, "piece": tokenizer.token(*id)}))
+                .collect::<Vec<_>>();
+            println!("{}", json!({"text": text, "tokens": pieces}));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_and_detokenize() -> Result<()> {
+        let tokenizer = load_tokenizer("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let tokens = tokenizer.encode_with_offsets("hello world", true, false, true)?;
+        assert_eq!(
+            format_tokens(&tokenizer, &tokens, &OutputFormat::Json),
+            r#"[{"end":0,"id":1,"piece":"<s>","start":0},{"end":5,"id":22172,"piece":"▁hello","start":0},{"end":11,"id":3186,"piece":"▁world","start":5}]"#
+        );
+        assert_eq!(
+            format_tokens(&tokenizer, &tokens[1..2], &OutputFormat::Text),
+            " 22172 \"▁hello\"             0..5"
+        );
+
+        let ids = parse_ids("[1, 22172, 3186]\n")?;
+        assert_eq!(ids, vec![1, 22172, 3186]);
+        assert_eq!(decode_tokens(&tokenizer, &ids, false)?, " hello world");
+        assert_eq!(decode_tokens(&tokenizer, &ids, true)?, "<s> hello world");
+        assert!(decode_tokens(&tokenizer, &[40000], false).is_err());
+        Ok(())
+    }
+}
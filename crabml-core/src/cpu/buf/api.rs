@@ -1,14 +1,24 @@
 use std::borrow::Cow;
 
+use half::bf16;
 use half::f16;
 
+use super::buf_bf16::bf16_buf_from_bytes;
+use super::buf_bf16::dequantize_bf16_buf;
+use super::buf_bf16::dequantize_bf16_f16_buf;
+use super::buf_bf16::quantize_f32_bf16;
+use super::buf_bf16::vec_dot_bf16_bf16;
 use super::buf_f16::dequantize_f16_buf;
 use super::buf_f16::f16_buf_from_bytes;
 use super::buf_f16::quantize_f32_f16;
 use super::buf_f32::f32_buf_from_bytes;
 use super::buf_f32::vec_dot_f32_f32;
+use super::buf_int::int_buf_from_bytes;
 use crate::bail;
 use crate::cpu::buf::buf_f16::vec_dot_f16_f16;
+use crate::cpu::buf::util::QK_K;
+use crate::cpu::buf::QuantBufIQ4NL;
+use crate::cpu::buf::QuantBufIQ4XS;
 use crate::cpu::buf::QuantBufQ2K;
 use crate::cpu::buf::QuantBufQ3K;
 use crate::cpu::buf::QuantBufQ4K;
@@ -32,6 +42,7 @@ use crate::gguf::GGMLType;
 pub enum CpuTensorBuf<'a> {
     F32(Cow<'a, [f32]>),
     F16(Cow<'a, [f16]>),
+    BF16(Cow<'a, [bf16]>),
     Q2K(QuantBufQ2K<'a>),
     Q3K(QuantBufQ3K<'a>),
     Q8_0(QuantBufQ8_0<'a>),
@@ -44,6 +55,11 @@ pub enum CpuTensorBuf<'a> {
     Q5_1(QuantBufQ5_1<'a>),
     Q5K(QuantBufQ5K<'a>),
     Q6K(QuantBufQ6K<'a>),
+    IQ4NL(QuantBufIQ4NL<'a>),
+    IQ4XS(QuantBufIQ4XS<'a>),
+    I8(Cow<'a, [i8]>),
+    I16(Cow<'a, [i16]>),
+    I32(Cow<'a, [i32]>),
 }
 
 impl<'a> CpuTensorBuf<'a> {
@@ -51,6 +67,7 @@ impl<'a> CpuTensorBuf<'a> {
         match typ {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(f32_buf_from_bytes(buf))),
             GGMLType::F16 => Ok(CpuTensorBuf::F16(f16_buf_from_bytes(buf))),
+            GGMLType::BF16 => Ok(CpuTensorBuf::BF16(bf16_buf_from_bytes(buf))),
             GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::from_bytes(buf))),
             GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::from_bytes(buf))),
             GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::from_bytes(buf))),
@@ -63,7 +80,16 @@ impl<'a> CpuTensorBuf<'a> {
             GGMLType::Q5_1 => Ok(CpuTensorBuf::Q5_1(QuantBufQ5_1::from_bytes(buf))),
             GGMLType::Q5K => Ok(CpuTensorBuf::Q5K(QuantBufQ5K::from_bytes(buf))),
             GGMLType::Q6K => Ok(CpuTensorBuf::Q6K(QuantBufQ6K::from_bytes(buf))),
-            _ => unimplemented!(),
+            GGMLType::IQ4NL => Ok(CpuTensorBuf::IQ4NL(QuantBufIQ4NL::from_bytes(buf))),
+            GGMLType::IQ4XS => Ok(CpuTensorBuf::IQ4XS(QuantBufIQ4XS::from_bytes(buf))),
+            GGMLType::I8 => Ok(CpuTensorBuf::I8(int_buf_from_bytes(buf))),
+            GGMLType::I16 => Ok(CpuTensorBuf::I16(int_buf_from_bytes(buf))),
+            GGMLType::I32 => Ok(CpuTensorBuf::I32(int_buf_from_bytes(buf))),
+            _ => bail!(
+                ErrorKind::NotImplemented,
+                "loading {} tensors is not supported",
+                typ
+            ),
         }
     }
 
@@ -71,6 +97,7 @@ impl<'a> CpuTensorBuf<'a> {
         match self {
             Self::F32(buf) => bytemuck::cast_slice(buf),
             Self::F16(buf) => bytemuck::cast_slice(buf),
+            Self::BF16(buf) => bytemuck::cast_slice(buf),
             Self::Q2K(buf) => buf.as_bytes(),
             Self::Q3K(buf) => buf.as_bytes(),
             Self::Q4_0(buf) => buf.as_bytes(),
@@ -83,6 +110,11 @@ impl<'a> CpuTensorBuf<'a> {
             Self::Q8_0(buf) => buf.as_bytes(),
             Self::Q8_1(buf) => buf.as_bytes(),
             Self::Q8K(buf) => buf.as_bytes(),
+            Self::IQ4NL(buf) => buf.as_bytes(),
+            Self::IQ4XS(buf) => buf.as_bytes(),
+            Self::I8(buf) => bytemuck::cast_slice(buf),
+            Self::I16(buf) => bytemuck::cast_slice(buf),
+            Self::I32(buf) => bytemuck::cast_slice(buf),
         }
     }
 
@@ -101,6 +133,7 @@ impl<'a> CpuTensorBuf<'a> {
         match self {
             CpuTensorBuf::F32(buf) => buf.len(),
             CpuTensorBuf::F16(buf) => buf.len(),
+            CpuTensorBuf::BF16(buf) => buf.len(),
             CpuTensorBuf::Q2K(buf) => buf.len(),
             CpuTensorBuf::Q3K(buf) => buf.len(),
             CpuTensorBuf::Q8_0(buf) => buf.len(),
@@ -113,6 +146,11 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q4K(buf) => buf.len(),
             CpuTensorBuf::Q5K(buf) => buf.len(),
             CpuTensorBuf::Q6K(buf) => buf.len(),
+            CpuTensorBuf::IQ4NL(buf) => buf.len(),
+            CpuTensorBuf::IQ4XS(buf) => buf.len(),
+            CpuTensorBuf::I8(buf) => buf.len(),
+            CpuTensorBuf::I16(buf) => buf.len(),
+            CpuTensorBuf::I32(buf) => buf.len(),
         }
     }
 
@@ -124,6 +162,7 @@ impl<'a> CpuTensorBuf<'a> {
         match self {
             CpuTensorBuf::F32(_) => GGMLType::F32,
             CpuTensorBuf::F16(_) => GGMLType::F16,
+            CpuTensorBuf::BF16(_) => GGMLType::BF16,
             CpuTensorBuf::Q2K(_) => GGMLType::Q2K,
             CpuTensorBuf::Q3K(_) => GGMLType::Q3K,
             CpuTensorBuf::Q8_0(_) => GGMLType::Q8_0,
@@ -136,13 +175,19 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q5_1(_) => GGMLType::Q5_1,
             CpuTensorBuf::Q5K(_) => GGMLType::Q5K,
             CpuTensorBuf::Q6K(_) => GGMLType::Q6K,
+            CpuTensorBuf::IQ4NL(_) => GGMLType::IQ4NL,
+            CpuTensorBuf::IQ4XS(_) => GGMLType::IQ4XS,
+            CpuTensorBuf::I8(_) => GGMLType::I8,
+            CpuTensorBuf::I16(_) => GGMLType::I16,
+            CpuTensorBuf::I32(_) => GGMLType::I32,
         }
     }
 
-    pub fn vec_dot_rhs_dtype(&self) -> GGMLType {
-        match self {
+    pub fn vec_dot_rhs_dtype(&self) -> Result<GGMLType> {
+        let typ = match self {
             CpuTensorBuf::F32(_) => GGMLType::F32,
             CpuTensorBuf::F16(_) => GGMLType::F16,
+            CpuTensorBuf::BF16(_) => GGMLType::BF16,
             CpuTensorBuf::Q2K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q3K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q8_0(_) => GGMLType::Q8_0,
@@ -155,7 +200,16 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q4K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q5K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q6K(_) => GGMLType::Q8K,
-        }
+            CpuTensorBuf::IQ4NL(_) => GGMLType::Q8_0,
+            CpuTensorBuf::IQ4XS(_) => GGMLType::Q8K,
+            // the integer tensors are not expected to be multiplied
+            CpuTensorBuf::I8(_) | CpuTensorBuf::I16(_) | CpuTensorBuf::I32(_) => bail!(
+                ErrorKind::NotImplemented,
+                "vec_dot on {} tensors is not supported",
+                self.dtype()
+            ),
+        };
+        Ok(typ)
     }
 
     /// dequantize the quantized tensors to f32 or f16.
@@ -174,6 +228,7 @@ impl<'a> CpuTensorBuf<'a> {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(match self {
                 CpuTensorBuf::F32(buf) => buf,
                 CpuTensorBuf::F16(buf) => dequantize_f16_buf(&buf, 0).collect(),
+                CpuTensorBuf::BF16(buf) => dequantize_bf16_buf(&buf, 0).collect(),
                 CpuTensorBuf::Q2K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q3K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q8_0(buf) => buf.dequantize(0).collect(),
@@ -186,8 +241,19 @@ impl<'a> CpuTensorBuf<'a> {
                 CpuTensorBuf::Q5_1(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q5K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q6K(buf) => buf.dequantize(0).collect(),
+                CpuTensorBuf::IQ4NL(buf) => buf.dequantize(0).collect(),
+                CpuTensorBuf::IQ4XS(buf) => buf.dequantize(0).collect(),
+                CpuTensorBuf::I8(buf) => buf.iter().map(|v| *v as f32).collect(),
+                CpuTensorBuf::I16(buf) => buf.iter().map(|v| *v as f32).collect(),
+                CpuTensorBuf::I32(buf) => buf.iter().map(|v| *v as f32).collect(),
             })),
-            GGMLType::F16 => unimplemented!(),
+            GGMLType::F16 => match self {
+                CpuTensorBuf::F16(buf) => Ok(CpuTensorBuf::F16(buf)),
+                CpuTensorBuf::BF16(buf) => Ok(CpuTensorBuf::F16(dequantize_bf16_f16_buf(&buf))),
+                buf => Ok(CpuTensorBuf::F16(quantize_f32_f16(
+                    buf.dequantize(GGMLType::F32)?.as_f32_ref(),
+                ))),
+            },
             _ => unreachable!(),
         }
     }
@@ -196,6 +262,7 @@ impl<'a> CpuTensorBuf<'a> {
         match dtype {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(self.as_f32_ref().to_vec().into())),
             GGMLType::F16 => Ok(CpuTensorBuf::F16(quantize_f32_f16(self.as_f32_ref()))),
+            GGMLType::BF16 => Ok(CpuTensorBuf::BF16(quantize_f32_bf16(self.as_f32_ref()))),
             GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::quantize(self.as_f32_ref()))),
             GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::quantize(self.as_f32_ref()))),
             GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::quantize(
@@ -227,11 +294,43 @@ impl<'a> CpuTensorBuf<'a> {
         }
     }
 
+    /// quantize with the importance of each column in a row, which is usually computed from an
+    /// imatrix file. the importance only takes effect on Q2_K, Q3_K and Q4_K, the other types
+    /// are quantized as usual.
+    pub fn quantize_with_imatrix(&self, dtype: GGMLType, imatrix: &[f32]) -> Result<Self> {
+        if !matches!(dtype, GGMLType::Q2K | GGMLType::Q3K | GGMLType::Q4K) {
+            return self.quantize(dtype);
+        }
+        if imatrix.is_empty() || imatrix.len() % QK_K != 0 || self.len() % imatrix.len() != 0 {
+            bail!(
+                ErrorKind::TensorError,
+                "the imatrix of {} values does not fit the rows of a {} tensor",
+                imatrix.len(),
+                dtype
+            );
+        }
+
+        let data = self.as_f32_ref();
+        match dtype {
+            GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::quantize_with_imatrix(
+                data, imatrix,
+            ))),
+            GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::quantize_with_imatrix(
+                data, imatrix,
+            ))),
+            GGMLType::Q4K => Ok(CpuTensorBuf::Q4K(QuantBufQ4K::quantize_with_imatrix(
+                data, imatrix,
+            ))),
+            _ => unreachable!(),
+        }
+    }
+
     pub fn vec_dot(&self, a_offset: usize, b: &Self, b_offset: usize, len: usize) -> f32 {
         use CpuTensorBuf::*;
         match (self, b) {
             (F32(a), F32(b)) => vec_dot_f32_f32(a, a_offset, b, b_offset, len),
             (F16(a), F16(b)) => vec_dot_f16_f16(a, a_offset, b, b_offset, len),
+            (BF16(a), BF16(b)) => vec_dot_bf16_bf16(a, a_offset, b, b_offset, len),
             (Q2K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q3K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q8_0(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
@@ -244,6 +343,8 @@ impl<'a> CpuTensorBuf<'a> {
             (Q5_1(a), Q8_1(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q5K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q6K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (IQ4NL(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (IQ4XS(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             _ => unreachable!(),
         }
     }
@@ -279,6 +380,9 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::F16(buf) => {
                 self.copy_from_iter(dequantize_f16_buf(buf, src_offset), dst_offset, len)
             }
+            CpuTensorBuf::BF16(buf) => {
+                self.copy_from_iter(dequantize_bf16_buf(buf, src_offset), dst_offset, len)
+            }
             CpuTensorBuf::Q2K(buf) => {
                 self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
             }
@@ -315,6 +419,21 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q6K(buf) => {
                 self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
             }
+            CpuTensorBuf::IQ4NL(buf) => {
+                self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
+            }
+            CpuTensorBuf::IQ4XS(buf) => {
+                self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
+            }
+            CpuTensorBuf::I8(buf) => {
+                self.copy_from_iter(buf[src_offset..].iter().map(|v| *v as f32), dst_offset, len)
+            }
+            CpuTensorBuf::I16(buf) => {
+                self.copy_from_iter(buf[src_offset..].iter().map(|v| *v as f32), dst_offset, len)
+            }
+            CpuTensorBuf::I32(buf) => {
+                self.copy_from_iter(buf[src_offset..].iter().map(|v| *v as f32), dst_offset, len)
+            }
         };
 
         Ok(())
@@ -384,6 +503,7 @@ impl Clone for CpuTensorBuf<'_> {
         match self {
             CpuTensorBuf::F32(buf) => Self::F32(buf.clone()),
             CpuTensorBuf::F16(buf) => Self::F16(buf.clone()),
+            CpuTensorBuf::BF16(buf) => Self::BF16(buf.clone()),
             CpuTensorBuf::Q2K(buf) => Self::Q2K(buf.clone()),
             CpuTensorBuf::Q3K(buf) => Self::Q3K(buf.clone()),
             CpuTensorBuf::Q8_0(buf) => Self::Q8_0(buf.clone()),
@@ -396,6 +516,11 @@ impl Clone for CpuTensorBuf<'_> {
             CpuTensorBuf::Q4K(buf) => Self::Q4K(buf.clone()),
             CpuTensorBuf::Q5K(buf) => Self::Q5K(buf.clone()),
             CpuTensorBuf::Q6K(buf) => Self::Q6K(buf.clone()),
+            CpuTensorBuf::IQ4NL(buf) => Self::IQ4NL(buf.clone()),
+            CpuTensorBuf::IQ4XS(buf) => Self::IQ4XS(buf.clone()),
+            CpuTensorBuf::I8(buf) => Self::I8(buf.clone()),
+            CpuTensorBuf::I16(buf) => Self::I16(buf.clone()),
+            CpuTensorBuf::I32(buf) => Self::I32(buf.clone()),
         }
     }
 }
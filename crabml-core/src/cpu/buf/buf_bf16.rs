@@ -0,0 +1,174 @@
+use std::borrow::Cow;
+
+use half::bf16;
+use half::f16;
+
+use super::util::pod_buf_from_bytes;
+
+/// the bf16 tensors are referenced without copying if the buffer is aligned, the tensors in the
+/// safetensors files are not always aligned to 2 bytes.
+pub fn bf16_buf_from_bytes(buf: &[u8]) -> Cow<'_, [bf16]> {
+    assert_eq!(
+        buf.len() % std::mem::size_of::<bf16>(),
+        0,
+        "Length of slice must be multiple of bf16 size"
+    );
+    pod_buf_from_bytes(buf)
+}
+
+pub fn dequantize_bf16_buf(buf: &[bf16], start: usize) -> impl Iterator<Item = f32> + '_ {
+    buf.iter().skip(start).map(|x| x.to_f32())
+}
+
+pub fn dequantize_bf16_f16_buf(buf: &[bf16]) -> Cow<'static, [f16]> {
+    buf.iter()
+        .map(|x| f16::from_f32(x.to_f32()))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+pub fn quantize_f32_bf16<'a>(buf: &[f32]) -> Cow<'a, [bf16]> {
+    buf.iter()
+        .map(|x| bf16::from_f32(*x))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+pub fn vec_dot_bf16_bf16(
+    a: &[bf16],
+    a_offset: usize,
+    b: &[bf16],
+    b_offset: usize,
+    len: usize,
+) -> f32 {
+    let ac = &a[a_offset..a_offset + len];
+    let bc = &b[b_offset..b_offset + len];
+
+    // the AVX2 kernel is chosen at runtime, the binaries are not always built with AVX2 enabled
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        return unsafe { vec_dot_bf16_bf16_avx2(ac, bc) };
+    }
+
+    vec_dot_bf16_bf16_fallback(ac, bc)
+}
+
+/// a bf16 is the upper half of a f32, it's converted into f32 by shifting it left by 16 bits.
+/// the caller must check the CPU supports AVX2 and FMA.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn vec_dot_bf16_bf16_avx2(a: &[bf16], b: &[bf16]) -> f32 {
+    use std::arch::x86_64::__m128i;
+    use std::arch::x86_64::__m256;
+    use std::arch::x86_64::_mm256_add_ps;
+    use std::arch::x86_64::_mm256_castsi256_ps;
+    use std::arch::x86_64::_mm256_cvtepu16_epi32;
+    use std::arch::x86_64::_mm256_fmadd_ps;
+    use std::arch::x86_64::_mm256_setzero_ps;
+    use std::arch::x86_64::_mm256_slli_epi32;
+    use std::arch::x86_64::_mm_loadu_si128;
+
+    use crate::cpu::archutil::x86_64::hsum_float_8;
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn load(ptr: *const bf16) -> __m256 {
+        let v = _mm_loadu_si128(ptr as *const __m128i);
+        _mm256_castsi256_ps(_mm256_slli_epi32(_mm256_cvtepu16_epi32(v), 16))
+    }
+
+    debug_assert_eq!(a.len(), b.len());
+    let len = a.len();
+    let len_rounded = len - len % 16;
+
+    unsafe {
+        let mut acc0 = _mm256_setzero_ps();
+        let mut acc1 = _mm256_setzero_ps();
+        for i in (0..len_rounded).step_by(16) {
+            let av0 = load(a.as_ptr().add(i));
+            let bv0 = load(b.as_ptr().add(i));
+            let av1 = load(a.as_ptr().add(i + 8));
+            let bv1 = load(b.as_ptr().add(i + 8));
+            acc0 = _mm256_fmadd_ps(av0, bv0, acc0);
+            acc1 = _mm256_fmadd_ps(av1, bv1, acc1);
+        }
+
+        let mut sum = hsum_float_8(_mm256_add_ps(acc0, acc1));
+        for i in len_rounded..len {
+            sum += a.get_unchecked(i).to_f32() * b.get_unchecked(i).to_f32();
+        }
+        sum
+    }
+}
+
+fn vec_dot_bf16_bf16_fallback(a: &[bf16], b: &[bf16]) -> f32 {
+    let mut sum = 0.0;
+    for i in 0..a.len() {
+        sum += a[i].to_f32() * b[i].to_f32();
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vec_dot_bf16_bf16() {
+        let a = (0..37).map(|i| i as f32 * 0.25 - 4.0).collect::<Vec<_>>();
+        let b = (0..37).map(|i| 2.0 - i as f32 * 0.125).collect::<Vec<_>>();
+        let a16 = quantize_f32_bf16(&a);
+        let b16 = quantize_f32_bf16(&b);
+
+        let expected = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f32>();
+        assert_eq!(vec_dot_bf16_bf16_fallback(&a16, &b16), expected);
+        assert_eq!(vec_dot_bf16_bf16(&a16, 0, &b16, 0, 37), expected);
+        assert_eq!(
+            vec_dot_bf16_bf16(&a16, 5, &b16, 5, 32),
+            vec_dot_bf16_bf16_fallback(&a16[5..], &b16[5..])
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_vec_dot_bf16_bf16_avx2() {
+        if !is_x86_feature_detected!("avx2") || !is_x86_feature_detected!("fma") {
+            return;
+        }
+        let a = (0..1000)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let b = (0..1000)
+            .map(|i| (i as f32 * 0.11).cos())
+            .collect::<Vec<_>>();
+        let a16 = quantize_f32_bf16(&a);
+        let b16 = quantize_f32_bf16(&b);
+        // the lengths cover the full 16 lanes, the remainder and the remainder only
+        for len in [1000, 999, 15] {
+            let expected = vec_dot_bf16_bf16_fallback(&a16[..len], &b16[..len]);
+            let got = unsafe { vec_dot_bf16_bf16_avx2(&a16[..len], &b16[..len]) };
+            assert!(
+                (got - expected).abs() < 1e-3,
+                "{} {} {}",
+                len,
+                got,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_bf16_buf_from_bytes() {
+        let bytes = [1.5f32, -2.0, 3.25]
+            .iter()
+            .flat_map(|v| bf16::from_f32(*v).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut unaligned = vec![0u8];
+        unaligned.extend_from_slice(&bytes);
+        for buf in [&bytes[..], &unaligned[1..]] {
+            let buf = bf16_buf_from_bytes(buf);
+            assert_eq!(dequantize_bf16_buf(&buf, 1).collect::<Vec<_>>(), vec![
+                -2.0, 3.25
+            ]);
+        }
+    }
+}
@@ -1,19 +1,16 @@
 use std::borrow::Cow;
-use std::slice;
 
 use half::f16;
 
-pub fn f16_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [f16]> {
-    let len = buf.len();
+use super::util::pod_buf_from_bytes;
+
+pub fn f16_buf_from_bytes(buf: &[u8]) -> Cow<'_, [f16]> {
     assert_eq!(
-        len % std::mem::size_of::<f32>(),
+        buf.len() % std::mem::size_of::<f16>(),
         0,
-        "Length of slice must be multiple of f32 size"
+        "Length of slice must be multiple of f16 size"
     );
-    let new_len = len / std::mem::size_of::<f16>();
-    let ptr = buf.as_ptr() as *const f16;
-    let f16_buf = unsafe { slice::from_raw_parts(ptr, new_len) };
-    f16_buf.into()
+    pod_buf_from_bytes(buf)
 }
 
 // it's slow to initialize a vec![f16::ZERO; buf_size], nearly 80~200ms on preparing kv cache.
@@ -0,0 +1,69 @@
+use std::borrow::Cow;
+
+use super::util::pod_buf_from_bytes;
+
+/// the integer tensors are referenced without copying if the buffer is aligned, otherwise they
+/// are copied into an owned buffer, like the tensors in a GGUF file read into memory.
+pub fn int_buf_from_bytes<T: bytemuck::Pod>(buf: &[u8]) -> Cow<'_, [T]> {
+    assert_eq!(
+        buf.len() % std::mem::size_of::<T>(),
+        0,
+        "Length of slice must be multiple of the element size"
+    );
+    pod_buf_from_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CpuTensorBuf;
+    use crate::error::Result;
+    use crate::gguf::GGMLType;
+
+    #[test]
+    fn test_int_buf_from_bytes() {
+        let bytes = [1i16, -2, 3]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        // make an unaligned slice
+        let mut unaligned = vec![0u8];
+        unaligned.extend_from_slice(&bytes);
+        for buf in [&bytes[..], &unaligned[1..]] {
+            assert_eq!(&int_buf_from_bytes::<i16>(buf)[..], &[1, -2, 3]);
+        }
+    }
+
+    #[test]
+    fn test_int_tensor_buf() -> Result<()> {
+        let i8_bytes = [1i8, -2, 3, -4].map(|v| v as u8);
+        let i16_bytes = [1i16, -2, 3, -4]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let i32_bytes = [1i32, -2, 3, -4]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let tests = vec![
+            (GGMLType::I8, &i8_bytes[..]),
+            (GGMLType::I16, &i16_bytes[..]),
+            (GGMLType::I32, &i32_bytes[..]),
+        ];
+        for (typ, bytes) in tests {
+            let buf = CpuTensorBuf::from_raw_bytes(bytes, typ)?;
+            assert_eq!(buf.dtype(), typ);
+            assert_eq!(buf.len(), 4);
+            assert_eq!(buf.as_bytes(), bytes);
+
+            let mut dst = CpuTensorBuf::from(vec![0.0; 3]);
+            dst.copy_from(&buf, 1, 0, 3)?;
+            assert_eq!(dst.as_f32_ref(), &[-2.0, 3.0, -4.0]);
+
+            let buf = buf.dequantize(GGMLType::F32)?;
+            assert_eq!(buf.as_f32_ref(), &[1.0, -2.0, 3.0, -4.0]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use std::borrow::Cow;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use half::f16;
+
+use super::util::vec_dot_iq4nl_i8;
+use super::util::KVALUES_IQ4NL;
+use super::QuantBufQ8_0;
+use crate::cpu::buf::buf_q8_0::BlockQ8_0;
+use crate::cpu::buf::util::pod_buf_from_bytes;
+
+/// IQ4_NL is like Q4_0, but the 4-bit quants are mapped into a non-linear grid.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct BlockIQ4NL {
+    d: f16,       // delta
+    qs: [u8; 16], // quants
+}
+
+impl BlockIQ4NL {
+    pub fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        for i in 0..16 {
+            buf[i] = d * KVALUES_IQ4NL[(self.qs[i] & 0x0F) as usize] as f32;
+            buf[i + 16] = d * KVALUES_IQ4NL[(self.qs[i] >> 4) as usize] as f32;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuantBufIQ4NL<'a> {
+    pub blocks: Cow<'a, [BlockIQ4NL]>,
+}
+
+impl<'a> QuantBufIQ4NL<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Self {
+        let blk_size = std::mem::size_of::<BlockIQ4NL>();
+        assert_eq!(
+            data.len() % blk_size,
+            0,
+            "data length must be a multiple of QuantBlockIQ4_NL size"
+        );
+        Self {
+            blocks: pod_buf_from_bytes(data),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.blocks)
+    }
+
+    fn blocks(&self) -> &[BlockIQ4NL] {
+        &self.blocks
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len() * 32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn dequantize(&'a self, start: usize) -> impl Iterator<Item = f32> + 'a {
+        assert!(start % 32 == 0);
+
+        let block_start = start / 32;
+        self.blocks()[block_start..].iter().flat_map(|blk| {
+            let mut buf = [0f32; 32];
+            blk.dequantize(&mut buf);
+            buf.into_iter()
+        })
+    }
+
+    pub fn vec_dot(&self, a_offset: usize, b: &QuantBufQ8_0, b_offset: usize, len: usize) -> f32 {
+        let abs = &self.blocks[a_offset / 32..(a_offset + len) / 32];
+        let bbs = &b.blocks[b_offset / 32..(b_offset + len) / 32];
+
+        vec_dot_iq4_nl_q8_0(abs, bbs)
+    }
+}
+
+pub fn vec_dot_iq4_nl_q8_0(abs: &[BlockIQ4NL], bbs: &[BlockQ8_0]) -> f32 {
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let sumi = vec_dot_iq4nl_i8(&{ a.qs }, &b.qs);
+        sumf += a.d.to_f32() * b.d.to_f32() * sumi as f32;
+    }
+    sumf
+}
+
+pub fn vec_dot_iq4_nl_q8_0_fallback(abs: &[BlockIQ4NL], bbs: &[BlockQ8_0]) -> f32 {
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let mut sumi1 = 0;
+        let mut sumi2 = 0;
+        for j in 0..16 {
+            sumi1 += b.qs[j] as i32 * KVALUES_IQ4NL[(a.qs[j] & 0x0F) as usize] as i32;
+            sumi2 += b.qs[j + 16] as i32 * KVALUES_IQ4NL[(a.qs[j] >> 4) as usize] as i32;
+        }
+        sumf += a.d.to_f32() * b.d.to_f32() * (sumi1 + sumi2) as f32;
+    }
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::buf::util::tests::dot_product;
+    use crate::cpu::buf::util::tests::generate_data;
+
+    /// a block of random quants with d = 0.0421, the expected values are the output of
+    /// `dequantize_row_iq4_nl` in ggml-quants.c on this block.
+    const FIXTURE_BLOCK: [u8; 18] = [
+        0x64, 0x29, 0xa5, 0x75, 0x20, 0x45, 0x6d, 0x84, 0x61, 0x81, 0x54, 0x12, 0xee, 0xd4, 0xb5,
+        0x30, 0x45, 0x0b,
+    ];
+    #[rustfmt::skip]
+    const FIXTURE_VALUES: [f32; 32] = [
+        -1.473999, -1.473999, -5.3485107, -1.473999, 2.9058838, -2.0635986, -4.379883, -4.379883,
+        -2.0635986, -3.4954834, 3.748169, -2.0635986, -1.473999, -5.3485107, -1.473999, 1.6003418,
+        1.0528564, -0.42114258, -3.4954834, -2.0635986, -0.9265137, 0.042114258, -0.9265137, 0.042114258,
+        -1.473999, -4.379883, 3.748169, 2.9058838, 1.6003418, -2.7374268, -2.0635986, -5.3485107,
+    ];
+
+    #[test]
+    fn test_iq4_nl_dequantize() {
+        let buf = QuantBufIQ4NL::from_bytes(&FIXTURE_BLOCK);
+        assert_eq!(buf.len(), 32);
+        assert_eq!(buf.as_bytes(), &FIXTURE_BLOCK);
+        assert_eq!(buf.dequantize(0).collect::<Vec<_>>(), FIXTURE_VALUES);
+    }
+
+    #[test]
+    fn test_iq4_nl_vec_dot_q8_0() {
+        let bytes = (0..18 * 8)
+            .map(|i| match i % 18 {
+                0 => 0x66, // d ~= 0.0013
+                1 => 0x15,
+                i => (i * 37 % 256) as u8,
+            })
+            .collect::<Vec<_>>();
+        let a = QuantBufIQ4NL::from_bytes(&bytes);
+        let b = QuantBufQ8_0::quantize(&generate_data(1.0, 256));
+
+        let a_data = a.dequantize(0).collect::<Vec<_>>();
+        let b_data = b.dequantize(0).collect::<Vec<_>>();
+        let dot_ref = dot_product(&a_data, &b_data);
+        let dot_result = a.vec_dot(0, &b, 0, 256);
+        assert!(
+            (dot_ref - dot_result).abs() < 1e-3,
+            "{dot_ref} {dot_result}"
+        );
+        let dot_fallback = vec_dot_iq4_nl_q8_0_fallback(&a.blocks, &b.blocks);
+        assert!(
+            (dot_fallback - dot_result).abs() < 1e-4,
+            "{dot_fallback} {dot_result}"
+        );
+    }
+}
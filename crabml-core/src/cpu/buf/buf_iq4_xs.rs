@@ -0,0 +1,215 @@
+use std::borrow::Cow;
+
+use bytemuck::Pod;
+use bytemuck::Zeroable;
+use half::f16;
+
+use super::util::vec_dot_iq4nl_i8;
+use super::util::KVALUES_IQ4NL;
+use super::util::QK_K;
+use super::QuantBufQ8K;
+use crate::cpu::buf::buf_q8_k::BlockQ8K;
+use crate::cpu::buf::util::pod_buf_from_bytes;
+
+/// IQ4_XS shares the non-linear grid of IQ4_NL in a super block, each 32 elements have a 6-bit
+/// scale, the low 4 bits are in `scales_l` and the high 2 bits are in `scales_h`.
+#[repr(C)]
+#[derive(Debug, Clone, Pod, Zeroable, Copy)]
+pub struct BlockIQ4XS {
+    pub d: f16,
+    pub scales_h: u16,
+    pub scales_l: [u8; QK_K / 64],
+    pub qs: [u8; QK_K / 2],
+}
+
+impl BlockIQ4XS {
+    /// the scale of the ib-th 32 elements, in the range of [-32, 31].
+    fn scale(&self, ib: usize) -> i32 {
+        let ls_l = (self.scales_l[ib / 2] >> (4 * (ib % 2))) & 0x0F;
+        let ls_h = ((self.scales_h >> (2 * ib)) & 0x03) as u8;
+        (ls_l | (ls_h << 4)) as i32 - 32
+    }
+
+    pub fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        for (ib, (qs, buf)) in self.qs.chunks(16).zip(buf.chunks_mut(32)).enumerate() {
+            let dl = d * self.scale(ib) as f32;
+            for j in 0..16 {
+                buf[j] = dl * KVALUES_IQ4NL[(qs[j] & 0x0F) as usize] as f32;
+                buf[j + 16] = dl * KVALUES_IQ4NL[(qs[j] >> 4) as usize] as f32;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuantBufIQ4XS<'a> {
+    pub blocks: Cow<'a, [BlockIQ4XS]>,
+}
+
+impl<'a> QuantBufIQ4XS<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Self {
+        let blk_size = std::mem::size_of::<BlockIQ4XS>();
+        assert_eq!(
+            data.len() % blk_size,
+            0,
+            "data length must be a multiple of QuantBlockIQ4_XS size"
+        );
+        Self {
+            blocks: pod_buf_from_bytes(data),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.blocks)
+    }
+
+    pub fn blocks(&self) -> &[BlockIQ4XS] {
+        &self.blocks
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len() * QK_K
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn dequantize(&'a self, start: usize) -> impl Iterator<Item = f32> + 'a {
+        assert!(start % QK_K == 0);
+
+        let block_start = start / QK_K;
+        self.blocks()[block_start..].iter().flat_map(|blk| {
+            let mut buf = [0f32; QK_K];
+            blk.dequantize(&mut buf);
+            buf.into_iter()
+        })
+    }
+
+    pub fn vec_dot(&self, a_offset: usize, b: &QuantBufQ8K, b_offset: usize, len: usize) -> f32 {
+        let abs = &self.blocks[a_offset / QK_K..(a_offset + len) / QK_K];
+        let bbs = &b.blocks[b_offset / QK_K..(b_offset + len) / QK_K];
+
+        vec_dot_iq4_xs_q8_k(abs, bbs)
+    }
+}
+
+pub fn vec_dot_iq4_xs_q8_k(abs: &[BlockIQ4XS], bbs: &[BlockQ8K]) -> f32 {
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let mut sumi = 0;
+        for (ib, (qs, q8)) in a.qs.chunks(16).zip(b.qs.chunks(32)).enumerate() {
+            sumi += a.scale(ib) * vec_dot_iq4nl_i8(qs, q8);
+        }
+        sumf += a.d.to_f32() * b.d * sumi as f32;
+    }
+    sumf
+}
+
+pub fn vec_dot_iq4_xs_q8_k_fallback(abs: &[BlockIQ4XS], bbs: &[BlockQ8K]) -> f32 {
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let d4d8 = a.d.to_f32() * b.d;
+        for (ib, (qs, q8)) in a.qs.chunks(16).zip(b.qs.chunks(32)).enumerate() {
+            let mut sumi1 = 0;
+            let mut sumi2 = 0;
+            for j in 0..16 {
+                sumi1 += q8[j] as i32 * KVALUES_IQ4NL[(qs[j] & 0x0F) as usize] as i32;
+                sumi2 += q8[j + 16] as i32 * KVALUES_IQ4NL[(qs[j] >> 4) as usize] as i32;
+            }
+            sumf += d4d8 * a.scale(ib) as f32 * (sumi1 + sumi2) as f32;
+        }
+    }
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::buf::util::tests::dot_product;
+    use crate::cpu::buf::util::tests::generate_data;
+
+    /// a super block of random scales and quants with d = 0.00731, the expected values are the
+    /// output of `dequantize_row_iq4_xs` in ggml-quants.c on this block.
+    const FIXTURE_BLOCK: [u8; 136] = [
+        0x7c, 0x1f, 0x99, 0xd4, 0x8e, 0xb0, 0x6e, 0x2f, 0x77, 0xe9, 0xf7, 0x95, 0x71, 0xba, 0x2f,
+        0xe5, 0xbc, 0xf3, 0xb9, 0x12, 0x13, 0x93, 0x78, 0x8e, 0xa9, 0x18, 0xee, 0x75, 0xe4, 0x89,
+        0x56, 0x7a, 0xdb, 0x3f, 0x80, 0x92, 0x91, 0x77, 0x6f, 0x62, 0xef, 0x99, 0xb3, 0x69, 0x83,
+        0x46, 0x87, 0xed, 0xfb, 0x24, 0xab, 0xa7, 0x5f, 0x69, 0x00, 0x2d, 0x12, 0x28, 0xe5, 0x23,
+        0x86, 0x5e, 0x53, 0x22, 0xc2, 0xbc, 0xba, 0x63, 0x95, 0x32, 0x9b, 0x34, 0x97, 0x40, 0xe2,
+        0x17, 0xe5, 0xfc, 0x09, 0xba, 0x98, 0xe2, 0xed, 0x9b, 0x0a, 0x5e, 0x6e, 0x77, 0xc8, 0x1b,
+        0xca, 0x77, 0x55, 0x09, 0xb7, 0x1a, 0xa4, 0x30, 0x42, 0xe1, 0x53, 0x38, 0x6a, 0xbc, 0xa9,
+        0xb4, 0x7b, 0x37, 0x4e, 0x32, 0x2c, 0x64, 0xcd, 0x03, 0x78, 0x87, 0xca, 0xcb, 0x3c, 0x84,
+        0x02, 0xc7, 0x95, 0x09, 0x07, 0xe1, 0xf7, 0x7b, 0xb9, 0x75, 0x0e, 0xa2, 0x84, 0xe1, 0x55,
+        0x13,
+    ];
+    #[rustfmt::skip]
+    const FIXTURE_VALUES: [f32; 256] = [
+        0.1461792, -0.19003296, 0.1461792, 0.5116272, 1.5202637, -0.365448, -1.651825, 0.5116272,
+        -0.77474976, 0.9501648, -0.19003296, 1.2132874, 0.9501648, 0.9501648, -0.01461792, -1.3009949,
+        0.1461792, -1.3009949, -1.651825, -0.19003296, 0.1461792, -0.55548096, 1.2132874, -1.3009949,
+        -0.55548096, -1.651825, -0.55548096, 1.5202637, 1.5202637, -0.19003296, 0.1461792, -0.01461792,
+        0.76013184, 0.05847168, 5.2039795, -2.0465088, -2.8651123, 0.76013184, -1.286377, 1.461792,
+        2.2219238, 6.6073, -7.4259033, -4.8531494, -6.0810547, -0.5847168, 6.6073, -4.8531494,
+        1.461792, -6.0810547, 5.2039795, -0.5847168, 5.2039795, 0.05847168, -2.0465088, -0.5847168,
+        4.034546, -3.8006592, 0.05847168, 0.76013184, 0.76013184, -0.5847168, -1.286377, -1.286377,
+        -13.2146, -1.5202637, 7.6013184, -1.5202637, 7.6013184, 2.572754, 1.1694336, -8.069092,
+        -4.4438477, 5.7302246, -4.4438477, 1.1694336, -13.2146, -1.5202637, 14.851807, -8.069092,
+        -10.407959, -1.5202637, -4.4438477, 2.572754, -0.11694336, 5.7302246, -0.11694336, -10.407959,
+        -13.2146, 9.706299, -2.923584, -2.923584, 4.0930176, 2.572754, 14.851807, 9.706299,
+        -6.6730804, 0.08039856, -2.8139496, -5.2259064, -1.7687683, 7.155472, -5.2259064, -6.6730804,
+        -6.6730804, 4.2611237, 2.009964, -5.2259064, -2.8139496, -6.6730804, 3.0551453, -3.9395294,
+        -8.36145, -6.6730804, 7.155472, -6.6730804, 0.08039856, -2.8139496, -2.8139496, -6.6730804,
+        4.2611237, 3.0551453, 3.0551453, -1.7687683, 1.0451813, -5.2259064, 1.0451813, -5.2259064,
+        1.3156128, 16.708282, 10.919586, 1.3156128, 4.604645, -6.972748, -1.7102966, -3.289032,
+        -0.13156128, 10.919586, -9.077728, -4.9993286, -3.289032, -11.708954, -11.708954, 1.3156128,
+        -1.7102966, 6.4465027, -11.708954, 13.682373, -11.708954, -14.866425, 16.708282, -4.9993286,
+        -1.7102966, -11.708954, -11.708954, -1.7102966, 16.708282, 4.604645, 2.8943481, 1.3156128,
+        -0.0730896, -2.7774048, -1.82724, 0.730896, 2.558136, -0.9501648, 0.730896, -1.82724,
+        3.5813904, 9.282379, 6.066437, 7.6013184, 4.750824, -0.0730896, -1.82724, -3.8737488,
+        -3.8737488, 7.6013184, -3.8737488, 0.730896, 2.558136, 9.282379, -2.7774048, 7.6013184,
+        -1.82724, 4.750824, 3.5813904, -6.5049744, 2.558136, 4.750824, 1.6079712, -2.7774048,
+        -0.09501648, 0.35813904, -0.27774048, 0.0730896, -0.65049744, 0.6066437, -0.38737488, 0.35813904,
+        -0.50431824, 0.4750824, -0.00730896, 0.0730896, -0.182724, -0.27774048, -0.38737488, 0.35813904,
+        -0.182724, -0.27774048, 0.0730896, 0.4750824, 0.35813904, 0.4750824, 0.6066437, 0.16079712,
+        -0.38737488, 0.9282379, 0.0730896, -0.00730896, -0.38737488, -0.38737488, 0.4750824, -0.00730896,
+        -10.919586, -1.3156128, -4.604645, 1.7102966, -1.3156128, -13.682373, -1.3156128, 4.9993286,
+        1.7102966, -4.604645, 11.708954, -10.919586, -6.4465027, -13.682373, -4.604645, -8.551483,
+        -16.708282, 6.972748, 1.7102966, -16.708282, -16.708282, 11.708954, 14.866425, -1.3156128,
+        4.9993286, -1.3156128, -16.708282, 3.289032, 0.13156128, 11.708954, -4.604645, -13.682373,
+    ];
+
+    #[test]
+    fn test_iq4_xs_dequantize() {
+        let buf = QuantBufIQ4XS::from_bytes(&FIXTURE_BLOCK);
+        assert_eq!(buf.len(), 256);
+        assert_eq!(buf.as_bytes(), &FIXTURE_BLOCK);
+        assert_eq!(buf.dequantize(0).collect::<Vec<_>>(), FIXTURE_VALUES);
+    }
+
+    #[test]
+    fn test_iq4_xs_vec_dot_q8_k() {
+        let mut bytes = vec![];
+        for i in 0..2 {
+            bytes.extend([0x66, 0x15, 0x1b + i, 0xe4, 0x9c, 0x3f, 0x70, 0x5a + i]);
+            bytes.extend((0..128).map(|j| (j * 37 % 256) as u8 ^ i));
+        }
+        let a = QuantBufIQ4XS::from_bytes(&bytes);
+        let b = QuantBufQ8K::quantize(&generate_data(1.0, 512));
+
+        let a_data = a.dequantize(0).collect::<Vec<_>>();
+        let b_data = b.dequantize(0).collect::<Vec<_>>();
+        let dot_ref = dot_product(&a_data, &b_data);
+        let dot_result = a.vec_dot(0, &b, 0, 512);
+        assert!(
+            (dot_ref - dot_result).abs() < 1e-3,
+            "{dot_ref} {dot_result}"
+        );
+        let dot_fallback = vec_dot_iq4_xs_q8_k_fallback(&a.blocks, &b.blocks);
+        assert!(
+            (dot_fallback - dot_result).abs() < 1e-4,
+            "{dot_fallback} {dot_result}"
+        );
+    }
+}
@@ -92,11 +92,8 @@ impl<'a> QuantBufQ2K<'a> {
             data.len() % blk_size == 0,
             "data length must be a multiple of BlockQ2K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ2K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
@@ -106,7 +103,16 @@ impl<'a> QuantBufQ2K<'a> {
 
     pub fn quantize(data: &[f32]) -> Self {
         assert!(data.len() % QK_K == 0);
-        let bs = quantize_f32_q2_k(data);
+        let bs = quantize_f32_q2_k(data, None);
+        Self { blocks: bs.into() }
+    }
+
+    /// quantize with the importance of each column in a row, the row size is the length of
+    /// `imatrix`.
+    pub fn quantize_with_imatrix(data: &[f32], imatrix: &[f32]) -> Self {
+        assert!(data.len() % QK_K == 0);
+        assert!(imatrix.len() % QK_K == 0 && data.len() % imatrix.len() == 0);
+        let bs = quantize_f32_q2_k(data, Some(imatrix));
         Self { blocks: bs.into() }
     }
 
@@ -143,22 +149,33 @@ impl<'a> QuantBufQ2K<'a> {
 
 use crate::cpu::buf::buf_q8_k::BlockQ8K;
 
-pub fn quantize_f32_q2_k(data: &[f32]) -> Vec<BlockQ2K> {
+pub fn quantize_f32_q2_k(data: &[f32], imatrix: Option<&[f32]>) -> Vec<BlockQ2K> {
     let mut bs = Vec::with_capacity(data.len() / QK_K);
 
     const Q4SCALE: f32 = 15f32;
     let mut l = [0u8; QK_K];
     let mut mins = [0f32; QK_K / 16];
     let mut scales = [0f32; QK_K / 16];
+    let mut weights = [0f32; QK_K];
     // super block
     for (i, data_chunk) in data.chunks(QK_K).enumerate() {
         bs.push(BlockQ2K::new_zero());
 
+        let qw = match imatrix {
+            Some(imatrix) => {
+                let imatrix = super_block_imatrix(imatrix, i);
+                imatrix_weights(data_chunk, imatrix, 1.0, &mut weights);
+                Some(&weights[..])
+            }
+            None => None,
+        };
+
         let mut max_scale = 0f32;
         let mut max_min = 0f32;
         // 16 elements in each block
         for (j, (data_block, l)) in data_chunk.chunks(16).zip(l.chunks_mut(16)).enumerate() {
-            scales[j] = make_qkx1_quants(16, 3, data_block, l, &mut mins[j], 5);
+            let qw = qw.map(|qw| &qw[16 * j..16 * (j + 1)]);
+            scales[j] = make_qkx1_quants(16, 3, data_block, l, &mut mins[j], 5, qw);
             let scale = scales[j];
             if scale > max_scale {
                 max_scale = scale;
@@ -193,7 +210,7 @@ pub fn quantize_f32_q2_k(data: &[f32]) -> Vec<BlockQ2K> {
             }
             let dm = Into::<f32>::into(bs[i].dmin) * (block_scale >> 4) as f32;
             for ii in 0..16 {
-                let _l = nearest_i32((data[16 * j + ii] + dm) / d);
+                let _l = nearest_i32((data_chunk[16 * j + ii] + dm) / d);
                 let _l = 0.max(3.min(_l));
                 l[16 * j + ii] = _l as u8;
             }
@@ -291,4 +308,38 @@ mod tests {
 
         assert!(diff < MAX_Q2K_PRODUCT_ERROR);
     }
+
+    #[test]
+    fn test_q2_k_quantize_with_imatrix() {
+        // two rows of 512 columns
+        let data = generate_data(0.0, 1024);
+        let imatrix = generate_imatrix(512);
+        let dequantize = |bs: QuantBufQ2K| bs.dequantize(0).collect::<Vec<_>>();
+
+        let plain = dequantize(QuantBufQ2K::quantize(&data));
+        let weighted = dequantize(QuantBufQ2K::quantize_with_imatrix(&data, &imatrix));
+        let plain_error = weighted_error(&data, &plain, &imatrix);
+        let weighted_error = weighted_error(&data, &weighted, &imatrix);
+        assert!(
+            weighted_error < plain_error,
+            "{weighted_error} >= {plain_error}"
+        );
+    }
+
+    #[test]
+    fn test_q2_k_quantize_super_blocks() {
+        // each super block is quantized from its own data, the blocks after the first one
+        // were once quantized from the data of the first super block
+        let data = [generate_data(0.0, 256), generate_data(3.0, 256)].concat();
+        let bs = QuantBufQ2K::quantize(&data);
+        for (i, chunk) in data.chunks(256).enumerate() {
+            let expected = QuantBufQ2K::quantize(chunk);
+            assert_eq!(bs.as_bytes()[84 * i..84 * (i + 1)], *expected.as_bytes());
+        }
+
+        let dequantized = bs.dequantize(0).collect::<Vec<_>>();
+        let diff0 = array_rmse(&dequantized[..256], &data[..256]);
+        let diff1 = array_rmse(&dequantized[256..], &data[256..]);
+        assert!(diff1 < diff0 * 2.0, "{diff0} {diff1}");
+    }
 }
@@ -107,11 +107,8 @@ impl<'a> QuantBufQ3K<'a> {
             data.len() % blk_size == 0,
             "data length must be a multiple of BlockQ3K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ3K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
@@ -121,7 +118,16 @@ impl<'a> QuantBufQ3K<'a> {
 
     pub fn quantize(data: &[f32]) -> Self {
         assert!(data.len() % QK_K == 0);
-        let bs = quantize_f32_q3_k(data);
+        let bs = quantize_f32_q3_k(data, None);
+        Self { blocks: bs.into() }
+    }
+
+    /// quantize with the importance of each column in a row, the row size is the length of
+    /// `imatrix`.
+    pub fn quantize_with_imatrix(data: &[f32], imatrix: &[f32]) -> Self {
+        assert!(data.len() % QK_K == 0);
+        assert!(imatrix.len() % QK_K == 0 && data.len() % imatrix.len() == 0);
+        let bs = quantize_f32_q3_k(data, Some(imatrix));
         Self { blocks: bs.into() }
     }
 
@@ -156,19 +162,30 @@ impl<'a> QuantBufQ3K<'a> {
     }
 }
 
-pub fn quantize_f32_q3_k(data: &[f32]) -> Vec<BlockQ3K> {
+pub fn quantize_f32_q3_k(data: &[f32], imatrix: Option<&[f32]>) -> Vec<BlockQ3K> {
     let mut bs = Vec::with_capacity(data.len() / QK_K);
 
     let mut l = [0i8; QK_K];
     let mut scales = [0f32; QK_K / 16];
+    let mut weights = [0f32; QK_K];
 
     for (i, data_chunk) in data.chunks(QK_K).enumerate() {
         bs.push(BlockQ3K::new_zero());
 
+        let qw = match imatrix {
+            Some(imatrix) => {
+                let imatrix = super_block_imatrix(imatrix, i);
+                imatrix_weights(data_chunk, imatrix, 2.0, &mut weights);
+                Some(&weights[..])
+            }
+            None => None,
+        };
+
         let mut max_scale = 0f32;
         let mut amax = 0f32;
         for (j, (data_block, l)) in data_chunk.chunks(16).zip(l.chunks_mut(16)).enumerate() {
-            scales[j] = make_q3_quants(16, 4, data_block, l, true);
+            let qw = qw.map(|qw| &qw[16 * j..16 * (j + 1)]);
+            scales[j] = make_q3_quants(16, 4, data_block, l, true, qw);
             let scale = scales[j].abs();
             if scale > amax {
                 amax = scale;
@@ -363,4 +380,21 @@ mod tests {
         // temporarily pass the diff assertion at present.
         // assert!(diff < MAX_Q3K_PRODUCT_ERROR);
     }
+
+    #[test]
+    fn test_q3_k_quantize_with_imatrix() {
+        // two rows of 512 columns
+        let data = generate_data(0.0, 1024);
+        let imatrix = generate_imatrix(512);
+        let dequantize = |bs: QuantBufQ3K| bs.dequantize(0).collect::<Vec<_>>();
+
+        let plain = dequantize(QuantBufQ3K::quantize(&data));
+        let weighted = dequantize(QuantBufQ3K::quantize_with_imatrix(&data, &imatrix));
+        let plain_error = weighted_error(&data, &plain, &imatrix);
+        let weighted_error = weighted_error(&data, &weighted, &imatrix);
+        assert!(
+            weighted_error < plain_error,
+            "{weighted_error} >= {plain_error}"
+        );
+    }
 }
@@ -6,6 +6,7 @@ use half::f16;
 
 use super::QuantBufQ8_0;
 use crate::cpu::buf::buf_q8_0::BlockQ8_0;
+use crate::cpu::buf::util::pod_buf_from_bytes;
 
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
@@ -32,7 +33,7 @@ pub struct QuantBufQ4_0<'a> {
     pub blocks: Cow<'a, [BlockQ4_0]>,
 }
 
-impl<'a> QuantBufQ4_0<'_> {
+impl<'a> QuantBufQ4_0<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
         let blk_size = std::mem::size_of::<BlockQ4_0>();
         assert_eq!(
@@ -40,11 +41,8 @@ impl<'a> QuantBufQ4_0<'_> {
             0,
             "data length must be a multiple of QuantBlockQ4_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4_0, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
     pub fn quantize(data: &[f32]) -> Self {
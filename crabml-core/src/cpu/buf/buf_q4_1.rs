@@ -6,6 +6,7 @@ use half::f16;
 
 use super::QuantBufQ8_1;
 use crate::cpu::buf::buf_q8_1::BlockQ8_1;
+use crate::cpu::buf::util::pod_buf_from_bytes;
 
 #[repr(C)]
 #[derive(Debug, Clone, Pod, Zeroable, Copy)]
@@ -43,11 +44,8 @@ impl<'a> QuantBufQ4_1<'a> {
             0,
             "data length must be a multiple of QuantBlockQ8_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4_1, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
@@ -8,8 +8,11 @@ use super::util::get_scale_min_k4;
 use super::util::QK_K;
 use super::QuantBufQ8K;
 use crate::cpu::buf::buf_q8_k::BlockQ8K;
+use crate::cpu::buf::util::imatrix_weights;
 use crate::cpu::buf::util::make_qkx1_quants;
 use crate::cpu::buf::util::nearest_i32;
+use crate::cpu::buf::util::pod_buf_from_bytes;
+use crate::cpu::buf::util::super_block_imatrix;
 
 #[repr(C)]
 #[derive(Debug, Clone, Pod, Zeroable, Copy)]
@@ -52,7 +55,7 @@ pub struct QuantBufQ4K<'a> {
     pub blocks: Cow<'a, [BlockQ4K]>,
 }
 
-impl<'a> QuantBufQ4K<'_> {
+impl<'a> QuantBufQ4K<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
         let blk_size = std::mem::size_of::<BlockQ4K>();
         assert_eq!(
@@ -60,16 +63,22 @@ impl<'a> QuantBufQ4K<'_> {
             0,
             "data length must be a multiple of QuantBlockQ4_K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
     pub fn quantize(data: &[f32]) -> Self {
-        let bs = quantize_f32_q4_k(data);
+        let bs = quantize_f32_q4_k(data, None);
+        Self { blocks: bs.into() }
+    }
+
+    /// quantize with the importance of each column in a row, the row size is the length of
+    /// `imatrix`.
+    pub fn quantize_with_imatrix(data: &[f32], imatrix: &[f32]) -> Self {
+        assert!(data.len() % QK_K == 0);
+        assert!(imatrix.len() % QK_K == 0 && data.len() % imatrix.len() == 0);
+        let bs = quantize_f32_q4_k(data, Some(imatrix));
         Self { blocks: bs.into() }
     }
 
@@ -108,20 +117,30 @@ impl<'a> QuantBufQ4K<'_> {
     }
 }
 
-pub fn quantize_f32_q4_k(data: &[f32]) -> Vec<BlockQ4K> {
+pub fn quantize_f32_q4_k(data: &[f32], imatrix: Option<&[f32]>) -> Vec<BlockQ4K> {
     assert!(data.len() % QK_K == 0);
     let mut bs = Vec::with_capacity(data.len() / QK_K);
 
     let mut scales = [0f32; 8];
     let mut mins = [0f32; 8];
-    for chunk in data.chunks(QK_K) {
+    let mut weights = [0f32; QK_K];
+    for (i, chunk) in data.chunks(QK_K).enumerate() {
+        let qw = match imatrix {
+            Some(imatrix) => {
+                let imatrix = super_block_imatrix(imatrix, i);
+                imatrix_weights(chunk, imatrix, 2.0, &mut weights);
+                Some(&weights[..])
+            }
+            None => None,
+        };
         let mut l = [0_u8; 256];
         let mut max_scale = 0.0;
         let mut max_min = 0.0;
         let mut block_scales = [0_u8; 12];
 
         for (ib, (data_block, l)) in chunk.chunks(32).zip(l.chunks_mut(32)).enumerate() {
-            scales[ib] = make_qkx1_quants(32, 15, data_block, l, &mut mins[ib], 5);
+            let qw = qw.map(|qw| &qw[32 * ib..32 * (ib + 1)]);
+            scales[ib] = make_qkx1_quants(32, 15, data_block, l, &mut mins[ib], 5, qw);
             let scale = scales[ib];
             if scale > max_scale {
                 max_scale = scale;
@@ -283,6 +302,8 @@ mod tests {
     use crate::cpu::buf::util::tests::array_rmse;
     use crate::cpu::buf::util::tests::dot_product;
     use crate::cpu::buf::util::tests::generate_data;
+    use crate::cpu::buf::util::tests::generate_imatrix;
+    use crate::cpu::buf::util::tests::weighted_error;
     use crate::cpu::buf::QuantBufQ8K;
 
     const TEST_SIZE: usize = 256;
@@ -313,4 +334,21 @@ mod tests {
 
         assert!(diff < MAX_Q4K_PRODUCT_ERROR);
     }
+
+    #[test]
+    fn test_q4_k_quantize_with_imatrix() {
+        // two rows of 512 columns
+        let data = generate_data(0.0, 1024);
+        let imatrix = generate_imatrix(512);
+        let dequantize = |bs: QuantBufQ4K| bs.dequantize(0).collect::<Vec<_>>();
+
+        let plain = dequantize(QuantBufQ4K::quantize(&data));
+        let weighted = dequantize(QuantBufQ4K::quantize_with_imatrix(&data, &imatrix));
+        let plain_error = weighted_error(&data, &plain, &imatrix);
+        let weighted_error = weighted_error(&data, &weighted, &imatrix);
+        assert!(
+            weighted_error < plain_error,
+            "{weighted_error} >= {plain_error}"
+        );
+    }
 }
@@ -9,6 +9,7 @@ use half::f16;
 
 use super::QuantBufQ8_0;
 use crate::cpu::buf::buf_q8_0::BlockQ8_0;
+use crate::cpu::buf::util::pod_buf_from_bytes;
 
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
@@ -41,7 +42,7 @@ pub struct QuantBufQ5_0<'a> {
     pub blocks: Cow<'a, [BlockQ5_0]>,
 }
 
-impl<'a> QuantBufQ5_0<'_> {
+impl<'a> QuantBufQ5_0<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
         let blk_size = std::mem::size_of::<BlockQ5_0>();
         assert_eq!(
@@ -49,11 +50,8 @@ impl<'a> QuantBufQ5_0<'_> {
             0,
             "data length must be a multiple of QuantBlockQ5_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ5_0, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
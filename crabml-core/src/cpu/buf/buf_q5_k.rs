@@ -9,6 +9,7 @@ use super::util::QK_K;
 use crate::cpu::buf::buf_q8_k::BlockQ8K;
 use crate::cpu::buf::util::make_qkx1_quants;
 use crate::cpu::buf::util::nearest_i32;
+use crate::cpu::buf::util::pod_buf_from_bytes;
 
 #[repr(C)]
 #[derive(Debug, Clone, Pod, Zeroable, Copy)]
@@ -65,7 +66,7 @@ pub struct QuantBufQ5K<'a> {
     pub blocks: Cow<'a, [BlockQ5K]>,
 }
 
-impl<'a> QuantBufQ5K<'_> {
+impl<'a> QuantBufQ5K<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
         let blk_size = std::mem::size_of::<BlockQ5K>();
         assert_eq!(
@@ -73,11 +74,8 @@ impl<'a> QuantBufQ5K<'_> {
             0,
             "data length must be a multiple of QuantBlockQ5_K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ5K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
     pub fn quantize(data: &[f32]) -> Self {
@@ -133,7 +131,7 @@ pub fn quantize_f32_q5_k(data: &[f32]) -> Vec<BlockQ5K> {
         let mut block_scales = [0_u8; 12];
 
         for (ib, (data_block, l)) in chunk.chunks(32).zip(l.chunks_mut(32)).enumerate() {
-            scales[ib] = make_qkx1_quants(32, 31, data_block, l, &mut mins[ib], 9);
+            scales[ib] = make_qkx1_quants(32, 31, data_block, l, &mut mins[ib], 9, None);
             let scale = scales[ib];
             if scale > max_scale {
                 max_scale = scale;
@@ -7,6 +7,7 @@ use half::f16;
 use crate::cpu::buf::buf_q8_k::BlockQ8K;
 use crate::cpu::buf::util::make_qx_quants;
 use crate::cpu::buf::util::nearest_i32;
+use crate::cpu::buf::util::pod_buf_from_bytes;
 
 #[repr(C)]
 #[derive(Debug, Clone, Zeroable, Copy, Pod)]
@@ -52,7 +53,7 @@ pub struct QuantBufQ6K<'a> {
     pub blocks: Cow<'a, [BlockQ6K]>,
 }
 
-impl<'a> QuantBufQ6K<'_> {
+impl<'a> QuantBufQ6K<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
         let blk_size = std::mem::size_of::<BlockQ6K>();
         assert_eq!(
@@ -60,11 +61,8 @@ impl<'a> QuantBufQ6K<'_> {
             0,
             "data length must be a multiple of QuantBlockQ6_K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ6K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
@@ -122,7 +120,7 @@ pub fn quantize_f32_q6_k(data: &[f32]) -> Vec<BlockQ6K> {
 
         // Find the maximum absolute scale in the chunk
         for (ib, (data_block, l)) in chunk.chunks(16).zip(l.chunks_mut(16)).enumerate() {
-            scales[ib] = make_qx_quants(16, 32, data_block, l, 1);
+            scales[ib] = make_qx_quants(16, 32, data_block, l, 1, None);
             let scale = scales[ib];
             let abs_scale = scale.abs();
             if abs_scale > max_abs_scale {
@@ -5,6 +5,8 @@ use bytemuck::Pod;
 use bytemuck::Zeroable;
 use half::f16;
 
+use crate::cpu::buf::util::pod_buf_from_bytes;
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 pub struct BlockQ8_0 {
@@ -36,11 +38,8 @@ impl<'a> QuantBufQ8_0<'a> {
             0,
             "data length must be a multiple of QuantBlockQ8_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8_0, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
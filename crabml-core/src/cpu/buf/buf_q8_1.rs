@@ -4,6 +4,8 @@ use bytemuck::Pod;
 use bytemuck::Zeroable;
 use half::f16;
 
+use crate::cpu::buf::util::pod_buf_from_bytes;
+
 /// Q8_1 is only used as intermediate format for matmul on Q4_1, Q5_1 quantization. There's no need to implement
 /// vec_dot for Q8_1. Compare to Q8_0, Q8_1 adds an extra `sum(d * qs[i])` value to the dot product
 /// calculation. Take Q4_1 as example, it adds an extra `min` value than Q4_0. So calculating the dot product
@@ -17,7 +19,7 @@ pub struct QuantBufQ8_1<'a> {
     pub blocks: Cow<'a, [BlockQ8_1]>,
 }
 
-impl<'a> QuantBufQ8_1<'_> {
+impl<'a> QuantBufQ8_1<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
         let blk_size = std::mem::size_of::<BlockQ8_1>();
         assert_eq!(
@@ -25,11 +27,8 @@ impl<'a> QuantBufQ8_1<'_> {
             0,
             "data length must be a multiple of QuantBlockQ8_1 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8_1, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
@@ -3,6 +3,8 @@ use std::borrow::Cow;
 use bytemuck::Pod;
 use bytemuck::Zeroable;
 
+use crate::cpu::buf::util::pod_buf_from_bytes;
+
 #[repr(C)]
 #[derive(Debug, Clone, Zeroable, Pod, Copy)]
 pub struct BlockQ8K {
@@ -25,7 +27,7 @@ pub struct QuantBufQ8K<'a> {
     pub blocks: Cow<'a, [BlockQ8K]>,
 }
 
-impl<'a> QuantBufQ8K<'_> {
+impl<'a> QuantBufQ8K<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
         let blk_size = std::mem::size_of::<BlockQ8K>();
         assert_eq!(
@@ -33,11 +35,8 @@ impl<'a> QuantBufQ8K<'_> {
             0,
             "data length must be a multiple of QuantBlockQ8_K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: pod_buf_from_bytes(data),
         }
     }
 
@@ -1,10 +1,14 @@
 pub mod api;
 pub use api::CpuTensorBuf;
 
+pub mod buf_bf16;
 pub mod buf_f16;
 pub mod buf_f32;
+pub mod buf_int;
+pub mod buf_iq4_nl;
+pub mod buf_iq4_xs;
 
-mod util;
+pub(crate) mod util;
 
 pub mod buf_q2_k;
 pub mod buf_q3_k;
@@ -19,6 +23,8 @@ pub mod buf_q8_0;
 pub mod buf_q8_1;
 pub mod buf_q8_k;
 
+pub use buf_iq4_nl::QuantBufIQ4NL;
+pub use buf_iq4_xs::QuantBufIQ4XS;
 pub use buf_q2_k::QuantBufQ2K;
 pub use buf_q3_k::QuantBufQ3K;
 pub use buf_q4_0::QuantBufQ4_0;
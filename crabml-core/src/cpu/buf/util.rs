@@ -2,11 +2,51 @@
 //!
 //! Including shared constants and functions
 
+use std::borrow::Cow;
+use std::simd::i16x16;
+use std::simd::i8x16;
+use std::simd::num::SimdInt;
+use std::simd::num::SimdUint;
+use std::simd::u8x16;
+
+/// the elements are referenced without copying if the buffer is aligned, otherwise they are
+/// copied into an owned buffer, the GGUF buffers are not always aligned to the element type.
+pub fn pod_buf_from_bytes<T: bytemuck::Pod>(buf: &[u8]) -> Cow<'_, [T]> {
+    match bytemuck::try_cast_slice(buf) {
+        Ok(buf) => Cow::Borrowed(buf),
+        Err(_) => buf
+            .chunks_exact(std::mem::size_of::<T>())
+            .map(bytemuck::pod_read_unaligned)
+            .collect(),
+    }
+}
+
 /// Super-block size for Quants-K.
 ///
 /// `QK_K` elements in a super block
 pub const QK_K: usize = 256;
 
+/// The non-linear 4-bit grid shared by IQ4_NL and IQ4_XS.
+pub const KVALUES_IQ4NL: [i8; 16] = [
+    -127, -104, -83, -65, -49, -35, -22, -10, 1, 13, 25, 38, 53, 69, 89, 113,
+];
+
+/// the dot product of 32 IQ4_NL quants and 32 8-bit quants. the quants are packed in 16 bytes,
+/// the low nibbles make the first 16 values. the grid is looked up with a byte shuffle, and the
+/// products of a pair never overflow i16 as the 8-bit quants are in [-128, 127].
+#[inline]
+pub fn vec_dot_iq4nl_i8(qs: &[u8], q8: &[i8]) -> i32 {
+    let grid = u8x16::from_array(KVALUES_IQ4NL.map(|v| v as u8));
+    let qs = u8x16::from_slice(&qs[..16]);
+    let lo: i8x16 = grid.swizzle_dyn(qs & u8x16::splat(0x0F)).cast();
+    let hi: i8x16 = grid.swizzle_dyn(qs >> 4).cast();
+    let q8_lo = i8x16::from_slice(&q8[..16]);
+    let q8_hi = i8x16::from_slice(&q8[16..32]);
+    let sum: i16x16 =
+        lo.cast::<i16>() * q8_lo.cast::<i16>() + hi.cast::<i16>() * q8_hi.cast::<i16>();
+    sum.cast::<i32>().reduce_sum()
+}
+
 pub fn nearest_i32(fval: f32) -> i32 {
     assert!(fval <= 4194303.0f32);
     let mut val = (fval + 12582912.0f32) as i32;
@@ -15,6 +55,24 @@ pub fn nearest_i32(fval: f32) -> i32 {
     (i & 0x007fffff) - 0x00400000
 }
 
+/// the slice of the importance matrix for the i-th super block, the importance matrix has a
+/// value for each column in a row, and a row is made of several super blocks.
+pub fn super_block_imatrix(imatrix: &[f32], i: usize) -> &[f32] {
+    let offset = (i * QK_K) % imatrix.len();
+    &imatrix[offset..offset + QK_K]
+}
+
+/// like llama.cpp, the weights of the quantization errors are the importance of the columns
+/// scaled by the magnitude of each value, `sigma2_factor` tunes the contribution of the average
+/// magnitude of the super block.
+pub fn imatrix_weights(data: &[f32], imatrix: &[f32], sigma2_factor: f32, weights: &mut [f32]) {
+    let sum_x2 = data.iter().map(|x| x * x).sum::<f32>();
+    let sigma2 = sigma2_factor * sum_x2 / data.len() as f32;
+    for ((w, &x), &qw) in weights.iter_mut().zip(data.iter()).zip(imatrix.iter()) {
+        *w = qw * (sigma2 + x * x).sqrt();
+    }
+}
+
 #[inline]
 pub(crate) fn get_scale_min_k4(j: usize, q: &[u8], d: &mut u8, m: &mut u8) {
     if j < 4 {
@@ -26,7 +84,17 @@ pub(crate) fn get_scale_min_k4(j: usize, q: &[u8], d: &mut u8, m: &mut u8) {
     }
 }
 
-pub fn make_qx_quants(n: usize, nmax: i32, data: &[f32], ls: &mut [i8], rmse_type: i32) -> f32 {
+/// quantize `data` into `ls` with a scale, `nmax` is the max absolute value of the quants.
+/// the errors are weighted by `quant_weights` if given, which are usually the importance of
+/// each column computed from an imatrix, otherwise the weights are decided by `rmse_type`.
+pub fn make_qx_quants(
+    n: usize,
+    nmax: i32,
+    data: &[f32],
+    ls: &mut [i8],
+    rmse_type: i32,
+    quant_weights: Option<&[f32]>,
+) -> f32 {
     let mut max = 0.0;
     let mut abs_max = 0.0;
     for &x in data.iter().take(n) {
@@ -52,13 +120,18 @@ pub fn make_qx_quants(n: usize, nmax: i32, data: &[f32], ls: &mut [i8], rmse_typ
         return 1.0 / iscale;
     }
     let weight_type = rmse_type % 2;
+    let weight = |i: usize, xi: f32| match quant_weights {
+        Some(qw) => qw[i],
+        None if weight_type == 1 => xi * xi,
+        None => 1.0,
+    };
     let mut sumlx = 0f32;
     let mut suml2 = 0f32;
     for (i, &xi) in data.iter().take(n).enumerate() {
         let l = nearest_i32(iscale * xi);
         let l = l.clamp(-nmax, nmax - 1);
         ls[i] = (l + nmax) as i8;
-        let w = if weight_type == 1 { xi * xi } else { 1.0 };
+        let w = weight(i, xi);
         let l = l as f32;
         sumlx += w * xi * l;
         suml2 += w * l * l;
@@ -76,7 +149,7 @@ pub fn make_qx_quants(n: usize, nmax: i32, data: &[f32], ls: &mut [i8], rmse_typ
             if l + nmax != ls[i] as i32 {
                 changed = true;
             }
-            let w = if weight_type == 1 { xi * xi } else { 1f32 };
+            let w = weight(i, xi);
             let l = l as f32;
             slx += w * xi * l;
             sl2 += w * l * l;
@@ -96,7 +169,7 @@ pub fn make_qx_quants(n: usize, nmax: i32, data: &[f32], ls: &mut [i8], rmse_typ
     for _itry in 0..5 {
         let mut n_changed = 0;
         for (i, &xi) in data.iter().take(n).enumerate() {
-            let w = if weight_type == 1 { xi * xi } else { 1. };
+            let w = weight(i, xi);
             let l = ls[i] as i32 - nmax;
             let mut slx = sumlx - w * xi * l as f32;
             if slx > 0. {
@@ -131,10 +204,10 @@ pub fn make_qx_quants(n: usize, nmax: i32, data: &[f32], ls: &mut [i8], rmse_typ
         iscale = -(nmax as f32 + 0.1f32 * is as f32) / max;
         let mut sumlx = 0.;
         let mut suml2 = 0.;
-        for &xi in data.iter().take(n) {
+        for (i, &xi) in data.iter().take(n).enumerate() {
             let l = nearest_i32(iscale * xi);
             let l = l.clamp(-nmax, nmax - 1);
-            let w = if weight_type == 1 { xi * xi } else { 1. };
+            let w = weight(i, xi);
             let l = l as f32;
             sumlx += w * xi * l;
             suml2 += w * l * l;
@@ -151,6 +224,8 @@ pub fn make_qx_quants(n: usize, nmax: i32, data: &[f32], ls: &mut [i8], rmse_typ
     scale
 }
 
+/// quantize `data` into `l` with a scale and a min, the min is returned in `the_min`. the
+/// scale and the min are fit by the least squares weighted by `quant_weights` if given.
 pub fn make_qkx1_quants(
     n: usize,
     nmax: i32,
@@ -158,6 +233,7 @@ pub fn make_qkx1_quants(
     l: &mut [u8],
     the_min: &mut f32,
     ntry: i32,
+    quant_weights: Option<&[f32]>,
 ) -> f32 {
     let mut min = data[0];
     let mut max = data[0];
@@ -181,11 +257,13 @@ pub fn make_qkx1_quants(
         min = 0.0f32;
     }
 
+    let weight = |i: usize| quant_weights.map(|qw| qw[i]).unwrap_or(1.0);
+    let sum_w = (0..n).map(weight).sum::<f32>();
     let mut iscale = nmax as f32 / (max - min);
     let mut scale = 1.0f32 / iscale;
     for _ in 0..ntry {
         let mut sumlx = 0.0f32;
-        let mut suml2 = 0i32;
+        let mut suml2 = 0.0f32;
         let mut did_change = false;
         for i in 0..n {
             let _l = nearest_i32(iscale * (data[i] - min));
@@ -194,15 +272,19 @@ pub fn make_qkx1_quants(
                 l[i] = _l as u8;
                 did_change = true;
             }
-            sumlx += (data[i] - min) * _l as f32;
-            suml2 += _l * _l;
+            let w = weight(i);
+            sumlx += w * (data[i] - min) * _l as f32;
+            suml2 += w * (_l * _l) as f32;
         }
-        scale = sumlx / suml2 as f32;
+        if suml2 == 0.0 {
+            break;
+        }
+        scale = sumlx / suml2;
         let mut sum = 0.0f32;
         for i in 0..n {
-            sum += data[i] - scale * l[i] as f32;
+            sum += weight(i) * (data[i] - scale * l[i] as f32);
         }
-        min = sum / n as f32;
+        min = if sum_w > 0.0 { sum / sum_w } else { 0.0 };
         if min > 0f32 {
             min = 0f32;
         }
@@ -215,7 +297,16 @@ pub fn make_qkx1_quants(
     scale
 }
 
-pub fn make_q3_quants(n: usize, nmax: i32, data: &[f32], l: &mut [i8], do_rmse: bool) -> f32 {
+/// like `make_qx_quants`, but the quants are kept signed in `l` during the search. the errors
+/// are weighted by `quant_weights` if given, otherwise by the square of each value.
+pub fn make_q3_quants(
+    n: usize,
+    nmax: i32,
+    data: &[f32],
+    l: &mut [i8],
+    do_rmse: bool,
+    quant_weights: Option<&[f32]>,
+) -> f32 {
     let mut max = 0f32;
     let mut amax = 0f32;
     for &d in data.iter().take(n) {
@@ -234,21 +325,22 @@ pub fn make_q3_quants(n: usize, nmax: i32, data: &[f32], l: &mut [i8], do_rmse:
     }
     let iscale = -nmax as f32 / max;
     if do_rmse {
+        let weight = |i: usize, d: f32| quant_weights.map(|qw| qw[i]).unwrap_or(d * d);
         let mut sumlx = 0f32;
         let mut suml2 = 0f32;
-        for (&d, l) in data.iter().zip(l.iter_mut()).take(n) {
+        for (i, (&d, l)) in data.iter().zip(l.iter_mut()).take(n).enumerate() {
             let mut _l = nearest_i32(iscale * d);
             _l = _l.min(nmax - 1).max(-nmax);
             *l = _l as i8;
-            let w = d * d;
+            let w = weight(i, d);
             sumlx += w * d * _l as f32;
             suml2 += w * (_l * _l) as f32;
         }
         // try at most 5 times
         for _ in 0..5 {
             let mut n_changed = 0;
-            for (&d, l) in data.iter().zip(l.iter_mut()).take(n) {
-                let w = d * d;
+            for (i, (&d, l)) in data.iter().zip(l.iter_mut()).take(n).enumerate() {
+                let w = weight(i, d);
                 let mut slx = sumlx - w * d * *l as f32;
                 if slx > 0f32 {
                     let mut sl2 = suml2 - w * (*l as f32) * (*l as f32);
@@ -286,6 +378,8 @@ pub fn make_q3_quants(n: usize, nmax: i32, data: &[f32], l: &mut [i8], do_rmse:
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crate::cpu::buf::buf_q4_k::BlockQ4K;
+    use crate::cpu::buf::QuantBufQ4K;
 
     /// Generate synthetic test data
     pub fn generate_data(offset: f32, n: usize) -> Vec<f32> {
@@ -315,6 +409,22 @@ pub(crate) mod tests {
         f32::sqrt(sum) / n as f32
     }
 
+    /// Calculate the squared errors weighted by the importance of each column
+    pub fn weighted_error(s1: &[f32], s2: &[f32], imatrix: &[f32]) -> f32 {
+        s1.iter()
+            .zip(s2.iter())
+            .enumerate()
+            .map(|(i, (a, b))| imatrix[i % imatrix.len()] * (a - b) * (a - b))
+            .sum()
+    }
+
+    /// Generate an importance matrix which emphasizes one in every 4 columns
+    pub fn generate_imatrix(n: usize) -> Vec<f32> {
+        (0..n)
+            .map(|i| if i % 4 == 0 { 100.0 } else { 0.01 })
+            .collect()
+    }
+
     /// Calculate the dot product of original inputs for test reference
     pub fn dot_product(s1: &[f32], s2: &[f32]) -> f32 {
         let mut sum = 0.0;
@@ -324,6 +434,31 @@ pub(crate) mod tests {
         sum
     }
 
+    #[test]
+    fn test_make_qkx1_quants_with_weights() {
+        let data = generate_data(0.0, 32);
+        let mut l1 = [0u8; 32];
+        let mut l2 = [0u8; 32];
+        let mut min1 = 0.0;
+        let mut min2 = 0.0;
+        let scale1 = make_qkx1_quants(32, 15, &data, &mut l1, &mut min1, 5, None);
+        let scale2 = make_qkx1_quants(32, 15, &data, &mut l2, &mut min2, 5, Some(&[1.0; 32]));
+        assert_eq!((scale1, min1, l1), (scale2, min2, l2));
+    }
+
+    #[test]
+    fn test_make_qkx1_quants_with_zero_weights() {
+        // the weights are zero where the quants are non-zero, the scale must not be divided
+        // by the zero sum of the weighted squares
+        let data = [0.0, 1.0, 0.0, 1.0];
+        let mut l = [0u8; 4];
+        let mut min = 0.0;
+        let weights = [1.0, 0.0, 1.0, 0.0];
+        let scale = make_qkx1_quants(4, 3, &data, &mut l, &mut min, 5, Some(&weights));
+        assert!(scale.is_finite() && min.is_finite(), "{scale} {min}");
+        assert_eq!(l, [0, 3, 0, 3]);
+    }
+
     #[test]
     fn test_nearest_i32() {
         let data_against = [
@@ -357,4 +492,21 @@ pub(crate) mod tests {
         assert_eq!(sc, 63);
         assert_eq!(m, 63);
     }
+
+    #[test]
+    fn test_pod_buf_from_bytes_unaligned() {
+        let blocks = QuantBufQ4K::quantize(&generate_data(0.0, 512));
+        let bytes = blocks.as_bytes();
+        // make an unaligned slice
+        let mut unaligned = vec![0u8];
+        unaligned.extend_from_slice(bytes);
+        let aligned = pod_buf_from_bytes::<BlockQ4K>(bytes);
+        let copied = pod_buf_from_bytes::<BlockQ4K>(&unaligned[1..]);
+        assert!(matches!(aligned, Cow::Borrowed(_)));
+        assert!(matches!(copied, Cow::Owned(_)));
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(&aligned),
+            bytemuck::cast_slice::<_, u8>(&copied)
+        );
+    }
 }
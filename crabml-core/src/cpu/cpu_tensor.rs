@@ -27,7 +27,7 @@ pub struct CpuTensor<'a> {
 // copying the owned buffer. Feel free to clone() the tensor.
 impl<'a> CpuTensor<'a> {
     pub fn new(buf: Vec<f32>, shape: &[usize], device: CpuTensorDeviceRef<'a>) -> Result<Self> {
-        if buf.len() != shape.iter().product() {
+        if buf.len() != shape.iter().product::<usize>() {
             bail!(
                 ErrorKind::TensorError,
                 "invalid shape {:?} for data of length {}",
@@ -340,11 +340,21 @@ impl<'a> Tensor for CpuTensor<'a> {
         let _t = self.device.metrics.export_walltime.track();
         assert!(self.is_contiguous());
 
-        dst.iter_mut()
-            .zip(self.buf.iter_f32())
-            .for_each(|(dst, src)| {
-                *dst = src;
-            });
+        match self.buf.dtype() {
+            GGMLType::F32 => dst
+                .iter_mut()
+                .zip(self.buf.iter_f32())
+                .for_each(|(dst, src)| {
+                    *dst = src;
+                }),
+            GGMLType::I8 | GGMLType::I16 | GGMLType::I32 => {
+                let buf = self.buf.clone().dequantize(GGMLType::F32)?;
+                dst.iter_mut().zip(buf.iter_f32()).for_each(|(dst, src)| {
+                    *dst = src;
+                });
+            }
+            typ => bail!(ErrorKind::TensorError, "can not export tensor of {:?}", typ),
+        }
         Ok(())
     }
 
@@ -381,7 +391,7 @@ impl<'a> Tensor for CpuTensor<'a> {
         let strider1 = self.strider();
         let strider2 = x.strider();
         // let _t = self.device.metrics.matmul_walltime.track();
-        primitives::matmul_vec(&self.device, bufa, bufb, bufc, strider1, strider2);
+        primitives::matmul_vec(&self.device, bufa, bufb, bufc, strider1, strider2)?;
         Ok(c)
     }
 
@@ -443,6 +453,14 @@ impl<'a> Tensor for CpuTensor<'a> {
         primitives::rms_norm_inplace(buf1, &strider1, eps)?;
         Ok(self)
     }
+
+    fn layer_norm_inplace(mut self, eps: f32) -> Result<Self> {
+        let _t = self.device.metrics.layer_norm_walltime.track();
+        let strider1 = self.strider().clone();
+        let buf1 = self.buf_mut();
+        primitives::layer_norm_inplace(buf1, &strider1, eps)?;
+        Ok(self)
+    }
 }
 
 #[cfg(test)]
@@ -463,6 +481,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_int_tensor() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let bytes = [1i32, -2, 3, -4]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let t1 = CpuTensor::from_bytes(&bytes, GGMLType::I32, &[2, 2], device.clone())?;
+        assert_eq!(t1.dtype(), GGMLType::I32);
+
+        let mut dst = vec![0.0; 4];
+        t1.export(&mut dst)?;
+        assert_eq!(dst, vec![1.0, -2.0, 3.0, -4.0]);
+
+        let mut t2 = CpuTensor::new(vec![0.0; 2], &[2], device.clone())?;
+        t2.copy_rows_from(&t1, &[1])?;
+        assert_eq!(t2.to_vec(), vec![3.0, -4.0]);
+
+        let x = CpuTensor::new(vec![1.0; 2], &[2], device.clone())?;
+        let err = t1.matmul_vec(&x).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::NotImplemented);
+        Ok(())
+    }
+
     #[test]
     fn test_copy_from() -> Result<()> {
         // 1 2
@@ -505,6 +547,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_layer_norm() -> Result<()> {
+        fn simple_layernorm(x: &mut [f32]) {
+            let mean = x.iter().sum::<f32>() / x.len() as f32;
+            let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / x.len() as f32;
+            for i in x {
+                *i = (*i - mean) / (var + 1e-5).sqrt();
+            }
+        }
+
+        let device = CpuTensorDevice::new();
+        let v = (0..64)
+            .map(|i| (i * i % 17) as f32 + 100.0)
+            .collect::<Vec<_>>();
+        let t = CpuTensor::new(v.clone(), &[2, 32], device.clone())?;
+        let t = t.layer_norm_inplace(1e-5)?;
+
+        let mut expected = v.clone();
+        simple_layernorm(&mut expected[0..32]);
+        simple_layernorm(&mut expected[32..64]);
+        assert_relative_eq!(&t.to_vec()[..], &expected[..], epsilon = 1e-4);
+
+        // the mean is removed, so it differs from the rms norm on the same input
+        let mean = t.to_vec()[0..32].iter().sum::<f32>() / 32.0;
+        assert_relative_eq!(mean, 0.0, epsilon = 1e-5);
+        Ok(())
+    }
+
     #[test]
     fn test_rope() -> Result<()> {
         let device = CpuTensorDevice::new();
@@ -523,6 +593,20 @@ mod tests {
             epsilon = 1e-5
         );
 
+        // the partial neox rope rotates the pairs of (i, i + rope_dim / 2) in each head
+        let v1 = (0..32).map(|v| v as f32).collect::<Vec<_>>();
+        let t1 = CpuTensor::new(v1, &[2, 16], device.clone())?;
+        let r1 = t1.rope_inplace(RopeMode::Neox, 1, 4)?;
+        assert_relative_eq!(
+            &r1.to_vec()[..],
+            &[
+                -1.682942, 0.9699505, 1.0806046, 3.0098498, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0,
+                11.0, 12.0, 13.0, 14.0, 15.0, -6.5016408, 16.809153, 23.188977, 19.169047, 20.0,
+                21.0, 22.0, 23.0, 24.0, 25.0, 26.0, 27.0, 28.0, 29.0, 30.0, 31.0
+            ][..],
+            epsilon = 1e-5
+        );
+
         Ok(())
     }
 
@@ -537,6 +621,16 @@ mod tests {
             12.0, 12.0
         ]);
 
+        // the rows of the output are shorter than the chunk of the threads
+        let w = CpuTensor::new((0..8).map(|v| v as f32).collect(), &[4, 2], device.clone())?;
+        let b = CpuTensor::new((0..10).map(|v| v as f32).collect(), &[5, 2], device.clone())?;
+        let out = w.matmul_vec(&b)?;
+        assert_eq!(out.shape(), &[5, 4]);
+        assert_eq!(out.to_vec(), &[
+            1.0, 3.0, 5.0, 7.0, 3.0, 13.0, 23.0, 33.0, 5.0, 23.0, 41.0, 59.0, 7.0, 33.0, 59.0,
+            85.0, 9.0, 43.0, 77.0, 111.0
+        ]);
+
         Ok(())
     }
 
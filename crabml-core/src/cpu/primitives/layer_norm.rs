@@ -0,0 +1,56 @@
+use std::simd::f32x32;
+use std::simd::num::SimdFloat;
+
+use crate::cpu::buf::CpuTensorBuf;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::tensor::TensorStrider;
+
+/// normalize each row to zero mean and unit variance, the weight and the bias are not
+/// applied here.
+pub fn layer_norm_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    eps: f32,
+) -> Result<()> {
+    assert!(strider.is_contiguous());
+    assert!(strider.shape().len() == 1 || strider.shape().len() == 2);
+    assert!(buf.dtype() == GGMLType::F32);
+
+    let (rows, cols) = if strider.shape().len() == 1 {
+        (1, strider.shape()[0])
+    } else {
+        (strider.shape()[0], strider.shape()[1])
+    };
+
+    let buf = buf.as_f32_mut();
+    for row in 0..rows {
+        layer_norm_inplace_vec_f32(&mut buf[row * cols..(row + 1) * cols], eps)
+    }
+
+    Ok(())
+}
+
+fn layer_norm_inplace_vec_f32(x: &mut [f32], eps: f32) {
+    let len = x.len();
+    assert!(len % 32 == 0);
+    let mut sum = 0.0;
+    for chunk in x.as_chunks::<32>().0 {
+        sum += f32x32::from_slice(chunk).reduce_sum();
+    }
+    let mean = sum / len as f32;
+
+    let mut var = 0.0;
+    for chunk in x.as_chunks_mut::<32>().0 {
+        let mut v = f32x32::from_slice(chunk);
+        v -= f32x32::splat(mean);
+        v.copy_to_slice(chunk);
+        var += (v * v).reduce_sum();
+    }
+    let scale = 1.0 / ((var / len as f32) + eps).sqrt();
+    for chunk in x.as_chunks_mut::<32>().0 {
+        let mut v = f32x32::from_slice(chunk);
+        v *= f32x32::splat(scale);
+        v.copy_to_slice(chunk);
+    }
+}
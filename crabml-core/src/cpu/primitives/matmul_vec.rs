@@ -1,5 +1,6 @@
 use crate::cpu::buf::CpuTensorBuf;
 use crate::cpu::CpuTensorDeviceRef;
+use crate::error::Result;
 use crate::tensor::metrics::TimeMetric;
 use crate::tensor::TensorStrider;
 
@@ -13,13 +14,13 @@ pub fn matmul_vec<'a>(
     bufc: &mut CpuTensorBuf<'a>,
     strider1: &TensorStrider,
     strider2: &TensorStrider,
-) {
+) -> Result<()> {
     assert!(strider1.is_contiguous());
     assert!(strider2.is_contiguous());
     assert!(strider1.shape().last() == strider2.shape().last());
 
     let (m, k) = (strider1.shape()[0], strider1.shape()[1]);
-    gemv_dense_2d_2d(device, bufa, bufb, bufc, m, k);
+    gemv_dense_2d_2d(device, bufa, bufb, bufc, m, k)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -30,13 +31,13 @@ fn gemv_dense_2d_2d(
     bufc: &mut CpuTensorBuf, // (b, m)
     m: usize,
     k: usize,
-) {
+) -> Result<()> {
     let metrics = device.metrics.clone();
     let bufc = bufc.as_f32_mut();
 
     let bufb = &{
         let _t = metrics.matmul_quantize_walltime.track();
-        bufb.quantize(bufa.vec_dot_rhs_dtype()).unwrap()
+        bufb.quantize(bufa.vec_dot_rhs_dtype()?)?
     };
     let thread_num = device.thread_num();
 
@@ -64,10 +65,11 @@ fn gemv_dense_2d_2d(
                         work_buf.chunks_mut(chunk_len).enumerate().for_each(
                             |(chunk_idx, chunk_buf)| {
                                 let elem_idx = work_idx * work_len + chunk_idx * chunk_len;
-                                let mi = elem_idx % m;
-                                let bi = (elem_idx - mi) / m;
+                                // the chunk may cross the rows of C when m is not a multiple
+                                // of chunk_len, like the router of the experts
                                 for (i, cval) in chunk_buf.iter_mut().enumerate() {
-                                    *cval = bufa.vec_dot((mi + i) * k, bufb, bi * k, k);
+                                    let (bi, mi) = ((elem_idx + i) / m, (elem_idx + i) % m);
+                                    *cval = bufa.vec_dot(mi * k, bufb, bi * k, k);
                                 }
                             },
                         );
@@ -75,4 +77,5 @@ fn gemv_dense_2d_2d(
                 });
         });
     }
+    Ok(())
 }
@@ -3,6 +3,7 @@ mod batch_matmul;
 mod concatenate;
 mod contiguous;
 mod gelu;
+mod layer_norm;
 mod matmul_vec;
 mod rms_norm;
 mod rope;
@@ -16,6 +17,7 @@ pub use concatenate::concatenate_inplace;
 pub use contiguous::contiguous;
 pub use gelu::gelu_inplace;
 pub use gelu::gelu_single;
+pub use layer_norm::layer_norm_inplace;
 pub use matmul_vec::matmul_vec;
 pub use rms_norm::rms_norm_inplace;
 pub use rope::rope_inplace;
@@ -62,19 +62,42 @@ fn rope_llama(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize) {
     });
 }
 
+// the neox style rotates the pairs of (i, i + rope_dim / 2) in the first rope_dim dimensions
+// of each head, the rest are left as is
 fn rope_neox(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize) {
     buf.chunks_exact_mut(head_dim).for_each(|chunk| {
         for i in 0..rope_dim / 2 {
-            let freq_exponents = 2.0 * i as f32 / head_dim as f32;
+            let freq_exponents = 2.0 * i as f32 / rope_dim as f32;
             let timescale = 10000_f32.powf(freq_exponents);
             let theta = pos as f32 / timescale;
             let cos_theta = theta.cos();
             let sin_theta = theta.sin();
 
             let qp0 = chunk[i];
-            let qp1 = chunk[i + head_dim / 2];
+            let qp1 = chunk[i + rope_dim / 2];
             chunk[i] = qp0 * cos_theta - qp1 * sin_theta;
-            chunk[i + head_dim / 2] = qp0 * sin_theta + qp1 * cos_theta;
+            chunk[i + rope_dim / 2] = qp0 * sin_theta + qp1 * cos_theta;
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_rope_neox_partial() {
+        // 2 heads of 8 dims, only the first 4 dims of each head are rotated, and the
+        // frequencies are scaled by rope_dim instead of head_dim
+        let mut buf = (0..16).map(|v| v as f32).collect::<Vec<_>>();
+        super::rope_neox(&mut buf, 3, 8, 4);
+        assert_relative_eq!(
+            &buf[..],
+            &[
+                -0.28224, 0.9095635, -1.979985, 3.0286456, 4.0, 5.0, 6.0, 7.0, -9.33114, 8.6659998,
+                -8.770965, 11.26501, 12.0, 13.0, 14.0, 15.0
+            ][..],
+            epsilon = 1e-5
+        );
+    }
+}
@@ -1,13 +1,22 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt::Display;
 use std::fs::File;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
 use std::mem;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use int_enum::IntEnum;
 use memmap2::Mmap;
 
 use crate::bail;
+use crate::cpu::buf::util::pod_buf_from_bytes;
 use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
@@ -51,6 +60,7 @@ pub const KEY_ROPE_SCALE_LINEAR: &str = "{arch}.rope.scale_linear";
 
 // Tokenization
 pub const KEY_TOKENIZER_MODEL: &str = "tokenizer.ggml.model";
+pub const KEY_TOKENIZER_PRE: &str = "tokenizer.ggml.pre";
 pub const KEY_TOKENIZER_LIST: &str = "tokenizer.ggml.tokens";
 pub const KEY_TOKENIZER_TOKEN_TYPE: &str = "tokenizer.ggml.token_type";
 pub const KEY_TOKENIZER_SCORES: &str = "tokenizer.ggml.scores";
@@ -60,11 +70,21 @@ pub const KEY_TOKENIZER_EOS_ID: &str = "tokenizer.ggml.eos_token_id";
 pub const KEY_TOKENIZER_UNK_ID: &str = "tokenizer.ggml.unknown_token_id";
 pub const KEY_TOKENIZER_SEP_ID: &str = "tokenizer.ggml.separator_token_id";
 pub const KEY_TOKENIZER_PAD_ID: &str = "tokenizer.ggml.padding_token_id";
+pub const KEY_TOKENIZER_EOT_ID: &str = "tokenizer.ggml.eot_token_id";
+pub const KEY_TOKENIZER_ADD_BOS: &str = "tokenizer.ggml.add_bos_token";
+pub const KEY_TOKENIZER_ADD_EOS: &str = "tokenizer.ggml.add_eos_token";
+pub const KEY_TOKENIZER_ADD_SPACE_PREFIX: &str = "tokenizer.ggml.add_space_prefix";
 pub const KEY_TOKENIZER_HF_JSON: &str = "tokenizer.huggingface.json";
 pub const KEY_TOKENIZER_RWKV: &str = "tokenizer.rwkv.world";
 
+// Split
+pub const KEY_SPLIT_NO: &str = "split.no";
+pub const KEY_SPLIT_COUNT: &str = "split.count";
+pub const KEY_SPLIT_TENSORS_COUNT: &str = "split.tensors.count";
+const SPLIT_KEYS: [&str; 3] = [KEY_SPLIT_NO, KEY_SPLIT_COUNT, KEY_SPLIT_TENSORS_COUNT];
+
 #[repr(u32)]
-#[derive(Debug, Clone, Copy, IntEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntEnum)]
 pub enum GGUFVersion {
     V1 = 1,
     V2 = 2,
@@ -101,10 +121,16 @@ pub enum GGMLType {
     Q5K = 13,
     Q6K = 14,
     Q8K = 15,
-    I8 = 16,
-    I16 = 17,
-    I32 = 18,
-    COUNT = 19,
+    // i-quantizations, the types not listed are not supported yet
+    IQ2XXS = 16,
+    IQ4NL = 20,
+    IQ3S = 21,
+    IQ4XS = 23,
+    I8 = 24,
+    I16 = 25,
+    I32 = 26,
+    BF16 = 30,
+    COUNT = 31,
 }
 
 impl Display for GGMLType {
@@ -124,14 +150,100 @@ impl Display for GGMLType {
             GGMLType::Q5K => write!(f, "Q5_K"),
             GGMLType::Q6K => write!(f, "Q6_K"),
             GGMLType::Q8K => write!(f, "Q8_K"),
+            GGMLType::IQ2XXS => write!(f, "IQ2_XXS"),
+            GGMLType::IQ4NL => write!(f, "IQ4_NL"),
+            GGMLType::IQ3S => write!(f, "IQ3_S"),
+            GGMLType::IQ4XS => write!(f, "IQ4_XS"),
             GGMLType::I8 => write!(f, "I8"),
             GGMLType::I16 => write!(f, "I16"),
             GGMLType::I32 => write!(f, "I32"),
+            GGMLType::BF16 => write!(f, "BF16"),
             GGMLType::COUNT => write!(f, "COUNT"),
         }
     }
 }
 
+impl FromStr for GGMLType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let typ = match s.to_uppercase().as_str() {
+            "F32" => GGMLType::F32,
+            "F16" => GGMLType::F16,
+            "Q4_0" => GGMLType::Q4_0,
+            "Q4_1" => GGMLType::Q4_1,
+            "Q5_0" => GGMLType::Q5_0,
+            "Q5_1" => GGMLType::Q5_1,
+            "Q8_0" => GGMLType::Q8_0,
+            "Q8_1" => GGMLType::Q8_1,
+            "Q2_K" => GGMLType::Q2K,
+            "Q3_K" => GGMLType::Q3K,
+            "Q4_K" => GGMLType::Q4K,
+            "Q5_K" => GGMLType::Q5K,
+            "Q6_K" => GGMLType::Q6K,
+            "Q8_K" => GGMLType::Q8K,
+            "IQ2_XXS" => GGMLType::IQ2XXS,
+            "IQ4_NL" => GGMLType::IQ4NL,
+            "IQ3_S" => GGMLType::IQ3S,
+            "IQ4_XS" => GGMLType::IQ4XS,
+            "I8" => GGMLType::I8,
+            "I16" => GGMLType::I16,
+            "I32" => GGMLType::I32,
+            "BF16" => GGMLType::BF16,
+            _ => bail!(ErrorKind::BadInput, "unknown ggml type: {}", s),
+        };
+        Ok(typ)
+    }
+}
+
+impl GGMLType {
+    /// the number of elements in a block, the size of a row in this type must be a
+    /// multiple of it.
+    pub fn block_size(&self) -> usize {
+        match self {
+            GGMLType::F32 | GGMLType::F16 | GGMLType::BF16 => 1,
+            GGMLType::I8 | GGMLType::I16 | GGMLType::I32 => 1,
+            GGMLType::Q4_0 | GGMLType::Q4_1 => 32,
+            GGMLType::Q5_0 | GGMLType::Q5_1 => 32,
+            GGMLType::Q8_0 | GGMLType::Q8_1 => 32,
+            GGMLType::Q2K | GGMLType::Q3K | GGMLType::Q4K => 256,
+            GGMLType::Q5K | GGMLType::Q6K | GGMLType::Q8K => 256,
+            GGMLType::IQ4NL => 32,
+            GGMLType::IQ2XXS | GGMLType::IQ3S | GGMLType::IQ4XS => 256,
+            GGMLType::COUNT => 1,
+        }
+    }
+
+    /// the size in bytes of a block.
+    pub fn type_size(&self) -> usize {
+        match self {
+            GGMLType::F32 => 4,
+            GGMLType::F16 => 2,
+            GGMLType::Q4_0 => 18,
+            GGMLType::Q4_1 => 20,
+            GGMLType::Q5_0 => 22,
+            GGMLType::Q5_1 => 24,
+            GGMLType::Q8_0 => 34,
+            GGMLType::Q8_1 => 36,
+            GGMLType::Q2K => 84,
+            GGMLType::Q3K => 110,
+            GGMLType::Q4K => 144,
+            GGMLType::Q5K => 176,
+            GGMLType::Q6K => 210,
+            GGMLType::Q8K => 292,
+            GGMLType::IQ2XXS => 66,
+            GGMLType::IQ4NL => 18,
+            GGMLType::IQ3S => 110,
+            GGMLType::IQ4XS => 136,
+            GGMLType::I8 => 1,
+            GGMLType::I16 => 2,
+            GGMLType::I32 => 4,
+            GGMLType::BF16 => 2,
+            GGMLType::COUNT => 0,
+        }
+    }
+}
+
 impl TryFrom<u32> for GGMLType {
     type Error = Error;
 
@@ -203,7 +315,8 @@ pub enum GGUFMetadataValue<'a> {
     F32(f32),
     F64(f64),
     Bool(u8),
-    String(&'a str),
+    // the strings edited after loading are owned, the loaded ones are borrowed from the file.
+    String(Cow<'a, str>),
     Array(GGUFMetadataArray<'a>),
 }
 
@@ -229,21 +342,64 @@ impl GGUFMetadataValue<'_> {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum GGUFMetadataArray<'a> {
-    U8Array(&'a [u8]),
-    I8Array(&'a [i8]),
-    U16Array(&'a [u16]),
-    I16Array(&'a [i16]),
-    U32Array(&'a [u32]),
-    I32Array(&'a [i32]),
-    U64Array(&'a [u64]),
-    I64Array(&'a [i64]),
-    F32Array(&'a [f32]),
-    F64Array(&'a [f64]),
-    BoolArray(&'a [u8]),
+    U8Array(Cow<'a, [u8]>),
+    I8Array(Cow<'a, [i8]>),
+    U16Array(Cow<'a, [u16]>),
+    I16Array(Cow<'a, [i16]>),
+    U32Array(Cow<'a, [u32]>),
+    I32Array(Cow<'a, [i32]>),
+    U64Array(Cow<'a, [u64]>),
+    I64Array(Cow<'a, [i64]>),
+    F32Array(Cow<'a, [f32]>),
+    F64Array(Cow<'a, [f64]>),
+    BoolArray(Cow<'a, [u8]>),
     StringArray(Vec<&'a str>),
     NestedArray(Vec<GGUFMetadataArray<'a>>),
 }
 
+impl GGUFMetadataArray<'_> {
+    /// the type of the elements in this array.
+    pub fn typ(&self) -> GGUFMetadataValueType {
+        match self {
+            GGUFMetadataArray::U8Array(_) => GGUFMetadataValueType::U8,
+            GGUFMetadataArray::I8Array(_) => GGUFMetadataValueType::I8,
+            GGUFMetadataArray::U16Array(_) => GGUFMetadataValueType::U16,
+            GGUFMetadataArray::I16Array(_) => GGUFMetadataValueType::I16,
+            GGUFMetadataArray::U32Array(_) => GGUFMetadataValueType::U32,
+            GGUFMetadataArray::I32Array(_) => GGUFMetadataValueType::I32,
+            GGUFMetadataArray::U64Array(_) => GGUFMetadataValueType::U64,
+            GGUFMetadataArray::I64Array(_) => GGUFMetadataValueType::I64,
+            GGUFMetadataArray::F32Array(_) => GGUFMetadataValueType::F32,
+            GGUFMetadataArray::F64Array(_) => GGUFMetadataValueType::F64,
+            GGUFMetadataArray::BoolArray(_) => GGUFMetadataValueType::Bool,
+            GGUFMetadataArray::StringArray(_) => GGUFMetadataValueType::String,
+            GGUFMetadataArray::NestedArray(_) => GGUFMetadataValueType::Array,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            GGUFMetadataArray::U8Array(arr) => arr.len(),
+            GGUFMetadataArray::I8Array(arr) => arr.len(),
+            GGUFMetadataArray::U16Array(arr) => arr.len(),
+            GGUFMetadataArray::I16Array(arr) => arr.len(),
+            GGUFMetadataArray::U32Array(arr) => arr.len(),
+            GGUFMetadataArray::I32Array(arr) => arr.len(),
+            GGUFMetadataArray::U64Array(arr) => arr.len(),
+            GGUFMetadataArray::I64Array(arr) => arr.len(),
+            GGUFMetadataArray::F32Array(arr) => arr.len(),
+            GGUFMetadataArray::F64Array(arr) => arr.len(),
+            GGUFMetadataArray::BoolArray(arr) => arr.len(),
+            GGUFMetadataArray::StringArray(arr) => arr.len(),
+            GGUFMetadataArray::NestedArray(arr) => arr.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 pub struct GGUFBufReader<'a> {
     cursor: &'a [u8],
     read_bytes: usize,
@@ -290,24 +446,19 @@ where 'a: 'b
 
 macro_rules! define_gguf_metadata_value_read_fn {
     ($read_array_func:ident, $read_item_func:ident, $typ:ty) => {
-        fn $read_array_func(&mut self, n: usize) -> Result<&'a [$typ]> {
+        fn $read_array_func(&mut self, n: usize) -> Result<Cow<'a, [$typ]>> {
             let typ_size = mem::size_of::<$typ>();
-            let data = self.buf.read(n * typ_size)?;
-            let transmuted_data = unsafe {
-                assert!(data.len() % typ_size == 0);
-                let ptr = data.as_ptr();
-                // assert!(ptr.align_offset(typ_size) == 0, "unaligned data: {:p}", ptr);
-                mem::transmute::<&[u8], &[$typ]>(std::slice::from_raw_parts(
-                    ptr,
-                    data.len() / typ_size,
-                ))
+            let size = match n.checked_mul(typ_size) {
+                Some(size) => size,
+                None => bail!(ErrorKind::FormatError, "array length overflow: {}", n),
             };
-            Ok(transmuted_data)
+            let data = self.buf.read(size)?;
+            Ok(pod_buf_from_bytes(data))
         }
 
         fn $read_item_func(&mut self) -> Result<$typ> {
-            let arr = self.$read_array_func(1)?;
-            Ok(arr[0])
+            let data = self.buf.read(mem::size_of::<$typ>())?;
+            Ok(bytemuck::pod_read_unaligned(data))
         }
     };
 }
@@ -331,7 +482,9 @@ impl<'a, 'b> GGUFMetadataReader<'a, 'b> {
             GGUFMetadataValueType::F64 => GGUFMetadataValue::F64(self.read_f64()?),
             GGUFMetadataValueType::U64 => GGUFMetadataValue::U64(self.read_u64()?),
             GGUFMetadataValueType::I64 => GGUFMetadataValue::I64(self.read_i64()?),
-            GGUFMetadataValueType::String => GGUFMetadataValue::String(self.read_string()?),
+            GGUFMetadataValueType::String => {
+                GGUFMetadataValue::String(Cow::Borrowed(self.read_string()?))
+            }
             GGUFMetadataValueType::Bool => GGUFMetadataValue::Bool(self.read_u8()?),
             GGUFMetadataValueType::Array => GGUFMetadataValue::Array(self.read_array()?),
         };
@@ -355,14 +508,14 @@ impl<'a, 'b> GGUFMetadataReader<'a, 'b> {
             GGUFMetadataValueType::I64 => GGUFMetadataArray::I64Array(self.read_i64_array(len)?),
             GGUFMetadataValueType::Bool => GGUFMetadataArray::BoolArray(self.read_u8_array(len)?),
             GGUFMetadataValueType::String => {
-                let mut v = Vec::with_capacity(len);
+                let mut v = Vec::with_capacity(len.min(self.buf.cursor().len()));
                 for _ in 0..len {
                     v.push(self.read_string()?);
                 }
                 GGUFMetadataArray::StringArray(v)
             }
             GGUFMetadataValueType::Array => {
-                let mut v = Vec::with_capacity(len);
+                let mut v = Vec::with_capacity(len.min(self.buf.cursor().len()));
                 for _ in 0..len {
                     v.push(self.read_array()?);
                 }
@@ -445,7 +598,7 @@ macro_rules! define_gguf_metadata_get_primitive_fn {
                 _ => return None,
             };
             match arr {
-                GGUFMetadataArray::$array_enum_kind(arr) => Some(arr),
+                GGUFMetadataArray::$array_enum_kind(arr) => Some(arr.as_ref()),
                 _ => None,
             }
         }
@@ -457,6 +610,21 @@ impl<'a> GGUFMetadata<'a> {
         &self.metadata_kv
     }
 
+    /// insert the value on the key, or replace the existing one. the replaced value is returned.
+    /// the value is owned by the metadata, a string value can be an owned `Cow::Owned`.
+    pub fn set(
+        &mut self,
+        key: impl Into<String>,
+        value: GGUFMetadataValue<'a>,
+    ) -> Option<GGUFMetadataValue<'a>> {
+        self.metadata_kv.insert(key.into(), value)
+    }
+
+    /// delete the value on the key, the deleted value is returned.
+    pub fn remove(&mut self, key: &str) -> Option<GGUFMetadataValue<'a>> {
+        self.metadata_kv.remove(key)
+    }
+
     define_gguf_metadata_get_primitive_fn!(get_u8, get_u8_array, u8, U8, U8Array);
     define_gguf_metadata_get_primitive_fn!(get_i8, get_i8_array, i8, I8, I8Array);
     define_gguf_metadata_get_primitive_fn!(get_u16, get_u16_array, u16, U16, U16Array);
@@ -468,10 +636,21 @@ impl<'a> GGUFMetadata<'a> {
     define_gguf_metadata_get_primitive_fn!(get_f32, get_f32_array, f32, F32, F32Array);
     define_gguf_metadata_get_primitive_fn!(get_bool, get_bool_array, u8, Bool, BoolArray);
 
+    /// the split metadata are written as u16 by llama.cpp, but some writers use other integer types.
+    fn get_split_value(&self, key: &str) -> Option<usize> {
+        match self.metadata_kv.get(key)? {
+            GGUFMetadataValue::U16(v) => Some(*v as usize),
+            GGUFMetadataValue::U32(v) => Some(*v as usize),
+            GGUFMetadataValue::I32(v) if *v >= 0 => Some(*v as usize),
+            GGUFMetadataValue::U64(v) => Some(*v as usize),
+            _ => None,
+        }
+    }
+
     pub fn get_string(&self, key: &str) -> Option<&str> {
         let val = self.metadata_kv.get(key)?;
         match val {
-            GGUFMetadataValue::String(val) => Some(val),
+            GGUFMetadataValue::String(val) => Some(val.as_ref()),
             _ => None,
         }
     }
@@ -489,6 +668,39 @@ impl<'a> GGUFMetadata<'a> {
     }
 }
 
+fn alignment_from_metadata(value: Option<&GGUFMetadataValue>) -> u64 {
+    match value {
+        Some(GGUFMetadataValue::U64(v)) => *v,
+        Some(GGUFMetadataValue::U32(v)) => *v as u64,
+        Some(GGUFMetadataValue::U16(v)) => *v as u64,
+        Some(GGUFMetadataValue::U8(v)) => *v as u64,
+        Some(GGUFMetadataValue::I64(v)) if *v > 0 => *v as u64,
+        Some(GGUFMetadataValue::I32(v)) if *v > 0 => *v as u64,
+        Some(GGUFMetadataValue::I16(v)) if *v > 0 => *v as u64,
+        Some(GGUFMetadataValue::I8(v)) if *v > 0 => *v as u64,
+        _ => GGUF_DEFAULT_ALIGNMENT,
+    }
+}
+
+/// the alignment must be a non-zero multiple of 8, or the offsets can not be padded to it.
+fn check_alignment(alignment: u64) -> Result<()> {
+    if alignment == 0 || alignment % 8 != 0 {
+        bail!(
+            ErrorKind::FormatError,
+            "invalid {}: {}, it must be a multiple of 8",
+            KEY_GENERAL_ALIGNMENT,
+            alignment
+        );
+    }
+    Ok(())
+}
+
+/// round the position up to the next multiple of alignment, it's a no-op if the position
+/// is already aligned.
+fn align_offset(position: usize, alignment: usize) -> usize {
+    position.div_ceil(alignment) * alignment
+}
+
 struct GGUFHeader<'a> {
     // Magic number to announce that this is a GGUF file.
     // Must be `GGUF` at the byte level: `0x47` `0x47` `0x55` `0x46`.
@@ -548,11 +760,16 @@ impl<'a> GGUFHeader<'a> {
             metadata_kv.insert(key.to_string(), value);
         }
         let metadata = GGUFMetadata { metadata_kv };
+        check_alignment(alignment_from_metadata(
+            metadata.as_hashmap().get(KEY_GENERAL_ALIGNMENT),
+        ))?;
 
-        // load the required fields
+        // load the required fields, the shards except the first one of a split model
+        // may not contain the architecture, it's checked on merging the shards.
         let architecture = match metadata.get_string(KEY_GENERAL_ARCHITECTURE) {
             Some(s) => s.to_string(),
-            _ => {
+            None if metadata.get_split_value(KEY_SPLIT_NO).unwrap_or(0) > 0 => String::new(),
+            None => {
                 bail!(
                     ErrorKind::FormatError,
                     "Missing string metadata general.architecture"
@@ -573,17 +790,7 @@ impl<'a> GGUFHeader<'a> {
     /// but it must be a multiple of 8. Some writers may not write the alignment. If the alignment is not specified,
     /// assume it is 32.
     pub fn alignment(&self) -> u64 {
-        match self.metadata.as_hashmap().get(KEY_GENERAL_ALIGNMENT) {
-            Some(GGUFMetadataValue::U64(v)) => *v,
-            Some(GGUFMetadataValue::U32(v)) => *v as u64,
-            Some(GGUFMetadataValue::U16(v)) => *v as u64,
-            Some(GGUFMetadataValue::U8(v)) => *v as u64,
-            Some(GGUFMetadataValue::I64(v)) if *v > 0 => *v as u64,
-            Some(GGUFMetadataValue::I32(v)) if *v > 0 => *v as u64,
-            Some(GGUFMetadataValue::I16(v)) if *v > 0 => *v as u64,
-            Some(GGUFMetadataValue::I8(v)) if *v > 0 => *v as u64,
-            _ => GGUF_DEFAULT_ALIGNMENT,
-        }
+        alignment_from_metadata(self.metadata.as_hashmap().get(KEY_GENERAL_ALIGNMENT))
     }
 
     /// describes what architecture this model implements. All lowercase ASCII, with only [a-z0-9]+ characters
@@ -629,12 +836,29 @@ struct GGUFOnDiskTensorInfo {
 }
 
 impl GGUFOnDiskTensorInfo {
+    /// the size of the tensor data in bytes, it's none if the number of elements is not
+    /// a multiple of the block size.
+    fn data_size(&self) -> Option<usize> {
+        let numel = self
+            .dimensions
+            .iter()
+            .try_fold(1usize, |acc, dim| acc.checked_mul(*dim))?;
+        if self.typ == GGMLType::COUNT || numel % self.typ.block_size() != 0 {
+            return None;
+        }
+        (numel / self.typ.block_size()).checked_mul(self.typ.type_size())
+    }
+
     pub fn decode(buf: &mut GGUFBufReader, version: GGUFVersion) -> Result<Self> {
         let mut r = GGUFMetadataReader::new(buf, version);
         let name = r.read_string()?.to_string();
         let n_dimensions = r.read_u32()? as usize;
         let dimensions = r.read_len_array(n_dimensions)?;
-        let typ = GGMLType::try_from(r.read_u32()?)?;
+        let typ = GGMLType::try_from(r.read_u32()?).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: format!("unknown type of tensor {}: {}", name, err.message),
+            cause: err.cause,
+        })?;
         let offset = r.read_u64()?;
         Ok(Self {
             name,
@@ -704,14 +928,92 @@ pub struct GGUFFile<'a> {
     // The offset of each tensor's data must be a multiple of `ALIGNMENT`, and the space between tensors
     // should be padded to `ALIGNMENT` bytes.
     _tensor_data: &'a [u8],
+
+    // The on-disk layouts of the tensor data, one for each shard of the model. They're kept
+    // to validate the offsets of the tensors.
+    layouts: Vec<GGUFTensorDataLayout>,
+}
+
+struct GGUFTensorDataLayout {
+    tensor_infos: Vec<GGUFOnDiskTensorInfo>,
+    tensor_data_len: usize,
+    alignment: usize,
+}
+
+impl GGUFTensorDataLayout {
+    fn validate(&self) -> Result<()> {
+        let mut ranges = Vec::with_capacity(self.tensor_infos.len());
+        for tensor_info in self.tensor_infos.iter() {
+            let name = &tensor_info.name;
+            if tensor_info.typ == GGMLType::COUNT {
+                bail!(
+                    ErrorKind::FormatError,
+                    "unknown type of tensor {}: {}",
+                    name,
+                    tensor_info.typ as u32
+                );
+            }
+            let size = match tensor_info.data_size() {
+                Some(size) => size,
+                None => bail!(
+                    ErrorKind::FormatError,
+                    "size mismatch on tensor {}: dimensions {:?} can not be stored as {}, whose block size is {}",
+                    name,
+                    tensor_info.dimensions,
+                    tensor_info.typ,
+                    tensor_info.typ.block_size()
+                ),
+            };
+            if tensor_info.offset % self.alignment as u64 != 0 {
+                bail!(
+                    ErrorKind::FormatError,
+                    "misaligned tensor {}: offset {} is not a multiple of {}",
+                    name,
+                    tensor_info.offset,
+                    self.alignment
+                );
+            }
+            let start = tensor_info.offset as usize;
+            let end = match start.checked_add(size) {
+                Some(end) if end <= self.tensor_data_len => end,
+                _ => bail!(
+                    ErrorKind::FormatError,
+                    "out of range tensor {}: offset {} with size {} exceeds the tensor data of {} bytes",
+                    name,
+                    tensor_info.offset,
+                    size,
+                    self.tensor_data_len
+                ),
+            };
+            ranges.push((start, end, name));
+        }
+
+        // the tensors are not necessarily stored in the order of the tensor infos
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            let (_, prev_end, prev_name) = pair[0];
+            let (start, _, name) = pair[1];
+            if start < prev_end {
+                bail!(
+                    ErrorKind::FormatError,
+                    "overlapped tensor {}: it starts at offset {} before the end of tensor {} at {}",
+                    name,
+                    start,
+                    prev_name,
+                    prev_end
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a> GGUFFile<'a> {
     fn decode(buf: &mut GGUFBufReader<'a>) -> Result<Self> {
         let header = GGUFHeader::decode(buf)?;
 
-        // load on disk tensor infos
-        let mut on_disk_tensor_infos = Vec::with_capacity(header.tensor_count);
+        // load on disk tensor infos, the tensor count is not trusted on pre-allocating
+        let mut on_disk_tensor_infos = Vec::with_capacity(header.tensor_count.min(1024));
         for _ in 0..header.tensor_count {
             let tensor_info = GGUFOnDiskTensorInfo::decode(buf, header.version)?;
             on_disk_tensor_infos.push(tensor_info);
@@ -720,32 +1022,77 @@ impl<'a> GGUFFile<'a> {
         // find the tensor_data position
         let position = buf.read_bytes();
         let alignment = header.alignment() as usize;
-        let next_position = position - (position % alignment) + alignment;
+        let next_position = align_offset(position, alignment);
         let _ = buf.read(next_position - position)?;
         let tensor_data = buf.cursor();
 
+        let layout = GGUFTensorDataLayout {
+            tensor_infos: on_disk_tensor_infos,
+            tensor_data_len: tensor_data.len(),
+            alignment,
+        };
+        layout.validate()?;
+
         // convert the on-disk tensor infos to in-memory
-        let tensor_infos = Self::convert_tensor_infos(&on_disk_tensor_infos, tensor_data)?;
+        let tensor_infos = Self::convert_tensor_infos(&layout.tensor_infos, tensor_data)?;
 
         Ok(Self {
             header,
             tensor_infos,
             _tensor_data: tensor_data,
+            layouts: vec![layout],
         })
     }
 
+    /// check the tensor infos against the tensor data, reports the first tensor which is out
+    /// of range, overlapped with others, misaligned, or mismatched on size. it's run on
+    /// opening the file.
+    pub fn validate(&self) -> Result<()> {
+        for layout in self.layouts.iter() {
+            layout.validate()?;
+        }
+        Ok(())
+    }
+
     fn convert_tensor_infos(
         tensor_infos: &[GGUFOnDiskTensorInfo],
         tensor_data: &'a [u8],
     ) -> Result<Vec<GGUFTensorInfo<'a>>> {
         let mut result = Vec::with_capacity(tensor_infos.len());
-        for (i, tensor_info) in tensor_infos.iter().enumerate() {
-            let next_offset = if i + 1 >= tensor_infos.len() {
-                tensor_data.len()
-            } else {
-                tensor_infos[i + 1].offset as usize
+        for tensor_info in tensor_infos.iter() {
+            let size = match tensor_info.data_size() {
+                Some(size) => size,
+                None => bail!(
+                    ErrorKind::FormatError,
+                    "invalid size of tensor {}: dimensions {:?} in {}",
+                    tensor_info.name,
+                    tensor_info.dimensions,
+                    tensor_info.typ
+                ),
+            };
+            let offset = match usize::try_from(tensor_info.offset) {
+                Ok(offset) => offset,
+                Err(_) => bail!(
+                    ErrorKind::FormatError,
+                    "invalid offset of tensor {}: {}",
+                    tensor_info.name,
+                    tensor_info.offset
+                ),
+            };
+            let data = match offset
+                .checked_add(size)
+                .and_then(|end| tensor_data.get(offset..end))
+            {
+                Some(data) => data,
+                None => bail!(
+                    ErrorKind::FormatError,
+                    "invalid data range of tensor {}: offset {} with size {} exceeds the tensor data of {} bytes",
+                    tensor_info.name,
+                    offset,
+                    size,
+                    tensor_data.len()
+                ),
             };
-            let data = &tensor_data[tensor_info.offset as usize..next_offset];
 
             let item = GGUFTensorInfo::new(
                 tensor_info.name.clone(),
@@ -758,6 +1105,114 @@ impl<'a> GGUFFile<'a> {
         Ok(result)
     }
 
+    /// decode the GGUF file from the bytes owned by the caller, like the bytes embedded with
+    /// `include_bytes!` or downloaded in wasm. the bytes need not be aligned, the tensor data and
+    /// the metadata arrays are borrowed without copying if they're aligned, otherwise copied.
+    pub fn from_bytes(buf: &'a [u8]) -> Result<Self> {
+        Self::from_shard_bytes(&[buf])
+    }
+
+    /// decode a split model from the bytes of all its shards, ordered by `split.no`.
+    pub fn from_shard_bytes(bufs: &[&'a [u8]]) -> Result<Self> {
+        let mut shards = Vec::with_capacity(bufs.len());
+        for buf in bufs {
+            shards.push(Self::decode(&mut GGUFBufReader::new(buf))?);
+        }
+        Self::merge_shards(shards)
+    }
+
+    /// merge the shards of a split model into one view. the metadata are taken from the first
+    /// shard, and the tensor infos of all the shards are concatenated in order.
+    fn merge_shards(shards: Vec<GGUFFile<'a>>) -> Result<Self> {
+        let split_count = shards.len();
+        let mut tensor_infos: Vec<GGUFTensorInfo<'a>> = vec![];
+        let mut tensor_names = HashSet::new();
+        let mut layouts = vec![];
+        let mut first: Option<GGUFFile<'a>> = None;
+        for (i, mut shard) in shards.into_iter().enumerate() {
+            let metadata = shard.metadata();
+            let (no, count) = match (
+                metadata.get_split_value(KEY_SPLIT_NO),
+                metadata.get_split_value(KEY_SPLIT_COUNT),
+            ) {
+                (Some(no), Some(count)) => (no, count),
+                (None, None) if split_count == 1 => (0, 1),
+                _ => bail!(
+                    ErrorKind::FormatError,
+                    "missing {} or {} in the shard {}",
+                    KEY_SPLIT_NO,
+                    KEY_SPLIT_COUNT,
+                    i
+                ),
+            };
+            if count != split_count {
+                bail!(
+                    ErrorKind::FormatError,
+                    "the shard {} expects {} shards, but {} shards are found",
+                    i,
+                    count,
+                    split_count
+                );
+            }
+            if no != i {
+                bail!(
+                    ErrorKind::FormatError,
+                    "the shard {} is marked as {} {}",
+                    i,
+                    KEY_SPLIT_NO,
+                    no
+                );
+            }
+
+            for tensor_info in shard.tensor_infos.iter() {
+                if !tensor_names.insert(tensor_info.name().to_string()) {
+                    bail!(
+                        ErrorKind::FormatError,
+                        "duplicated tensor {} in the shard {}",
+                        tensor_info.name(),
+                        i
+                    );
+                }
+                tensor_infos.push(tensor_info.clone());
+            }
+            layouts.append(&mut shard.layouts);
+            if first.is_none() {
+                first = Some(shard);
+            }
+        }
+
+        let mut gf = match first {
+            Some(gf) => gf,
+            None => bail!(ErrorKind::FormatError, "no shard to load"),
+        };
+        if let Some(n) = gf.metadata().get_split_value(KEY_SPLIT_TENSORS_COUNT) {
+            if n != tensor_infos.len() {
+                bail!(
+                    ErrorKind::FormatError,
+                    "{} is {}, but {} tensors are found in the shards",
+                    KEY_SPLIT_TENSORS_COUNT,
+                    n,
+                    tensor_infos.len()
+                );
+            }
+        }
+        if gf.header.architecture.is_empty() {
+            bail!(
+                ErrorKind::FormatError,
+                "Missing string metadata general.architecture"
+            );
+        }
+        // the merged file is not split any more, the split metadata would be wrong on
+        // writing it back
+        for key in SPLIT_KEYS {
+            gf.header.metadata.remove(key);
+        }
+        gf.header.tensor_count = tensor_infos.len();
+        gf.tensor_infos = tensor_infos;
+        gf.layouts = layouts;
+        Ok(gf)
+    }
+
     pub fn architecture(&self) -> &str {
         self.header.architecture()
     }
@@ -774,6 +1229,40 @@ impl<'a> GGUFFile<'a> {
         &self.header.metadata
     }
 
+    /// set the metadata value on the key, the replaced value is returned. the edited metadata
+    /// can be written into a new file with `GGUFFileBuilder::from_gguf_file`, the tensor data
+    /// are kept unchanged.
+    pub fn set_metadata(
+        &mut self,
+        key: impl Into<String>,
+        value: GGUFMetadataValue<'a>,
+    ) -> Result<Option<GGUFMetadataValue<'a>>> {
+        let key = key.into();
+        if key == KEY_GENERAL_ARCHITECTURE {
+            match &value {
+                GGUFMetadataValue::String(s) if !s.is_empty() => {
+                    self.header.architecture = s.to_string()
+                }
+                _ => bail!(
+                    ErrorKind::BadInput,
+                    "{} must be a non-empty string, got {:?}",
+                    key,
+                    value
+                ),
+            }
+        }
+        Ok(self.header.metadata.set(key, value))
+    }
+
+    /// remove the metadata value on the key, the removed value is returned. the architecture
+    /// is required and can not be removed.
+    pub fn remove_metadata(&mut self, key: &str) -> Result<Option<GGUFMetadataValue<'a>>> {
+        if key == KEY_GENERAL_ARCHITECTURE {
+            bail!(ErrorKind::BadInput, "{} can not be removed", key);
+        }
+        Ok(self.header.metadata.remove(key))
+    }
+
     pub fn tensor_infos(&self) -> &[GGUFTensorInfo] {
         &self.tensor_infos
     }
@@ -787,12 +1276,37 @@ impl<'a> GGUFFile<'a> {
 }
 
 pub struct GGUFFileLoader {
-    mmap: memmap2::Mmap,
+    // the mmaps of all the shards, a model which is not split has only one shard.
+    mmaps: Vec<memmap2::Mmap>,
 }
 
 impl GGUFFileLoader {
-    #[allow(unused_variables)]
+    /// map the GGUF file on the path. if the file name is like `model-00001-of-00003.gguf`,
+    /// all the shards of the model are mapped, they're expected to be in the same directory.
     pub fn new(path: &str, mlock: bool) -> Result<Self> {
+        let paths = match parse_split_path(path) {
+            Some((prefix, count)) => (1..=count)
+                .map(|no| format!("{}-{:05}-of-{:05}.gguf", prefix, no, count))
+                .collect::<Vec<_>>(),
+            None => vec![path.to_string()],
+        };
+
+        let mut mmaps = Vec::with_capacity(paths.len());
+        for path in paths.iter() {
+            if paths.len() > 1 && !std::path::Path::new(path).exists() {
+                bail!(
+                    ErrorKind::FormatError,
+                    "missing the shard {} of the split model",
+                    path
+                );
+            }
+            mmaps.push(Self::mmap_file(path, mlock)?);
+        }
+        Ok(Self { mmaps })
+    }
+
+    #[allow(unused_variables)]
+    pub(crate) fn mmap_file(path: &str, mlock: bool) -> Result<Mmap> {
         let file = File::open(path).map_err(|err| Error {
             kind: ErrorKind::IOError,
             message: format!("failed to open the file: {}", path),
@@ -823,18 +1337,301 @@ impl GGUFFileLoader {
                 cause: Some(Arc::new(err)),
             })?;
         }
-        Ok(Self { mmap })
+        Ok(mmap)
+    }
+
+    pub fn open(&self) -> Result<GGUFFile<'_>> {
+        let bufs = self.mmaps.iter().map(|mmap| &mmap[..]).collect::<Vec<_>>();
+        GGUFFile::from_shard_bytes(&bufs)
+    }
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(32))]
+struct GGUFAlignedChunk([u8; GGUF_DEFAULT_ALIGNMENT as usize]);
+
+/// GGUFReaderLoader reads the whole GGUF file from a reader into an owned buffer, it's useful
+/// when the file can not be mmaped, like on wasm or reading from a network stream.
+pub struct GGUFReaderLoader {
+    // the buffer is allocated in chunks to keep the tensor data aligned.
+    chunks: Vec<GGUFAlignedChunk>,
+    len: usize,
+}
+
+impl GGUFReaderLoader {
+    pub fn new<R: Read + Seek>(r: &mut R) -> Result<Self> {
+        let map_io_err = |err: std::io::Error| Error {
+            kind: ErrorKind::IOError,
+            message: "failed to read the GGUF file".to_string(),
+            cause: Some(Arc::new(err)),
+        };
+
+        let start = r.stream_position().map_err(map_io_err)?;
+        let end = r.seek(SeekFrom::End(0)).map_err(map_io_err)?;
+        r.seek(SeekFrom::Start(start)).map_err(map_io_err)?;
+
+        let len = (end - start) as usize;
+        let chunk_size = mem::size_of::<GGUFAlignedChunk>();
+        let mut chunks =
+            vec![GGUFAlignedChunk([0; GGUF_DEFAULT_ALIGNMENT as usize]); len.div_ceil(chunk_size)];
+        let buf: &mut [u8] = bytemuck::cast_slice_mut(&mut chunks);
+        r.read_exact(&mut buf[..len]).map_err(map_io_err)?;
+        Ok(Self { chunks, len })
     }
 
     pub fn open(&self) -> Result<GGUFFile<'_>> {
-        let buf = &mut GGUFBufReader::new(&self.mmap[..]);
-        GGUFFile::decode(buf)
+        let buf: &[u8] = bytemuck::cast_slice(&self.chunks);
+        GGUFFile::from_bytes(&buf[..self.len])
+    }
+}
+
+/// parse the path like `model-00001-of-00003.gguf` into the prefix `model` and the count 3.
+fn parse_split_path(path: &str) -> Option<(&str, usize)> {
+    let stem = path.strip_suffix(".gguf")?;
+    let (rest, count) = stem.rsplit_once("-of-")?;
+    let (prefix, no) = rest.rsplit_once('-')?;
+    let is_digits = |s: &str| s.len() == 5 && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(no) || !is_digits(count) {
+        return None;
+    }
+    let count = count.parse::<usize>().ok()?;
+    if count == 0 {
+        return None;
+    }
+    Some((prefix, count))
+}
+
+struct GGUFMetadataWriter<'w, W: Write> {
+    w: &'w mut W,
+    written_bytes: usize,
+}
+
+impl<'w, W: Write> GGUFMetadataWriter<'w, W> {
+    fn new(w: &'w mut W) -> Self {
+        Self {
+            w,
+            written_bytes: 0,
+        }
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<()> {
+        self.w.write_all(buf).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to write {} bytes", buf.len()),
+            cause: Some(Arc::new(err)),
+        })?;
+        self.written_bytes += buf.len();
+        Ok(())
+    }
+
+    fn write_u32(&mut self, v: u32) -> Result<()> {
+        self.write_bytes(&v.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, v: u64) -> Result<()> {
+        self.write_bytes(&v.to_le_bytes())
+    }
+
+    /// the writer always emits the v3 format, which uses 64 bit lengths for strings & arrays.
+    fn write_len(&mut self, len: usize) -> Result<()> {
+        self.write_u64(len as u64)
+    }
+
+    fn write_string(&mut self, s: &str) -> Result<()> {
+        self.write_len(s.len())?;
+        self.write_bytes(s.as_bytes())
+    }
+
+    fn write_padding(&mut self, alignment: usize) -> Result<()> {
+        let padding = align_offset(self.written_bytes, alignment) - self.written_bytes;
+        self.write_bytes(&vec![0u8; padding])
+    }
+
+    fn write_value(&mut self, v: &GGUFMetadataValue) -> Result<()> {
+        self.write_u32(v.typ() as u32)?;
+        match v {
+            GGUFMetadataValue::U8(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::I8(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::U16(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::I16(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::U32(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::I32(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::U64(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::I64(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::F32(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::F64(v) => self.write_bytes(&v.to_le_bytes()),
+            GGUFMetadataValue::Bool(v) => self.write_bytes(&[*v]),
+            GGUFMetadataValue::String(v) => self.write_string(v),
+            GGUFMetadataValue::Array(arr) => self.write_array(arr),
+        }
+    }
+
+    fn write_array(&mut self, arr: &GGUFMetadataArray) -> Result<()> {
+        self.write_u32(arr.typ() as u32)?;
+        self.write_len(arr.len())?;
+        match arr {
+            GGUFMetadataArray::U8Array(arr) => self.write_bytes(arr),
+            GGUFMetadataArray::I8Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::U16Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::I16Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::U32Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::I32Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::U64Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::I64Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::F32Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::F64Array(arr) => self.write_bytes(bytemuck::cast_slice(arr)),
+            GGUFMetadataArray::BoolArray(arr) => self.write_bytes(arr),
+            GGUFMetadataArray::StringArray(arr) => {
+                for s in arr.iter() {
+                    self.write_string(s)?;
+                }
+                Ok(())
+            }
+            GGUFMetadataArray::NestedArray(arr) => {
+                for nested in arr.iter() {
+                    self.write_array(nested)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// GGUFFileBuilder collects the metadata and the tensors of a model, and serializes them
+/// into a GGUF v3 file. The tensor data is borrowed, it's written as is without any
+/// conversion, the caller is responsible for making the data consistent with the tensor's
+/// type and dimensions.
+#[derive(Default)]
+pub struct GGUFFileBuilder<'a> {
+    // the metadata key-value pairs, kept in the insertion order.
+    metadata: Vec<(String, GGUFMetadataValue<'a>)>,
+
+    tensor_infos: Vec<GGUFTensorInfo<'a>>,
+}
+
+impl<'a> GGUFFileBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// copy all the metadata and tensors from an opened GGUF file. the metadata keys are
+    /// sorted to make the output deterministic, and the split metadata are skipped as all
+    /// the tensors are written into one file.
+    pub fn from_gguf_file(gf: &'a GGUFFile<'a>) -> Self {
+        let mut builder = Self::new();
+        let mut metadata = gf
+            .metadata()
+            .as_hashmap()
+            .iter()
+            .filter(|(k, _)| !SPLIT_KEYS.contains(&k.as_str()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        metadata.sort_by(|a, b| a.0.cmp(&b.0));
+        builder.metadata = metadata;
+        builder.tensor_infos = gf.tensor_infos().to_vec();
+        builder
+    }
+
+    /// set the metadata value on the key, an existing value on the same key is replaced
+    /// in place.
+    pub fn add_metadata(&mut self, key: impl Into<String>, value: GGUFMetadataValue<'a>) {
+        let key = key.into();
+        match self.metadata.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.metadata.push((key, value)),
+        }
+    }
+
+    pub fn add_tensor(&mut self, tensor_info: GGUFTensorInfo<'a>) -> Result<()> {
+        if self
+            .tensor_infos
+            .iter()
+            .any(|ti| ti.name() == tensor_info.name())
+        {
+            bail!(
+                ErrorKind::BadInput,
+                "duplicated tensor name: {}",
+                tensor_info.name()
+            );
+        }
+        self.tensor_infos.push(tensor_info);
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> &[(String, GGUFMetadataValue<'a>)] {
+        &self.metadata
+    }
+
+    pub fn tensor_infos(&self) -> &[GGUFTensorInfo<'a>] {
+        &self.tensor_infos
+    }
+
+    pub fn alignment(&self) -> u64 {
+        let value = self
+            .metadata
+            .iter()
+            .find(|(k, _)| k == KEY_GENERAL_ALIGNMENT)
+            .map(|(_, v)| v);
+        alignment_from_metadata(value)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        check_alignment(self.alignment())?;
+        let alignment = self.alignment() as usize;
+        let mut w = GGUFMetadataWriter::new(w);
+
+        // header
+        w.write_u32(GGUF_MAGIC)?;
+        w.write_u32(GGUFVersion::V3 as u32)?;
+        w.write_len(self.tensor_infos.len())?;
+        w.write_len(self.metadata.len())?;
+        for (key, value) in self.metadata.iter() {
+            w.write_string(key)?;
+            w.write_value(value)?;
+        }
+
+        // tensor infos, the offsets are relative to the start of the tensor data
+        let mut offset = 0;
+        for tensor_info in self.tensor_infos.iter() {
+            w.write_string(tensor_info.name())?;
+            w.write_u32(tensor_info.dimensions().len() as u32)?;
+            for dim in tensor_info.dimensions() {
+                w.write_u64(*dim as u64)?;
+            }
+            w.write_u32(tensor_info.typ() as u32)?;
+            w.write_u64(offset as u64)?;
+            offset = align_offset(offset + tensor_info.data().len(), alignment);
+        }
+
+        // tensor data, both the start of the tensor data and each tensor are aligned
+        for tensor_info in self.tensor_infos.iter() {
+            w.write_padding(alignment)?;
+            w.write_bytes(tensor_info.data())?;
+        }
+        Ok(())
+    }
+
+    pub fn write_to_file(&self, path: &str) -> Result<()> {
+        let file = File::create(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to create the file: {}", path),
+            cause: Some(Arc::new(err)),
+        })?;
+        let mut w = BufWriter::new(file);
+        self.write(&mut w)?;
+        w.flush().map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to flush the file: {}", path),
+            cause: Some(Arc::new(err)),
+        })?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cpu::CpuTensorBuf;
 
     #[test]
     fn test_load_tensors() -> Result<()> {
@@ -988,4 +1785,473 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_ggml_type_size() {
+        use crate::cpu::buf::*;
+
+        let sizes = vec![
+            (GGMLType::Q4_0, mem::size_of::<buf_q4_0::BlockQ4_0>()),
+            (GGMLType::Q4_1, mem::size_of::<buf_q4_1::BlockQ4_1>()),
+            (GGMLType::Q5_0, mem::size_of::<buf_q5_0::BlockQ5_0>()),
+            (GGMLType::Q5_1, mem::size_of::<buf_q5_1::BlockQ5_1>()),
+            (GGMLType::Q8_0, mem::size_of::<buf_q8_0::BlockQ8_0>()),
+            (GGMLType::Q8_1, mem::size_of::<buf_q8_1::BlockQ8_1>()),
+            (GGMLType::Q2K, mem::size_of::<buf_q2_k::BlockQ2K>()),
+            (GGMLType::Q3K, mem::size_of::<buf_q3_k::BlockQ3K>()),
+            (GGMLType::Q4K, mem::size_of::<buf_q4_k::BlockQ4K>()),
+            (GGMLType::Q5K, mem::size_of::<buf_q5_k::BlockQ5K>()),
+            (GGMLType::Q6K, mem::size_of::<buf_q6_k::BlockQ6K>()),
+            (GGMLType::Q8K, mem::size_of::<buf_q8_k::BlockQ8K>()),
+        ];
+        for (typ, size) in sizes {
+            assert_eq!(typ.type_size(), size, "{}", typ);
+        }
+        assert_eq!("q4_k".parse::<GGMLType>().unwrap(), GGMLType::Q4K);
+        assert!("q4_x".parse::<GGMLType>().is_err());
+    }
+
+    #[test]
+    fn test_write_round_trip() -> Result<()> {
+        let paths = vec![
+            "../testdata/tinyllamas-stories-260k-f32.gguf",
+            "../testdata/tinyllamas-stories-15m-q4_0.gguf",
+            "../testdata/tinyllamas-stories-15m-q8_0.gguf",
+            "../testdata/TinyLLama-v0-5M-F16.gguf",
+        ];
+        for path in paths {
+            let loader = GGUFFileLoader::new(path, false)?;
+            let gf = loader.open()?;
+
+            let mut buf = vec![];
+            GGUFFileBuilder::from_gguf_file(&gf).write(&mut buf)?;
+            let gf2 = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+
+            assert_eq!(gf2.version(), GGUFVersion::V3);
+            assert_eq!(gf.metadata().as_hashmap(), gf2.metadata().as_hashmap());
+            assert_eq!(gf.tensor_infos().len(), gf2.tensor_infos().len());
+            for (t1, t2) in gf.tensor_infos().iter().zip(gf2.tensor_infos()) {
+                assert_eq!(t1.name(), t2.name());
+                assert_eq!(t1.dimensions(), t2.dimensions());
+                assert_eq!(t1.typ(), t2.typ());
+                assert_eq!(t1.data(), t2.data(), "{}: {}", path, t1.name());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_metadata_values() -> Result<()> {
+        let strs = vec!["a", "bc"];
+        let nested = vec![
+            GGUFMetadataArray::U16Array(vec![1, 2].into()),
+            GGUFMetadataArray::StringArray(vec!["d"]),
+        ];
+        let mut builder = GGUFFileBuilder::new();
+        builder.add_metadata(
+            KEY_GENERAL_ARCHITECTURE,
+            GGUFMetadataValue::String("llama".into()),
+        );
+        builder.add_metadata(KEY_GENERAL_ALIGNMENT, GGUFMetadataValue::U32(64));
+        builder.add_metadata("t.u8", GGUFMetadataValue::U8(1));
+        builder.add_metadata("t.i8", GGUFMetadataValue::I8(-1));
+        builder.add_metadata("t.i16", GGUFMetadataValue::I16(-2));
+        builder.add_metadata("t.i64", GGUFMetadataValue::I64(-3));
+        builder.add_metadata("t.f64", GGUFMetadataValue::F64(0.5));
+        builder.add_metadata("t.bool", GGUFMetadataValue::Bool(1));
+        builder.add_metadata("t.bool", GGUFMetadataValue::Bool(0));
+        builder.add_metadata(
+            "t.strs",
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(strs)),
+        );
+        builder.add_metadata(
+            "t.nested",
+            GGUFMetadataValue::Array(GGUFMetadataArray::NestedArray(nested)),
+        );
+        let data = [1u8; 40];
+        builder.add_tensor(GGUFTensorInfo::new(
+            "a".to_string(),
+            vec![10],
+            GGMLType::F32,
+            &data,
+        ))?;
+        builder.add_tensor(GGUFTensorInfo::new(
+            "b".to_string(),
+            vec![2, 5],
+            GGMLType::I32,
+            &data,
+        ))?;
+        assert!(builder
+            .add_tensor(GGUFTensorInfo::new(
+                "b".to_string(),
+                vec![10],
+                GGMLType::F32,
+                &data,
+            ))
+            .is_err());
+
+        let mut buf = vec![];
+        builder.write(&mut buf)?;
+        let gf = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+        assert_eq!(gf.header.alignment(), 64);
+        assert_eq!(gf.metadata().as_hashmap().len(), 10);
+        assert_eq!(gf.metadata().get_bool("t.bool"), Some(0));
+        for (k, v) in builder.metadata() {
+            assert_eq!(gf.metadata().as_hashmap().get(k), Some(v));
+        }
+        // the data is sliced by the size of the tensor, without the padding after it
+        assert_eq!(gf.tensor_infos()[0].data(), &data);
+        assert_eq!(gf.tensor_infos()[1].data(), &data);
+        assert_eq!(gf.tensor_infos()[1].dimensions(), &[2, 5]);
+        assert_eq!(gf.tensor_infos()[1].typ(), GGMLType::I32);
+        Ok(())
+    }
+
+    fn write_shards(gf: &GGUFFile, prefix: &str, count: usize, split_count: u16) -> Result<()> {
+        let tensor_infos = gf.tensor_infos();
+        let chunk_size = tensor_infos.len().div_ceil(count);
+        for (no, chunk) in tensor_infos.chunks(chunk_size).enumerate() {
+            let mut builder = GGUFFileBuilder::new();
+            if no == 0 {
+                builder = GGUFFileBuilder::from_gguf_file(gf);
+                builder.tensor_infos.clear();
+                builder.add_metadata(
+                    KEY_SPLIT_TENSORS_COUNT,
+                    GGUFMetadataValue::I32(tensor_infos.len() as i32),
+                );
+            }
+            builder.add_metadata(KEY_SPLIT_NO, GGUFMetadataValue::U16(no as u16));
+            builder.add_metadata(KEY_SPLIT_COUNT, GGUFMetadataValue::U16(split_count));
+            for tensor_info in chunk {
+                builder.add_tensor(tensor_info.clone())?;
+            }
+            builder.write_to_file(&format!("{}-{:05}-of-{:05}.gguf", prefix, no + 1, count))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_split_files() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = loader.open()?;
+
+        let dir = std::env::temp_dir();
+        let prefix = dir.join("crabml-test-split").to_str().unwrap().to_string();
+        write_shards(&gf, &prefix, 3, 3)?;
+
+        let path = format!("{}-00001-of-00003.gguf", prefix);
+        let loader2 = GGUFFileLoader::new(&path, false)?;
+        let gf2 = loader2.open()?;
+        assert_eq!(gf2.architecture(), "llama");
+        assert_eq!(gf2.tensor_infos().len(), 48);
+        for (t1, t2) in gf.tensor_infos().iter().zip(gf2.tensor_infos()) {
+            assert_eq!(t1.name(), t2.name());
+            assert_eq!(t1.dimensions(), t2.dimensions());
+            assert_eq!(t1.data(), t2.data());
+        }
+        for key in SPLIT_KEYS {
+            assert!(gf2.metadata().as_hashmap().get(key).is_none(), "{}", key);
+        }
+
+        // write the merged shards back into one file
+        let merged_path = format!("{}-merged.gguf", prefix);
+        GGUFFileBuilder::from_gguf_file(&gf2).write_to_file(&merged_path)?;
+        let loader4 = GGUFFileLoader::new(&merged_path, false)?;
+        let gf4 = loader4.open()?;
+        assert_eq!(gf.metadata().as_hashmap(), gf4.metadata().as_hashmap());
+        assert_eq!(gf4.tensor_infos().len(), 48);
+        for (t1, t2) in gf.tensor_infos().iter().zip(gf4.tensor_infos()) {
+            assert_eq!(t1.name(), t2.name());
+            assert_eq!(t1.dimensions(), t2.dimensions());
+            assert_eq!(t1.data(), t2.data());
+        }
+        std::fs::remove_file(&merged_path).unwrap();
+
+        // the shards disagree on the split count
+        let prefix = dir
+            .join("crabml-test-split-mismatch")
+            .to_str()
+            .unwrap()
+            .to_string();
+        write_shards(&gf, &prefix, 3, 2)?;
+        let loader3 = GGUFFileLoader::new(&format!("{}-00001-of-00003.gguf", prefix), false)?;
+        let err = loader3.open().err().unwrap();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+
+        // a shard is missing
+        std::fs::remove_file(format!("{}-00003-of-00003.gguf", prefix)).unwrap();
+        let err = GGUFFileLoader::new(&path.replace("split", "split-mismatch"), false)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert!(err.message.contains("00003-of-00003"));
+
+        for i in 1..=3 {
+            let _ = std::fs::remove_file(format!("{}-{:05}-of-00003.gguf", prefix, i));
+            let _ = std::fs::remove_file(path.replace("00001", &format!("{:05}", i)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_split_path() {
+        assert_eq!(
+            parse_split_path("a/model-00002-of-00003.gguf"),
+            Some(("a/model", 3))
+        );
+        assert_eq!(parse_split_path("a/model-q4_0.gguf"), None);
+        assert_eq!(parse_split_path("a/model-1-of-3.gguf"), None);
+        assert_eq!(parse_split_path("a/model-00001-of-00000.gguf"), None);
+    }
+
+    fn build_gguf(tensors: &[(&str, Vec<usize>, GGMLType, &[u8])]) -> Result<Vec<u8>> {
+        let mut builder = GGUFFileBuilder::new();
+        builder.add_metadata(
+            KEY_GENERAL_ARCHITECTURE,
+            GGUFMetadataValue::String("llama".into()),
+        );
+        for (name, dims, typ, data) in tensors {
+            builder.add_tensor(GGUFTensorInfo::new(
+                name.to_string(),
+                dims.clone(),
+                *typ,
+                data,
+            ))?;
+        }
+        let mut buf = vec![];
+        builder.write(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn decode_err(buf: &[u8]) -> Error {
+        match GGUFFile::decode(&mut GGUFBufReader::new(buf)) {
+            Ok(_) => panic!("expect an error"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let data = [0u8; 80];
+
+        let buf = build_gguf(&[("a", vec![20], GGMLType::F32, &data)])?;
+        let gf = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+        gf.validate()?;
+
+        // out of range
+        let buf = build_gguf(&[("a", vec![100], GGMLType::F32, &data)])?;
+        let err = decode_err(&buf);
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert!(err.message.starts_with("out of range tensor a"), "{}", err);
+
+        // overlapped: a is declared as 80 bytes, but b is placed at the offset 64
+        let buf = build_gguf(&[
+            ("a", vec![20], GGMLType::F32, &data[..40]),
+            ("b", vec![10], GGMLType::F32, &data[..40]),
+        ])?;
+        let err = decode_err(&buf);
+        assert!(err.message.starts_with("overlapped tensor b"), "{}", err);
+
+        // size mismatch
+        let buf = build_gguf(&[("a", vec![10], GGMLType::Q8_0, &data)])?;
+        let err = decode_err(&buf);
+        assert!(
+            err.message.starts_with("size mismatch on tensor a"),
+            "{}",
+            err
+        );
+
+        // unknown type
+        let buf = build_gguf(&[("a", vec![10], GGMLType::COUNT, &data)])?;
+        let err = decode_err(&buf);
+        assert!(
+            err.message.starts_with("unknown type of tensor a"),
+            "{}",
+            err
+        );
+        let mut buf = build_gguf(&[("abc", vec![10], GGMLType::F32, &data)])?;
+        let pos = buf.windows(3).position(|w| w == b"abc").unwrap() + 3 + 4 + 8;
+        buf[pos..pos + 4].copy_from_slice(&99u32.to_le_bytes());
+        let err = decode_err(&buf);
+        assert!(
+            err.message.starts_with("unknown type of tensor abc"),
+            "{}",
+            err
+        );
+
+        // misaligned
+        let mut buf = build_gguf(&[("abc", vec![10], GGMLType::F32, &data)])?;
+        let pos = buf.windows(3).position(|w| w == b"abc").unwrap() + 3 + 4 + 8 + 4;
+        buf[pos..pos + 8].copy_from_slice(&4u64.to_le_bytes());
+        let err = decode_err(&buf);
+        assert!(err.message.starts_with("misaligned tensor abc"), "{}", err);
+
+        // truncated file
+        let buf = build_gguf(&[("a", vec![20], GGMLType::F32, &data)])?;
+        let err = decode_err(&buf[..buf.len() - 1]);
+        assert!(err.message.starts_with("out of range tensor a"), "{}", err);
+        let err = decode_err(&buf[..20]);
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_alignment() -> Result<()> {
+        let data = [0u8; 40];
+        for alignment in [0u32, 4, 12] {
+            let mut builder = GGUFFileBuilder::new();
+            builder.add_metadata(
+                KEY_GENERAL_ARCHITECTURE,
+                GGUFMetadataValue::String("llama".into()),
+            );
+            builder.add_metadata(KEY_GENERAL_ALIGNMENT, GGUFMetadataValue::U32(alignment));
+            builder.add_tensor(GGUFTensorInfo::new(
+                "a".to_string(),
+                vec![10],
+                GGMLType::F32,
+                &data,
+            ))?;
+            let err = builder.write(&mut vec![]).err().unwrap();
+            assert_eq!(err.kind, ErrorKind::FormatError);
+
+            // patch the alignment of a valid file in place
+            builder.add_metadata(KEY_GENERAL_ALIGNMENT, GGUFMetadataValue::U32(8));
+            let mut buf = vec![];
+            builder.write(&mut buf)?;
+            let pos = buf
+                .windows(KEY_GENERAL_ALIGNMENT.len())
+                .position(|w| w == KEY_GENERAL_ALIGNMENT.as_bytes())
+                .unwrap()
+                + KEY_GENERAL_ALIGNMENT.len()
+                + 4;
+            buf[pos..pos + 4].copy_from_slice(&alignment.to_le_bytes());
+            let err = decode_err(&buf);
+            assert_eq!(err.kind, ErrorKind::FormatError);
+            assert!(
+                err.message.starts_with("invalid general.alignment"),
+                "{}",
+                err
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert_tensor_infos() {
+        let data = [0u8; 80];
+        let tensor_info = |dimensions: Vec<usize>, offset: u64| GGUFOnDiskTensorInfo {
+            name: "a".to_string(),
+            dimensions,
+            typ: GGMLType::F32,
+            offset,
+        };
+
+        let infos = GGUFFile::convert_tensor_infos(&[tensor_info(vec![10], 32)], &data).unwrap();
+        assert_eq!(infos[0].data().len(), 40);
+
+        let tests = vec![
+            (tensor_info(vec![20], 32), "invalid data range of tensor a"),
+            (tensor_info(vec![1], u64::MAX), "invalid"),
+            (
+                tensor_info(vec![usize::MAX, 2], 0),
+                "invalid size of tensor a",
+            ),
+        ];
+        for (info, msg) in tests {
+            let err = GGUFFile::convert_tensor_infos(&[info], &data)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind, ErrorKind::FormatError);
+            assert!(err.message.starts_with(msg), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_load_from_bytes() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let loader = GGUFFileLoader::new(path, false)?;
+        let gf = loader.open()?;
+
+        let mut file = File::open(path).unwrap();
+        let loader2 = GGUFReaderLoader::new(&mut file)?;
+        let gf2 = loader2.open()?;
+        let gf3 = GGUFFile::from_bytes(&loader.mmaps[0])?;
+        for gf_other in [&gf2, &gf3] {
+            assert_eq!(gf.metadata().as_hashmap(), gf_other.metadata().as_hashmap());
+            assert_eq!(gf.tensor_infos().len(), gf_other.tensor_infos().len());
+            for (t1, t2) in gf.tensor_infos().iter().zip(gf_other.tensor_infos()) {
+                assert_eq!(t1.name(), t2.name());
+                assert_eq!(t1.data(), t2.data());
+            }
+        }
+
+        // the tensor data is copied into the owned buffer
+        let data = gf2.tensor_infos()[0].data();
+        assert!(!loader.mmaps[0].as_ptr_range().contains(&data.as_ptr()));
+
+        // the misaligned bytes are decoded as well, the arrays and tensors are copied
+        let buf = [&[0u8][..], &loader.mmaps[0][..]].concat();
+        let gf4 = GGUFFile::from_bytes(&buf[1..])?;
+        assert_eq!(gf.metadata().as_hashmap(), gf4.metadata().as_hashmap());
+        for (t1, t2) in gf.tensor_infos().iter().zip(gf4.tensor_infos()) {
+            assert_eq!(t1.data(), t2.data());
+            let b1 = CpuTensorBuf::from_raw_bytes(t1.data(), t1.typ())?;
+            let b2 = CpuTensorBuf::from_raw_bytes(t2.data(), t2.typ())?;
+            assert!(!b1.is_owned() && b2.is_owned());
+            assert!(b1.iter_f32().eq(b2.iter_f32()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_metadata() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let mut gf = loader.open()?;
+
+        // the value is owned, it does not need to outlive the file
+        let name = format!("renamed-{}", 1);
+        let old = gf.set_metadata(KEY_GENERAL_NAME, GGUFMetadataValue::String(name.into()))?;
+        assert_eq!(
+            old,
+            Some(GGUFMetadataValue::String("tinyllamas-stories-260k".into()))
+        );
+        assert_eq!(gf.set_metadata("a.b", GGUFMetadataValue::I16(-3))?, None);
+        assert_eq!(
+            gf.remove_metadata("tokenizer.ggml.padding_token_id")?,
+            Some(GGUFMetadataValue::U32(0))
+        );
+        assert_eq!(gf.remove_metadata("tokenizer.ggml.padding_token_id")?, None);
+
+        // the architecture is required
+        assert!(gf.remove_metadata(KEY_GENERAL_ARCHITECTURE).is_err());
+        assert!(gf
+            .set_metadata(KEY_GENERAL_ARCHITECTURE, GGUFMetadataValue::U32(1))
+            .is_err());
+        assert_eq!(gf.architecture(), "llama");
+        gf.set_metadata(
+            KEY_GENERAL_ARCHITECTURE,
+            GGUFMetadataValue::String("qwen2".into()),
+        )?;
+        assert_eq!(gf.architecture(), "qwen2");
+
+        let mut buf = vec![];
+        GGUFFileBuilder::from_gguf_file(&gf).write(&mut buf)?;
+        let loader2 = GGUFReaderLoader::new(&mut std::io::Cursor::new(&buf))?;
+        let gf2 = loader2.open()?;
+        assert_eq!(gf2.architecture(), "qwen2");
+        assert_eq!(
+            gf2.metadata().get_string(KEY_GENERAL_NAME),
+            Some("renamed-1")
+        );
+        assert_eq!(gf2.metadata().get_i16("a.b"), Some(-3));
+        assert_eq!(
+            gf2.metadata().get_u32("tokenizer.ggml.padding_token_id"),
+            None
+        );
+        assert_eq!(gf.metadata().as_hashmap(), gf2.metadata().as_hashmap());
+        for (t1, t2) in gf.tensor_infos().iter().zip(gf2.tensor_infos()) {
+            assert_eq!(t1.data(), t2.data());
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,399 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cpu::CpuTensorBuf;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::gguf::GGUFFile;
+use crate::gguf::GGUFMetadataArray;
+use crate::gguf::GGUFMetadataValue;
+use crate::gguf::GGUFTensorInfo;
+
+/// the difference between two GGUF files, `a` is the left file and `b` is the right file.
+#[derive(Debug, Clone, Default)]
+pub struct GGUFDiffReport {
+    /// the metadata keys whose values differ, or exist only in one file
+    pub metadata_diffs: Vec<GGUFMetadataDiff>,
+
+    /// the tensors exist only in the left file
+    pub tensors_only_in_a: Vec<String>,
+
+    /// the tensors exist only in the right file
+    pub tensors_only_in_b: Vec<String>,
+
+    /// the tensors exist in both files, in the order of the left file
+    pub tensor_diffs: Vec<GGUFTensorDiff>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GGUFMetadataDiff {
+    pub key: String,
+    /// the value formatted for display, none if the key is missing
+    pub a: Option<String>,
+    pub b: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GGUFTensorDiff {
+    pub name: String,
+    pub a_dimensions: Vec<usize>,
+    pub b_dimensions: Vec<usize>,
+    pub a_typ: GGMLType,
+    pub b_typ: GGMLType,
+    /// the errors are only computed on the tensors with the same shape and being able to
+    /// be dequantized.
+    pub errors: Option<GGUFTensorErrors>,
+    /// whether the raw bytes are equal, it decides whether the tensors are identical when
+    /// the errors are not computed.
+    pub same_bytes: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GGUFTensorErrors {
+    pub rmse: f64,
+    pub max_abs_error: f64,
+    pub cosine_similarity: f64,
+}
+
+impl GGUFDiffReport {
+    pub fn is_identical(&self) -> bool {
+        self.metadata_diffs.is_empty()
+            && self.tensors_only_in_a.is_empty()
+            && self.tensors_only_in_b.is_empty()
+            && self.tensor_diffs.iter().all(|d| {
+                d.is_same_layout()
+                    && match d.errors {
+                        Some(e) => e.max_abs_error == 0.0,
+                        None => d.same_bytes,
+                    }
+            })
+    }
+}
+
+impl GGUFTensorDiff {
+    pub fn is_same_layout(&self) -> bool {
+        self.a_dimensions == self.b_dimensions && self.a_typ == self.b_typ
+    }
+}
+
+/// compare the metadata and the tensors of two GGUF files. the tensors with the same name and
+/// shape are dequantized to f32 to compute the errors.
+pub fn diff_gguf_files(a: &GGUFFile, b: &GGUFFile) -> Result<GGUFDiffReport> {
+    let mut report = GGUFDiffReport::default();
+
+    let a_metadata = a.metadata().as_hashmap();
+    let b_metadata = b.metadata().as_hashmap();
+    let keys = a_metadata
+        .keys()
+        .chain(b_metadata.keys())
+        .collect::<BTreeSet<_>>();
+    for key in keys {
+        let a_value = a_metadata.get(key);
+        let b_value = b_metadata.get(key);
+        if a_value != b_value {
+            report.metadata_diffs.push(GGUFMetadataDiff {
+                key: key.clone(),
+                a: a_value.map(format_metadata_value),
+                b: b_value.map(format_metadata_value),
+            });
+        }
+    }
+
+    let a_infos = a
+        .tensor_infos()
+        .iter()
+        .map(|info| (info.name(), info))
+        .collect::<HashMap<_, _>>();
+    let b_infos = b
+        .tensor_infos()
+        .iter()
+        .map(|info| (info.name(), info))
+        .collect::<HashMap<_, _>>();
+    for a_info in a.tensor_infos() {
+        let b_info = match b_infos.get(a_info.name()) {
+            Some(b_info) => *b_info,
+            None => {
+                report.tensors_only_in_a.push(a_info.name().to_string());
+                continue;
+            }
+        };
+        let errors = if a_info.dimensions() == b_info.dimensions() {
+            compute_tensor_errors(a_info, b_info)?
+        } else {
+            None
+        };
+        report.tensor_diffs.push(GGUFTensorDiff {
+            name: a_info.name().to_string(),
+            a_dimensions: a_info.dimensions().to_vec(),
+            b_dimensions: b_info.dimensions().to_vec(),
+            a_typ: a_info.typ(),
+            b_typ: b_info.typ(),
+            errors,
+            same_bytes: a_info.data() == b_info.data(),
+        });
+    }
+    for b_info in b.tensor_infos() {
+        if !a_infos.contains_key(b_info.name()) {
+            report.tensors_only_in_b.push(b_info.name().to_string());
+        }
+    }
+    Ok(report)
+}
+
+/// the tensors in the types which can not be loaded are not dequantized.
+fn dequantize_tensor<'a>(info: &GGUFTensorInfo<'a>) -> Result<Option<CpuTensorBuf<'a>>> {
+    let buf = match CpuTensorBuf::from_raw_bytes(info.data(), info.typ()) {
+        Ok(buf) => buf,
+        Err(err) if err.kind == ErrorKind::NotImplemented => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    Ok(Some(buf.dequantize(GGMLType::F32)?))
+}
+
+/// the tensors in the types which can not be dequantized are skipped.
+fn compute_tensor_errors(
+    a: &GGUFTensorInfo,
+    b: &GGUFTensorInfo,
+) -> Result<Option<GGUFTensorErrors>> {
+    let (a_buf, b_buf) = match (dequantize_tensor(a)?, dequantize_tensor(b)?) {
+        (Some(a_buf), Some(b_buf)) => (a_buf, b_buf),
+        _ => return Ok(None),
+    };
+    Ok(Some(tensor_errors(a_buf.as_f32_ref(), b_buf.as_f32_ref())))
+}
+
+fn tensor_errors(a: &[f32], b: &[f32]) -> GGUFTensorErrors {
+    let mut sum_sq_error = 0.0f64;
+    let mut max_abs_error = 0.0f64;
+    let mut dot = 0.0f64;
+    let mut a_norm = 0.0f64;
+    let mut b_norm = 0.0f64;
+    for (a, b) in a.iter().zip(b.iter()) {
+        let (a, b) = (*a as f64, *b as f64);
+        let error = (a - b).abs();
+        sum_sq_error += error * error;
+        // f64::max drops the NaN, a NaN in either tensor must not be reported as no error
+        if error > max_abs_error || error.is_nan() {
+            max_abs_error = error;
+        }
+        dot += a * b;
+        a_norm += a * a;
+        b_norm += b * b;
+    }
+
+    let n = a.len().min(b.len()).max(1) as f64;
+    let cosine_similarity = if a_norm == 0.0 && b_norm == 0.0 {
+        1.0
+    } else if a_norm == 0.0 || b_norm == 0.0 {
+        0.0
+    } else {
+        dot / (a_norm.sqrt() * b_norm.sqrt())
+    };
+    GGUFTensorErrors {
+        rmse: (sum_sq_error / n).sqrt(),
+        max_abs_error,
+        cosine_similarity,
+    }
+}
+
+/// the arrays like the vocabulary are too long to display, only their type and length are shown.
+fn format_metadata_value(value: &GGUFMetadataValue) -> String {
+    match value {
+        GGUFMetadataValue::Array(arr) if arr.len() > 8 => {
+            format!("Array({:?}, len={})", arr.typ(), arr.len())
+        }
+        GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(arr)) => {
+            format!("Array({:?})", arr)
+        }
+        _ => format!("{:?}", value),
+    }
+}
+
+impl fmt::Display for GGUFDiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "metadata: {} keys differ", self.metadata_diffs.len())?;
+        for diff in self.metadata_diffs.iter() {
+            let a = diff.a.as_deref().unwrap_or("<missing>");
+            let b = diff.b.as_deref().unwrap_or("<missing>");
+            writeln!(f, "  {}: {} | {}", diff.key, a, b)?;
+        }
+
+        writeln!(f, "tensors:")?;
+        for name in self.tensors_only_in_a.iter() {
+            writeln!(f, "  {}: only in a", name)?;
+        }
+        for name in self.tensors_only_in_b.iter() {
+            writeln!(f, "  {}: only in b", name)?;
+        }
+        for diff in self.tensor_diffs.iter() {
+            write!(f, "  {:<32}", diff.name)?;
+            if diff.is_same_layout() {
+                write!(f, " {} {:?}", diff.a_typ, diff.a_dimensions)?;
+            } else {
+                write!(
+                    f,
+                    " {} {:?} | {} {:?}",
+                    diff.a_typ, diff.a_dimensions, diff.b_typ, diff.b_dimensions
+                )?;
+            }
+            match diff.errors {
+                Some(errors) => writeln!(
+                    f,
+                    ", rmse={:.6}, max_abs={:.6}, cos={:.6}",
+                    errors.rmse, errors.max_abs_error, errors.cosine_similarity
+                )?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGUFFileBuilder;
+    use crate::gguf::GGUFFileLoader;
+
+    #[test]
+    fn test_tensor_errors() {
+        let errors = tensor_errors(&[1.0, 2.0, 3.0, 4.0], &[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(errors.rmse, 0.0);
+        assert_eq!(errors.max_abs_error, 0.0);
+        assert!((errors.cosine_similarity - 1.0).abs() < 1e-12);
+
+        let errors = tensor_errors(&[1.0, 0.0], &[0.0, 1.0]);
+        assert!((errors.rmse - 1.0).abs() < 1e-12);
+        assert_eq!(errors.max_abs_error, 1.0);
+        assert_eq!(errors.cosine_similarity, 0.0);
+
+        let errors = tensor_errors(&[0.0, 0.0], &[0.0, 0.0]);
+        assert_eq!(errors.cosine_similarity, 1.0);
+
+        // the NaN is kept wherever it is, and the tensors are not identical
+        for a in [[f32::NAN, 1.0, 5.0], [1.0, 5.0, f32::NAN]] {
+            let errors = tensor_errors(&a, &[1.0, 1.0, 1.0]);
+            assert!(errors.max_abs_error.is_nan(), "{:?}", errors);
+            assert!(errors.rmse.is_nan(), "{:?}", errors);
+        }
+    }
+
+    #[test]
+    fn test_compute_tensor_errors() -> Result<()> {
+        let a = [1.0f32, f32::NAN]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let b = [1.0f32, 2.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let a_info = GGUFTensorInfo::new("a".to_string(), vec![2], GGMLType::F32, &a);
+        let b_info = GGUFTensorInfo::new("b".to_string(), vec![2], GGMLType::F32, &b);
+        let errors = compute_tensor_errors(&a_info, &b_info)?.unwrap();
+        assert!(errors.max_abs_error.is_nan());
+        let report = GGUFDiffReport {
+            tensor_diffs: vec![GGUFTensorDiff {
+                name: "a".to_string(),
+                a_dimensions: vec![2],
+                b_dimensions: vec![2],
+                a_typ: GGMLType::F32,
+                b_typ: GGMLType::F32,
+                errors: Some(errors),
+                same_bytes: false,
+            }],
+            ..Default::default()
+        };
+        assert!(!report.is_identical());
+
+        // the tensors in the types which can not be loaded are skipped
+        let c = vec![0u8; 66];
+        let c_info = GGUFTensorInfo::new("c".to_string(), vec![256], GGMLType::IQ2XXS, &c);
+        assert_eq!(compute_tensor_errors(&c_info, &c_info)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_gguf_files() -> Result<()> {
+        let gl_a = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf_a = gl_a.open()?;
+        let gl_b = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf_b = gl_b.open()?;
+
+        let report = diff_gguf_files(&gf_a, &gf_a)?;
+        assert!(report.is_identical());
+
+        let report = diff_gguf_files(&gf_a, &gf_b)?;
+        assert!(!report.is_identical());
+        assert!(report.tensors_only_in_a.is_empty());
+        assert!(report.tensors_only_in_b.is_empty());
+        let diff = report
+            .tensor_diffs
+            .iter()
+            .find(|d| d.name == "blk.0.attn_q.weight")
+            .unwrap();
+        assert_eq!(diff.a_typ, GGMLType::F32);
+        assert_eq!(diff.b_typ, GGMLType::Q8_0);
+        let errors = diff.errors.unwrap();
+        assert!(errors.rmse > 0.0 && errors.rmse < 0.01, "{:?}", errors);
+        assert!(errors.cosine_similarity > 0.999, "{:?}", errors);
+
+        // drop a tensor and change the metadata
+        let mut builder = GGUFFileBuilder::new();
+        let mut metadata = gf_a.metadata().as_hashmap().iter().collect::<Vec<_>>();
+        metadata.sort_by_key(|(k, _)| *k);
+        for (k, v) in metadata {
+            builder.add_metadata(k.clone(), v.clone());
+        }
+        builder.add_metadata("general.name", GGUFMetadataValue::String("other".into()));
+        for info in gf_a.tensor_infos().iter().skip(1) {
+            builder.add_tensor(info.clone())?;
+        }
+        let mut buf = vec![];
+        builder.write(&mut buf)?;
+        let gf_c = GGUFFile::from_bytes(&buf)?;
+
+        let report = diff_gguf_files(&gf_a, &gf_c)?;
+        assert_eq!(report.tensors_only_in_a, vec!["token_embd.weight"]);
+        assert_eq!(report.metadata_diffs.len(), 1);
+        assert_eq!(report.metadata_diffs[0].key, "general.name");
+        assert_eq!(
+            report.metadata_diffs[0].b.as_deref(),
+            Some("String(\"other\")")
+        );
+        assert!(report.to_string().contains("token_embd.weight: only in a"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_identical_without_errors() {
+        // the tensors which can not be dequantized are compared by the raw bytes
+        let diff = GGUFTensorDiff {
+            name: "a".to_string(),
+            a_dimensions: vec![256],
+            b_dimensions: vec![256],
+            a_typ: GGMLType::IQ2XXS,
+            b_typ: GGMLType::IQ2XXS,
+            errors: None,
+            same_bytes: true,
+        };
+        let mut report = GGUFDiffReport {
+            tensor_diffs: vec![diff],
+            ..Default::default()
+        };
+        assert!(report.is_identical());
+
+        report.tensor_diffs[0].same_bytes = false;
+        assert!(!report.is_identical());
+
+        // the errors take precedence over the bytes, like the -0.0 and 0.0
+        report.tensor_diffs[0].errors = Some(GGUFTensorErrors {
+            rmse: 0.0,
+            max_abs_error: 0.0,
+            cosine_similarity: 1.0,
+        });
+        assert!(report.is_identical());
+    }
+}
@@ -0,0 +1,428 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::bail;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// the importance matrix of a model, it holds the sum of the squared activations on each column
+/// of the weights, which tells the quantization which columns matter more. the file format is
+/// compatible with the `imatrix.dat` of llama.cpp:
+///
+/// ```text
+/// i32 n_entries
+/// n_entries * { i32 name_len, [u8; name_len] name, i32 ncall, i32 nval, [f32; nval] values }
+/// i32 last_call, i32 dataset_len, [u8; dataset_len] dataset  (optional)
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IMatrix {
+    entries: Vec<IMatrixEntry>,
+    /// the number of chunks the matrix was computed with
+    last_call: i32,
+    /// the calibration text file the matrix was computed from
+    dataset: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IMatrixEntry {
+    pub name: String,
+    /// the number of the calls the values are summed over
+    pub ncall: i32,
+    pub values: Vec<f32>,
+}
+
+impl IMatrix {
+    pub fn new(last_call: i32, dataset: impl Into<String>) -> Self {
+        Self {
+            entries: vec![],
+            last_call,
+            dataset: dataset.into(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let buf = std::fs::read(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the imatrix file: {}", path),
+            cause: Some(Arc::new(err)),
+        })?;
+        Self::from_bytes(&buf)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let mut r = IMatrixReader { buf, pos: 0 };
+        let n_entries = r.read_len()?;
+        let mut entries = Vec::with_capacity(n_entries.min(4096));
+        for _ in 0..n_entries {
+            let name = r.read_string()?;
+            let ncall = r.read_i32()?;
+            let nval = r.read_len()?;
+            let values = r
+                .read_bytes(nval * 4)?
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            entries.push(IMatrixEntry {
+                name,
+                ncall,
+                values,
+            });
+        }
+
+        // the files written by the older versions of llama.cpp do not have the trailer
+        let (last_call, dataset) = if r.is_eof() {
+            (0, String::new())
+        } else {
+            (r.read_i32()?, r.read_string()?)
+        };
+        Ok(Self {
+            entries,
+            last_call,
+            dataset,
+        })
+    }
+
+    pub fn entries(&self) -> &[IMatrixEntry] {
+        &self.entries
+    }
+
+    pub fn get_entry(&self, name: &str) -> Option<&IMatrixEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    pub fn last_call(&self) -> i32 {
+        self.last_call
+    }
+
+    pub fn dataset(&self) -> &str {
+        &self.dataset
+    }
+
+    /// add or replace the entry of a tensor.
+    pub fn add_entry(&mut self, name: impl Into<String>, ncall: i32, values: Vec<f32>) {
+        let name = name.into();
+        let entry = IMatrixEntry {
+            name,
+            ncall,
+            values,
+        };
+        match self.entries.iter_mut().find(|e| e.name == entry.name) {
+            Some(e) => *e = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    /// the importance of each column of the tensor, which is the average of the values over
+    /// the calls, like how llama.cpp's quantize loads the imatrix.
+    pub fn importance(&self, name: &str) -> Option<Vec<f32>> {
+        let entry = self.get_entry(name)?;
+        let ncall = entry.ncall.max(1) as f32;
+        Some(entry.values.iter().map(|v| v / ncall).collect())
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = vec![];
+        buf.extend((self.entries.len() as i32).to_le_bytes());
+        for entry in self.entries.iter() {
+            buf.extend((entry.name.len() as i32).to_le_bytes());
+            buf.extend(entry.name.as_bytes());
+            buf.extend(entry.ncall.to_le_bytes());
+            buf.extend((entry.values.len() as i32).to_le_bytes());
+            buf.extend(entry.values.iter().flat_map(|v| v.to_le_bytes()));
+        }
+        buf.extend(self.last_call.to_le_bytes());
+        buf.extend((self.dataset.len() as i32).to_le_bytes());
+        buf.extend(self.dataset.as_bytes());
+
+        w.write_all(&buf).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: "failed to write the imatrix".to_string(),
+            cause: Some(Arc::new(err)),
+        })
+    }
+
+    pub fn write_to_file(&self, path: &str) -> Result<()> {
+        let file = File::create(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to create the file: {}", path),
+            cause: Some(Arc::new(err)),
+        })?;
+        let mut w = BufWriter::new(file);
+        self.write(&mut w)?;
+        w.flush().map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to flush the file: {}", path),
+            cause: Some(Arc::new(err)),
+        })?;
+        Ok(())
+    }
+}
+
+/// accumulates the squared activations fed into the weights on the forward passes, like the
+/// `imatrix` tool of llama.cpp.
+#[derive(Debug, Clone, Default)]
+pub struct IMatrixCollector {
+    entries: Vec<CollectorEntry>,
+}
+
+#[derive(Debug, Clone)]
+struct CollectorEntry {
+    name: String,
+    ncall: i32,
+    /// the number of the activation rows summed into each expert of `values`, there's only one
+    /// expert on the dense weights
+    nrows: Vec<usize>,
+    values: Vec<f64>,
+}
+
+impl IMatrixCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// collect the activations of a matmul on the weight `name`, the activations are in the
+    /// shape of (n_rows, n_cols), and n_cols is the number of the columns of the weight.
+    pub fn collect(&mut self, name: &str, activations: &[f32], n_cols: usize) -> Result<()> {
+        let n_rows = if n_cols == 0 {
+            0
+        } else {
+            activations.len() / n_cols
+        };
+        self.collect_experts(name, activations, n_cols, 1, &vec![0; n_rows])
+    }
+
+    /// collect the activations of the matmuls on the stacked experts `name`, every row of the
+    /// activations is fed into the expert in `experts`. the values of the experts are saved in
+    /// turn, like what llama.cpp does on the `_exps` weights.
+    pub fn collect_experts(
+        &mut self,
+        name: &str,
+        activations: &[f32],
+        n_cols: usize,
+        n_experts: usize,
+        experts: &[usize],
+    ) -> Result<()> {
+        if n_cols == 0 || activations.len() != experts.len() * n_cols {
+            bail!(
+                ErrorKind::BadInput,
+                "invalid activations of {}: {} values in {} columns",
+                name,
+                activations.len(),
+                n_cols
+            );
+        }
+        if let Some(e) = experts.iter().find(|e| **e >= n_experts) {
+            bail!(
+                ErrorKind::BadInput,
+                "invalid expert {} of {}, there are {} experts",
+                e,
+                name,
+                n_experts
+            );
+        }
+
+        let idx = match self.entries.iter().position(|e| e.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.entries.push(CollectorEntry {
+                    name: name.to_string(),
+                    ncall: 0,
+                    nrows: vec![0; n_experts],
+                    values: vec![0.0; n_cols * n_experts],
+                });
+                self.entries.len() - 1
+            }
+        };
+        let entry = &mut self.entries[idx];
+        if entry.values.len() != n_cols * n_experts || entry.nrows.len() != n_experts {
+            bail!(
+                ErrorKind::BadInput,
+                "the activations of {} have {} columns of {} experts, but {} values were collected before",
+                name,
+                n_cols,
+                n_experts,
+                entry.values.len()
+            );
+        }
+
+        entry.ncall += 1;
+        for (row, e) in activations.chunks_exact(n_cols).zip(experts.iter()) {
+            let values = &mut entry.values[e * n_cols..(e + 1) * n_cols];
+            for (v, x) in values.iter_mut().zip(row.iter()) {
+                *v += (*x as f64) * (*x as f64);
+            }
+            entry.nrows[*e] += 1;
+        }
+        Ok(())
+    }
+
+    /// the names of the weights collected so far, in the order they were first seen.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|e| e.name.as_str())
+    }
+
+    /// the values are saved as the mean of the squared activations multiplied by ncall, the
+    /// same as llama.cpp does, so that `IMatrix::importance` gives back the mean.
+    pub fn to_imatrix(&self, last_call: i32, dataset: impl Into<String>) -> IMatrix {
+        let mut imatrix = IMatrix::new(last_call, dataset);
+        for entry in self.entries.iter() {
+            let n_cols = entry.values.len() / entry.nrows.len();
+            let values = entry
+                .values
+                .chunks(n_cols)
+                .zip(entry.nrows.iter())
+                .flat_map(|(values, nrows)| {
+                    let nrows = (*nrows).max(1) as f64;
+                    values
+                        .iter()
+                        .map(move |v| (v / nrows * entry.ncall as f64) as f32)
+                })
+                .collect();
+            imatrix.add_entry(entry.name.clone(), entry.ncall, values);
+        }
+        imatrix
+    }
+}
+
+struct IMatrixReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> IMatrixReader<'a> {
+    fn is_eof(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            bail!(
+                ErrorKind::FormatError,
+                "unexpected end of the imatrix at offset {}",
+                self.pos
+            );
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_i32(&mut self) -> Result<i32> {
+        let b = self.read_bytes(4)?;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn read_len(&mut self) -> Result<usize> {
+        let len = self.read_i32()?;
+        if len < 0 {
+            bail!(
+                ErrorKind::FormatError,
+                "invalid length in the imatrix: {}",
+                len
+            );
+        }
+        Ok(len as usize)
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_len()?;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: "invalid utf-8 string in the imatrix".to_string(),
+            cause: Some(Arc::new(err)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_imatrix_roundtrip() -> Result<()> {
+        let mut imatrix = IMatrix::new(10, "calibration.txt");
+        imatrix.add_entry("blk.0.attn_q.weight", 10, vec![10.0, 20.0, 30.0]);
+        imatrix.add_entry("blk.0.ffn_up.weight", 5, vec![5.0, 0.0]);
+        imatrix.add_entry("blk.0.ffn_up.weight", 2, vec![4.0, 2.0]);
+
+        let mut buf = vec![];
+        imatrix.write(&mut buf)?;
+        let imatrix2 = IMatrix::from_bytes(&buf)?;
+        assert_eq!(imatrix, imatrix2);
+        assert_eq!(imatrix2.entries().len(), 2);
+        assert_eq!(imatrix2.last_call(), 10);
+        assert_eq!(imatrix2.dataset(), "calibration.txt");
+        assert_eq!(
+            imatrix2.importance("blk.0.attn_q.weight"),
+            Some(vec![1.0, 2.0, 3.0])
+        );
+        assert_eq!(
+            imatrix2.importance("blk.0.ffn_up.weight"),
+            Some(vec![2.0, 1.0])
+        );
+        assert_eq!(imatrix2.importance("output.weight"), None);
+
+        // the older files do not have the trailer
+        let legacy = &buf[..buf.len() - 4 - 4 - "calibration.txt".len()];
+        let imatrix3 = IMatrix::from_bytes(legacy)?;
+        assert_eq!(imatrix3.entries(), imatrix.entries());
+        assert_eq!(imatrix3.last_call(), 0);
+
+        assert!(IMatrix::from_bytes(&buf[..buf.len() - 20]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_imatrix_collector() -> Result<()> {
+        let mut collector = IMatrixCollector::new();
+        collector.collect("blk.0.attn_q.weight", &[1.0, 2.0, 3.0, 3.0, 2.0, 1.0], 3)?;
+        collector.collect("blk.0.attn_q.weight", &[2.0, 0.0, 1.0], 3)?;
+        collector.collect("blk.0.ffn_down.weight", &[-2.0, 4.0], 2)?;
+        assert!(collector
+            .collect("blk.0.ffn_down.weight", &[1.0], 3)
+            .is_err());
+        assert!(collector
+            .collect("blk.0.ffn_down.weight", &[1.0, 2.0, 3.0], 3)
+            .is_err());
+        assert_eq!(collector.names().collect::<Vec<_>>(), vec![
+            "blk.0.attn_q.weight",
+            "blk.0.ffn_down.weight"
+        ]);
+
+        let imatrix = collector.to_imatrix(1, "calibration.txt");
+        let entry = imatrix.get_entry("blk.0.attn_q.weight").unwrap();
+        assert_eq!(entry.ncall, 2);
+        assert_eq!(entry.values, vec![28.0 / 3.0, 16.0 / 3.0, 22.0 / 3.0]);
+        assert_eq!(
+            imatrix.importance("blk.0.attn_q.weight"),
+            Some(vec![14.0 / 3.0, 8.0 / 3.0, 11.0 / 3.0])
+        );
+        assert_eq!(
+            imatrix.importance("blk.0.ffn_down.weight"),
+            Some(vec![4.0, 16.0])
+        );
+
+        // every expert is averaged over its own rows, the unused ones are zeros
+        let name = "blk.0.ffn_up_exps.weight";
+        let mut collector = IMatrixCollector::new();
+        collector.collect_experts(name, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 2, 3, &[2, 0, 2])?;
+        collector.collect_experts(name, &[1.0, 1.0], 2, 3, &[0])?;
+        assert!(collector
+            .collect_experts(name, &[1.0, 1.0], 2, 3, &[3])
+            .is_err());
+        assert!(collector
+            .collect_experts(name, &[1.0, 1.0], 2, 2, &[0])
+            .is_err());
+        assert!(collector.collect_experts(name, &[1.0], 2, 3, &[0]).is_err());
+        let imatrix = collector.to_imatrix(1, "calibration.txt");
+        assert_eq!(
+            imatrix.importance(name),
+            Some(vec![5.0, 8.5, 0.0, 0.0, 13.0, 20.0])
+        );
+        Ok(())
+    }
+}
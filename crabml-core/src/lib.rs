@@ -8,5 +8,8 @@
 pub mod cpu;
 pub mod error;
 pub mod gguf;
+pub mod gguf_diff;
+pub mod imatrix;
+pub mod safetensors;
 pub mod tensor;
 pub mod tokenizer;
@@ -0,0 +1,480 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use half::bf16;
+use half::f16;
+use memmap2::Mmap;
+use serde_json::Value;
+
+use crate::bail;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGUFFileLoader;
+
+/// the single file checkpoint in a Hugging Face model directory
+pub const SAFETENSORS_FILE: &str = "model.safetensors";
+
+/// the index of a sharded checkpoint, its `weight_map` maps the tensor names to the shard files
+pub const SAFETENSORS_INDEX_FILE: &str = "model.safetensors.index.json";
+
+// the header of a safetensors file is a JSON object, it's unlikely to be larger than 100MB.
+const SAFETENSORS_MAX_HEADER_LEN: usize = 100 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafeTensorsDType {
+    Bool,
+    U8,
+    I8,
+    U16,
+    I16,
+    F16,
+    BF16,
+    U32,
+    I32,
+    F32,
+    U64,
+    I64,
+    F64,
+}
+
+impl SafeTensorsDType {
+    /// the size of an element in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            SafeTensorsDType::Bool | SafeTensorsDType::U8 | SafeTensorsDType::I8 => 1,
+            SafeTensorsDType::U16
+            | SafeTensorsDType::I16
+            | SafeTensorsDType::F16
+            | SafeTensorsDType::BF16 => 2,
+            SafeTensorsDType::U32 | SafeTensorsDType::I32 | SafeTensorsDType::F32 => 4,
+            SafeTensorsDType::U64 | SafeTensorsDType::I64 | SafeTensorsDType::F64 => 8,
+        }
+    }
+}
+
+impl FromStr for SafeTensorsDType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let dtype = match s {
+            "BOOL" => SafeTensorsDType::Bool,
+            "U8" => SafeTensorsDType::U8,
+            "I8" => SafeTensorsDType::I8,
+            "U16" => SafeTensorsDType::U16,
+            "I16" => SafeTensorsDType::I16,
+            "F16" => SafeTensorsDType::F16,
+            "BF16" => SafeTensorsDType::BF16,
+            "U32" => SafeTensorsDType::U32,
+            "I32" => SafeTensorsDType::I32,
+            "F32" => SafeTensorsDType::F32,
+            "U64" => SafeTensorsDType::U64,
+            "I64" => SafeTensorsDType::I64,
+            "F64" => SafeTensorsDType::F64,
+            _ => bail!(ErrorKind::FormatError, "unknown safetensors dtype {}", s),
+        };
+        Ok(dtype)
+    }
+}
+
+impl fmt::Display for SafeTensorsDType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            SafeTensorsDType::Bool => "BOOL",
+            SafeTensorsDType::U8 => "U8",
+            SafeTensorsDType::I8 => "I8",
+            SafeTensorsDType::U16 => "U16",
+            SafeTensorsDType::I16 => "I16",
+            SafeTensorsDType::F16 => "F16",
+            SafeTensorsDType::BF16 => "BF16",
+            SafeTensorsDType::U32 => "U32",
+            SafeTensorsDType::I32 => "I32",
+            SafeTensorsDType::F32 => "F32",
+            SafeTensorsDType::U64 => "U64",
+            SafeTensorsDType::I64 => "I64",
+            SafeTensorsDType::F64 => "F64",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SafeTensorsTensorInfo<'a> {
+    name: String,
+    dtype: SafeTensorsDType,
+    // the shape is in the row major order like numpy, which is reversed from GGUF
+    shape: Vec<usize>,
+    data: &'a [u8],
+}
+
+impl<'a> SafeTensorsTensorInfo<'a> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dtype(&self) -> SafeTensorsDType {
+        self.dtype
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// convert the floating point tensors into an owned f32 buffer.
+    pub fn to_f32_vec(&self) -> Result<Vec<f32>> {
+        let buf = match self.dtype {
+            SafeTensorsDType::F32 => self
+                .data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            SafeTensorsDType::F16 => self
+                .data
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(),
+            SafeTensorsDType::BF16 => self
+                .data
+                .chunks_exact(2)
+                .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(),
+            dtype => bail!(
+                ErrorKind::NotImplemented,
+                "converting {} tensor {} into f32 is not supported",
+                dtype,
+                self.name
+            ),
+        };
+        Ok(buf)
+    }
+}
+
+/// SafeTensorsFile is the checkpoint format used by Hugging Face. a file begins with a u64 of
+/// the header length, followed by a JSON header describing the dtype, shape and data offsets
+/// of each tensor, the tensor data come after the header.
+pub struct SafeTensorsFile<'a> {
+    metadata: HashMap<String, String>,
+    tensor_infos: Vec<SafeTensorsTensorInfo<'a>>,
+}
+
+impl<'a> SafeTensorsFile<'a> {
+    pub fn from_bytes(buf: &'a [u8]) -> Result<Self> {
+        Self::from_shard_bytes(&[buf])
+    }
+
+    /// decode a checkpoint which is sharded into multiple files, the tensors of all the shards
+    /// are merged in the order of the shards.
+    pub fn from_shard_bytes(bufs: &[&'a [u8]]) -> Result<Self> {
+        let mut metadata = HashMap::new();
+        let mut tensor_infos: Vec<SafeTensorsTensorInfo<'a>> = vec![];
+        let mut names = BTreeSet::new();
+        for buf in bufs.iter() {
+            let (shard_metadata, shard_tensor_infos) = Self::decode(buf)?;
+            metadata.extend(shard_metadata);
+            for info in shard_tensor_infos {
+                if !names.insert(info.name.clone()) {
+                    bail!(
+                        ErrorKind::FormatError,
+                        "duplicated tensor {} in the shards",
+                        info.name
+                    );
+                }
+                tensor_infos.push(info);
+            }
+        }
+        Ok(Self {
+            metadata,
+            tensor_infos,
+        })
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(HashMap<String, String>, Vec<SafeTensorsTensorInfo<'a>>)> {
+        if buf.len() < 8 {
+            bail!(ErrorKind::FormatError, "the safetensors file is too short");
+        }
+        let header_len = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+        if header_len > SAFETENSORS_MAX_HEADER_LEN as u64 || header_len + 8 > buf.len() as u64 {
+            bail!(
+                ErrorKind::FormatError,
+                "invalid safetensors header length {}",
+                header_len
+            );
+        }
+        let header_end = 8 + header_len as usize;
+        let header: Value = serde_json::from_slice(&buf[8..header_end]).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: "failed to parse the safetensors header".to_string(),
+            cause: Some(Arc::new(err)),
+        })?;
+        let header = match header {
+            Value::Object(header) => header,
+            _ => bail!(
+                ErrorKind::FormatError,
+                "the safetensors header is not an object"
+            ),
+        };
+        let data = &buf[header_end..];
+
+        let mut metadata = HashMap::new();
+        let mut entries = Vec::with_capacity(header.len());
+        for (name, value) in header.iter() {
+            if name == "__metadata__" {
+                if let Value::Object(kvs) = value {
+                    for (k, v) in kvs.iter() {
+                        if let Value::String(v) = v {
+                            metadata.insert(k.clone(), v.clone());
+                        }
+                    }
+                }
+                continue;
+            }
+            entries.push(Self::decode_tensor_entry(name, value)?);
+        }
+
+        // keep the tensors in the order of their data, and make sure they do not overlap
+        entries.sort_by_key(|(_, _, _, (begin, _))| *begin);
+        let mut tensor_infos = Vec::with_capacity(entries.len());
+        let mut prev_end = 0;
+        for (name, dtype, shape, (begin, end)) in entries {
+            if end > data.len() {
+                bail!(ErrorKind::FormatError, "out of range tensor {}", name);
+            }
+            if begin < prev_end {
+                bail!(ErrorKind::FormatError, "overlapped tensor {}", name);
+            }
+            let expected_len = shape
+                .iter()
+                .try_fold(dtype.size(), |acc, d| acc.checked_mul(*d));
+            if expected_len != Some(end - begin) {
+                bail!(ErrorKind::FormatError, "size mismatch on tensor {}", name);
+            }
+            prev_end = end;
+            tensor_infos.push(SafeTensorsTensorInfo {
+                name,
+                dtype,
+                shape,
+                data: &data[begin..end],
+            });
+        }
+        Ok((metadata, tensor_infos))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn decode_tensor_entry(
+        name: &str,
+        value: &Value,
+    ) -> Result<(String, SafeTensorsDType, Vec<usize>, (usize, usize))> {
+        let invalid = || {
+            Err(Error {
+                kind: ErrorKind::FormatError,
+                message: format!("invalid header of tensor {}", name),
+                cause: None,
+            })
+        };
+
+        let dtype = match value.get("dtype").and_then(|v| v.as_str()) {
+            Some(dtype) => dtype.parse::<SafeTensorsDType>()?,
+            None => return invalid(),
+        };
+        let shape = match value.get("shape").and_then(|v| v.as_array()) {
+            Some(shape) => shape
+                .iter()
+                .map(|d| d.as_u64().map(|d| d as usize))
+                .collect::<Option<Vec<_>>>(),
+            None => None,
+        };
+        let offsets = match value.get("data_offsets").and_then(|v| v.as_array()) {
+            Some(offsets) if offsets.len() == 2 => offsets[0]
+                .as_u64()
+                .zip(offsets[1].as_u64())
+                .map(|(begin, end)| (begin as usize, end as usize)),
+            _ => None,
+        };
+        match (shape, offsets) {
+            (Some(shape), Some((begin, end))) if begin <= end => {
+                Ok((name.to_string(), dtype, shape, (begin, end)))
+            }
+            _ => invalid(),
+        }
+    }
+
+    /// the free form string metadata in the `__metadata__` of the header
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn tensor_infos(&self) -> &[SafeTensorsTensorInfo<'a>] {
+        &self.tensor_infos
+    }
+
+    pub fn get_tensor_info(&self, name: &str) -> Option<&SafeTensorsTensorInfo<'a>> {
+        self.tensor_infos.iter().find(|info| info.name == name)
+    }
+}
+
+pub struct SafeTensorsFileLoader {
+    mmaps: Vec<Mmap>,
+}
+
+impl SafeTensorsFileLoader {
+    /// map the safetensors checkpoint on the path. the path can be a `.safetensors` file, a
+    /// `model.safetensors.index.json` of a sharded checkpoint, or a Hugging Face model directory
+    /// containing either of them.
+    pub fn new(path: &str, mlock: bool) -> Result<Self> {
+        let paths = Self::resolve_shard_paths(Path::new(path))?;
+        let mut mmaps = Vec::with_capacity(paths.len());
+        for path in paths.iter() {
+            mmaps.push(GGUFFileLoader::mmap_file(&path.to_string_lossy(), mlock)?);
+        }
+        Ok(Self { mmaps })
+    }
+
+    fn resolve_shard_paths(path: &Path) -> Result<Vec<PathBuf>> {
+        if path.is_dir() {
+            let index_path = path.join(SAFETENSORS_INDEX_FILE);
+            if index_path.exists() {
+                return Self::read_index(&index_path);
+            }
+            return Ok(vec![path.join(SAFETENSORS_FILE)]);
+        }
+        if path.extension().is_some_and(|ext| ext == "json") {
+            return Self::read_index(path);
+        }
+        Ok(vec![path.to_path_buf()])
+    }
+
+    /// read the shard files from the `weight_map` of the index, the shards are sorted by name.
+    fn read_index(path: &Path) -> Result<Vec<PathBuf>> {
+        let content = std::fs::read_to_string(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the file: {}", path.display()),
+            cause: Some(Arc::new(err)),
+        })?;
+        let index: Value = serde_json::from_str(&content).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: format!("failed to parse the index: {}", path.display()),
+            cause: Some(Arc::new(err)),
+        })?;
+        let files = match index.get("weight_map").and_then(|v| v.as_object()) {
+            Some(weight_map) => weight_map
+                .values()
+                .filter_map(|v| v.as_str())
+                .collect::<BTreeSet<_>>(),
+            None => bail!(
+                ErrorKind::FormatError,
+                "missing weight_map in the index: {}",
+                path.display()
+            ),
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Ok(files.into_iter().map(|f| dir.join(f)).collect())
+    }
+
+    pub fn open(&self) -> Result<SafeTensorsFile<'_>> {
+        let bufs = self.mmaps.iter().map(|mmap| &mmap[..]).collect::<Vec<_>>();
+        SafeTensorsFile::from_shard_bytes(&bufs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_safetensors(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        buf.extend_from_slice(header.as_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn test_load_safetensors() -> Result<()> {
+        let header = r#"{
+            "__metadata__": {"format": "pt"},
+            "b": {"dtype": "BF16", "shape": [2], "data_offsets": [8, 12]},
+            "a": {"dtype": "F32", "shape": [1, 2], "data_offsets": [0, 8]}
+        }"#;
+        let mut data = vec![];
+        data.extend_from_slice(&1.5f32.to_le_bytes());
+        data.extend_from_slice(&(-2.0f32).to_le_bytes());
+        data.extend_from_slice(&bf16::from_f32(0.5).to_le_bytes());
+        data.extend_from_slice(&bf16::from_f32(3.0).to_le_bytes());
+        let buf = build_safetensors(header, &data);
+
+        let st = SafeTensorsFile::from_bytes(&buf)?;
+        assert_eq!(st.metadata().get("format").map(|s| s.as_str()), Some("pt"));
+        let names = st
+            .tensor_infos()
+            .iter()
+            .map(|t| t.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "b"]);
+
+        let a = st.get_tensor_info("a").unwrap();
+        assert_eq!(a.dtype(), SafeTensorsDType::F32);
+        assert_eq!(a.shape(), &[1, 2]);
+        assert_eq!(a.to_f32_vec()?, vec![1.5, -2.0]);
+        let b = st.get_tensor_info("b").unwrap();
+        assert_eq!(b.dtype(), SafeTensorsDType::BF16);
+        assert_eq!(b.to_f32_vec()?, vec![0.5, 3.0]);
+
+        let shard = build_safetensors(
+            r#"{"c": {"dtype": "F16", "shape": [1], "data_offsets": [0, 2]}}"#,
+            &f16::from_f32(1.0).to_le_bytes(),
+        );
+        let st = SafeTensorsFile::from_shard_bytes(&[&buf, &shard])?;
+        assert_eq!(st.tensor_infos().len(), 3);
+        assert_eq!(st.get_tensor_info("c").unwrap().to_f32_vec()?, vec![1.0]);
+        assert!(SafeTensorsFile::from_shard_bytes(&[&shard, &shard]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_invalid_safetensors() {
+        let tests = vec![
+            (
+                r#"{"a": {"dtype": "F32", "shape": [3], "data_offsets": [0, 8]}}"#,
+                "size mismatch on tensor a",
+            ),
+            (
+                r#"{"a": {"dtype": "F32", "shape": [4], "data_offsets": [0, 16]}}"#,
+                "out of range tensor a",
+            ),
+            (
+                r#"{"a": {"dtype": "F32", "shape": [2], "data_offsets": [0, 8]},
+                    "b": {"dtype": "F32", "shape": [1], "data_offsets": [4, 8]}}"#,
+                "overlapped tensor b",
+            ),
+            (
+                r#"{"a": {"dtype": "Q4", "shape": [2], "data_offsets": [0, 8]}}"#,
+                "unknown safetensors dtype Q4",
+            ),
+            (
+                r#"{"a": {"dtype": "F32", "shape": [2]}}"#,
+                "invalid header of tensor a",
+            ),
+            (r#"[1, 2]"#, "the safetensors header is not an object"),
+        ];
+        for (header, message) in tests {
+            let buf = build_safetensors(header, &[0; 8]);
+            let err = SafeTensorsFile::from_bytes(&buf).err().unwrap();
+            assert_eq!(err.kind, ErrorKind::FormatError);
+            assert!(err.message.starts_with(message), "{}", err.message);
+        }
+
+        let mut buf = build_safetensors("{}", &[]);
+        buf[0] = 0xff;
+        assert!(SafeTensorsFile::from_bytes(&buf).is_err());
+        assert!(SafeTensorsFile::from_bytes(&[0; 4]).is_err());
+    }
+}
@@ -58,6 +58,9 @@ pub trait Tensor: Sized + Clone {
 
     fn rms_norm_inplace(self, eps: f32) -> Result<Self>;
 
+    /// normalize the last axis to zero mean and unit variance, without the weight and bias.
+    fn layer_norm_inplace(self, eps: f32) -> Result<Self>;
+
     fn softmax_inplace(self, axis: usize) -> Result<Self>;
 
     fn silu_inplace(self) -> Result<Self>;
@@ -5,6 +5,7 @@ use std::sync::Arc;
 #[derive(Debug, Default, Clone)]
 pub struct TensorMetrics {
     pub rms_norm_walltime: TimeMetric,
+    pub layer_norm_walltime: TimeMetric,
     pub add_walltime: TimeMetric,
     pub total_walltime: TimeMetric,
     pub mul_walltime: TimeMetric,
@@ -32,6 +33,7 @@ pub struct TensorMetrics {
 impl TensorMetrics {
     pub fn reset(&self) {
         self.rms_norm_walltime.reset();
+        self.layer_norm_walltime.reset();
         self.add_walltime.reset();
         self.mul_walltime.reset();
         self.rope_walltime.reset();
@@ -62,6 +64,10 @@ impl TensorMetrics {
                 "rms_norm_walltime".to_string(),
                 self.rms_norm_walltime.as_millis(),
             ),
+            (
+                "layer_norm_walltime".to_string(),
+                self.layer_norm_walltime.as_millis(),
+            ),
             (
                 "forward_walltime".to_string(),
                 self.forward_walltime.as_millis(),
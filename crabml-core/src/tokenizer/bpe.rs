@@ -0,0 +1,242 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::ops::Range;
+
+use super::TokenID;
+
+/// the score of a sentencepiece piece, the higher one is merged first.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Score(pub f32);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Symbol {
+    token: TokenID,
+    start: usize,
+    end: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+/// a candidate merge of the symbols at `left` and `right`, it's stale once any of the two
+/// symbols has been changed.
+#[derive(PartialEq, Eq)]
+struct Candidate<P> {
+    priority: P,
+    left: usize,
+    right: usize,
+    left_token: TokenID,
+    right_token: TokenID,
+    merged: TokenID,
+}
+
+impl<P: Ord> PartialOrd for Candidate<P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P: Ord> Ord for Candidate<P> {
+    // the leftmost pair wins on the same priority
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.left.cmp(&self.left))
+    }
+}
+
+/// merge the adjacent tokens like sentencepiece, the symbols are kept in a linked list and the
+/// candidate merges in a heap, so it takes O(n log n) instead of rescanning all the pairs on
+/// every merge. `pair` tells the priority and the merged token of two adjacent tokens, or None
+/// if they can't be merged. the pair of the highest priority is merged first, and the leftmost
+/// one on ties, which gives the same result as merging the best pair of a full scan each time.
+/// every token carries the span of the text it covers, the span of a merged token is from the
+/// start of the left one to the end of the right one.
+pub fn bpe_merge<P: Ord>(
+    tokens: Vec<(TokenID, Range<usize>)>,
+    mut pair: impl FnMut(TokenID, TokenID) -> Option<(P, TokenID)>,
+) -> Vec<(TokenID, Range<usize>)> {
+    if tokens.len() < 2 {
+        return tokens;
+    }
+
+    let n = tokens.len();
+    let mut symbols = tokens
+        .into_iter()
+        .enumerate()
+        .map(|(i, (token, span))| Symbol {
+            token,
+            start: span.start,
+            end: span.end,
+            prev: i.checked_sub(1),
+            next: (i + 1 < n).then_some(i + 1),
+        })
+        .collect::<Vec<_>>();
+
+    let mut heap = BinaryHeap::new();
+    let mut push_candidate =
+        |heap: &mut BinaryHeap<Candidate<P>>, symbols: &[Symbol], left: usize, right: usize| {
+            let (left_token, right_token) = (symbols[left].token, symbols[right].token);
+            if let Some((priority, merged)) = pair(left_token, right_token) {
+                heap.push(Candidate {
+                    priority,
+                    left,
+                    right,
+                    left_token,
+                    right_token,
+                    merged,
+                });
+            }
+        };
+    for i in 0..n - 1 {
+        push_candidate(&mut heap, &symbols, i, i + 1);
+    }
+
+    let mut alive = vec![true; n];
+    while let Some(candidate) = heap.pop() {
+        let Candidate {
+            left,
+            right,
+            left_token,
+            right_token,
+            merged,
+            ..
+        } = candidate;
+        let stale = !alive[left]
+            || !alive[right]
+            || symbols[left].next != Some(right)
+            || symbols[left].token != left_token
+            || symbols[right].token != right_token;
+        if stale {
+            continue;
+        }
+
+        // merge the right symbol into the left one
+        symbols[left].token = merged;
+        symbols[left].end = symbols[right].end;
+        symbols[left].next = symbols[right].next;
+        if let Some(next) = symbols[right].next {
+            symbols[next].prev = Some(left);
+        }
+        alive[right] = false;
+
+        if let Some(prev) = symbols[left].prev {
+            push_candidate(&mut heap, &symbols, prev, left);
+        }
+        if let Some(next) = symbols[left].next {
+            push_candidate(&mut heap, &symbols, left, next);
+        }
+    }
+
+    let mut merged_tokens = vec![];
+    let mut cursor = Some(0);
+    while let Some(i) = cursor {
+        merged_tokens.push((symbols[i].token, symbols[i].start..symbols[i].end));
+        cursor = symbols[i].next;
+    }
+    merged_tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Reverse;
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn merge<P: Ord>(
+        tokens: Vec<TokenID>,
+        pair: impl FnMut(TokenID, TokenID) -> Option<(P, TokenID)>,
+    ) -> Vec<TokenID> {
+        let tokens = tokens.into_iter().map(|t| (t, 0..0)).collect();
+        bpe_merge(tokens, pair)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect()
+    }
+
+    // merge the best pair of a full scan on every iteration
+    fn naive_merge(
+        mut tokens: Vec<TokenID>,
+        pairs: &HashMap<(TokenID, TokenID), (usize, TokenID)>,
+    ) -> Vec<TokenID> {
+        loop {
+            let mut best: Option<(usize, usize, TokenID)> = None;
+            for (i, w) in tokens.windows(2).enumerate() {
+                if let Some((rank, merged)) = pairs.get(&(w[0], w[1])) {
+                    if best.is_none_or(|(r, _, _)| *rank < r) {
+                        best = Some((*rank, i, *merged));
+                    }
+                }
+            }
+            match best {
+                Some((_, i, merged)) => {
+                    tokens[i] = merged;
+                    tokens.remove(i + 1);
+                }
+                None => return tokens,
+            }
+        }
+    }
+
+    #[test]
+    fn test_bpe_merge() {
+        assert_eq!(merge(vec![], |_, _| Some((0, 0))), Vec::<TokenID>::new());
+        assert_eq!(merge(vec![3], |_, _| Some((0, 0))), vec![3]);
+
+        // a small linear congruential generator to make the cases reproducible
+        let mut seed = 42u64;
+        let mut rand = move |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            ((seed >> 33) as usize) % n
+        };
+        for _ in 0..200 {
+            // the ranks are shared by some pairs to check the leftmost one wins on ties
+            let mut pairs = HashMap::new();
+            for _ in 0..30 {
+                let pair = (rand(6), rand(6));
+                pairs.insert(pair, (rand(10), rand(12)));
+            }
+            let tokens = (0..rand(40)).map(|_| rand(6)).collect::<Vec<_>>();
+            let got = merge(tokens.clone(), |a, b| {
+                pairs.get(&(a, b)).map(|(rank, t)| (Reverse(*rank), *t))
+            });
+            assert_eq!(got, naive_merge(tokens, &pairs));
+        }
+    }
+
+    #[test]
+    fn test_bpe_merge_by_scores() {
+        let scores = [("ab", 1.0), ("bc", 2.0), ("abc", 0.5)];
+        let tokens = ["a", "b", "c", "ab", "bc", "abc"];
+        let merged = merge(vec![0, 1, 2, 0, 1], |a, b| {
+            let s = format!("{}{}", tokens[a], tokens[b]);
+            let (i, (_, score)) = scores.iter().enumerate().find(|(_, (t, _))| *t == s)?;
+            Some((Score(*score), i + 3))
+        });
+        // "bc" is merged first, then "ab", and "abc" at last
+        assert_eq!(merged, vec![5, 3]);
+    }
+
+    #[test]
+    fn test_bpe_merge_spans() {
+        // the tokens of "a", "bb" and "c" are merged into "abb" and "c"
+        let merged = bpe_merge(vec![(0, 0..1), (1, 1..3), (2, 3..4)], |a, b| {
+            (a == 0 && b == 1).then_some((0, 3))
+        });
+        assert_eq!(merged, vec![(3, 0..3), (2, 3..4)]);
+    }
+}
@@ -1,30 +1,88 @@
+mod bpe;
+mod pre_tokenizer;
 mod tokenizer_gpt2;
+mod tokenizer_hf;
 mod tokenizer_llama;
 
+use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::Arc;
 
+pub use pre_tokenizer::PreTokenizerKind;
+use regex::Regex;
+use serde_json::Value;
 use tokenizer_gpt2::Gpt2Tokenizer;
+use tokenizer_hf::HfTokenizer;
 use tokenizer_llama::LlamaTokenizer;
 
+use crate::error::Error;
+use crate::error::ErrorKind;
 use crate::error::Result;
 
 pub type TokenID = usize;
 
+/// the control tokens which end the generation besides eos and eot, like what llama.cpp does.
+const EOG_TOKENS: [&str; 7] = [
+    "<|eot_id|>",
+    "<|eom_id|>",
+    "<|im_end|>",
+    "<|end|>",
+    "<end_of_turn>",
+    "<|endoftext|>",
+    "<EOT>",
+];
+
 pub struct Tokenizer {
     tokens: Arc<Vec<String>>,
+    token_types: Vec<TokenType>,
+    special_tokens: Option<SpecialTokens>,
+    user_defined_tokens: Option<SpecialTokens>,
+    bos_token: TokenID,
     eos_token: TokenID,
+    eot_token: Option<TokenID>,
+    eog_tokens: Vec<TokenID>,
+    add_bos_token: bool,
+    add_eos_token: bool,
     inner: TokenizerInner,
 }
 
 enum TokenizerInner {
     Llama(LlamaTokenizer),
     GPT2(Gpt2Tokenizer),
+    HuggingFace(HfTokenizer),
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum TokenizerKind {
     Llama,
     GPT2,
+    HuggingFace,
+}
+
+/// the types of the tokens in `tokenizer.ggml.token_type`, the ids are the same as llama.cpp.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum TokenType {
+    #[default]
+    Normal,
+    Unknown,
+    Control,
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+impl TokenType {
+    /// the undefined types are taken as normal tokens like llama.cpp does.
+    pub fn from_i32(v: i32) -> Self {
+        match v {
+            2 => TokenType::Unknown,
+            3 => TokenType::Control,
+            4 => TokenType::UserDefined,
+            5 => TokenType::Unused,
+            6 => TokenType::Byte,
+            _ => TokenType::Normal,
+        }
+    }
 }
 
 impl Tokenizer {
@@ -36,23 +94,30 @@ impl Tokenizer {
         eos_token: TokenID,
     ) -> Self {
         let tokens = Arc::new(tokens);
-        let inner = TokenizerInner::Llama(LlamaTokenizer::new(
-            tokens.clone(),
-            scores,
-            bos_token,
-            eos_token,
-        ));
+        let inner = TokenizerInner::Llama(LlamaTokenizer::new(tokens.clone(), scores));
 
         Self {
             tokens,
+            token_types: vec![],
+            special_tokens: None,
+            user_defined_tokens: None,
+            bos_token,
             eos_token,
+            eot_token: None,
+            eog_tokens: vec![eos_token],
+            add_bos_token: true,
+            add_eos_token: false,
             inner,
         }
     }
 
+    /// the text is split into words by the pre-tokenizer before BPE, a space is prepended to the
+    /// text if `add_prefix_space`.
     pub fn new_gpt2(
         tokens: Vec<String>,
         merges: Vec<String>,
+        pre_tokenizer: PreTokenizerKind,
+        add_prefix_space: bool,
         bos_token: TokenID,
         eos_token: TokenID,
     ) -> Self {
@@ -60,20 +125,109 @@ impl Tokenizer {
         let inner = TokenizerInner::GPT2(Gpt2Tokenizer::new(
             tokens.clone(),
             &merges,
-            bos_token,
-            eos_token,
+            pre_tokenizer,
+            add_prefix_space,
         ));
         Self {
             tokens,
+            token_types: vec![],
+            special_tokens: None,
+            user_defined_tokens: None,
+            bos_token,
             eos_token,
+            eot_token: None,
+            eog_tokens: vec![eos_token],
+            add_bos_token: true,
+            add_eos_token: false,
             inner,
         }
     }
 
+    /// load the Hugging Face `tokenizer.json`, the vocab is padded to `vocab_size` with the
+    /// `[PAD{id}]` tokens, and the added tokens are parsed as the special tokens.
+    pub fn new_hf(
+        tokenizer_json: &str,
+        vocab_size: usize,
+        bos_token: TokenID,
+        eos_token: TokenID,
+    ) -> Result<Self> {
+        let json: Value = serde_json::from_str(tokenizer_json).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: "failed to parse the tokenizer.json".to_string(),
+            cause: Some(Arc::new(err)),
+        })?;
+        let (tokens, token_types) = HfTokenizer::load_vocab(&json, vocab_size)?;
+        let tokens = Arc::new(tokens);
+        let inner = TokenizerInner::HuggingFace(HfTokenizer::new(&json, tokens.clone())?);
+        let tokenizer = Self {
+            tokens,
+            token_types: vec![],
+            special_tokens: None,
+            user_defined_tokens: None,
+            bos_token,
+            eos_token,
+            eot_token: None,
+            eog_tokens: vec![eos_token],
+            add_bos_token: true,
+            add_eos_token: false,
+            inner,
+        };
+        Ok(tokenizer.with_token_types(token_types))
+    }
+
+    /// the control, unknown and user-defined tokens are matched on the text before tokenization.
+    pub fn with_token_types(mut self, token_types: Vec<TokenType>) -> Self {
+        let typed_tokens = || self.tokens.iter().zip(token_types.iter()).enumerate();
+        self.special_tokens = SpecialTokens::new(typed_tokens().filter_map(|(id, (t, typ))| {
+            matches!(
+                typ,
+                TokenType::Control | TokenType::Unknown | TokenType::UserDefined
+            )
+            .then_some((t.as_str(), id))
+        }));
+        self.user_defined_tokens =
+            SpecialTokens::new(typed_tokens().filter_map(|(id, (t, typ))| {
+                (*typ == TokenType::UserDefined).then_some((t.as_str(), id))
+            }));
+        let eog_tokens = typed_tokens()
+            .filter(|(_, (t, typ))| **typ == TokenType::Control && EOG_TOKENS.contains(&t.as_str()))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        self.token_types = token_types;
+        self.with_eog_tokens(&eog_tokens)
+    }
+
+    /// the end of turn token, like `<|eot_id|>` of llama3, it ends the generation as well.
+    pub fn with_eot_token(mut self, eot_token: TokenID) -> Self {
+        self.eot_token = Some(eot_token);
+        self.with_eog_tokens(&[eot_token])
+    }
+
+    /// the extra tokens to end the generation besides eos.
+    pub fn with_eog_tokens(mut self, eog_tokens: &[TokenID]) -> Self {
+        for token in eog_tokens {
+            if !self.eog_tokens.contains(token) {
+                self.eog_tokens.push(*token);
+            }
+        }
+        self
+    }
+
+    pub fn with_add_bos_token(mut self, add_bos_token: bool) -> Self {
+        self.add_bos_token = add_bos_token;
+        self
+    }
+
+    pub fn with_add_eos_token(mut self, add_eos_token: bool) -> Self {
+        self.add_eos_token = add_eos_token;
+        self
+    }
+
     pub fn kind(&self) -> TokenizerKind {
         match &self.inner {
             TokenizerInner::Llama(_) => TokenizerKind::Llama,
             TokenizerInner::GPT2(_) => TokenizerKind::GPT2,
+            TokenizerInner::HuggingFace(_) => TokenizerKind::HuggingFace,
         }
     }
 
@@ -81,30 +235,222 @@ impl Tokenizer {
         &self.tokens
     }
 
+    pub fn bos_token(&self) -> TokenID {
+        self.bos_token
+    }
+
     pub fn eos_token(&self) -> TokenID {
         self.eos_token
     }
 
+    pub fn eot_token(&self) -> Option<TokenID> {
+        self.eot_token
+    }
+
+    /// whether the token ends the generation, like eos, eot or `<end_of_turn>`.
+    pub fn is_eog(&self, token: TokenID) -> bool {
+        self.eog_tokens.contains(&token)
+    }
+
+    /// whether a bos token should be prepended on the start of the sequence, it's true by default.
+    pub fn add_bos_token(&self) -> bool {
+        self.add_bos_token
+    }
+
+    pub fn add_eos_token(&self) -> bool {
+        self.add_eos_token
+    }
+
     pub fn token(&self, token_id: TokenID) -> String {
         self.tokens[token_id].clone()
     }
 
+    pub fn token_type(&self, token_id: TokenID) -> TokenType {
+        self.token_types.get(token_id).copied().unwrap_or_default()
+    }
+
+    /// the control and unused tokens are decoded into empty strings, use `decode_special` to
+    /// render them.
     /// TODO: make it consume an Iterator<Item=Result<TokenID>>
     pub fn decode(&self, token: TokenID, decode_buf: &mut Utf8Buf) -> Result<String> {
+        match self.token_type(token) {
+            TokenType::Control | TokenType::Unused => Ok(String::new()),
+            _ => self.decode_special(token, decode_buf),
+        }
+    }
+
+    pub fn decode_special(&self, token: TokenID, decode_buf: &mut Utf8Buf) -> Result<String> {
         let bytes = match &self.inner {
             TokenizerInner::Llama(inner) => inner.decode(token),
             TokenizerInner::GPT2(inner) => inner.decode(token),
+            TokenizerInner::HuggingFace(inner) => inner.decode(token),
         };
         Ok(decode_buf.step(&bytes))
     }
 
     // encode the string text (input) into an upper-bound preallocated tokens[] array
     // bos != 0 means prepend the BOS token (=1), eos != 0 means append the EOS token (=2)
-    pub fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<TokenID>> {
-        match &self.inner {
-            TokenizerInner::Llama(inner) => Ok(inner.encode(text, bos, eos, true)),
-            TokenizerInner::GPT2(inner) => Ok(inner.encode(text, bos, eos, true)),
+    //
+    // the control tokens like `<|im_start|>` in the text are mapped to their ids only if `special`,
+    // it should be false on the untrusted user text. the user-defined tokens are always mapped.
+    pub fn encode(&self, text: &str, bos: bool, eos: bool, special: bool) -> Result<Vec<TokenID>> {
+        let tokens = self.encode_with_offsets(text, bos, eos, special)?;
+        Ok(tokens.into_iter().map(|(token, _)| token).collect())
+    }
+
+    /// the same as `encode`, but every token comes with the byte span of the text it covers. the
+    /// spans are in order, the tokens which are not in the text like bos, eos or the prefix space
+    /// cover an empty span. a char might be covered by several tokens on the byte fallback.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+        special: bool,
+    ) -> Result<Vec<(TokenID, Range<usize>)>> {
+        self.encode_pieces_with_offsets(&[(text, special)], bos, eos)
+    }
+
+    /// encode the pieces as a whole text, the control tokens are only parsed in the pieces marked
+    /// as special, like the scaffolding of a chat template around the untrusted user prompts.
+    pub fn encode_pieces(
+        &self,
+        pieces: &[(&str, bool)],
+        bos: bool,
+        eos: bool,
+    ) -> Result<Vec<TokenID>> {
+        let tokens = self.encode_pieces_with_offsets(pieces, bos, eos)?;
+        Ok(tokens.into_iter().map(|(token, _)| token).collect())
+    }
+
+    fn encode_pieces_with_offsets(
+        &self,
+        pieces: &[(&str, bool)],
+        bos: bool,
+        eos: bool,
+    ) -> Result<Vec<(TokenID, Range<usize>)>> {
+        let text = pieces.iter().map(|(text, _)| *text).collect::<String>();
+        let mut fragments: Vec<Fragment> = vec![];
+        let mut offset = 0;
+        for (piece, special) in pieces.iter() {
+            let special_tokens = if *special {
+                &self.special_tokens
+            } else {
+                &self.user_defined_tokens
+            };
+            let piece_fragments = match special_tokens {
+                Some(special_tokens) => special_tokens.split(piece),
+                None => vec![Fragment::Text(0..piece.len())],
+            };
+            // the texts of the adjacent pieces are joined into one fragment
+            for fragment in piece_fragments {
+                match (fragment, fragments.last_mut()) {
+                    (Fragment::Text(range), Some(Fragment::Text(last)))
+                        if last.end == offset + range.start =>
+                    {
+                        last.end = offset + range.end;
+                    }
+                    (Fragment::Text(range), _) => {
+                        fragments.push(Fragment::Text(offset + range.start..offset + range.end))
+                    }
+                    (Fragment::Token(token, range), _) => fragments.push(Fragment::Token(
+                        token,
+                        offset + range.start..offset + range.end,
+                    )),
+                }
+            }
+            offset += piece.len();
+        }
+
+        let mut tokens = vec![];
+        if bos {
+            tokens.push((self.bos_token, 0..0));
+        }
+        // the SPM vocab prepends a space to the text after a special token again like llama.cpp,
+        // but the BPE vocabs only prepend it at the start of the text
+        let mut is_prev_special = true;
+        for fragment in fragments {
+            let range = match fragment {
+                Fragment::Token(token, range) => {
+                    tokens.push((token, range));
+                    is_prev_special = true;
+                    continue;
+                }
+                Fragment::Text(range) if !range.is_empty() => range,
+                Fragment::Text(_) => continue,
+            };
+            let is_first = range.start == 0;
+            let fragment_tokens = match &self.inner {
+                TokenizerInner::Llama(inner) => {
+                    inner.encode_with_offsets(&text[range.clone()], is_prev_special)
+                }
+                TokenizerInner::GPT2(inner) => {
+                    inner.encode_with_offsets(&text[range.clone()], is_first)
+                }
+                TokenizerInner::HuggingFace(inner) => {
+                    inner.encode_with_offsets(&text[range.clone()], is_first)
+                }
+            };
+            is_prev_special = false;
+            tokens.extend(
+                fragment_tokens
+                    .into_iter()
+                    .map(|(token, span)| (token, range.start + span.start..range.start + span.end)),
+            );
+        }
+        if eos {
+            tokens.push((self.eos_token, text.len()..text.len()));
         }
+        Ok(tokens)
+    }
+}
+
+/// the byte ranges of the text between the special tokens, and the special tokens.
+enum Fragment {
+    Text(Range<usize>),
+    Token(TokenID, Range<usize>),
+}
+
+/// the regex to find the special tokens in the text, the longer tokens are matched first.
+struct SpecialTokens {
+    token_ids: HashMap<String, TokenID>,
+    regex: Regex,
+}
+
+impl SpecialTokens {
+    fn new<'a>(tokens: impl Iterator<Item = (&'a str, TokenID)>) -> Option<Self> {
+        let token_ids = tokens
+            .filter(|(t, _)| !t.is_empty())
+            .map(|(t, id)| (t.to_string(), id))
+            .collect::<HashMap<_, _>>();
+        if token_ids.is_empty() {
+            return None;
+        }
+        let mut keywords = token_ids.keys().collect::<Vec<_>>();
+        keywords.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        let pattern = keywords
+            .iter()
+            .map(|k| regex::escape(k))
+            .collect::<Vec<_>>()
+            .join("|");
+        let regex = Regex::new(&pattern).unwrap();
+        Some(Self { token_ids, regex })
+    }
+
+    fn split(&self, text: &str) -> Vec<Fragment> {
+        let mut fragments = vec![];
+        let mut last = 0;
+        for mat in self.regex.find_iter(text) {
+            if mat.start() > last {
+                fragments.push(Fragment::Text(last..mat.start()));
+            }
+            fragments.push(Fragment::Token(self.token_ids[mat.as_str()], mat.range()));
+            last = mat.end();
+        }
+        if last < text.len() {
+            fragments.push(Fragment::Text(last..text.len()));
+        }
+        fragments
     }
 }
 
@@ -151,3 +497,81 @@ impl Utf8Buf {
         "".to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_special_tokens() {
+        let special_tokens =
+            SpecialTokens::new([("<|im_start|>", 1), ("<|im_end|>", 2), ("<|im", 3)].into_iter())
+                .unwrap();
+        let text = "<|im_start|>i don't eat beaf<|im_end|> <|im_start|> i don't <|im";
+        let fragments = special_tokens
+            .split(text)
+            .into_iter()
+            .map(|f| match f {
+                Fragment::Text(range) => text[range].to_string(),
+                Fragment::Token(id, range) => format!("#{}{}", id, &text[range]),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fragments, vec![
+            "#1<|im_start|>",
+            "i don't eat beaf",
+            "#2<|im_end|>",
+            " ",
+            "#1<|im_start|>",
+            " i don't ",
+            "#3<|im"
+        ]);
+        assert!(SpecialTokens::new([("", 1)].into_iter()).is_none());
+    }
+
+    #[test]
+    fn test_eog_tokens() -> Result<()> {
+        let tokens = [
+            "a",
+            "b",
+            "</s>",
+            "<|eot_id|>",
+            "<|im_end|>",
+            "<end_of_turn>",
+        ];
+        let tk = Tokenizer::new_llama(tokens.map(|t| t.to_string()).to_vec(), vec![0.0; 6], 1, 2)
+            .with_token_types(vec![
+                TokenType::Normal,
+                TokenType::Normal,
+                TokenType::Control,
+                TokenType::Control,
+                TokenType::Unused,
+                TokenType::UserDefined,
+            ]);
+        assert!(tk.is_eog(2));
+        assert!(tk.is_eog(3));
+        // only the control tokens are taken as the end of generation by their names
+        assert!(!tk.is_eog(4));
+        assert!(!tk.is_eog(5));
+        assert_eq!(tk.eot_token(), None);
+        assert!(tk.add_bos_token());
+        assert!(!tk.add_eos_token());
+
+        let tk = tk
+            .with_eot_token(5)
+            .with_eog_tokens(&[1])
+            .with_add_bos_token(false);
+        assert_eq!(tk.eot_token(), Some(5));
+        assert!(tk.is_eog(5));
+        assert!(tk.is_eog(1));
+        assert!(!tk.add_bos_token());
+
+        // the control and unused tokens are skipped on decoding
+        let mut buf = Utf8Buf::new();
+        assert_eq!(tk.decode(0, &mut buf)?, "a");
+        assert_eq!(tk.decode(2, &mut buf)?, "");
+        assert_eq!(tk.decode(4, &mut buf)?, "");
+        assert_eq!(tk.decode(5, &mut buf)?, "<end_of_turn>");
+        assert_eq!(tk.decode_special(2, &mut buf)?, "</s>");
+        Ok(())
+    }
+}
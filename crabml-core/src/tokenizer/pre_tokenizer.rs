@@ -0,0 +1,698 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+const LLAMA3_PATTERN: &str = r"(?:'[sS]|'[tT]|'[rR][eE]|'[vV][eE]|'[mM]|'[lL][lL]|'[dD])|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+const QWEN2_PATTERN: &str = r"(?:'[sS]|'[tT]|'[rR][eE]|'[vV][eE]|'[mM]|'[lL][lL]|'[dD])|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+const DEEPSEEK_LLM_PATTERNS: &[&str] = &[
+    r"[\r\n]",
+    r"\s?[A-Za-zµÀ-ÖØ-öø-ƺƼ-ƿǄ-ʓʕ-ʯͰ-ͳͶͷͻ-ͽͿΆΈ-ΊΌΎ-ΡΣ-ϵϷ-ҁҊ-ԯԱ-ՖႠ-ჅᎠ-Ᏽᏸ-ᏽᲐ-ᲺᲽ-Ჿᴀ-ᴫᵫ-ᵷᵹ-ᶚḀ-ἕἘ-Ἕἠ-ὅὈ-Ὅὐ-ὗὙὛὝὟ-\x{1F7D}ᾀ-ᾴᾶ-ᾼ\x{1FBE}ῂ-ῄῆ-ῌῐ-\x{1FD3}ῖ-\x{1FDB}ῠ-Ῥῲ-ῴῶ-ῼℂℇℊ-ℓℕℙ-ℝℤ\x{2126}ℨ\x{212A}-ℭℯ-ℴℹℼ-ℿⅅ-ⅉⅎↃↄⰀ-ⱻⱾ-ⳤⳫ-ⳮⳲⳳꙀ-ꙭꚀ-ꚛꜢ-ꝯꝱ-ꞇꞋ-ꞎꭰ-ꮿﬀ-ﬆﬓ-ﬗＡ-Ｚａ-ｚ𐐀-𐑏𐒰-𐓓𐓘-𐓻𐲀-𐲲𐳀-𐳲𑢠-𑣟𞤀-𞥃]+",
+    r"\s?[!-/:-~！-／：-～‘-‟　-。]+",
+    r"\s+$",
+    r"[一-龥ࠀ-一가-퟿]+",
+    r"\p{N}+",
+];
+
+const DEEPSEEK_CODER_PATTERNS: &[&str] = &[
+    r"[\r\n]",
+    r"\s?\p{L}+",
+    r"\s?\p{P}+",
+    r"[一-龥ࠀ-一가-퟿]+",
+    r"\p{N}",
+];
+
+const FALCON_PATTERNS: &[&str] = &[r"[\p{P}\$\+<=>\^~\|`]+", GPT2_PATTERN, r"[0-9][0-9][0-9]"];
+
+const STARCODER_PATTERNS: &[&str] = &[r"\p{N}", GPT2_PATTERN];
+
+/// the `\s+(?!\S)` in the patterns matches the whitespaces but leaves the last one to the next
+/// word. the regex crate does not support look-around, it's matched as a named group, and the
+/// last whitespace is given back after the match.
+const LOOKAHEAD_WHITESPACES: &str = r"\s+(?!\S)";
+const LOOKAHEAD_WHITESPACES_GROUP: &str = r"(?P<ws>\s+)";
+
+/// how the text is split into words before BPE, it's specified by `tokenizer.ggml.pre` in the
+/// GGUF files, named the same as llama.cpp.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum PreTokenizerKind {
+    #[default]
+    Gpt2,
+    Llama3,
+    Qwen2,
+    DeepseekLlm,
+    DeepseekCoder,
+    Falcon,
+    Starcoder,
+}
+
+impl PreTokenizerKind {
+    fn patterns(&self) -> &'static [&'static str] {
+        match self {
+            PreTokenizerKind::Gpt2 => &[GPT2_PATTERN],
+            PreTokenizerKind::Llama3 => &[LLAMA3_PATTERN],
+            PreTokenizerKind::Qwen2 => &[QWEN2_PATTERN],
+            PreTokenizerKind::DeepseekLlm => DEEPSEEK_LLM_PATTERNS,
+            PreTokenizerKind::DeepseekCoder => DEEPSEEK_CODER_PATTERNS,
+            PreTokenizerKind::Falcon => FALCON_PATTERNS,
+            PreTokenizerKind::Starcoder => STARCODER_PATTERNS,
+        }
+    }
+
+    /// find the kind by the split regex of a Hugging Face tokenizer.json.
+    pub fn from_pattern(pattern: &str) -> Option<Self> {
+        [
+            PreTokenizerKind::Gpt2,
+            PreTokenizerKind::Llama3,
+            PreTokenizerKind::Qwen2,
+        ]
+        .into_iter()
+        .find(|kind| kind.patterns() == [pattern])
+    }
+}
+
+impl FromStr for PreTokenizerKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let kind = match s {
+            "default" | "gpt-2" | "gpt2" | "mpt" | "olmo" | "jais" => PreTokenizerKind::Gpt2,
+            "llama3" | "llama-v3" | "llama-bpe" | "dbrx" | "smaug-bpe" => PreTokenizerKind::Llama3,
+            "qwen2" | "stablelm2" => PreTokenizerKind::Qwen2,
+            "deepseek-llm" => PreTokenizerKind::DeepseekLlm,
+            "deepseek-coder" => PreTokenizerKind::DeepseekCoder,
+            "falcon" => PreTokenizerKind::Falcon,
+            "starcoder" | "refact" | "command-r" | "smollm" | "codeshell" => {
+                PreTokenizerKind::Starcoder
+            }
+            _ => {
+                return Err(Error {
+                    kind: ErrorKind::ModelError,
+                    message: format!("unsupported pre-tokenizer: {}", s),
+                    cause: None,
+                });
+            }
+        };
+        Ok(kind)
+    }
+}
+
+/// splits the text by a sequence of regexes, every regex splits the pieces of the previous one
+/// further, both the matched and the unmatched parts are kept as pieces like llama.cpp does.
+pub struct PreTokenizer {
+    regexes: Vec<Regex>,
+}
+
+impl PreTokenizer {
+    pub fn new(kind: PreTokenizerKind) -> Self {
+        let regexes = kind
+            .patterns()
+            .iter()
+            .map(|p| Regex::new(&p.replace(LOOKAHEAD_WHITESPACES, LOOKAHEAD_WHITESPACES_GROUP)))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        Self { regexes }
+    }
+
+    /// split by a custom regex, like the `Split` step of a Hugging Face tokenizer.json.
+    pub fn with_pattern(pattern: &str) -> Result<Self> {
+        if let Some(kind) = PreTokenizerKind::from_pattern(pattern) {
+            return Ok(Self::new(kind));
+        }
+        let re = Regex::new(&pattern.replace(LOOKAHEAD_WHITESPACES, LOOKAHEAD_WHITESPACES_GROUP))
+            .map_err(|err| Error {
+            kind: ErrorKind::ModelError,
+            message: format!("unsupported pre-tokenizer regex: {}", pattern),
+            cause: Some(Arc::new(err)),
+        })?;
+        Ok(Self { regexes: vec![re] })
+    }
+
+    pub fn split<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let mut pieces = vec![text];
+        for re in self.regexes.iter() {
+            pieces = pieces
+                .into_iter()
+                .flat_map(|piece| split_by_regex(re, piece))
+                .collect();
+        }
+        pieces
+    }
+}
+
+fn split_by_regex<'a>(re: &Regex, text: &'a str) -> Vec<&'a str> {
+    let mut pieces = vec![];
+    let mut last = 0;
+    let mut pos = 0;
+    while pos < text.len() {
+        let caps = match re.captures_at(text, pos) {
+            Some(caps) => caps,
+            None => break,
+        };
+        let m = caps.get(0).unwrap();
+        let mut end = m.end();
+        if caps.name("ws").is_some() && text[end..].starts_with(|c: char| !c.is_whitespace()) {
+            let last_len = text[m.start()..end].chars().last().unwrap().len_utf8();
+            if end - last_len > m.start() {
+                end -= last_len;
+            }
+        }
+        if end == m.start() {
+            // skip the empty matches
+            pos = end + text[end..].chars().next().map_or(1, |c| c.len_utf8());
+            continue;
+        }
+
+        if m.start() > last {
+            pieces.push(&text[last..m.start()]);
+        }
+        pieces.push(&text[m.start()..end]);
+        last = end;
+        pos = end;
+    }
+    if last < text.len() {
+        pieces.push(&text[last..]);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tokenizer_gpt2::build_byte_encode_map;
+    use crate::tokenizer::tokenizer_gpt2::Gpt2Tokenizer;
+
+    // the expected pieces are produced by the same patterns with the look-ahead in Python `regex`
+    const CORPUS: &[&str] = &[
+        "Hello world",
+        " Hello World!!",
+        "I'm don't YOU'LL we'Ve",
+        "  leading spaces",
+        "trailing   ",
+        "line1\n\nline2\r\n",
+        "12345 67 3.14159",
+        "tab\tand  double   spaced",
+        "中文字符 and 한국어",
+        "emoji 🦀🦀! ok",
+        "a  \n  b",
+        "\n\n\n",
+        "x = foo(bar, \"baz\") + 1_000;",
+        "   ",
+        "Ⅻ naïve café ½",
+        "$100 <= 200|300",
+        "fn main() {\n    println!(\"hi\");\n}",
+    ];
+
+    fn expected(kind: PreTokenizerKind) -> Vec<Vec<&'static str>> {
+        match kind {
+            PreTokenizerKind::Gpt2 => vec![
+                vec!["Hello", " world"],
+                vec![" Hello", " World", "!!"],
+                vec!["I", "'m", " don", "'t", " YOU", "'", "LL", " we", "'", "Ve"],
+                vec![" ", " leading", " spaces"],
+                vec!["trailing", "   "],
+                vec!["line", "1", "\n", "\n", "line", "2", "\r\n"],
+                vec!["12345", " 67", " 3", ".", "14159"],
+                vec!["tab", "\t", "and", " ", " double", "  ", " spaced"],
+                vec!["中文字符", " and", " 한국어"],
+                vec!["emoji", " 🦀🦀!", " ok"],
+                vec!["a", "  \n ", " b"],
+                vec!["\n\n\n"],
+                vec![
+                    "x", " =", " foo", "(", "bar", ",", " \"", "baz", "\")", " +", " 1", "_",
+                    "000", ";",
+                ],
+                vec!["   "],
+                vec!["Ⅻ", " naïve", " café", " ½"],
+                vec!["$", "100", " <=", " 200", "|", "300"],
+                vec![
+                    "fn", " main", "()", " {", "\n   ", " println", "!(\"", "hi", "\");", "\n", "}",
+                ],
+            ],
+            PreTokenizerKind::Llama3 => vec![
+                vec!["Hello", " world"],
+                vec![" Hello", " World", "!!"],
+                vec!["I", "'m", " don", "'t", " YOU", "'LL", " we", "'Ve"],
+                vec![" ", " leading", " spaces"],
+                vec!["trailing", "   "],
+                vec!["line", "1", "\n\n", "line", "2", "\r\n"],
+                vec!["123", "45", " ", "67", " ", "3", ".", "141", "59"],
+                vec!["tab", "\tand", " ", " double", "  ", " spaced"],
+                vec!["中文字符", " and", " 한국어"],
+                vec!["emoji", " 🦀🦀!", " ok"],
+                vec!["a", "  \n", " ", " b"],
+                vec!["\n\n\n"],
+                vec![
+                    "x", " =", " foo", "(bar", ",", " \"", "baz", "\")", " +", " ", "1", "_",
+                    "000", ";",
+                ],
+                vec!["   "],
+                vec!["Ⅻ", " naïve", " café", " ", "½"],
+                vec!["$", "100", " <=", " ", "200", "|", "300"],
+                vec![
+                    "fn", " main", "()", " {\n", "   ", " println", "!(\"", "hi", "\");\n", "}",
+                ],
+            ],
+            PreTokenizerKind::Qwen2 => vec![
+                vec!["Hello", " world"],
+                vec![" Hello", " World", "!!"],
+                vec!["I", "'m", " don", "'t", " YOU", "'LL", " we", "'Ve"],
+                vec![" ", " leading", " spaces"],
+                vec!["trailing", "   "],
+                vec!["line", "1", "\n\n", "line", "2", "\r\n"],
+                vec![
+                    "1", "2", "3", "4", "5", " ", "6", "7", " ", "3", ".", "1", "4", "1", "5", "9",
+                ],
+                vec!["tab", "\tand", " ", " double", "  ", " spaced"],
+                vec!["中文字符", " and", " 한국어"],
+                vec!["emoji", " 🦀🦀!", " ok"],
+                vec!["a", "  \n", " ", " b"],
+                vec!["\n\n\n"],
+                vec![
+                    "x", " =", " foo", "(bar", ",", " \"", "baz", "\")", " +", " ", "1", "_", "0",
+                    "0", "0", ";",
+                ],
+                vec!["   "],
+                vec!["Ⅻ", " naïve", " café", " ", "½"],
+                vec![
+                    "$", "1", "0", "0", " <=", " ", "2", "0", "0", "|", "3", "0", "0",
+                ],
+                vec![
+                    "fn", " main", "()", " {\n", "   ", " println", "!(\"", "hi", "\");\n", "}",
+                ],
+            ],
+            PreTokenizerKind::DeepseekLlm => vec![
+                vec!["Hello", " world"],
+                vec![" Hello", " World", "!!"],
+                vec![
+                    "I", "'", "m", " don", "'", "t", " YOU", "'", "LL", " we", "'", "Ve",
+                ],
+                vec![" ", " leading", " spaces"],
+                vec!["trailing", "   "],
+                vec!["line", "1", "\n", "\n", "line", "2", "\r", "\n"],
+                vec!["12345", " ", "67", " ", "3", ".", "14159"],
+                vec!["tab", "\tand", " ", " double", "  ", " spaced"],
+                vec!["中文字符", " and", " ", "한국어"],
+                vec!["emoji", " 🦀🦀", "!", " ok"],
+                vec!["a", "  ", "\n", " ", " b"],
+                vec!["\n", "\n", "\n"],
+                vec![
+                    "x", " =", " foo", "(", "bar", ",", " \"", "baz", "\")", " +", " ", "1", "_",
+                    "000", ";",
+                ],
+                vec!["   "],
+                vec!["Ⅻ", " na", "ï", "ve", " caf", "é", " ", "½"],
+                vec!["$", "100", " <=", " ", "200", "|", "300"],
+                vec![
+                    "fn", " main", "()", " {", "\n", "   ", " println", "!(\"", "hi", "\");", "\n",
+                    "}",
+                ],
+            ],
+            PreTokenizerKind::DeepseekCoder => vec![
+                vec!["Hello", " world"],
+                vec![" Hello", " World", "!!"],
+                vec![
+                    "I", "'", "m", " don", "'", "t", " YOU", "'", "LL", " we", "'", "Ve",
+                ],
+                vec![" ", " leading", " spaces"],
+                vec!["trailing", "   "],
+                vec!["line", "1", "\n", "\n", "line", "2", "\r", "\n"],
+                vec![
+                    "1", "2", "3", "4", "5", " ", "6", "7", " ", "3", ".", "1", "4", "1", "5", "9",
+                ],
+                vec!["tab", "\tand", " ", " double", "  ", " spaced"],
+                vec!["中文字符", " and", " ", "한국어"],
+                vec!["emoji", " 🦀🦀", "!", " ok"],
+                vec!["a", "  ", "\n", " ", " b"],
+                vec!["\n", "\n", "\n"],
+                vec![
+                    "x", " =", " foo", "(", "bar", ",", " \"", "baz", "\")", " + ", "1", "_", "0",
+                    "0", "0", ";",
+                ],
+                vec!["   "],
+                vec!["Ⅻ", " naïve", " café", " ", "½"],
+                vec![
+                    "$", "1", "0", "0", " <= ", "2", "0", "0", "|", "3", "0", "0",
+                ],
+                vec![
+                    "fn", " main", "()", " {", "\n", "   ", " println", "!(\"", "hi", "\");", "\n",
+                    "}",
+                ],
+            ],
+            PreTokenizerKind::Falcon => vec![
+                vec!["Hello", " world"],
+                vec![" Hello", " World", "!!"],
+                vec![
+                    "I", "'", "m", " don", "'", "t", " YOU", "'", "LL", " we", "'", "Ve",
+                ],
+                vec![" ", " leading", " spaces"],
+                vec!["trailing", "   "],
+                vec!["line", "1", "\n", "\n", "line", "2", "\r\n"],
+                vec!["123", "45", " 67", " 3", ".", "141", "59"],
+                vec!["tab", "\t", "and", " ", " double", "  ", " spaced"],
+                vec!["中文字符", " and", " 한국어"],
+                vec!["emoji", " 🦀🦀", "!", " ok"],
+                vec!["a", "  \n ", " b"],
+                vec!["\n\n\n"],
+                vec![
+                    "x", " ", "=", " foo", "(", "bar", ",", " ", "\"", "baz", "\")", " ", "+",
+                    " 1", "_", "000", ";",
+                ],
+                vec!["   "],
+                vec!["Ⅻ", " naïve", " café", " ½"],
+                vec!["$", "100", " ", "<=", " ", "200", "|", "300"],
+                vec![
+                    "fn", " main", "()", " ", "{", "\n   ", " println", "!(\"", "hi", "\");", "\n",
+                    "}",
+                ],
+            ],
+            PreTokenizerKind::Starcoder => vec![
+                vec!["Hello", " world"],
+                vec![" Hello", " World", "!!"],
+                vec!["I", "'m", " don", "'t", " YOU", "'", "LL", " we", "'", "Ve"],
+                vec![" ", " leading", " spaces"],
+                vec!["trailing", "   "],
+                vec!["line", "1", "\n", "\n", "line", "2", "\r\n"],
+                vec![
+                    "1", "2", "3", "4", "5", " ", "6", "7", " ", "3", ".", "1", "4", "1", "5", "9",
+                ],
+                vec!["tab", "\t", "and", " ", " double", "  ", " spaced"],
+                vec!["中文字符", " and", " 한국어"],
+                vec!["emoji", " 🦀🦀!", " ok"],
+                vec!["a", "  \n ", " b"],
+                vec!["\n\n\n"],
+                vec![
+                    "x", " =", " foo", "(", "bar", ",", " \"", "baz", "\")", " +", " ", "1", "_",
+                    "0", "0", "0", ";",
+                ],
+                vec!["   "],
+                vec!["Ⅻ", " naïve", " café", " ", "½"],
+                vec![
+                    "$", "1", "0", "0", " <=", " ", "2", "0", "0", "|", "3", "0", "0",
+                ],
+                vec![
+                    "fn", " main", "()", " {", "\n   ", " println", "!(\"", "hi", "\");", "\n", "}",
+                ],
+            ],
+        }
+    }
+
+    // the merges straddle the words where the pre-tokenizers differ, the expected ids are
+    // encoded by the byte level BPE of openai's gpt-2 `encoder.py` on the pieces split by
+    // Python `regex`, over the 256 byte tokens followed by the merged tokens
+    #[rustfmt::skip]
+    const MERGES: &[&str] = &[
+        "1 2", "12 3", "4 5", "123 45", "0 0", "00 0", "' L", "'L L", "' m", "Ċ Ċ",
+        "Ġ Ġ", "ĠĠ Ġ", "b a", "ba r", "( bar", "Ġ +", "Ġ+ Ġ", "< =", "Ġ <=", "Ġ 1",
+        "Ġ 6", "Ġ6 7", "ĉ a", "ĉa n", "ĉan d", "Ġ d", "Ġd o", "Ġdo n", "Ã ¯", "n a",
+        "na Ã¯", "Ġ na", "Â ½", "Ġ Â½", "{ Ċ", "Ġ {", "Ġ{ Ċ", "; Ċ", "Ċ Ġ", "ĊĠ ĠĠ",
+    ];
+
+    fn expected_ids(kind: PreTokenizerKind) -> Vec<Vec<usize>> {
+        match kind {
+            PreTokenizerKind::Gpt2 => vec![
+                vec![
+                    73, 264, 283, 39, 116, 32, 89, 79, 85, 39, 76, 76, 32, 119, 101, 39, 86, 101,
+                ],
+                vec![
+                    108, 105, 110, 101, 49, 10, 10, 108, 105, 110, 101, 50, 13, 10,
+                ],
+                vec![259, 277, 32, 51, 46, 49, 52, 49, 53, 57],
+                vec![
+                    116, 97, 98, 9, 97, 110, 100, 32, 282, 117, 98, 108, 101, 266, 32, 115, 112,
+                    97, 99, 101, 100,
+                ],
+                vec![
+                    120, 32, 61, 32, 102, 111, 111, 40, 269, 44, 32, 34, 268, 122, 34, 41, 271,
+                    275, 95, 261, 59,
+                ],
+                vec![
+                    226, 133, 171, 32, 286, 118, 101, 32, 99, 97, 102, 195, 169, 289,
+                ],
+                vec![36, 49, 260, 274, 32, 50, 260, 124, 51, 260],
+                vec![
+                    102, 110, 32, 109, 97, 105, 110, 40, 41, 291, 10, 267, 32, 112, 114, 105, 110,
+                    116, 108, 110, 33, 40, 34, 104, 105, 34, 41, 59, 10, 125,
+                ],
+            ],
+            PreTokenizerKind::Llama3 => vec![
+                vec![
+                    73, 264, 283, 39, 116, 32, 89, 79, 85, 263, 32, 119, 101, 39, 86, 101,
+                ],
+                vec![108, 105, 110, 101, 49, 265, 108, 105, 110, 101, 50, 13, 10],
+                vec![257, 258, 32, 54, 55, 32, 51, 46, 49, 52, 49, 53, 57],
+                vec![
+                    116, 97, 98, 280, 32, 282, 117, 98, 108, 101, 266, 32, 115, 112, 97, 99, 101,
+                    100,
+                ],
+                vec![
+                    120, 32, 61, 32, 102, 111, 111, 270, 44, 32, 34, 268, 122, 34, 41, 271, 32, 49,
+                    95, 261, 59,
+                ],
+                vec![
+                    226, 133, 171, 32, 286, 118, 101, 32, 99, 97, 102, 195, 169, 32, 288,
+                ],
+                vec![36, 49, 260, 274, 32, 50, 260, 124, 51, 260],
+                vec![
+                    102, 110, 32, 109, 97, 105, 110, 40, 41, 32, 290, 267, 32, 112, 114, 105, 110,
+                    116, 108, 110, 33, 40, 34, 104, 105, 34, 41, 293, 125,
+                ],
+            ],
+            PreTokenizerKind::Qwen2 => vec![
+                vec![
+                    73, 264, 283, 39, 116, 32, 89, 79, 85, 263, 32, 119, 101, 39, 86, 101,
+                ],
+                vec![108, 105, 110, 101, 49, 265, 108, 105, 110, 101, 50, 13, 10],
+                vec![
+                    49, 50, 51, 52, 53, 32, 54, 55, 32, 51, 46, 49, 52, 49, 53, 57,
+                ],
+                vec![
+                    116, 97, 98, 280, 32, 282, 117, 98, 108, 101, 266, 32, 115, 112, 97, 99, 101,
+                    100,
+                ],
+                vec![
+                    120, 32, 61, 32, 102, 111, 111, 270, 44, 32, 34, 268, 122, 34, 41, 271, 32, 49,
+                    95, 48, 48, 48, 59,
+                ],
+                vec![
+                    226, 133, 171, 32, 286, 118, 101, 32, 99, 97, 102, 195, 169, 32, 288,
+                ],
+                vec![36, 49, 48, 48, 274, 32, 50, 48, 48, 124, 51, 48, 48],
+                vec![
+                    102, 110, 32, 109, 97, 105, 110, 40, 41, 32, 290, 267, 32, 112, 114, 105, 110,
+                    116, 108, 110, 33, 40, 34, 104, 105, 34, 41, 293, 125,
+                ],
+            ],
+            PreTokenizerKind::DeepseekLlm => vec![
+                vec![
+                    73, 39, 109, 283, 39, 116, 32, 89, 79, 85, 39, 76, 76, 32, 119, 101, 39, 86,
+                    101,
+                ],
+                vec![
+                    108, 105, 110, 101, 49, 10, 10, 108, 105, 110, 101, 50, 13, 10,
+                ],
+                vec![259, 32, 54, 55, 32, 51, 46, 49, 52, 49, 53, 57],
+                vec![
+                    116, 97, 98, 280, 32, 282, 117, 98, 108, 101, 266, 32, 115, 112, 97, 99, 101,
+                    100,
+                ],
+                vec![
+                    120, 32, 61, 32, 102, 111, 111, 40, 269, 44, 32, 34, 268, 122, 34, 41, 271, 32,
+                    49, 95, 261, 59,
+                ],
+                vec![
+                    226, 133, 171, 287, 284, 118, 101, 32, 99, 97, 102, 195, 169, 32, 288,
+                ],
+                vec![36, 49, 260, 274, 32, 50, 260, 124, 51, 260],
+                vec![
+                    102, 110, 32, 109, 97, 105, 110, 40, 41, 291, 10, 267, 32, 112, 114, 105, 110,
+                    116, 108, 110, 33, 40, 34, 104, 105, 34, 41, 59, 10, 125,
+                ],
+            ],
+            PreTokenizerKind::DeepseekCoder => vec![
+                vec![
+                    73, 39, 109, 283, 39, 116, 32, 89, 79, 85, 39, 76, 76, 32, 119, 101, 39, 86,
+                    101,
+                ],
+                vec![
+                    108, 105, 110, 101, 49, 10, 10, 108, 105, 110, 101, 50, 13, 10,
+                ],
+                vec![
+                    49, 50, 51, 52, 53, 32, 54, 55, 32, 51, 46, 49, 52, 49, 53, 57,
+                ],
+                vec![
+                    116, 97, 98, 280, 32, 282, 117, 98, 108, 101, 266, 32, 115, 112, 97, 99, 101,
+                    100,
+                ],
+                vec![
+                    120, 32, 61, 32, 102, 111, 111, 40, 269, 44, 32, 34, 268, 122, 34, 41, 272, 49,
+                    95, 48, 48, 48, 59,
+                ],
+                vec![
+                    226, 133, 171, 32, 286, 118, 101, 32, 99, 97, 102, 195, 169, 32, 288,
+                ],
+                vec![36, 49, 48, 48, 274, 32, 50, 48, 48, 124, 51, 48, 48],
+                vec![
+                    102, 110, 32, 109, 97, 105, 110, 40, 41, 291, 10, 267, 32, 112, 114, 105, 110,
+                    116, 108, 110, 33, 40, 34, 104, 105, 34, 41, 59, 10, 125,
+                ],
+            ],
+            PreTokenizerKind::Falcon => vec![
+                vec![
+                    73, 39, 109, 283, 39, 116, 32, 89, 79, 85, 39, 76, 76, 32, 119, 101, 39, 86,
+                    101,
+                ],
+                vec![
+                    108, 105, 110, 101, 49, 10, 10, 108, 105, 110, 101, 50, 13, 10,
+                ],
+                vec![257, 258, 277, 32, 51, 46, 49, 52, 49, 53, 57],
+                vec![
+                    116, 97, 98, 9, 97, 110, 100, 32, 282, 117, 98, 108, 101, 266, 32, 115, 112,
+                    97, 99, 101, 100,
+                ],
+                vec![
+                    120, 32, 61, 32, 102, 111, 111, 40, 269, 44, 32, 34, 268, 122, 34, 41, 32, 43,
+                    275, 95, 261, 59,
+                ],
+                vec![
+                    226, 133, 171, 32, 286, 118, 101, 32, 99, 97, 102, 195, 169, 289,
+                ],
+                vec![36, 49, 260, 32, 273, 32, 50, 260, 124, 51, 260],
+                vec![
+                    102, 110, 32, 109, 97, 105, 110, 40, 41, 32, 123, 10, 267, 32, 112, 114, 105,
+                    110, 116, 108, 110, 33, 40, 34, 104, 105, 34, 41, 59, 10, 125,
+                ],
+            ],
+            PreTokenizerKind::Starcoder => vec![
+                vec![
+                    73, 264, 283, 39, 116, 32, 89, 79, 85, 39, 76, 76, 32, 119, 101, 39, 86, 101,
+                ],
+                vec![
+                    108, 105, 110, 101, 49, 10, 10, 108, 105, 110, 101, 50, 13, 10,
+                ],
+                vec![
+                    49, 50, 51, 52, 53, 32, 54, 55, 32, 51, 46, 49, 52, 49, 53, 57,
+                ],
+                vec![
+                    116, 97, 98, 9, 97, 110, 100, 32, 282, 117, 98, 108, 101, 266, 32, 115, 112,
+                    97, 99, 101, 100,
+                ],
+                vec![
+                    120, 32, 61, 32, 102, 111, 111, 40, 269, 44, 32, 34, 268, 122, 34, 41, 271, 32,
+                    49, 95, 48, 48, 48, 59,
+                ],
+                vec![
+                    226, 133, 171, 32, 286, 118, 101, 32, 99, 97, 102, 195, 169, 32, 288,
+                ],
+                vec![36, 49, 48, 48, 274, 32, 50, 48, 48, 124, 51, 48, 48],
+                vec![
+                    102, 110, 32, 109, 97, 105, 110, 40, 41, 291, 10, 267, 32, 112, 114, 105, 110,
+                    116, 108, 110, 33, 40, 34, 104, 105, 34, 41, 59, 10, 125,
+                ],
+            ],
+        }
+    }
+
+    #[test]
+    fn test_pre_tokenizer_split() {
+        for kind in [
+            PreTokenizerKind::Gpt2,
+            PreTokenizerKind::Llama3,
+            PreTokenizerKind::Qwen2,
+            PreTokenizerKind::DeepseekLlm,
+            PreTokenizerKind::DeepseekCoder,
+            PreTokenizerKind::Falcon,
+            PreTokenizerKind::Starcoder,
+        ] {
+            let pre = PreTokenizer::new(kind);
+            for (text, pieces) in CORPUS.iter().zip(expected(kind)) {
+                assert_eq!(pre.split(text), pieces, "{:?} on {:?}", kind, text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pre_tokenizer_falcon_backticks() {
+        // the backticks are split by the punctuations of falcon, not the GPT-2 pattern
+        let pre = PreTokenizer::new(PreTokenizerKind::Falcon);
+        assert_eq!(pre.split("run `cargo test` now"), vec![
+            "run", " ", "`", "cargo", " test", "`", " now"
+        ]);
+        assert_eq!(pre.split("a``b ` c"), vec!["a", "``", "b", " ", "`", " c"]);
+        let pre = PreTokenizer::new(PreTokenizerKind::Gpt2);
+        assert_eq!(pre.split("a``b ` c"), vec!["a", "``", "b", " `", " c"]);
+    }
+
+    #[test]
+    fn test_pre_tokenizer_token_ids() {
+        let byte_encodes = build_byte_encode_map();
+        let mut tokens = (0..=255u8)
+            .map(|b| byte_encodes[&b].to_string())
+            .collect::<Vec<_>>();
+        tokens.extend(MERGES.iter().map(|m| m.replace(' ', "")));
+        let tokens = Arc::new(tokens);
+        let merges = MERGES.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+
+        for kind in [
+            PreTokenizerKind::Gpt2,
+            PreTokenizerKind::Llama3,
+            PreTokenizerKind::Qwen2,
+            PreTokenizerKind::DeepseekLlm,
+            PreTokenizerKind::DeepseekCoder,
+            PreTokenizerKind::Falcon,
+            PreTokenizerKind::Starcoder,
+        ] {
+            let tk = Gpt2Tokenizer::new(tokens.clone(), &merges, kind, false);
+            let texts = [2, 5, 6, 7, 12, 14, 15, 16].map(|i| CORPUS[i]);
+            for (text, ids) in texts.iter().zip(expected_ids(kind)) {
+                assert_eq!(
+                    tk.encode_with_offsets(text, true)
+                        .into_iter()
+                        .map(|(token, _)| token)
+                        .collect::<Vec<_>>(),
+                    ids,
+                    "{:?} on {:?}",
+                    kind,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pre_tokenizer_kind() -> Result<()> {
+        assert_eq!(
+            "llama-bpe".parse::<PreTokenizerKind>()?,
+            PreTokenizerKind::Llama3
+        );
+        assert_eq!(
+            "qwen2".parse::<PreTokenizerKind>()?,
+            PreTokenizerKind::Qwen2
+        );
+        assert_eq!(
+            "default".parse::<PreTokenizerKind>()?,
+            PreTokenizerKind::Gpt2
+        );
+        assert_eq!(
+            "refact".parse::<PreTokenizerKind>()?,
+            PreTokenizerKind::Starcoder
+        );
+        assert!("tekken".parse::<PreTokenizerKind>().is_err());
+
+        assert_eq!(
+            PreTokenizerKind::from_pattern(QWEN2_PATTERN),
+            Some(PreTokenizerKind::Qwen2)
+        );
+        assert_eq!(PreTokenizerKind::from_pattern(r"\p{N}"), None);
+        Ok(())
+    }
+}
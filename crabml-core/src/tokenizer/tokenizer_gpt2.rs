@@ -1,8 +1,11 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::Arc;
 
-use regex::Regex;
-
+use super::bpe::bpe_merge;
+use super::pre_tokenizer::PreTokenizer;
+use super::pre_tokenizer::PreTokenizerKind;
 use super::TokenID;
 
 pub struct Gpt2Tokenizer {
@@ -11,16 +14,16 @@ pub struct Gpt2Tokenizer {
     bpe_ranks: HashMap<(TokenID, TokenID), usize>,
     byte_encodes: HashMap<u8, char>,
     byte_decodes: HashMap<char, u8>,
-    bos_token: TokenID,
-    eos_token: TokenID,
+    pre_tokenizer: PreTokenizer,
+    add_prefix_space: bool,
 }
 
 impl Gpt2Tokenizer {
     pub fn new(
         tokens: Arc<Vec<String>>,
         merges: &[String],
-        bos_token: TokenID,
-        eos_token: TokenID,
+        pre_tokenizer: PreTokenizerKind,
+        add_prefix_space: bool,
     ) -> Self {
         let token_ids: Arc<HashMap<String, TokenID>> = Arc::new(
             tokens
@@ -41,18 +44,14 @@ impl Gpt2Tokenizer {
             .collect();
         let byte_encodes = build_byte_encode_map();
         let byte_decodes = byte_encodes.iter().map(|(b, u)| (*u, *b)).collect();
-        // i still dunno what this regex is doing
-        let _pattern =
-            Regex::new(r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+")
-                .unwrap();
         Self {
             tokens,
             token_ids,
             bpe_ranks: merges,
             byte_encodes,
             byte_decodes,
-            bos_token,
-            eos_token,
+            pre_tokenizer: PreTokenizer::new(pre_tokenizer),
+            add_prefix_space,
         }
     }
 
@@ -89,93 +88,59 @@ impl Gpt2Tokenizer {
         String::from_utf8_lossy(&bytes).to_string()
     }
 
-    // encode the string text (input) into an upper-bound preallocated tokens[] array
-    // bos != 0 means prepend the BOS token (=1), eos != 0 means append the EOS token (=2)
-    pub fn encode(&self, text: &str, bos: bool, eos: bool, add_prefix_space: bool) -> Vec<TokenID> {
-        let text = if add_prefix_space {
-            format!(" {}", text)
+    /// encode the text with the byte span of the text covered by every token, the prefix space
+    /// covers an empty span at the start, it's only prepended at the start of the sequence.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        add_prefix_space: bool,
+    ) -> Vec<(TokenID, Range<usize>)> {
+        let (text, prefix_len) = if self.add_prefix_space && add_prefix_space {
+            (format!(" {}", text), 1)
         } else {
-            text.to_string()
+            (text.to_string(), 0)
+        };
+        let span = |pos: usize| match pos.checked_sub(prefix_len) {
+            Some(pos) => pos..pos + 1,
+            None => 0..0,
         };
 
-        let special_tokens = vec![
-            // for qwen2
-            "<|im_start|>",
-            "<|im_end|>",
-            "<|endoftext|>",
-            // for llama3
-            "<|begin_of_text|>",
-            "<|end_of_text|>",
-            "<|reserved_special_token_0|>",
-            "<|reserved_special_token_1|>",
-            "<|reserved_special_token_2|>",
-            "<|reserved_special_token_3|>",
-            "<|start_header_id|>",
-            "<|end_header_id|>",
-            "<|reserved_special_token_4|>",
-            "<|eot_id|>",
-        ];
-        let parts = split_text_by_keyword(&text, &special_tokens);
-
-        let mut tokens = parts
-            .iter()
-            .flat_map(|s| {
-                if special_tokens.contains(&s.as_str()) {
-                    return vec![*self.token_ids.get(s).unwrap()];
-                }
-                let mut toks = vec![];
-                for b in s.bytes() {
-                    let ch = self.byte_encodes.get(&b).unwrap().to_string();
-                    let token_id = self.token_ids.get(&ch).unwrap();
-                    toks.push(*token_id);
-                }
-                toks = self.bpe_merge(toks);
-                toks
+        // the text is split into words first, the merges never cross the words
+        self.pre_tokenizer
+            .split(&text)
+            .into_iter()
+            .flat_map(|word| {
+                // the words are the slices of the text
+                let word_start = word.as_ptr() as usize - text.as_ptr() as usize;
+                let toks = word
+                    .bytes()
+                    .enumerate()
+                    .map(|(i, b)| {
+                        let ch = self.byte_encodes.get(&b).unwrap().to_string();
+                        (*self.token_ids.get(&ch).unwrap(), span(word_start + i))
+                    })
+                    .collect::<Vec<_>>();
+                self.bpe_merge(toks)
             })
-            .collect::<Vec<_>>();
-
-        if bos {
-            tokens.insert(0, self.bos_token);
-        }
-        if eos {
-            tokens.push(self.eos_token);
-        }
-        tokens
+            .collect()
     }
 
-    fn bpe_merge(&self, mut tokens: Vec<TokenID>) -> Vec<TokenID> {
-        // merge the best consecutive pair each iteration, according the merges
-        loop {
-            let mut lowest_rank = usize::MAX;
-            let mut merging_pair: Option<(TokenID, TokenID)> = None;
-            let mut merging_idx = usize::MAX;
-            for (idx, pair) in tokens.windows(2).enumerate() {
-                if let Some(rank) = self.bpe_ranks.get(&(pair[0], pair[1])) {
-                    if *rank < lowest_rank {
-                        lowest_rank = *rank;
-                        merging_pair = Some((pair[0], pair[1]));
-                        merging_idx = idx;
-                    }
-                }
-            }
-            if let Some((tok1, tok2)) = merging_pair {
-                let token1 = self.tokens[tok1].clone();
-                let token2 = self.tokens[tok2].clone();
-                tokens[merging_idx] = *self
-                    .token_ids
-                    .get(&format!("{}{}", token1, token2))
-                    .unwrap();
-                tokens.remove(merging_idx + 1);
-            } else {
-                return tokens;
-            }
-        }
+    fn bpe_merge(&self, tokens: Vec<(TokenID, Range<usize>)>) -> Vec<(TokenID, Range<usize>)> {
+        // merge the consecutive pair of the lowest rank each time, according the merges
+        let mut token_buf = String::new();
+        bpe_merge(tokens, |left, right| {
+            let rank = *self.bpe_ranks.get(&(left, right))?;
+            token_buf.clear();
+            token_buf.push_str(&self.tokens[left]);
+            token_buf.push_str(&self.tokens[right]);
+            Some((Reverse(rank), *self.token_ids.get(&token_buf).unwrap()))
+        })
     }
 }
 
 /// the merge map are all unicodes, we need convert the raw bytes into an encoded
 /// unicode character.
-fn build_byte_encode_map() -> HashMap<u8, char> {
+pub(super) fn build_byte_encode_map() -> HashMap<u8, char> {
     let mut map = HashMap::new();
     let ranges = [('!', '~'), ('¡', '¬'), ('®', 'ÿ')];
     for (start, end) in ranges.iter() {
@@ -193,35 +158,19 @@ fn build_byte_encode_map() -> HashMap<u8, char> {
     map
 }
 
-fn split_text_by_keyword(text: &str, keywords: &[&str]) -> Vec<String> {
-    // Escape the keywords and join them into a regular expression pattern
-    let escaped_keywords: Vec<String> = keywords.iter().map(|k| regex::escape(k)).collect();
-    let pattern = format!("({})", escaped_keywords.join("|"));
-    let re = Regex::new(&pattern).unwrap();
-
-    let mut result = Vec::new();
-    let mut last = 0;
-
-    for mat in re.find_iter(text) {
-        if mat.start() > last {
-            result.push(text[last..mat.start()].to_string());
-        }
-        result.push(mat.as_str().to_string());
-        last = mat.end();
-    }
-    if last < text.len() {
-        result.push(text[last..].to_string());
-    }
-
-    result
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::error::Result;
     use crate::gguf::GGUFFileLoader;
 
+    fn encode(tk: &Gpt2Tokenizer, text: &str) -> Vec<TokenID> {
+        tk.encode_with_offsets(text, true)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
     #[ignore]
     #[test]
     fn test_gpt2_tokenizer() -> Result<()> {
@@ -244,9 +193,9 @@ mod tests {
             .iter()
             .map(|s| s.to_string())
             .collect::<Vec<_>>();
-        let tk = Gpt2Tokenizer::new(tokens.clone(), &merges, 1, 2);
+        let tk = Gpt2Tokenizer::new(tokens.clone(), &merges, PreTokenizerKind::Qwen2, false);
 
-        let token_ids = tk.encode("我不吃牛肉", false, false, false);
+        let token_ids = encode(&tk, "我不吃牛肉");
         assert_eq!(tk.tokens[token_ids[0]], "æĪĳä¸į");
         assert_eq!(tk.decode_tokens(&[token_ids[0]]), "我不");
 
@@ -262,7 +211,7 @@ mod tests {
         ];
 
         for tt in tests {
-            let outputs = tk.encode(tt.0, false, false, false);
+            let outputs = encode(&tk, tt.0);
             let tokens_in_string = tk.decode_tokens(&outputs);
             assert_eq!(tokens_in_string, tt.1, "failed to encode {}", tt.0);
         }
@@ -271,19 +220,30 @@ mod tests {
     }
 
     #[test]
-    fn test_split_words() {
-        let output: Vec<String> = split_text_by_keyword(
-            "<|im_start|>i don't eat beaf<|im_end|> <|im_start|> i don't eat beaf",
-            &["<|im_start|>", "<|im_end|>"],
-        );
-        assert_eq!(output, vec![
-            "<|im_start|>",
-            "i don't eat beaf",
-            "<|im_end|>",
-            " ",
-            "<|im_start|>",
-            " i don't eat beaf"
+    fn test_gpt2_tokenizer_pre_tokenize() {
+        // the byte tokens are at the ids of the bytes
+        let byte_encodes = build_byte_encode_map();
+        let mut tokens = (0..=255u8)
+            .map(|b| byte_encodes[&b].to_string())
+            .collect::<Vec<_>>();
+        tokens.extend(["34", "12", "123", "45", "oĠ", "Ġw"].map(|s| s.to_string()));
+        let merges = ["3 4", "1 2", "12 3", "4 5", "o Ġ", "Ġ w"].map(|s| s.to_string());
+        let tokens = Arc::new(tokens);
+
+        let text = "12345 hello world";
+        let tk = Gpt2Tokenizer::new(tokens.clone(), &merges, PreTokenizerKind::Llama3, false);
+        // 123 | 45 | " hello" | " world", "3 4" and "o Ġ" are not merged across the words
+        assert_eq!(encode(&tk, text), vec![
+            258, 259, 32, 104, 101, 108, 108, 111, 261, 111, 114, 108, 100
         ]);
+        assert_eq!(tk.decode_tokens(&encode(&tk, text)), text);
+        let offsets = tk.encode_with_offsets(text, true);
+        assert_eq!(&offsets[..3], &[(258, 0..3), (259, 3..5), (32, 5..6)]);
+        assert_eq!(offsets[8], (261, 11..13));
+
+        // qwen2 splits every digit
+        let tk = Gpt2Tokenizer::new(tokens, &merges, PreTokenizerKind::Qwen2, false);
+        assert_eq!(&encode(&tk, text)[..5], &[49, 50, 51, 52, 53]);
     }
 
     #[test]
@@ -0,0 +1,1155 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use regex::Regex;
+use serde_json::Value;
+
+use super::bpe::bpe_merge;
+use super::pre_tokenizer::PreTokenizer;
+use super::pre_tokenizer::PreTokenizerKind;
+use super::tokenizer_gpt2::build_byte_encode_map;
+use super::TokenID;
+use super::TokenType;
+use crate::bail;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+const PUNCTUATION_PATTERN: &str = r"[\p{P}!-/:-@\[-`{-~]";
+
+/// the tokenizer described by a Hugging Face `tokenizer.json`, which is embedded in some GGUF
+/// files as `tokenizer.huggingface.json`. the text is normalized and split into words by the
+/// pre-tokenizers, then every word is tokenized by the model. a token is turned back into bytes
+/// by the decoders.
+///
+/// the added tokens are not handled here, they're matched as the special tokens of `Tokenizer`.
+pub struct HfTokenizer {
+    tokens: Arc<Vec<String>>,
+    token_ids: HashMap<String, TokenID>,
+    model: HfModel,
+    normalizers: Vec<Normalizer>,
+    pre_tokenizers: Vec<PreTokenizerStep>,
+    decoders: Vec<Decoder>,
+    byte_encodes: HashMap<u8, char>,
+    byte_decodes: HashMap<char, u8>,
+}
+
+enum HfModel {
+    Bpe {
+        merges: HashMap<(TokenID, TokenID), (usize, TokenID)>,
+        unk_token: Option<TokenID>,
+        byte_fallback: bool,
+        ignore_merges: bool,
+    },
+    Unigram {
+        pieces: HashMap<String, (TokenID, f32)>,
+        max_piece_chars: usize,
+        unk_token: Option<TokenID>,
+        unk_score: f32,
+        byte_fallback: bool,
+    },
+    WordPiece {
+        unk_token: Option<TokenID>,
+        prefix: String,
+        max_input_chars: usize,
+    },
+}
+
+enum Pattern {
+    String(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn replace(&self, text: &str, content: &str) -> String {
+        match self {
+            Pattern::String(s) => text.replace(s.as_str(), content),
+            Pattern::Regex(re) => re.replace_all(text, content).to_string(),
+        }
+    }
+}
+
+enum Normalizer {
+    Lowercase,
+    Prepend(String),
+    Replace(Pattern, String),
+    Strip {
+        left: bool,
+        right: bool,
+    },
+    Bert {
+        clean_text: bool,
+        chinese_chars: bool,
+        lowercase: bool,
+    },
+}
+
+enum PreTokenizerStep {
+    ByteLevel {
+        add_prefix_space: bool,
+        split: Option<PreTokenizer>,
+    },
+    Metaspace {
+        replacement: char,
+        prepend: bool,
+        split: bool,
+    },
+    /// both the matched and the unmatched parts are kept
+    Split(PreTokenizer),
+    /// the matched parts are dropped
+    SplitRemoved(Regex),
+    /// only the matched parts are kept
+    Matches(Regex),
+    WhitespaceSplit,
+}
+
+enum Decoder {
+    ByteLevel,
+    ByteFallback,
+    Metaspace(char),
+    WordPiece(String),
+    Replace(Pattern, String),
+}
+
+impl HfTokenizer {
+    /// the tokens are expected to be loaded by `load_vocab` from the same json.
+    pub fn new(json: &Value, tokens: Arc<Vec<String>>) -> Result<Self> {
+        let token_ids = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.clone(), i))
+            .collect::<HashMap<_, _>>();
+        let model = parse_model(&json["model"], &token_ids)?;
+        let normalizers = parse_normalizers(&json["normalizer"])?;
+        let pre_tokenizers = parse_pre_tokenizers(&json["pre_tokenizer"])?;
+        let decoders = parse_decoders(&json["decoder"])?;
+        let byte_encodes = build_byte_encode_map();
+        let byte_decodes = byte_encodes.iter().map(|(b, u)| (*u, *b)).collect();
+        Ok(Self {
+            tokens,
+            token_ids,
+            model,
+            normalizers,
+            pre_tokenizers,
+            decoders,
+            byte_encodes,
+            byte_decodes,
+        })
+    }
+
+    /// load the tokens of the model and the added tokens, the missing ids are filled with
+    /// `[PAD{id}]` up to `vocab_size`. the special added tokens are taken as control tokens.
+    pub fn load_vocab(json: &Value, vocab_size: usize) -> Result<(Vec<String>, Vec<TokenType>)> {
+        let model = &json["model"];
+        let mut entries = vec![];
+        match &model["vocab"] {
+            Value::Object(vocab) => {
+                for (token, id) in vocab.iter() {
+                    entries.push((token.as_str(), id.as_u64(), TokenType::Normal));
+                }
+            }
+            // the unigram vocab is a list of [piece, score]
+            Value::Array(vocab) => {
+                for (id, piece) in vocab.iter().enumerate() {
+                    entries.push((
+                        piece[0].as_str().unwrap_or(""),
+                        Some(id as u64),
+                        TokenType::Normal,
+                    ));
+                }
+            }
+            _ => bail!(
+                ErrorKind::FormatError,
+                "missing vocab in the tokenizer.json"
+            ),
+        }
+        for token in json["added_tokens"].as_array().into_iter().flatten() {
+            let token_type = match token["special"].as_bool().unwrap_or(false) {
+                true => TokenType::Control,
+                false => TokenType::UserDefined,
+            };
+            entries.push((
+                token["content"].as_str().unwrap_or(""),
+                token["id"].as_u64(),
+                token_type,
+            ));
+        }
+
+        let mut vocab = vec![];
+        for (token, id, token_type) in entries {
+            let id = match id {
+                Some(id) => id as usize,
+                None => bail!(ErrorKind::FormatError, "invalid id of token {}", token),
+            };
+            if id >= vocab.len() {
+                vocab.resize(id + 1, None);
+            }
+            vocab[id] = Some((token.to_string(), token_type));
+        }
+        // the embeddings are often padded to a larger size than the vocab
+        vocab.resize(vocab.len().max(vocab_size), None);
+        Ok(vocab
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| t.unwrap_or_else(|| (format!("[PAD{}]", i), TokenType::Unused)))
+            .unzip())
+    }
+
+    pub fn decode(&self, token: TokenID) -> Vec<u8> {
+        let mut piece = self.tokens[token].clone();
+        for decoder in self.decoders.iter() {
+            match decoder {
+                Decoder::ByteLevel => {
+                    return piece
+                        .chars()
+                        .flat_map(|c| match self.byte_decodes.get(&c) {
+                            Some(b) => vec![*b],
+                            None => c.to_string().into_bytes(),
+                        })
+                        .collect();
+                }
+                Decoder::ByteFallback => {
+                    if let Some(byte) = parse_byte_token(&piece) {
+                        return vec![byte];
+                    }
+                }
+                Decoder::Metaspace(replacement) => {
+                    piece = piece.replace(*replacement, " ");
+                }
+                Decoder::WordPiece(prefix) => {
+                    piece = match piece.strip_prefix(prefix.as_str()) {
+                        Some(rest) => rest.to_string(),
+                        None => format!(" {}", piece),
+                    };
+                }
+                Decoder::Replace(pattern, content) => {
+                    piece = pattern.replace(&piece, content);
+                }
+            }
+        }
+        piece.into_bytes()
+    }
+
+    /// encode the text with the byte span of the text covered by every token, the spans are
+    /// tracked through the normalizers and the pre-tokenizers. the byte level prefix space is
+    /// only prepended if `add_prefix_space`.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        add_prefix_space: bool,
+    ) -> Vec<(TokenID, Range<usize>)> {
+        let text = self.normalize(Word::new(text));
+        let mut tokens = vec![];
+        for word in self.pre_tokenize(text, add_prefix_space) {
+            if word.text.is_empty() {
+                continue;
+            }
+            for (token, span) in self.tokenize_word(&word.text) {
+                tokens.push((token, word.span(span)));
+            }
+        }
+        tokens
+    }
+
+    fn normalize(&self, mut text: Word) -> Word {
+        for normalizer in self.normalizers.iter() {
+            text = match normalizer {
+                Normalizer::Lowercase => text.lowercase(),
+                Normalizer::Prepend(prefix) if !text.text.is_empty() => text.prepend(prefix),
+                Normalizer::Prepend(_) => text,
+                Normalizer::Replace(pattern, content) => text.replace(pattern, content),
+                Normalizer::Strip { left, right } => text.trim(*left, *right),
+                Normalizer::Bert {
+                    clean_text,
+                    chinese_chars,
+                    lowercase,
+                } => {
+                    let out = text.map_chars(|c, out| {
+                        if *clean_text && (c == '\0' || c == '\u{fffd}' || is_bert_control(c)) {
+                            return;
+                        }
+                        if *clean_text && c.is_whitespace() {
+                            out.push(' ');
+                        } else if *chinese_chars && is_chinese_char(c) {
+                            out.push(' ');
+                            out.push(c);
+                            out.push(' ');
+                        } else {
+                            out.push(c);
+                        }
+                    });
+                    if *lowercase {
+                        out.lowercase()
+                    } else {
+                        out
+                    }
+                }
+            };
+        }
+        text
+    }
+
+    fn pre_tokenize(&self, text: Word, allow_prefix_space: bool) -> Vec<Word> {
+        let mut words = vec![text];
+        for step in self.pre_tokenizers.iter() {
+            words = match step {
+                PreTokenizerStep::ByteLevel {
+                    add_prefix_space,
+                    split,
+                } => {
+                    if *add_prefix_space
+                        && allow_prefix_space
+                        && !words.first().is_none_or(|w| w.text.starts_with(' '))
+                    {
+                        words[0] = words[0].prepend(" ");
+                    }
+                    let words = match split {
+                        Some(split) => words
+                            .iter()
+                            .flat_map(|w| w.slices(split.split(&w.text)))
+                            .collect(),
+                        None => words,
+                    };
+                    words
+                        .iter()
+                        .map(|w| w.map_bytes(|b| self.byte_encodes[&b]))
+                        .collect()
+                }
+                PreTokenizerStep::Metaspace {
+                    replacement,
+                    prepend,
+                    split,
+                } => {
+                    let mut words = words
+                        .iter()
+                        .map(|w| {
+                            w.map_chars(|c, out| out.push(if c == ' ' { *replacement } else { c }))
+                        })
+                        .collect::<Vec<_>>();
+                    if *prepend
+                        && !words
+                            .first()
+                            .is_none_or(|w| w.text.starts_with(*replacement))
+                    {
+                        words[0] = words[0].prepend(&replacement.to_string());
+                    }
+                    if *split {
+                        words
+                            .iter()
+                            .flat_map(|w| w.slices(split_before(&w.text, *replacement)))
+                            .collect()
+                    } else {
+                        words
+                    }
+                }
+                PreTokenizerStep::Split(split) => words
+                    .iter()
+                    .flat_map(|w| w.slices(split.split(&w.text)))
+                    .collect(),
+                PreTokenizerStep::SplitRemoved(re) => words
+                    .iter()
+                    .flat_map(|w| w.slices(re.split(&w.text)))
+                    .collect(),
+                PreTokenizerStep::Matches(re) => words
+                    .iter()
+                    .flat_map(|w| w.slices(re.find_iter(&w.text).map(|m| m.as_str())))
+                    .collect(),
+                PreTokenizerStep::WhitespaceSplit => words
+                    .iter()
+                    .flat_map(|w| w.slices(w.text.split_whitespace()))
+                    .collect(),
+            };
+        }
+        words
+    }
+
+    /// tokenize the word with the byte span of the word covered by every token.
+    fn tokenize_word(&self, word: &str) -> Vec<(TokenID, Range<usize>)> {
+        match &self.model {
+            HfModel::Bpe {
+                merges,
+                unk_token,
+                byte_fallback,
+                ignore_merges,
+            } => {
+                if *ignore_merges {
+                    if let Some(token) = self.token_ids.get(word) {
+                        return vec![(*token, 0..word.len())];
+                    }
+                }
+                let mut tokens = vec![];
+                for (i, c) in word.char_indices() {
+                    match self.token_ids.get(c.encode_utf8(&mut [0; 4]) as &str) {
+                        Some(token) => tokens.push((*token, i..i + c.len_utf8())),
+                        None => self.push_unknown(c, i, *unk_token, *byte_fallback, &mut tokens),
+                    }
+                }
+                bpe_merge(tokens, |left, right| {
+                    let (rank, merged) = merges.get(&(left, right))?;
+                    Some((Reverse(*rank), *merged))
+                })
+            }
+            HfModel::Unigram {
+                pieces,
+                max_piece_chars,
+                unk_token,
+                unk_score,
+                byte_fallback,
+            } => {
+                let segments = viterbi(word, pieces, *max_piece_chars, *unk_score);
+                let mut tokens: Vec<(TokenID, Range<usize>)> = vec![];
+                let mut prev_unknown = false;
+                for (start, end, token) in segments {
+                    match token {
+                        Some(token) => tokens.push((token, start..end)),
+                        None if *byte_fallback => {
+                            for (i, c) in word[start..end].char_indices() {
+                                self.push_unknown(c, start + i, *unk_token, true, &mut tokens);
+                            }
+                        }
+                        // the consecutive unknown pieces are fused into one
+                        None if !prev_unknown => tokens.extend(unk_token.map(|t| (t, start..end))),
+                        None => {
+                            if let (Some(_), Some((_, span))) = (unk_token, tokens.last_mut()) {
+                                span.end = end;
+                            }
+                        }
+                    }
+                    prev_unknown = token.is_none();
+                }
+                tokens
+            }
+            HfModel::WordPiece {
+                unk_token,
+                prefix,
+                max_input_chars,
+            } => {
+                let unknown = || unk_token.iter().map(|t| (*t, 0..word.len())).collect();
+                if word.chars().count() > *max_input_chars {
+                    return unknown();
+                }
+                let mut tokens = vec![];
+                let mut start = 0;
+                while start < word.len() {
+                    // the longest piece in the vocab is taken greedily
+                    let mut end = word.len();
+                    let token = loop {
+                        if end == start {
+                            break None;
+                        }
+                        let piece = match start {
+                            0 => word[..end].to_string(),
+                            _ => format!("{}{}", prefix, &word[start..end]),
+                        };
+                        if let Some(token) = self.token_ids.get(&piece) {
+                            break Some(*token);
+                        }
+                        end -= word[start..end].chars().last().unwrap().len_utf8();
+                    };
+                    match token {
+                        Some(token) => tokens.push((token, start..end)),
+                        None => return unknown(),
+                    }
+                    start = end;
+                }
+                tokens
+            }
+        }
+    }
+
+    /// the char not in the vocab is encoded as the `<0xXX>` tokens of its bytes if
+    /// `byte_fallback`, or as the unknown token. `pos` is the position of the char in the word.
+    fn push_unknown(
+        &self,
+        c: char,
+        pos: usize,
+        unk_token: Option<TokenID>,
+        byte_fallback: bool,
+        tokens: &mut Vec<(TokenID, Range<usize>)>,
+    ) {
+        if byte_fallback {
+            let bytes = c
+                .encode_utf8(&mut [0; 4])
+                .bytes()
+                .map(|b| self.token_ids.get(&format!("<0x{:02X}>", b)).copied())
+                .collect::<Option<Vec<_>>>();
+            if let Some(bytes) = bytes {
+                tokens.extend(
+                    bytes
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, b)| (b, pos + i..pos + i + 1)),
+                );
+                return;
+            }
+        }
+        tokens.extend(unk_token.map(|t| (t, pos..pos + c.len_utf8())));
+    }
+}
+
+/// the text being normalized and pre-tokenized, with the span of the original text of every
+/// byte, so the tokens can be mapped back to the text they come from.
+struct Word {
+    text: String,
+    offsets: Vec<Range<usize>>,
+}
+
+impl Word {
+    fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            offsets: (0..text.len()).map(|i| i..i + 1).collect(),
+        }
+    }
+
+    /// the span of the original text covered by the bytes in the range, the inserted bytes
+    /// like a prefix space cover an empty span.
+    fn span(&self, range: Range<usize>) -> Range<usize> {
+        if range.is_empty() {
+            let pos = match self.offsets.get(range.start) {
+                Some(span) => span.start,
+                None => self.offsets.last().map_or(0, |span| span.end),
+            };
+            return pos..pos;
+        }
+        self.offsets[range.start].start..self.offsets[range.end - 1].end
+    }
+
+    fn push_str(&mut self, s: &str, span: Range<usize>) {
+        self.text.push_str(s);
+        self.offsets.extend(std::iter::repeat_n(span, s.len()));
+    }
+
+    fn slice(&self, range: Range<usize>) -> Word {
+        Word {
+            text: self.text[range.clone()].to_string(),
+            offsets: self.offsets[range].to_vec(),
+        }
+    }
+
+    /// the pieces are expected to be the slices of the text.
+    fn slices<'a>(&self, pieces: impl IntoIterator<Item = &'a str>) -> Vec<Word> {
+        pieces
+            .into_iter()
+            .map(|piece| {
+                let start = piece.as_ptr() as usize - self.text.as_ptr() as usize;
+                self.slice(start..start + piece.len())
+            })
+            .collect()
+    }
+
+    fn prepend(&self, prefix: &str) -> Word {
+        let mut word = Word {
+            text: String::with_capacity(prefix.len() + self.text.len()),
+            offsets: Vec::with_capacity(prefix.len() + self.text.len()),
+        };
+        word.push_str(prefix, self.span(0..0));
+        word.text.push_str(&self.text);
+        word.offsets.extend_from_slice(&self.offsets);
+        word
+    }
+
+    fn map_chars(&self, mut f: impl FnMut(char, &mut String)) -> Word {
+        let mut word = Word {
+            text: String::with_capacity(self.text.len()),
+            offsets: Vec::with_capacity(self.text.len()),
+        };
+        let mut buf = String::new();
+        for (i, c) in self.text.char_indices() {
+            buf.clear();
+            f(c, &mut buf);
+            word.push_str(&buf, self.span(i..i + c.len_utf8()));
+        }
+        word
+    }
+
+    fn map_bytes(&self, f: impl Fn(u8) -> char) -> Word {
+        let mut word = Word {
+            text: String::with_capacity(self.text.len()),
+            offsets: Vec::with_capacity(self.text.len()),
+        };
+        for (b, span) in self.text.bytes().zip(self.offsets.iter()) {
+            word.push_str(f(b).encode_utf8(&mut [0; 4]), span.clone());
+        }
+        word
+    }
+
+    /// the same as `str::to_lowercase`, which lowercases the final sigma by its context.
+    fn lowercase(&self) -> Word {
+        let mut lowercased = self
+            .text
+            .to_lowercase()
+            .chars()
+            .collect::<Vec<_>>()
+            .into_iter();
+        self.map_chars(|c, out| out.extend(lowercased.by_ref().take(c.to_lowercase().count())))
+    }
+
+    fn replace(&self, pattern: &Pattern, content: &str) -> Word {
+        let matches = match pattern {
+            Pattern::String(s) => self
+                .text
+                .match_indices(s.as_str())
+                .map(|(i, m)| i..i + m.len())
+                .collect::<Vec<_>>(),
+            Pattern::Regex(re) => re.find_iter(&self.text).map(|m| m.range()).collect(),
+        };
+        let mut word = Word {
+            text: String::with_capacity(self.text.len()),
+            offsets: Vec::with_capacity(self.text.len()),
+        };
+        let mut last = 0;
+        for m in matches {
+            word.text.push_str(&self.text[last..m.start]);
+            word.offsets.extend_from_slice(&self.offsets[last..m.start]);
+            word.push_str(content, self.span(m.clone()));
+            last = m.end;
+        }
+        word.text.push_str(&self.text[last..]);
+        word.offsets.extend_from_slice(&self.offsets[last..]);
+        word
+    }
+
+    fn trim(self, left: bool, right: bool) -> Word {
+        let start = match left {
+            true => self.text.len() - self.text.trim_start().len(),
+            false => 0,
+        };
+        let end = match right {
+            true => self.text.trim_end().len(),
+            false => self.text.len(),
+        };
+        self.slice(start..end.max(start))
+    }
+}
+
+/// find the segmentation of the word with the highest total score, every segment is returned as
+/// its byte range and the token, the unknown chars are segmented alone without a token.
+fn viterbi(
+    word: &str,
+    pieces: &HashMap<String, (TokenID, f32)>,
+    max_piece_chars: usize,
+    unk_score: f32,
+) -> Vec<(usize, usize, Option<TokenID>)> {
+    let mut offsets = word.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+    offsets.push(word.len());
+    let n = offsets.len() - 1;
+
+    // the best score of the first i chars, and the start and the token of its last segment
+    let mut best = vec![(f32::NEG_INFINITY, 0, None); n + 1];
+    best[0].0 = 0.0;
+    for end in 1..=n {
+        let mut has_single_char = false;
+        for start in end.saturating_sub(max_piece_chars)..end {
+            if best[start].0 == f32::NEG_INFINITY {
+                continue;
+            }
+            if let Some((token, score)) = pieces.get(&word[offsets[start]..offsets[end]]) {
+                has_single_char |= start + 1 == end;
+                let score = best[start].0 + score;
+                if score > best[end].0 {
+                    best[end] = (score, start, Some(*token));
+                }
+            }
+        }
+        if !has_single_char && best[end - 1].0 + unk_score > best[end].0 {
+            best[end] = (best[end - 1].0 + unk_score, end - 1, None);
+        }
+    }
+
+    let mut segments = vec![];
+    let mut end = n;
+    while end > 0 {
+        let (_, start, token) = best[end];
+        segments.push((offsets[start], offsets[end], token));
+        end = start;
+    }
+    segments.reverse();
+    segments
+}
+
+/// split the text before every `c`, like "▁a▁b" into "▁a" and "▁b".
+fn split_before(text: &str, c: char) -> Vec<&str> {
+    let mut words = vec![];
+    let mut last = 0;
+    for (i, _) in text.match_indices(c) {
+        if i > last {
+            words.push(&text[last..i]);
+        }
+        last = i;
+    }
+    if last < text.len() {
+        words.push(&text[last..]);
+    }
+    words
+}
+
+fn parse_byte_token(piece: &str) -> Option<u8> {
+    let hex = piece.strip_prefix("<0x")?.strip_suffix('>')?;
+    match hex.len() {
+        2 => u8::from_str_radix(hex, 16).ok(),
+        _ => None,
+    }
+}
+
+fn is_bert_control(c: char) -> bool {
+    !matches!(c, '\t' | '\n' | '\r') && c.is_control()
+}
+
+fn is_chinese_char(c: char) -> bool {
+    matches!(c as u32,
+        0x4E00..=0x9FFF
+        | 0x3400..=0x4DBF
+        | 0x20000..=0x2A6DF
+        | 0x2A700..=0x2B73F
+        | 0x2B740..=0x2B81F
+        | 0x2B820..=0x2CEAF
+        | 0xF900..=0xFAFF
+        | 0x2F800..=0x2FA1F)
+}
+
+fn parse_model(model: &Value, token_ids: &HashMap<String, TokenID>) -> Result<HfModel> {
+    let token_id = |key: &str| model[key].as_str().and_then(|t| token_ids.get(t)).copied();
+    let model_type = match (model["type"].as_str(), &model["vocab"]) {
+        (Some(typ), _) => typ,
+        (None, Value::Array(_)) => "Unigram",
+        (None, _) => "BPE",
+    };
+    match model_type {
+        "BPE" => {
+            let mut merges = HashMap::new();
+            for (rank, merge) in model["merges"].as_array().into_iter().flatten().enumerate() {
+                // the merges are in either `"a b"` or `["a", "b"]`
+                let (first, second) = match merge {
+                    Value::String(s) => match s.split_once(' ') {
+                        Some(pair) => pair,
+                        None => continue,
+                    },
+                    Value::Array(parts) => match (parts[0].as_str(), parts[1].as_str()) {
+                        (Some(first), Some(second)) => (first, second),
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                let merged = format!("{}{}", first, second);
+                if let (Some(first), Some(second), Some(merged)) = (
+                    token_ids.get(first),
+                    token_ids.get(second),
+                    token_ids.get(&merged),
+                ) {
+                    merges.entry((*first, *second)).or_insert((rank, *merged));
+                }
+            }
+            Ok(HfModel::Bpe {
+                merges,
+                unk_token: token_id("unk_token"),
+                byte_fallback: model["byte_fallback"].as_bool().unwrap_or(false),
+                ignore_merges: model["ignore_merges"].as_bool().unwrap_or(false),
+            })
+        }
+        "Unigram" => {
+            let mut pieces = HashMap::new();
+            let mut min_score = f32::MAX;
+            for (id, piece) in model["vocab"].as_array().into_iter().flatten().enumerate() {
+                let (Some(piece), Some(score)) = (piece[0].as_str(), piece[1].as_f64()) else {
+                    bail!(ErrorKind::FormatError, "invalid unigram piece {}", id);
+                };
+                min_score = min_score.min(score as f32);
+                pieces.insert(piece.to_string(), (id, score as f32));
+            }
+            let max_piece_chars = pieces.keys().map(|p| p.chars().count()).max().unwrap_or(1);
+            Ok(HfModel::Unigram {
+                pieces,
+                max_piece_chars,
+                unk_token: model["unk_id"].as_u64().map(|id| id as usize),
+                unk_score: min_score - 10.0,
+                byte_fallback: model["byte_fallback"].as_bool().unwrap_or(false),
+            })
+        }
+        "WordPiece" => Ok(HfModel::WordPiece {
+            unk_token: token_id("unk_token"),
+            prefix: model["continuing_subword_prefix"]
+                .as_str()
+                .unwrap_or("##")
+                .to_string(),
+            max_input_chars: model["max_input_chars_per_word"].as_u64().unwrap_or(100) as usize,
+        }),
+        other => bail!(
+            ErrorKind::ModelError,
+            "unsupported tokenizer model {}",
+            other
+        ),
+    }
+}
+
+fn parse_pattern(pattern: &Value) -> Result<Pattern> {
+    if let Some(s) = pattern["String"].as_str() {
+        return Ok(Pattern::String(s.to_string()));
+    }
+    match pattern["Regex"].as_str() {
+        Some(re) => Ok(Pattern::Regex(compile_regex(re)?)),
+        None => bail!(ErrorKind::FormatError, "invalid pattern {}", pattern),
+    }
+}
+
+fn compile_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).map_err(|err| Error {
+        kind: ErrorKind::ModelError,
+        message: format!("unsupported regex: {}", pattern),
+        cause: Some(Arc::new(err)),
+    })
+}
+
+fn parse_normalizers(normalizer: &Value) -> Result<Vec<Normalizer>> {
+    let normalizer = match normalizer["type"].as_str() {
+        None => return Ok(vec![]),
+        Some("Sequence") => {
+            let mut normalizers = vec![];
+            for n in normalizer["normalizers"].as_array().into_iter().flatten() {
+                normalizers.extend(parse_normalizers(n)?);
+            }
+            return Ok(normalizers);
+        }
+        Some("Lowercase") => Normalizer::Lowercase,
+        Some("Prepend") => Normalizer::Prepend(
+            normalizer["prepend"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        ),
+        Some("Replace") => Normalizer::Replace(
+            parse_pattern(&normalizer["pattern"])?,
+            normalizer["content"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        ),
+        Some("Strip") => Normalizer::Strip {
+            left: normalizer["strip_left"].as_bool().unwrap_or(true),
+            right: normalizer["strip_right"].as_bool().unwrap_or(true),
+        },
+        Some("BertNormalizer") => {
+            let lowercase = normalizer["lowercase"].as_bool().unwrap_or(true);
+            // the accents are stripped along with lowercasing unless it's told, which takes the
+            // NFD decomposition like the unicode normalizers below.
+            if normalizer["strip_accents"].as_bool().unwrap_or(lowercase) {
+                bail!(
+                    ErrorKind::NotImplemented,
+                    "the strip_accents of BertNormalizer is not supported yet"
+                );
+            }
+            Normalizer::Bert {
+                clean_text: normalizer["clean_text"].as_bool().unwrap_or(true),
+                chinese_chars: normalizer["handle_chinese_chars"].as_bool().unwrap_or(true),
+                lowercase,
+            }
+        }
+        // the unicode normalizations and the precompiled charsmap of sentencepiece need the
+        // unicode decomposition tables.
+        Some(other @ ("NFC" | "NFD" | "NFKC" | "NFKD" | "StripAccents" | "Precompiled")) => bail!(
+            ErrorKind::NotImplemented,
+            "the normalizer {} is not supported yet",
+            other
+        ),
+        Some(other) => bail!(ErrorKind::ModelError, "unsupported normalizer {}", other),
+    };
+    Ok(vec![normalizer])
+}
+
+fn parse_pre_tokenizers(pre_tokenizer: &Value) -> Result<Vec<PreTokenizerStep>> {
+    let step = match pre_tokenizer["type"].as_str() {
+        None => return Ok(vec![]),
+        Some("Sequence") => {
+            let mut steps = vec![];
+            for p in pre_tokenizer["pretokenizers"]
+                .as_array()
+                .into_iter()
+                .flatten()
+            {
+                steps.extend(parse_pre_tokenizers(p)?);
+            }
+            return Ok(steps);
+        }
+        Some("ByteLevel") => PreTokenizerStep::ByteLevel {
+            add_prefix_space: pre_tokenizer["add_prefix_space"].as_bool().unwrap_or(true),
+            split: match pre_tokenizer["use_regex"].as_bool().unwrap_or(true) {
+                true => Some(PreTokenizer::new(PreTokenizerKind::Gpt2)),
+                false => None,
+            },
+        },
+        Some("Metaspace") => {
+            let prepend = match pre_tokenizer["prepend_scheme"].as_str() {
+                Some(scheme) => scheme != "never",
+                None => pre_tokenizer["add_prefix_space"].as_bool().unwrap_or(true),
+            };
+            PreTokenizerStep::Metaspace {
+                replacement: metaspace_replacement(pre_tokenizer),
+                prepend,
+                split: pre_tokenizer["split"].as_bool().unwrap_or(true),
+            }
+        }
+        Some("Split") => {
+            if pre_tokenizer["invert"].as_bool().unwrap_or(false) {
+                bail!(ErrorKind::ModelError, "unsupported inverted split");
+            }
+            let pattern = match parse_pattern(&pre_tokenizer["pattern"]) {
+                Ok(Pattern::String(s)) => regex::escape(&s),
+                // the look-ahead is emulated by the pre-tokenizer
+                _ => pre_tokenizer["pattern"]["Regex"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            };
+            match pre_tokenizer["behavior"].as_str().unwrap_or("Isolated") {
+                "Isolated" => PreTokenizerStep::Split(PreTokenizer::with_pattern(&pattern)?),
+                "Removed" => PreTokenizerStep::SplitRemoved(compile_regex(&pattern)?),
+                other => bail!(
+                    ErrorKind::ModelError,
+                    "unsupported split behavior {}",
+                    other
+                ),
+            }
+        }
+        Some("Whitespace") => PreTokenizerStep::Matches(compile_regex(r"\w+|[^\w\s]+")?),
+        Some("WhitespaceSplit") => PreTokenizerStep::WhitespaceSplit,
+        Some("Punctuation") => {
+            PreTokenizerStep::Split(PreTokenizer::with_pattern(PUNCTUATION_PATTERN)?)
+        }
+        Some("BertPreTokenizer") => {
+            return Ok(vec![
+                PreTokenizerStep::WhitespaceSplit,
+                PreTokenizerStep::Split(PreTokenizer::with_pattern(PUNCTUATION_PATTERN)?),
+            ])
+        }
+        Some("Digits") => {
+            let pattern = match pre_tokenizer["individual_digits"].as_bool() {
+                Some(true) => r"\p{N}",
+                _ => r"\p{N}+",
+            };
+            PreTokenizerStep::Split(PreTokenizer::with_pattern(pattern)?)
+        }
+        Some(other) => bail!(ErrorKind::ModelError, "unsupported pre-tokenizer {}", other),
+    };
+    Ok(vec![step])
+}
+
+fn parse_decoders(decoder: &Value) -> Result<Vec<Decoder>> {
+    let decoder = match decoder["type"].as_str() {
+        None => return Ok(vec![]),
+        Some("Sequence") => {
+            let mut decoders = vec![];
+            for d in decoder["decoders"].as_array().into_iter().flatten() {
+                decoders.extend(parse_decoders(d)?);
+            }
+            return Ok(decoders);
+        }
+        Some("ByteLevel") => Decoder::ByteLevel,
+        Some("ByteFallback") => Decoder::ByteFallback,
+        Some("Metaspace") => Decoder::Metaspace(metaspace_replacement(decoder)),
+        Some("WordPiece") => {
+            Decoder::WordPiece(decoder["prefix"].as_str().unwrap_or("##").to_string())
+        }
+        Some("Replace") => Decoder::Replace(
+            parse_pattern(&decoder["pattern"])?,
+            decoder["content"].as_str().unwrap_or_default().to_string(),
+        ),
+        // the tokens are decoded one by one, the leading space of the first token is kept like
+        // the llama tokenizer, so there's nothing to fuse or strip.
+        Some("Fuse" | "Strip") => return Ok(vec![]),
+        Some(other) => bail!(ErrorKind::ModelError, "unsupported decoder {}", other),
+    };
+    Ok(vec![decoder])
+}
+
+fn metaspace_replacement(value: &Value) -> char {
+    value["replacement"]
+        .as_str()
+        .and_then(|s| s.chars().next())
+        .unwrap_or('▁')
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn build(json: &Value) -> Result<HfTokenizer> {
+        let (tokens, _) = HfTokenizer::load_vocab(json, 0)?;
+        HfTokenizer::new(json, Arc::new(tokens))
+    }
+
+    fn encode(tk: &HfTokenizer, text: &str) -> Vec<TokenID> {
+        tk.encode_with_offsets(text, true)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    fn decode_all(tk: &HfTokenizer, tokens: &[TokenID]) -> String {
+        let bytes = tokens
+            .iter()
+            .flat_map(|t| tk.decode(*t))
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).to_string()
+    }
+
+    #[test]
+    fn test_hf_bpe_metaspace() -> Result<()> {
+        // like the llama tokenizer.json with the sentencepiece pieces and byte fallback
+        let json = json!({
+            "added_tokens": [{"id": 0, "content": "<unk>", "special": true}],
+            "normalizer": {"type": "Sequence", "normalizers": [
+                {"type": "Prepend", "prepend": "▁"},
+                {"type": "Replace", "pattern": {"String": " "}, "content": "▁"},
+            ]},
+            "decoder": {"type": "Sequence", "decoders": [
+                {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                {"type": "ByteFallback"},
+                {"type": "Fuse"},
+                {"type": "Strip", "content": " ", "start": 1, "stop": 0},
+            ]},
+            "model": {
+                "type": "BPE",
+                "byte_fallback": true,
+                "unk_token": "<unk>",
+                "vocab": {"<unk>": 0, "<0xE4>": 1, "<0xB8>": 2, "<0xAD>": 3, "▁": 4, "a": 5,
+                          "b": 6, "▁a": 7, "ab": 8, "▁ab": 9},
+                "merges": ["▁ a", "a b", "▁a b"],
+            },
+        });
+        let tk = build(&json)?;
+        assert_eq!(encode(&tk, "ab ab"), vec![9, 9]);
+        assert_eq!(encode(&tk, "b中"), vec![4, 6, 1, 2, 3]);
+        assert_eq!(decode_all(&tk, &encode(&tk, "b中 ab")), " b中 ab");
+        // the prepended "▁" covers nothing, and the replaced one covers the space
+        assert_eq!(tk.encode_with_offsets("ab ab", true), vec![
+            (9, 0..2),
+            (9, 2..5)
+        ]);
+        assert_eq!(tk.encode_with_offsets("b中", true), vec![
+            (4, 0..0),
+            (6, 0..1),
+            (1, 1..2),
+            (2, 2..3),
+            (3, 3..4)
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hf_bpe_byte_level() -> Result<()> {
+        let byte_encodes = build_byte_encode_map();
+        let mut vocab = (0..=255u8)
+            .map(|b| (byte_encodes[&b].to_string(), Value::from(b)))
+            .collect::<serde_json::Map<_, _>>();
+        vocab.insert("ĠH".to_string(), 256.into());
+        vocab.insert("ĠHi".to_string(), 257.into());
+        let json = json!({
+            "added_tokens": [{"id": 258, "content": "<|endoftext|>", "special": true}],
+            "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": true, "use_regex": true},
+            "decoder": {"type": "ByteLevel"},
+            "model": {"type": "BPE", "vocab": vocab, "merges": [["Ġ", "H"], ["ĠH", "i"]]},
+        });
+        let (tokens, token_types) = HfTokenizer::load_vocab(&json, 260)?;
+        assert_eq!(tokens.len(), 260);
+        assert_eq!(tokens[259], "[PAD259]");
+        assert_eq!(token_types[258], TokenType::Control);
+        let tk = HfTokenizer::new(&json, Arc::new(tokens))?;
+        assert_eq!(encode(&tk, "Hi!"), vec![257, b'!' as usize]);
+        assert_eq!(tk.encode_with_offsets("Hi!", true), vec![
+            (257, 0..2),
+            (b'!' as usize, 2..3)
+        ]);
+        assert_eq!(decode_all(&tk, &encode(&tk, "Hi, 你好")), " Hi, 你好");
+        Ok(())
+    }
+
+    #[test]
+    fn test_hf_unigram() -> Result<()> {
+        let json = json!({
+            "pre_tokenizer": {"type": "Metaspace", "replacement": "▁", "prepend_scheme": "always"},
+            "decoder": {"type": "Metaspace", "replacement": "▁"},
+            "model": {
+                "type": "Unigram",
+                "unk_id": 0,
+                "vocab": [["<unk>", 0.0], ["▁", -2.0], ["▁hel", -3.0], ["lo", -3.0],
+                          ["▁hello", -5.0], ["h", -4.0], ["e", -4.0], ["l", -4.0], ["o", -4.0],
+                          ["▁w", -4.0], ["orld", -4.0]],
+            },
+        });
+        let tk = build(&json)?;
+        // ▁hello (-5) is preferred over ▁hel lo (-6)
+        assert_eq!(encode(&tk, "hello world"), vec![4, 9, 10]);
+        // the unknown chars are fused into one unknown token
+        assert_eq!(encode(&tk, "hexyz"), vec![1, 5, 6, 0]);
+        assert_eq!(tk.encode_with_offsets("hexyz", true), vec![
+            (1, 0..0),
+            (5, 0..1),
+            (6, 1..2),
+            (0, 2..5)
+        ]);
+        assert_eq!(decode_all(&tk, &encode(&tk, "hello world")), " hello world");
+        Ok(())
+    }
+
+    #[test]
+    fn test_hf_wordpiece() -> Result<()> {
+        let json = json!({
+            "normalizer": {"type": "BertNormalizer", "lowercase": true, "strip_accents": false},
+            "pre_tokenizer": {"type": "BertPreTokenizer"},
+            "decoder": {"type": "WordPiece", "prefix": "##"},
+            "model": {
+                "type": "WordPiece",
+                "unk_token": "[UNK]",
+                "vocab": {"[UNK]": 0, "un": 1, "##aff": 2, "##able": 3, "!": 4, "a": 5,
+                          "中": 6},
+            },
+        });
+        let tk = build(&json)?;
+        assert_eq!(encode(&tk, "Unaffable!  a中"), vec![1, 2, 3, 4, 5, 6]);
+        // the spans are of the text before lowercasing and splitting
+        assert_eq!(tk.encode_with_offsets("Unaffable!  a中", true), vec![
+            (1, 0..2),
+            (2, 2..5),
+            (3, 5..9),
+            (4, 9..10),
+            (5, 12..13),
+            (6, 13..16)
+        ]);
+        assert_eq!(encode(&tk, "unknown"), vec![0]);
+        assert_eq!(decode_all(&tk, &[1, 2, 3, 4]), " unaffable !");
+        Ok(())
+    }
+
+    #[test]
+    fn test_hf_unsupported() {
+        let json = json!({"model": {"type": "WordLevel", "vocab": {"a": 0}}});
+        assert!(build(&json).is_err());
+        let json = json!({
+            "normalizer": {"type": "Unknown"},
+            "model": {"type": "BPE", "vocab": {"a": 0}, "merges": []},
+        });
+        assert!(build(&json).is_err());
+
+        // the normalizers which need the unicode decomposition are rejected instead of skipped
+        let normalizers = [
+            json!({"type": "NFC"}),
+            json!({"type": "Sequence", "normalizers": [{"type": "NFKD"}, {"type": "Lowercase"}]}),
+            json!({"type": "StripAccents"}),
+            json!({"type": "Precompiled", "precompiled_charsmap": ""}),
+            json!({"type": "BertNormalizer", "lowercase": true}),
+            json!({"type": "BertNormalizer", "lowercase": false, "strip_accents": true}),
+        ];
+        for normalizer in normalizers {
+            let json = json!({
+                "normalizer": normalizer,
+                "model": {"type": "BPE", "vocab": {"a": 0}, "merges": []},
+            });
+            let err = build(&json).err().unwrap();
+            assert_eq!(err.kind, ErrorKind::NotImplemented, "{}", json);
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::Arc;
 
+use super::bpe::bpe_merge;
+use super::bpe::Score;
 use super::TokenID;
 
 pub struct LlamaTokenizer {
@@ -8,17 +11,10 @@ pub struct LlamaTokenizer {
     token_ids: HashMap<String, TokenID>,
     token_scores: HashMap<TokenID, f32>,
     token_buf_len: usize,
-    bos_token: TokenID,
-    eos_token: TokenID,
 }
 
 impl LlamaTokenizer {
-    pub fn new(
-        tokens: Arc<Vec<String>>,
-        scores: Vec<f32>,
-        bos_token: TokenID,
-        eos_token: TokenID,
-    ) -> Self {
+    pub fn new(tokens: Arc<Vec<String>>, scores: Vec<f32>) -> Self {
         let token_ids = tokens
             .iter()
             .enumerate()
@@ -30,8 +26,6 @@ impl LlamaTokenizer {
             token_ids,
             token_scores,
             token_buf_len: 128,
-            bos_token,
-            eos_token,
         }
     }
 
@@ -57,19 +51,17 @@ impl LlamaTokenizer {
         }
     }
 
-    // encode the string text (input) into an upper-bound preallocated tokens[] array
-    // bos != 0 means prepend the BOS token (=1), eos != 0 means append the EOS token (=2)
-    pub fn encode(&self, text: &str, bos: bool, eos: bool, add_prefix_space: bool) -> Vec<TokenID> {
+    /// encode the text with the byte span of the text covered by every token, the dummy prefix
+    /// covers an empty span at the start.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        add_prefix_space: bool,
+    ) -> Vec<(TokenID, Range<usize>)> {
         // create a temporary buffer that will store merge candidates of always two consecutive tokens
         // *2 for concat, +1 for null terminator +2 for UTF8 (in case max_token_length is 1)
         let mut token_buf = String::with_capacity(self.token_buf_len * 2 + 1 + 2);
-        let mut tokens: Vec<TokenID> = vec![];
-
-        let text = text.replace(' ', "▁");
-
-        if bos {
-            tokens.push(self.bos_token);
-        }
+        let mut tokens: Vec<(TokenID, Range<usize>)> = vec![];
 
         // add_dummy_prefix is true by default
         // so prepend a dummy prefix token to the input string, but only if text != ""
@@ -77,68 +69,44 @@ impl LlamaTokenizer {
         // energy to read more of the sentencepiece code to figure out what it's doing
         if add_prefix_space && !text.is_empty() {
             if let Some(dummy_prefix) = self.token_ids.get("▁") {
-                tokens.push(*dummy_prefix);
+                tokens.push((*dummy_prefix, 0..0));
             }
         }
 
-        let chars = text.chars();
-        for ch in chars {
+        for (pos, ch) in text.char_indices() {
             token_buf.clear();
-            token_buf.push(ch);
+            token_buf.push(if ch == ' ' { '▁' } else { ch });
             if let Some(tok) = self.token_ids.get(&token_buf) {
                 // we found this codepoint in vocab, add it as a token
-                tokens.push(*tok);
+                tokens.push((*tok, pos..pos + ch.len_utf8()));
             } else {
                 // byte_fallback encoding: just encode each byte as a token
                 // +3 is here because the first 3 vocab elements are <unk>, <s>, </s>
                 // so the individual bytes only start at index 3
-                for byte in token_buf.bytes() {
-                    tokens.push(byte as usize + 3);
+                for (i, byte) in token_buf.bytes().enumerate() {
+                    tokens.push((byte as usize + 3, pos + i..pos + i + 1));
                 }
             }
         }
 
-        // merge the best consecutive pair each iteration, according the scores in vocab_scores
-        loop {
-            let mut best_score = f32::NEG_INFINITY;
-            let mut best_idx: Option<usize> = None;
-            let mut best_token: Option<usize> = None;
-            let mut i = 0;
-
-            while i < (tokens.len() - 1) {
-                token_buf.clear();
-                token_buf.push_str(&self.tokens[tokens[i]]);
-                token_buf.push_str(&self.tokens[tokens[i + 1]]);
-                if let Some(tok) = self.token_ids.get(&token_buf) {
-                    let new_score = *self.token_scores.get(tok).unwrap();
-                    if new_score > best_score {
-                        best_score = new_score;
-                        best_idx = Some(i);
-                        best_token = Some(*tok);
-                    }
-                }
-                i += 1;
-            }
-
-            if let Some(idx) = best_idx {
-                tokens[idx] = best_token.unwrap();
-                tokens.remove(idx + 1);
-            } else {
-                break;
-            }
-        }
-
-        if eos {
-            tokens.push(self.eos_token);
-        }
-
-        tokens
+        // merge the best consecutive pair each time, according the scores in vocab_scores. the
+        // pairs with the -inf score are never merged.
+        bpe_merge(tokens, |left, right| {
+            token_buf.clear();
+            token_buf.push_str(&self.tokens[left]);
+            token_buf.push_str(&self.tokens[right]);
+            let token = *self.token_ids.get(&token_buf)?;
+            let score = *self.token_scores.get(&token).unwrap();
+            (score > f32::NEG_INFINITY).then_some((Score(score), token))
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::TokenType;
     use super::super::Tokenizer;
+    use super::super::Utf8Buf;
     use crate::error::Result;
     use crate::gguf::GGUFFileLoader;
 
@@ -194,7 +162,7 @@ mod tests {
         ];
 
         for tt in tests {
-            let tokens = tk.encode(tt.0, true, true)?;
+            let tokens = tk.encode(tt.0, true, true, false)?;
             let tokens_in_string = tokens
                 .iter()
                 .map(|t| tk.vocab()[*t].clone())
@@ -204,4 +172,84 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_gguf_tokenizer_special_tokens() -> Result<()> {
+        let gf_loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gf_loader.open()?;
+        let metadata = gf.metadata();
+        let tokens = metadata
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let token_scores = metadata.get_f32_array("tokenizer.ggml.scores").unwrap();
+        let token_types = metadata
+            .get_i32_array("tokenizer.ggml.token_type")
+            .unwrap()
+            .iter()
+            .map(|v| TokenType::from_i32(*v))
+            .collect::<Vec<_>>();
+        let tk =
+            Tokenizer::new_llama(tokens, token_scores.to_vec(), 1, 2).with_token_types(token_types);
+        assert_eq!(tk.token_type(2), TokenType::Control);
+        assert_eq!(tk.token_type(3), TokenType::Byte);
+        assert_eq!(tk.token_type(10842), TokenType::Normal);
+
+        // a space is prepended to the text after the special token
+        let tokens = tk.encode("hello</s>world", false, false, true)?;
+        let tokens_in_string = tokens
+            .iter()
+            .map(|t| tk.vocab()[*t].clone())
+            .collect::<Vec<String>>()
+            .join(" - ");
+        assert_eq!(tokens_in_string, "▁hello - </s> - ▁world");
+        assert_eq!(tk.encode("</s>", true, false, true)?, vec![1, 2]);
+
+        // the bos, eos and the dummy prefix cover nothing, the bytes of "🦀" are taken apart
+        let spans = tk
+            .encode_with_offsets("hello</s>world 🦀", true, true, true)?
+            .into_iter()
+            .map(|(_, span)| span)
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![
+            0..0,
+            0..5,
+            5..9,
+            9..14,
+            14..15,
+            15..16,
+            16..17,
+            17..18,
+            18..19,
+            19..19
+        ]);
+
+        // the untrusted text never produces the control tokens
+        let tokens = tk.encode("hello</s>world", false, false, false)?;
+        assert!(!tokens.contains(&2));
+        assert_eq!(tokens[0], tk.encode("hello", false, false, false)?[0]);
+
+        // the pieces are encoded as a whole text, only the special pieces produce control tokens
+        let pieces = [("hello", false), ("</s>", true), ("world</s>", false)];
+        let tokens = tk.encode_pieces(&pieces, false, false)?;
+        assert_eq!(
+            &tokens[..3],
+            tk.encode("hello</s>world", false, false, true)?
+        );
+        assert!(!tokens[3..].contains(&2));
+        let pieces = [("hel", false), ("lo", true)];
+        assert_eq!(
+            tk.encode_pieces(&pieces, true, false)?,
+            tk.encode("hello", true, false, false)?
+        );
+
+        let mut buf = Utf8Buf::new();
+        assert!(tk.is_eog(2));
+        assert_eq!(tk.decode(2, &mut buf)?, "");
+        assert_eq!(tk.decode_special(2, &mut buf)?, "</s>");
+        assert_eq!(tk.decode(10842, &mut buf)?, " Captain");
+        Ok(())
+    }
 }
@@ -32,12 +32,8 @@ impl<'a, T: Tensor> Llama2Chat<'a, T> {
     }
 
     pub fn reply(&mut self) -> Result<Llama2ChatReplyIterator> {
-        let templated_prompt =
-            self.chat_template
-                .apply(&self.prompt, self.system_prompt.as_deref(), true);
-
-        let bos = self.inner.kv_cache_len() == 0;
-        let (pos, _prev_token, token) = self.inner.prefill(&templated_prompt, bos, false)?;
+        let prompt_tokens = self.encode_prompt()?;
+        let (pos, _prev_token, token) = self.inner.prefill_tokens(&prompt_tokens, false)?;
         let iter = self.inner.generate(pos, token, None);
         let chat_iter = Llama2ChatReplyIterator::new(
             Box::new(iter),
@@ -47,11 +43,30 @@ impl<'a, T: Tensor> Llama2Chat<'a, T> {
         Ok(chat_iter)
     }
 
+    /// the chat template is made of the special tokens like <|im_start|>, but the special tokens
+    /// in the prompts are not parsed, the prompts are untrusted.
+    fn encode_prompt(&self) -> Result<Vec<usize>> {
+        let pieces = self
+            .chat_template
+            .apply(&self.prompt, self.system_prompt.as_deref(), true);
+        let pieces = pieces
+            .iter()
+            .map(|(text, special)| (text.as_str(), *special))
+            .collect::<Vec<_>>();
+        let tokenizer = self.inner.tokenizer();
+        let bos = self.inner.kv_cache_len() == 0;
+        tokenizer.encode_pieces(
+            &pieces,
+            bos && tokenizer.add_bos_token(),
+            bos && tokenizer.add_eos_token(),
+        )
+    }
+
     /// the reply might ended with <eos>, but not <end_of_turn>, so we need to append the <end_of_turn>
     pub fn finish(&mut self) -> Result<()> {
         if !self.stats.has_stop_mark {
             self.inner
-                .prefill(self.chat_template.stop_mark(), false, false)?;
+                .prefill(self.chat_template.stop_mark(), false, false, true)?;
         }
 
         Ok(())
@@ -226,71 +241,72 @@ impl ChatTemplate {
         }
     }
 
+    /// the pieces of the templated prompt, each with whether it's the template scaffolding whose
+    /// special tokens should be parsed. the pieces are tokenized as a whole text.
     fn apply(
         &self,
         prompt: &str,
         system_prompt: Option<&str>,
         append_assistant_prefix: bool,
-    ) -> String {
+    ) -> Vec<(String, bool)> {
+        let mut pieces = vec![];
+        let mut push = |text: &str, special: bool| {
+            if !text.is_empty() {
+                pieces.push((text.to_string(), special));
+            }
+        };
         match *self {
             ChatTemplate::Llama2 => {
-                let system_prompt = system_prompt
-                    .map(|s| format!("<<SYS>>{}<</SYS>>", s))
-                    .unwrap_or("".to_string());
-                let assistant_prefix = if append_assistant_prefix {
-                    "[[INST]]"
-                } else {
-                    ""
-                };
-                format!(
-                    "[INST] {} {} [/INST]{}",
-                    system_prompt, prompt, assistant_prefix
-                )
+                push("[INST] ", true);
+                if let Some(s) = system_prompt {
+                    push("<<SYS>>", true);
+                    push(s, false);
+                    push("<</SYS>>", true);
+                }
+                push(" ", true);
+                push(prompt, false);
+                push(" [/INST]", true);
+                if append_assistant_prefix {
+                    push("[[INST]]", true);
+                }
             }
             ChatTemplate::Llama3 => {
-                let system_prompt = system_prompt
-                    .map(|s| {
-                        format!(
-                            "<|start_header_id|>system<|end_header_id|>\n\n{}<|eot_id|>",
-                            s
-                        )
-                    })
-                    .unwrap_or("".to_string());
-                let assistant_prefix = if append_assistant_prefix {
-                    "<|start_header_id|>assistant<|end_header_id|>\n\n"
-                } else {
-                    ""
-                };
-                format!(
-                    "{}<|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>{}",
-                    system_prompt, prompt, assistant_prefix
-                )
+                if let Some(s) = system_prompt {
+                    push("<|start_header_id|>system<|end_header_id|>\n\n", true);
+                    push(s, false);
+                    push("<|eot_id|>", true);
+                }
+                push("<|start_header_id|>user<|end_header_id|>\n\n", true);
+                push(prompt, false);
+                push("<|eot_id|>", true);
+                if append_assistant_prefix {
+                    push("<|start_header_id|>assistant<|end_header_id|>\n\n", true);
+                }
             }
             ChatTemplate::Gemma => {
                 let system_prompt = system_prompt.unwrap_or("");
-                let assistant_prefix = match append_assistant_prefix {
-                    true => "<start_of_turn>model\n",
-                    false => "",
-                };
-                format!(
-                    "<start_of_turn>user\n{} {}<end_of_turn>{}",
-                    system_prompt, prompt, assistant_prefix
-                )
+                push("<start_of_turn>user\n", true);
+                push(&format!("{} {}", system_prompt, prompt), false);
+                push("<end_of_turn>", true);
+                if append_assistant_prefix {
+                    push("<start_of_turn>model\n", true);
+                }
             }
             ChatTemplate::ChatML => {
-                let system_prompt = system_prompt
-                    .map(|s| format!("<|im_start|>system\n{}<|im_end|>", s))
-                    .unwrap_or("".to_string());
-                let assistant_prefix = match append_assistant_prefix {
-                    true => "<|im_start|>assistant\n",
-                    false => "",
-                };
-                format!(
-                    "{}<|im_start|>user\n{}<|im_end|>{}",
-                    system_prompt, prompt, assistant_prefix
-                )
+                if let Some(s) = system_prompt {
+                    push("<|im_start|>system\n", true);
+                    push(s, false);
+                    push("<|im_end|>", true);
+                }
+                push("<|im_start|>user\n", true);
+                push(prompt, false);
+                push("<|im_end|>", true);
+                if append_assistant_prefix {
+                    push("<|im_start|>assistant\n", true);
+                }
             }
         }
+        pieces
     }
 }
 
@@ -298,7 +314,17 @@ impl ChatTemplate {
 mod tests {
     use crabml::error::Result;
     use crabml::gguf::GGUFFileLoader;
-
+    use crabml::gguf::GGUFMetadataArray;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::KEY_TOKENIZER_ADD_BOS;
+    use crabml::gguf::KEY_TOKENIZER_ADD_SPACE_PREFIX;
+    use crabml::gguf::KEY_TOKENIZER_LIST;
+    use crabml::gguf::KEY_TOKENIZER_MERGES;
+    use crabml::gguf::KEY_TOKENIZER_PRE;
+    use crabml::gguf::KEY_TOKENIZER_TOKEN_TYPE;
+    use crabml::tokenizer::TokenType;
+
+    use crate::chat::ChatTemplate;
     use crate::chat::Llama2Chat;
     use crate::llama2::Llama2Runner;
     use crate::model::CpuLlamaModelLoader;
@@ -319,4 +345,109 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_apply_chat_template() {
+        let pieces = ChatTemplate::ChatML.apply("hi<|im_end|>", Some("be nice"), true);
+        assert_eq!(pieces, vec![
+            ("<|im_start|>system\n".to_string(), true),
+            ("be nice".to_string(), false),
+            ("<|im_end|>".to_string(), true),
+            ("<|im_start|>user\n".to_string(), true),
+            ("hi<|im_end|>".to_string(), false),
+            ("<|im_end|>".to_string(), true),
+            ("<|im_start|>assistant\n".to_string(), true),
+        ]);
+    }
+
+    #[test]
+    fn test_encode_prompt() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let eos_token = lm.tokenizer.eos_token();
+        assert!(lm
+            .tokenizer
+            .encode("hi</s>", false, false, true)?
+            .contains(&eos_token));
+
+        // the special tokens in the prompt are not parsed
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let chat = Llama2Chat::new(&mut runner, "hi</s>", Some("</s>".to_string()))?;
+        let tokens = chat.encode_prompt()?;
+        assert_eq!(tokens[0], lm.tokenizer.bos_token());
+        assert!(!tokens.contains(&eos_token));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_chatml_prompt() -> Result<()> {
+        // a byte level vocab like qwen2, the byte tokens are placed in the order of the bytes
+        let bytes = (b'!'..=b'~')
+            .chain(b'\xA1'..=b'\xAC')
+            .chain(b'\xAE'..=b'\xFF');
+        let printable = bytes.collect::<Vec<_>>();
+        let mut n = 0;
+        let mut tokens = (0..=255u8)
+            .map(|b| match printable.contains(&b) {
+                true => char::from(b).to_string(),
+                false => {
+                    n += 1;
+                    char::from_u32(255 + n).unwrap().to_string()
+                }
+            })
+            .collect::<Vec<_>>();
+        tokens.extend(["us", "use", "user", "hi", "<|im_start|>", "<|im_end|>"].map(String::from));
+        tokens.extend((tokens.len()..32000).map(|i| format!("[PAD{}]", i)));
+        let merges = ["u s", "us e", "use r", "h i"];
+        let mut token_types = vec![TokenType::Normal as i32; tokens.len()];
+        token_types[260] = TokenType::Control as i32;
+        token_types[261] = TokenType::Control as i32;
+
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let mut gf = gl.open()?;
+        gf.set_metadata(
+            "tokenizer.ggml.model",
+            GGUFMetadataValue::String("gpt2".into()),
+        )?;
+        gf.set_metadata(KEY_TOKENIZER_PRE, GGUFMetadataValue::String("qwen2".into()))?;
+        gf.set_metadata(
+            KEY_TOKENIZER_LIST,
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(
+                tokens.iter().map(|t| t.as_str()).collect(),
+            )),
+        )?;
+        gf.set_metadata(
+            KEY_TOKENIZER_MERGES,
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(merges.to_vec())),
+        )?;
+        gf.set_metadata(
+            KEY_TOKENIZER_TOKEN_TYPE,
+            GGUFMetadataValue::Array(GGUFMetadataArray::I32Array(token_types.into())),
+        )?;
+        gf.set_metadata(KEY_TOKENIZER_ADD_BOS, GGUFMetadataValue::Bool(0))?;
+        gf.set_metadata(
+            "tokenizer.chat_template",
+            GGUFMetadataValue::String("<|im_start|>{{ message.role }}".into()),
+        )?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+
+        // llama.cpp parses the special tokens in the whole templated prompt, and never prepends
+        // a space to the BPE text after them:
+        // <|im_start|> user \n hi <|im_end|> <|im_start|> a s s i s t a n t \n
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let chat = Llama2Chat::new(&mut runner, "hi", None)?;
+        assert_eq!(chat.chat_template, ChatTemplate::ChatML);
+        let mut expected = vec![260, 258, 10, 259, 261, 260];
+        expected.extend(b"assistant\n".map(|b| b as usize));
+        assert_eq!(chat.encode_prompt()?, expected);
+
+        // the prefix space is only for the start of the text, not the text after <|im_start|>
+        gf.set_metadata(KEY_TOKENIZER_ADD_SPACE_PREFIX, GGUFMetadataValue::Bool(1))?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let chat = Llama2Chat::new(&mut runner, "hi", None)?;
+        assert_eq!(chat.encode_prompt()?, expected);
+        Ok(())
+    }
 }
@@ -0,0 +1,773 @@
+use std::sync::Arc;
+
+use crabml::bail;
+use crabml::cpu::CpuTensor;
+use crabml::cpu::CpuTensorDeviceRef;
+use crabml::error;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::safetensors::SafeTensorsDType;
+use crabml::safetensors::SafeTensorsFile;
+use crabml::safetensors::SafeTensorsTensorInfo;
+use crabml::tokenizer::PreTokenizerKind;
+use crabml::tokenizer::TokenType;
+use crabml::tokenizer::Tokenizer;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::model::LlamaConfig;
+use crate::model::LlamaWeights;
+use crate::model::ModelArchitecture;
+
+/// the `config.json` in a Hugging Face model directory, only the fields used by the supported
+/// architectures are parsed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HfConfig {
+    #[serde(default)]
+    pub architectures: Vec<String>,
+    #[serde(default)]
+    pub model_type: String,
+    #[serde(default, rename = "_name_or_path")]
+    pub name_or_path: String,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: Option<usize>,
+    pub vocab_size: usize,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_rms_norm_eps")]
+    pub rms_norm_eps: f32,
+    pub bos_token_id: Option<HfTokenIds>,
+    pub eos_token_id: Option<HfTokenIds>,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+}
+
+/// some models like llama3 have multiple eos tokens in a list
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum HfTokenIds {
+    One(usize),
+    Many(Vec<usize>),
+}
+
+impl HfTokenIds {
+    pub fn first(&self) -> Option<usize> {
+        match self {
+            HfTokenIds::One(id) => Some(*id),
+            HfTokenIds::Many(ids) => ids.first().copied(),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<usize> {
+        match self {
+            HfTokenIds::One(id) => vec![*id],
+            HfTokenIds::Many(ids) => ids.clone(),
+        }
+    }
+}
+
+fn default_max_position_embeddings() -> usize {
+    2048
+}
+
+fn default_rms_norm_eps() -> f32 {
+    1e-6
+}
+
+impl HfConfig {
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: "failed to parse the config.json".to_string(),
+            cause: Some(Arc::new(err)),
+        })
+    }
+
+    pub fn architecture(&self) -> Result<ModelArchitecture> {
+        let arch = self.architectures.first().map(|s| s.as_str());
+        match (self.model_type.as_str(), arch) {
+            ("llama" | "mistral", _) => Ok(ModelArchitecture::Llama),
+            (_, Some("LlamaForCausalLM" | "MistralForCausalLM")) => Ok(ModelArchitecture::Llama),
+            ("qwen2", _) | (_, Some("Qwen2ForCausalLM")) => Ok(ModelArchitecture::Qwen2),
+            (model_type, arch) => bail!(
+                ErrorKind::ModelError,
+                "unsupported architecture {} {:?}",
+                model_type,
+                arch
+            ),
+        }
+    }
+
+    pub fn bos_token(&self) -> usize {
+        self.bos_token_id
+            .as_ref()
+            .and_then(|t| t.first())
+            .unwrap_or(1)
+    }
+
+    pub fn eos_token(&self) -> usize {
+        self.eos_token_id
+            .as_ref()
+            .and_then(|t| t.first())
+            .unwrap_or(2)
+    }
+
+    /// all the eos tokens end the generation, the first one is taken as the eos token.
+    pub fn eos_tokens(&self) -> Vec<usize> {
+        self.eos_token_id
+            .as_ref()
+            .map(|t| t.to_vec())
+            .unwrap_or_default()
+    }
+
+    pub fn to_llama_config(&self) -> Result<LlamaConfig> {
+        Ok(LlamaConfig {
+            architecture: self.architecture()?,
+            model_name: self.name_or_path.clone(),
+            chat_template: "".to_string(),
+            embedding_dim: self.hidden_size,
+            hidden_dim: self.intermediate_size,
+            n_layers: self.num_hidden_layers,
+            n_heads: self.num_attention_heads,
+            n_kv_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
+            vocab_size: self.vocab_size,
+            seq_len: self.max_position_embeddings,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_dim: None,
+            n_experts: 0,
+            n_experts_used: 0,
+        })
+    }
+}
+
+pub(crate) fn load_hf_weights<'a>(
+    st: &'a SafeTensorsFile<'a>,
+    conf: &LlamaConfig,
+    device: CpuTensorDeviceRef<'a>,
+) -> Result<LlamaWeights<CpuTensor<'a>>> {
+    let load = |name: &str| load_hf_tensor(st, name, None, device.clone());
+    let load_f32 =
+        |name: &str| load_hf_tensor(st, name, None, device.clone())?.dequantize(GGMLType::F32);
+    // the llama forward pass rotates the adjacent pairs in a head, while the HF checkpoints of
+    // llama rotate the two halves of a head, the rows of wq and wk are permuted to match it like
+    // what convert_hf_to_gguf.py does. qwen2 rotates the halves in its forward pass as HF does.
+    let (q_heads, k_heads) = match conf.architecture {
+        ModelArchitecture::Llama => (Some(conf.n_heads), Some(conf.n_kv_heads)),
+        _ => (None, None),
+    };
+
+    let mut weights = LlamaWeights {
+        token_embed: load("model.embed_tokens.weight")?,
+        position_embed: None,
+        rms_att_weight: vec![],
+        rms_ffn_weight: vec![],
+        rms_att_bias: vec![],
+        rms_ffn_bias: vec![],
+        wq: vec![],
+        wk: vec![],
+        wv: vec![],
+        wo: vec![],
+        wqkv: vec![],
+        bq: vec![],
+        bk: vec![],
+        bv: vec![],
+        bo: vec![],
+        bqkv: vec![],
+        ffn_gate_weight: vec![],
+        ffn_down_weight: vec![],
+        ffn_up_weight: vec![],
+        ffn_down_bias: vec![],
+        ffn_up_bias: vec![],
+        ffn_gate_inp: vec![],
+        ffn_gate_exps: vec![],
+        ffn_down_exps: vec![],
+        ffn_up_exps: vec![],
+        rms_final_weight: load_f32("model.norm.weight")?,
+        rms_final_bias: None,
+        output_weight: None,
+        output_bias: None,
+    };
+    for layer in 0..conf.n_layers {
+        let prefix = format!("model.layers.{}", layer);
+        weights.wq.push(load_hf_tensor(
+            st,
+            &format!("{}.self_attn.q_proj.weight", prefix),
+            q_heads,
+            device.clone(),
+        )?);
+        weights.wk.push(load_hf_tensor(
+            st,
+            &format!("{}.self_attn.k_proj.weight", prefix),
+            k_heads,
+            device.clone(),
+        )?);
+        weights
+            .wv
+            .push(load(&format!("{}.self_attn.v_proj.weight", prefix))?);
+        weights
+            .wo
+            .push(load(&format!("{}.self_attn.o_proj.weight", prefix))?);
+        weights
+            .ffn_gate_weight
+            .push(load(&format!("{}.mlp.gate_proj.weight", prefix))?);
+        weights
+            .ffn_down_weight
+            .push(load(&format!("{}.mlp.down_proj.weight", prefix))?);
+        weights
+            .ffn_up_weight
+            .push(load(&format!("{}.mlp.up_proj.weight", prefix))?);
+        weights
+            .rms_att_weight
+            .push(load_f32(&format!("{}.input_layernorm.weight", prefix))?);
+        weights.rms_ffn_weight.push(load_f32(&format!(
+            "{}.post_attention_layernorm.weight",
+            prefix
+        ))?);
+        if conf.architecture == ModelArchitecture::Qwen2 {
+            weights
+                .bq
+                .push(load_f32(&format!("{}.self_attn.q_proj.bias", prefix))?);
+            weights
+                .bk
+                .push(load_f32(&format!("{}.self_attn.k_proj.bias", prefix))?);
+            weights
+                .bv
+                .push(load_f32(&format!("{}.self_attn.v_proj.bias", prefix))?);
+        }
+    }
+
+    // lm_head.weight is missing when the word embeddings are tied
+    weights.output_weight = match st.get_tensor_info("lm_head.weight") {
+        Some(info) => Some(hf_tensor(info, device.clone())?),
+        None => None,
+    };
+    Ok(weights)
+}
+
+fn load_hf_tensor<'a>(
+    st: &'a SafeTensorsFile<'a>,
+    name: &str,
+    permute_heads: Option<usize>,
+    device: CpuTensorDeviceRef<'a>,
+) -> Result<CpuTensor<'a>> {
+    let info = match st.get_tensor_info(name) {
+        Some(info) => info,
+        None => {
+            return Err(error!(
+                ErrorKind::TensorNotFound,
+                "failed to find tensor {}", name
+            ))
+        }
+    };
+    match permute_heads {
+        None => hf_tensor(info, device),
+        Some(n_heads) => {
+            let buf = permute_head_rows(&info.to_f32_vec()?, info.shape(), n_heads)?;
+            CpuTensor::new(buf, info.shape(), device)
+        }
+    }
+}
+
+/// the F32, F16 and BF16 tensors are referenced from the file without copying, the ones not
+/// aligned to their dtype are converted into owned F32 tensors.
+fn hf_tensor<'a>(
+    info: &SafeTensorsTensorInfo<'a>,
+    device: CpuTensorDeviceRef<'a>,
+) -> Result<CpuTensor<'a>> {
+    let data = info.data();
+    let aligned = |align: usize| data.as_ptr() as usize % align == 0;
+    match info.dtype() {
+        SafeTensorsDType::F32 if aligned(4) => {
+            CpuTensor::from_bytes(data, GGMLType::F32, info.shape(), device)
+        }
+        SafeTensorsDType::F16 if aligned(2) => {
+            CpuTensor::from_bytes(data, GGMLType::F16, info.shape(), device)
+        }
+        SafeTensorsDType::BF16 if aligned(2) => {
+            CpuTensor::from_bytes(data, GGMLType::BF16, info.shape(), device)
+        }
+        _ => CpuTensor::new(info.to_f32_vec()?, info.shape(), device),
+    }
+}
+
+/// permute the rows of each head from the halves order `[x0, x2, .., x1, x3, ..]` into the
+/// interleaved order `[x0, x1, x2, x3, ..]`.
+fn permute_head_rows(buf: &[f32], shape: &[usize], n_heads: usize) -> Result<Vec<f32>> {
+    let (n_rows, row_size) = match shape {
+        [n_rows, row_size] => (*n_rows, *row_size),
+        _ => bail!(ErrorKind::TensorError, "can not permute tensor {:?}", shape),
+    };
+    if n_heads == 0 || n_rows % (n_heads * 2) != 0 {
+        bail!(
+            ErrorKind::TensorError,
+            "can not permute tensor {:?} into {} heads",
+            shape,
+            n_heads
+        );
+    }
+
+    let head_dim = n_rows / n_heads;
+    let half = head_dim / 2;
+    let mut out = vec![0.0; buf.len()];
+    for h in 0..n_heads {
+        for i in 0..half {
+            for j in 0..2 {
+                let dst = h * head_dim + i * 2 + j;
+                let src = h * head_dim + j * half + i;
+                out[dst * row_size..(dst + 1) * row_size]
+                    .copy_from_slice(&buf[src * row_size..(src + 1) * row_size]);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// build the tokenizer from the BPE model in `tokenizer.json`. the sentencepiece-like models with
+/// `byte_fallback` (like llama) are loaded into the llama tokenizer, with the merge ranks as the
+/// scores, the other ones are loaded into the byte level GPT2 tokenizer. the non-BPE models are
+/// loaded by `Tokenizer::new_hf`.
+pub(crate) fn load_hf_tokenizer(
+    tokenizer_json: &str,
+    vocab_size: usize,
+    bos_token: usize,
+    eos_token: usize,
+) -> Result<Tokenizer> {
+    let json: Value = serde_json::from_str(tokenizer_json).map_err(|err| Error {
+        kind: ErrorKind::FormatError,
+        message: "failed to parse the tokenizer.json".to_string(),
+        cause: Some(Arc::new(err)),
+    })?;
+    let model = &json["model"];
+    // the other models like Unigram and WordPiece are run by the tokenizer.json backend
+    if !matches!(model["type"].as_str(), Some("BPE") | None) {
+        return Tokenizer::new_hf(tokenizer_json, vocab_size, bos_token, eos_token);
+    }
+
+    let mut vocab = vec![];
+    let entries = model["vocab"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(k, v)| (k.as_str(), v.as_u64()));
+    let added_tokens = json["added_tokens"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|t| (t["content"].as_str().unwrap_or(""), t["id"].as_u64()));
+    // the ids are dense, they never exceed the number of the tokens or the padded vocab size
+    let max_id = vocab_size.max(entries.clone().count() + added_tokens.clone().count());
+    for (token, id) in entries.chain(added_tokens) {
+        let id = match id {
+            Some(id) if (id as usize) < max_id => id as usize,
+            _ => bail!(ErrorKind::FormatError, "invalid id of token {}", token),
+        };
+        if id >= vocab.len() {
+            vocab.resize(id + 1, None);
+        }
+        vocab[id] = Some(token.to_string());
+    }
+    if vocab.is_empty() {
+        bail!(
+            ErrorKind::FormatError,
+            "missing vocab in the tokenizer.json"
+        );
+    }
+    // the embeddings are often padded to a larger size than the vocab
+    vocab.resize(vocab.len().max(vocab_size), None);
+    let vocab = vocab
+        .into_iter()
+        .enumerate()
+        .map(|(i, t)| t.unwrap_or_else(|| format!("[PAD{}]", i)))
+        .collect::<Vec<_>>();
+
+    // the added tokens are parsed from the text, only the non-special ones are allowed in the
+    // untrusted text like the user-defined tokens in GGUF
+    let mut token_types = vec![TokenType::Normal; vocab.len()];
+    for token in json["added_tokens"].as_array().into_iter().flatten() {
+        if let Some(id) = token["id"].as_u64() {
+            let typ = match token_types.get_mut(id as usize) {
+                Some(typ) => typ,
+                None => bail!(ErrorKind::FormatError, "invalid id of added token {}", id),
+            };
+            *typ = match token["special"].as_bool().unwrap_or(false) {
+                true => TokenType::Control,
+                false => TokenType::UserDefined,
+            };
+        }
+    }
+
+    // the merges are in either `"a b"` or `["a", "b"]`
+    let merges = model["merges"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|m| {
+            let pair = match m {
+                Value::String(s) => return Ok(s.clone()),
+                Value::Array(parts) if parts.len() == 2 => parts
+                    .first()
+                    .and_then(|a| a.as_str())
+                    .zip(parts.get(1).and_then(|b| b.as_str())),
+                _ => None,
+            };
+            match pair {
+                Some((a, b)) => Ok(format!("{} {}", a, b)),
+                None => Err(error!(
+                    ErrorKind::FormatError,
+                    "invalid merge in the tokenizer.json: {}", m
+                )),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if !model["byte_fallback"].as_bool().unwrap_or(false) {
+        let (pre_tokenizer, add_prefix_space) = hf_pre_tokenizer(&json["pre_tokenizer"]);
+        return Ok(Tokenizer::new_gpt2(
+            vocab,
+            merges,
+            pre_tokenizer,
+            add_prefix_space,
+            bos_token,
+            eos_token,
+        )
+        .with_token_types(token_types));
+    }
+
+    // the pieces not produced by any merge are never merged, unless there're no merges at all,
+    // then the pieces with smaller ids are preferred like sentencepiece does.
+    let mut scores = if merges.is_empty() {
+        (0..vocab.len()).map(|i| -(i as f32)).collect::<Vec<_>>()
+    } else {
+        vec![f32::NEG_INFINITY; vocab.len()]
+    };
+    let token_ids = vocab
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.as_str(), i))
+        .collect::<std::collections::HashMap<_, _>>();
+    for (rank, merge) in merges.iter().enumerate() {
+        let merged = merge.replacen(' ', "", 1);
+        if let Some(id) = token_ids.get(merged.as_str()) {
+            if scores[*id] == f32::NEG_INFINITY {
+                scores[*id] = -(rank as f32);
+            }
+        }
+    }
+    Ok(Tokenizer::new_llama(vocab, scores, bos_token, eos_token).with_token_types(token_types))
+}
+
+/// the pre-tokenizer is told by the regex of the `Split` step, and the `ByteLevel` step splits
+/// the text like GPT-2 by default. a space is prepended unless `add_prefix_space` is false.
+fn hf_pre_tokenizer(pre_tokenizer: &Value) -> (PreTokenizerKind, bool) {
+    let steps = match pre_tokenizer["type"].as_str() {
+        Some("Sequence") => pre_tokenizer["pretokenizers"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default(),
+        _ => vec![pre_tokenizer.clone()],
+    };
+    let mut kind = PreTokenizerKind::Gpt2;
+    let mut add_prefix_space = true;
+    for step in steps.iter() {
+        match step["type"].as_str() {
+            Some("Split") => {
+                if let Some(k) = step["pattern"]["Regex"]
+                    .as_str()
+                    .and_then(PreTokenizerKind::from_pattern)
+                {
+                    kind = k;
+                }
+            }
+            Some("ByteLevel") => {
+                add_prefix_space = step["add_prefix_space"].as_bool().unwrap_or(true);
+            }
+            _ => {}
+        }
+    }
+    (kind, add_prefix_space)
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGUFFile;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::tensor::Tensor;
+    use crabml::tokenizer::TokenizerKind;
+    use serde_json::json;
+
+    use super::*;
+    use crate::llama2::Llama2Runner;
+    use crate::model::CpuLlamaModelLoader;
+
+    fn build_safetensors(tensors: &[(String, Vec<usize>, Vec<f32>)]) -> Vec<u8> {
+        let mut header = serde_json::Map::new();
+        let mut data = vec![];
+        for (name, shape, buf) in tensors {
+            let begin = data.len();
+            data.extend(buf.iter().flat_map(|v| v.to_le_bytes()));
+            header.insert(
+                name.clone(),
+                json!({"dtype": "F32", "shape": shape, "data_offsets": [begin, data.len()]}),
+            );
+        }
+        let mut header = serde_json::to_vec(&Value::Object(header)).unwrap();
+        while header.len() % 8 != 0 {
+            header.push(b' ');
+        }
+        let mut buf = (header.len() as u64).to_le_bytes().to_vec();
+        buf.extend(header);
+        buf.extend(data);
+        buf
+    }
+
+    #[test]
+    fn test_permute_head_rows() -> Result<()> {
+        // 2 heads, head_dim 4, row_size 1
+        let buf = vec![0.0, 2.0, 1.0, 3.0, 4.0, 6.0, 5.0, 7.0];
+        let out = permute_head_rows(&buf, &[8, 1], 2)?;
+        assert_eq!(out, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+        assert!(permute_head_rows(&buf, &[8, 1], 3).is_err());
+        Ok(())
+    }
+
+    /// convert the GGUF llama model back into a HF checkpoint, wq and wk are permuted back into
+    /// the halves order. the qwen2 checkpoint has zero biases on q, k and v, and it's expected
+    /// to generate the same text as llama.
+    fn build_hf_checkpoint(gf: &GGUFFile, conf: &LlamaConfig, with_bias: bool) -> Vec<u8> {
+        let unpermute = |buf: Vec<f32>, n_heads: usize, row_size: usize| {
+            let head_dim = buf.len() / row_size / n_heads;
+            let mut out = vec![0.0; buf.len()];
+            for h in 0..n_heads {
+                for i in 0..head_dim / 2 {
+                    for j in 0..2 {
+                        let src = h * head_dim + i * 2 + j;
+                        let dst = h * head_dim + j * head_dim / 2 + i;
+                        out[dst * row_size..(dst + 1) * row_size]
+                            .copy_from_slice(&buf[src * row_size..(src + 1) * row_size]);
+                    }
+                }
+            }
+            out
+        };
+        let mut tensors = vec![];
+        for info in gf.tensor_infos() {
+            let shape = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+            let buf = info
+                .data()
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect::<Vec<_>>();
+            let (name, buf) = match info.name().split('.').collect::<Vec<_>>()[..] {
+                ["token_embd", "weight"] => ("model.embed_tokens.weight".to_string(), buf),
+                ["output_norm", "weight"] => ("model.norm.weight".to_string(), buf),
+                ["output", "weight"] => ("lm_head.weight".to_string(), buf),
+                ["blk", layer, name, "weight"] => {
+                    let (hf_name, buf) = match name {
+                        "attn_q" => ("self_attn.q_proj", unpermute(buf, conf.n_heads, shape[1])),
+                        "attn_k" => (
+                            "self_attn.k_proj",
+                            unpermute(buf, conf.n_kv_heads, shape[1]),
+                        ),
+                        "attn_v" => ("self_attn.v_proj", buf),
+                        "attn_output" => ("self_attn.o_proj", buf),
+                        "ffn_gate" => ("mlp.gate_proj", buf),
+                        "ffn_up" => ("mlp.up_proj", buf),
+                        "ffn_down" => ("mlp.down_proj", buf),
+                        "attn_norm" => ("input_layernorm", buf),
+                        "ffn_norm" => ("post_attention_layernorm", buf),
+                        _ => panic!("unexpected tensor {}", info.name()),
+                    };
+                    if with_bias && hf_name.ends_with("_proj") && hf_name != "self_attn.o_proj" {
+                        tensors.push((
+                            format!("model.layers.{}.{}.bias", layer, hf_name),
+                            vec![shape[0]],
+                            vec![0.0; shape[0]],
+                        ));
+                    }
+                    (format!("model.layers.{}.{}.weight", layer, hf_name), buf)
+                }
+                _ => panic!("unexpected tensor {}", info.name()),
+            };
+            tensors.push((name, shape, buf));
+        }
+        build_safetensors(&tensors)
+    }
+
+    #[test]
+    fn test_load_safetensors() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let conf = CpuLlamaModelLoader::new().load(&gf)?.conf;
+
+        let tokens = gf
+            .metadata()
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap();
+        let vocab = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.to_string(), json!(i)))
+            .collect::<serde_json::Map<_, _>>();
+        let tokenizer_json = json!({
+            "model": {"type": "BPE", "vocab": vocab, "merges": [], "byte_fallback": true},
+        })
+        .to_string();
+
+        let tests = vec![
+            ("LlamaForCausalLM", ModelArchitecture::Llama),
+            ("Qwen2ForCausalLM", ModelArchitecture::Qwen2),
+        ];
+        for (hf_arch, arch) in tests {
+            let st_buf = build_hf_checkpoint(&gf, &conf, arch == ModelArchitecture::Qwen2);
+            let st = SafeTensorsFile::from_bytes(&st_buf)?;
+            let config_json = json!({
+                "architectures": [hf_arch],
+                "hidden_size": conf.embedding_dim,
+                "intermediate_size": conf.hidden_dim,
+                "num_hidden_layers": conf.n_layers,
+                "num_attention_heads": conf.n_heads,
+                "num_key_value_heads": conf.n_kv_heads,
+                "vocab_size": conf.vocab_size,
+                "max_position_embeddings": conf.seq_len,
+                "rms_norm_eps": conf.rms_norm_eps,
+                "bos_token_id": 1,
+                "eos_token_id": 2,
+            })
+            .to_string();
+
+            let lm =
+                CpuLlamaModelLoader::new().load_safetensors(&st, &config_json, &tokenizer_json)?;
+            assert_eq!(lm.conf.architecture, arch);
+            assert_eq!(lm.conf.n_layers, 6);
+            assert_eq!(lm.weights.wq[0].dtype(), GGMLType::F32);
+            assert!(lm.weights.output_weight.is_some());
+
+            let mut runner = Llama2Runner::new(&lm, 200, false)?;
+            let output = runner.prefill_and_generate("Lily is a cat", 31)?;
+            let s = output.collect::<Result<Vec<String>>>()?.join("");
+            assert_eq!(
+                s,
+                " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_hf_config() -> Result<()> {
+        let conf = HfConfig::from_json(
+            r#"{
+                "architectures": ["Qwen2ForCausalLM"],
+                "model_type": "qwen2",
+                "hidden_size": 896,
+                "intermediate_size": 4864,
+                "num_hidden_layers": 24,
+                "num_attention_heads": 14,
+                "num_key_value_heads": 2,
+                "vocab_size": 151936,
+                "max_position_embeddings": 32768,
+                "rms_norm_eps": 1e-06,
+                "bos_token_id": 151643,
+                "eos_token_id": [151645, 151643],
+                "tie_word_embeddings": true
+            }"#,
+        )?;
+        assert_eq!(conf.bos_token(), 151643);
+        assert_eq!(conf.eos_token(), 151645);
+        assert_eq!(conf.eos_tokens(), vec![151645, 151643]);
+        let conf = conf.to_llama_config()?;
+        assert_eq!(conf.architecture, ModelArchitecture::Qwen2);
+        assert_eq!(conf.kv_dim(), 128);
+        assert_eq!(conf.head_size(), 64);
+
+        assert!(HfConfig::from_json(r#"{"model_type": "gpt2"}"#).is_err());
+        let conf = HfConfig::from_json(
+            r#"{"model_type": "gpt2", "hidden_size": 1, "intermediate_size": 1,
+                "num_hidden_layers": 1, "num_attention_heads": 1, "vocab_size": 1}"#,
+        )?;
+        assert!(conf.to_llama_config().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_hf_tokenizer() -> Result<()> {
+        let tokenizer_json = json!({
+            "added_tokens": [
+                {"id": 5, "content": "<|endoftext|>", "special": true},
+                {"id": 6, "content": "<x>", "special": false},
+            ],
+            "model": {
+                "type": "BPE",
+                "vocab": {"a": 0, "b": 1, "ab": 2, "Ġ": 3, "Ġab": 4},
+                "merges": ["a b", ["Ġ", "ab"]],
+            },
+        })
+        .to_string();
+        let tk = load_hf_tokenizer(&tokenizer_json, 8, 5, 5)?;
+        assert_eq!(tk.vocab().len(), 8);
+        assert_eq!(tk.vocab()[5], "<|endoftext|>");
+        assert_eq!(tk.vocab()[7], "[PAD7]");
+        // a space is prepended to the text like add_prefix_space
+        assert_eq!(tk.encode("ab ab", false, false, false)?, vec![4, 4]);
+
+        // the non-special added tokens are parsed even in the untrusted text, and the BPE text
+        // after them takes no prefix space
+        assert_eq!(tk.token_type(5), TokenType::Control);
+        assert_eq!(tk.encode("<x>ab", false, false, false)?, vec![6, 2]);
+        assert_eq!(tk.encode("ab<|endoftext|>", false, false, true)?, vec![
+            4, 5
+        ]);
+
+        // the malformed merges are rejected instead of panicking
+        for merge in [
+            json!(["a"]),
+            json!(["a", 1]),
+            json!(["a", "b", "c"]),
+            json!(1),
+        ] {
+            let tokenizer_json = json!({
+                "model": {"type": "BPE", "vocab": {"a": 0, "b": 1}, "merges": [merge]},
+            })
+            .to_string();
+            let err = load_hf_tokenizer(&tokenizer_json, 2, 0, 0).err().unwrap();
+            assert_eq!(err.kind, ErrorKind::FormatError);
+        }
+
+        // the ids out of the vocab are rejected instead of allocating for them
+        let tokenizer_json = json!({
+            "added_tokens": [{"id": 1u64 << 40, "content": "<x>", "special": false}],
+            "model": {"type": "BPE", "vocab": {"a": 0, "b": 1}, "merges": []},
+        })
+        .to_string();
+        let err = load_hf_tokenizer(&tokenizer_json, 2, 0, 0).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+
+        let tokenizer_json = json!({
+            "model": {"type": "WordPiece", "unk_token": "[UNK]", "vocab": {"[UNK]": 0, "a": 1}},
+        })
+        .to_string();
+        let tk = load_hf_tokenizer(&tokenizer_json, 2, 0, 0)?;
+        assert_eq!(tk.kind(), TokenizerKind::HuggingFace);
+        assert_eq!(tk.encode("ab", false, false, false)?, vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hf_pre_tokenizer() {
+        let qwen2 = json!({
+            "type": "Sequence",
+            "pretokenizers": [
+                {"type": "Split", "pattern": {"Regex": "(?:'[sS]|'[tT]|'[rR][eE]|'[vV][eE]|'[mM]|'[lL][lL]|'[dD])|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+"}},
+                {"type": "ByteLevel", "add_prefix_space": false, "use_regex": false},
+            ],
+        });
+        assert_eq!(hf_pre_tokenizer(&qwen2), (PreTokenizerKind::Qwen2, false));
+        let gpt2 = json!({"type": "ByteLevel", "add_prefix_space": false});
+        assert_eq!(hf_pre_tokenizer(&gpt2), (PreTokenizerKind::Gpt2, false));
+    }
+}
@@ -1,5 +1,7 @@
 pub mod chat;
+pub mod hf;
 pub mod llama2;
+pub mod llama2c;
 pub mod model;
 pub mod sampler;
 
@@ -5,6 +5,7 @@ use crabml::bail;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
+use crabml::imatrix::IMatrixCollector;
 use crabml::tensor::RopeMode;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
@@ -23,6 +24,27 @@ pub enum Activation {
     GeLU,
 }
 
+/// pick the k experts of the highest router logits, their weights are the softmax over the picked
+/// logits, which is the same as renormalizing the top-k softmax probabilities like Mixtral. the
+/// expert of a smaller index is picked first on ties.
+fn top_k_experts(logits: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let mut experts = (0..logits.len()).collect::<Vec<_>>();
+    experts.sort_by(|a, b| logits[*b].total_cmp(&logits[*a]));
+    experts.truncate(k);
+
+    let max = experts.first().map_or(0.0, |e| logits[*e]);
+    let exps = experts
+        .iter()
+        .map(|e| (logits[*e] - max).exp())
+        .collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f32>();
+    experts
+        .into_iter()
+        .zip(exps)
+        .map(|(e, exp)| (e, exp / sum))
+        .collect()
+}
+
 pub struct Llama2Runner<T: Tensor> {
     conf: LlamaConfig,
     weights: Arc<LlamaWeights<T>>,
@@ -39,6 +61,9 @@ pub struct Llama2Runner<T: Tensor> {
     key_cache: Vec<Option<T>>,   // (layer, n_kv_head, seq_len, kv_dim)
     value_cache: Vec<Option<T>>, // (layer, n_kv_head, seq_len, kv_dim)
 
+    // collects the input activations of the weights on computing the importance matrix
+    imatrix_collector: Option<IMatrixCollector>,
+
     pub metrics: TensorMetrics,
 }
 
@@ -95,26 +120,75 @@ impl<T: Tensor> Llama2Runner<T> {
             decode_buf: Utf8Buf::new(),
             prob_index,
             device,
+            imatrix_collector: None,
             metrics,
         })
     }
 
+    /// collect the squared input activations of the matmul weights on every forward pass, which
+    /// can be taken by `take_imatrix_collector()` later.
+    pub fn with_imatrix_collector(mut self) -> Self {
+        self.imatrix_collector = Some(IMatrixCollector::new());
+        self
+    }
+
+    pub fn take_imatrix_collector(&mut self) -> Option<IMatrixCollector> {
+        self.imatrix_collector.take()
+    }
+
     pub fn conf(&self) -> &LlamaConfig {
         &self.conf
     }
 
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
     pub fn kv_cache_len(&self) -> usize {
         self.key_cache[0].as_ref().unwrap().shape()[1]
     }
 
-    // prefill the model with the prompt, return the next position and the first generated token
+    /// clear the kv cache, the next token is expected to be forwarded at position 0.
+    pub fn reset_kv_cache(&mut self) -> Result<()> {
+        for cache in self.key_cache.iter_mut().chain(self.value_cache.iter_mut()) {
+            if let Some(t) = cache.take() {
+                cache.replace(t.resize(1, 0)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// forward a single token at the position and return the logits of the next token.
+    pub fn forward_token(&mut self, token: usize, pos: usize) -> Result<&[f32]> {
+        self.forward(&[token], pos)?;
+        Ok(&self.logits)
+    }
+
+    // prefill the model with the prompt, return the next position and the first generated token.
+    // the special tokens in the prompt are parsed only if `special`. if `bos`, the bos and eos
+    // tokens are added as the tokenizer's add_bos_token and add_eos_token tell.
     pub fn prefill(
         &mut self,
         prompt: &str,
         bos: bool,
+        batched: bool,
+        special: bool,
+    ) -> Result<(usize, usize, usize)> {
+        let prompt_tokens = self.tokenizer.encode(
+            prompt,
+            bos && self.tokenizer.add_bos_token(),
+            bos && self.tokenizer.add_eos_token(),
+            special,
+        )?;
+        self.prefill_tokens(&prompt_tokens, batched)
+    }
+
+    // prefill the model with the tokens of the prompt, like `prefill`.
+    pub fn prefill_tokens(
+        &mut self,
+        prompt_tokens: &[usize],
         _batched: bool,
     ) -> Result<(usize, usize, usize)> {
-        let prompt_tokens = self.tokenizer.encode(prompt, bos, false)?;
         if prompt_tokens.is_empty() {
             bail!(
                 ErrorKind::BadInput,
@@ -146,19 +220,25 @@ impl<T: Tensor> Llama2Runner<T> {
     ) -> impl Iterator<Item = Result<String>> + '_ {
         // the first token has already been generated in the prefill phase.
         let max_seq = self.conf.seq_len - pos - 1;
-        let max_steps = match steps {
+        let mut max_steps = match steps {
             Some(steps) => max_seq.min(steps - 1),
             None => max_seq,
         };
 
-        let first_token = self.tokenizer.decode(token, &mut self.decode_buf);
+        // the prefill might have generated an end of generation token already
+        let first_token = if self.tokenizer.is_eog(token) {
+            max_steps = 0;
+            None
+        } else {
+            Some(self.tokenizer.decode(token, &mut self.decode_buf))
+        };
         let tokens_iter = (pos..pos + max_steps).scan(token, move |current_token, pos| {
             self.forward(&[*current_token], pos).unwrap();
             let new_token = self
                 .sampler
                 .sample(&mut self.logits, &mut self.prob_index)
                 .unwrap();
-            if new_token == self.tokenizer.eos_token() {
+            if self.tokenizer.is_eog(new_token) {
                 return None;
             }
             let r = self
@@ -168,7 +248,7 @@ impl<T: Tensor> Llama2Runner<T> {
             *current_token = new_token;
             Some(Ok(r))
         });
-        std::iter::once(first_token).chain(tokens_iter)
+        first_token.into_iter().chain(tokens_iter)
     }
 
     // simplify the test cases
@@ -177,7 +257,7 @@ impl<T: Tensor> Llama2Runner<T> {
         prompt: &str,
         steps: usize,
     ) -> Result<impl Iterator<Item = Result<String>> + '_> {
-        let (pos, _prev_token, token) = self.prefill(prompt, true, false)?;
+        let (pos, _prev_token, token) = self.prefill(prompt, true, false, false)?;
         Ok(self.generate(pos, token, Some(steps)))
     }
 
@@ -185,10 +265,15 @@ impl<T: Tensor> Llama2Runner<T> {
         let _t = self.metrics.forward_walltime.track();
 
         let x = match self.conf.architecture {
-            ModelArchitecture::Llama => self.forward_llama(tokens, pos)?,
+            ModelArchitecture::Llama | ModelArchitecture::Mixtral => {
+                self.forward_llama(tokens, pos)?
+            }
             ModelArchitecture::Gemma => self.forward_gemma(tokens, pos)?,
             ModelArchitecture::Qwen2 => self.forward_qwen2(tokens, pos)?,
             ModelArchitecture::Phi2 => self.forward_phi2(tokens, pos)?,
+            ModelArchitecture::Gpt2 | ModelArchitecture::StarCoder => {
+                self.forward_gpt2(tokens, pos)?
+            }
         };
 
         let mut x_final = T::alloc(
@@ -205,7 +290,10 @@ impl<T: Tensor> Llama2Runner<T> {
             .output_weight
             .as_ref()
             .unwrap_or_else(|| &self.weights.token_embed);
-        let logits = output_weight.matmul_vec(&x_final)?; // (batch_size, vocab_size),
+        let mut logits = output_weight.matmul_vec(&x_final)?; // (batch_size, vocab_size),
+        if let Some(output_bias) = &self.weights.output_bias {
+            logits = logits.add_inplace(output_bias)?;
+        }
         logits.export(&mut self.logits)?;
         Ok(())
     }
@@ -241,6 +329,9 @@ impl<T: Tensor> Llama2Runner<T> {
                 // wq: (embed_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, embed_dim, )
                 // wk: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
                 // wv: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
+                self.collect_activations(l, "attn_q", &x)?;
+                self.collect_activations(l, "attn_k", &x)?;
+                self.collect_activations(l, "attn_v", &x)?;
                 let q = self.weights.wq[l].matmul_vec(&x)?;
                 let k = self.weights.wk[l].matmul_vec(&x)?;
                 let v = self.weights.wv[l].matmul_vec(&x)?;
@@ -266,7 +357,10 @@ impl<T: Tensor> Llama2Runner<T> {
             x = x.add_inplace(&x_attn_orig)?;
 
             // ffn
-            x = self.forward_ffn(x, l, pos, Activation::SiLU)?;
+            x = match self.conf.architecture {
+                ModelArchitecture::Mixtral => self.forward_moe_ffn(x, l, pos)?,
+                _ => self.forward_ffn(x, l, pos, Activation::SiLU)?,
+            };
             x = x.with_name(format!("ffn_out:{}:{}", l, pos));
         }
 
@@ -309,6 +403,9 @@ impl<T: Tensor> Llama2Runner<T> {
                 // wq: (embed_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, embed_dim, )
                 // wk: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
                 // wv: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
+                self.collect_activations(l, "attn_q", &x)?;
+                self.collect_activations(l, "attn_k", &x)?;
+                self.collect_activations(l, "attn_v", &x)?;
                 let q = self.weights.wq[l].matmul_vec(&x)?;
                 let k = self.weights.wk[l].matmul_vec(&x)?;
                 let v = self.weights.wv[l].matmul_vec(&x)?;
@@ -358,7 +455,6 @@ impl<T: Tensor> Llama2Runner<T> {
         let head_dim = self.conf.head_size();
         let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
         let n_batch = tokens.len();
-        let n_embd_gqa = head_dim * n_kv_heads;
 
         // copy the token embedding into x
         let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
@@ -370,29 +466,35 @@ impl<T: Tensor> Llama2Runner<T> {
 
             // attention norm
             let mut x_attn_norm = x.dup()?;
-            x_attn_norm = x_attn_norm.rms_norm_inplace(self.conf.rms_norm_eps)?;
+            x_attn_norm = x_attn_norm.layer_norm_inplace(self.conf.rms_norm_eps)?;
             x_attn_norm = x_attn_norm.mul_inplace(&self.weights.rms_att_weight[l])?;
             x_attn_norm = x_attn_norm.add_inplace(&self.weights.rms_att_bias[l])?;
             x_attn_norm = x_attn_norm.with_name(format!("attn_norm:{}:{}", l, pos));
 
             // matmul qkv for every head
             let (q, k, v) = {
+                self.collect_activations(l, "attn_qkv", &x_attn_norm)?;
                 let qkv = self.weights.wqkv[l].matmul_vec(&x_attn_norm)?;
                 let qkv = qkv.add_inplace(&self.weights.bqkv[l])?;
 
-                let mut q = T::alloc(&[embed_dim, n_batch], GGMLType::F32, self.device.clone())?;
+                // split the fused qkv of (n_batch, 3 * embed_dim) into the rows of
+                // (3, n_batch * embed_dim), phi2 does not have grouped kv heads
+                let qkv = qkv
+                    .reshape(&[n_batch, 3, embed_dim])?
+                    .transpose(&[1, 0, 2])?
+                    .contiguous()?
+                    .reshape(&[3, n_batch * embed_dim])?;
+
+                let mut q = T::alloc(&[n_batch * embed_dim], GGMLType::F32, self.device.clone())?;
                 q.copy_rows_from(&qkv, &[0])?;
-                q = q.reshape(&[embed_dim, n_batch])?.contiguous()?;
                 q = q.with_name("Qcur".to_string());
 
-                let mut k = T::alloc(&[embed_dim, n_batch], GGMLType::F32, self.device.clone())?;
-                k.copy_rows_from(&qkv, &[embed_dim])?;
-                k = k.reshape(&[embed_dim, n_batch])?.contiguous()?;
+                let mut k = T::alloc(&[n_batch * embed_dim], GGMLType::F32, self.device.clone())?;
+                k.copy_rows_from(&qkv, &[1])?;
                 k = k.with_name("Kcur".to_string());
 
-                let mut v = T::alloc(&[embed_dim, n_batch], GGMLType::F32, self.device.clone())?;
-                v.copy_rows_from(&qkv, &[embed_dim + n_embd_gqa])?;
-                v = v.reshape(&[embed_dim, n_batch])?.contiguous()?;
+                let mut v = T::alloc(&[n_batch * embed_dim], GGMLType::F32, self.device.clone())?;
+                v.copy_rows_from(&qkv, &[2])?;
                 v = v.with_name("Vcur".to_string());
 
                 (q, k, v)
@@ -407,23 +509,24 @@ impl<T: Tensor> Llama2Runner<T> {
                 let q = q.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
                 let k = k.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
 
-                let q = q.scale_inplace(1.0 / (head_dim as f32).sqrt())?;
-
                 (q, k)
             };
 
             x = self.forward_multi_query_attention(
                 q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
             )?;
+            x = x.add_inplace(&self.weights.bo[l])?;
             x = x.with_name(format!("attn_out:{}:{}", l, pos));
 
             // ffn
             let x_ffn = {
+                self.collect_activations(l, "ffn_up", &x_attn_norm)?;
                 let mut x_ffn = self.weights.ffn_up_weight[l].matmul_vec(&x_attn_norm)?;
                 x_ffn = x_ffn.add_inplace(&self.weights.ffn_up_bias[l])?;
 
                 x_ffn = x_ffn.gelu_inplace()?;
 
+                self.collect_activations(l, "ffn_down", &x_ffn)?;
                 x_ffn = self.weights.ffn_down_weight[l].matmul_vec(&x_ffn)?;
                 x_ffn = x_ffn.add_inplace(&self.weights.ffn_down_bias[l])?;
                 x_ffn
@@ -434,12 +537,111 @@ impl<T: Tensor> Llama2Runner<T> {
             x = x.with_name(format!("ffn_out:{}:{}", l, pos));
         }
 
-        // final rmsnorm
+        // final layernorm
         x = {
-            x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
+            x = x.layer_norm_inplace(self.conf.rms_norm_eps)?;
             x = x.mul_inplace(&self.weights.rms_final_weight)?;
             x = x.add_inplace(self.weights.rms_final_bias.as_ref().unwrap())?;
-            x.with_name(format!("final_rmsnorm:{}", pos))
+            x.with_name(format!("final_layernorm:{}", pos))
+        };
+
+        Ok(x)
+    }
+
+    // The differences between GPT2 (and StarCoder) and LLAMA are:
+    // 1. it adds the learned position embedding to the input instead of ROPE.
+    // 2. it uses layernorm with bias instead of rmsnorm.
+    // 3. the ffn is a GELU MLP without the gate, all the matmuls have bias.
+    // StarCoder is the same as GPT2 except it uses the multi query attention.
+    fn forward_gpt2(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_heads = self.conf.n_heads;
+        let n_kv_heads = self.conf.n_kv_heads;
+        let head_dim = self.conf.head_size();
+        let n_batch = tokens.len();
+
+        // copy the token embedding into x
+        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+        x.copy_rows_from(&self.weights.token_embed, tokens)?;
+
+        // add the position embedding of each token
+        x = {
+            let position_embed = match &self.weights.position_embed {
+                Some(position_embed) => position_embed,
+                None => bail!(ErrorKind::ModelError, "missing the position embedding"),
+            };
+            let positions = (pos..pos + n_batch).collect::<Vec<_>>();
+            let mut x_pos = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+            x_pos.copy_rows_from(position_embed, &positions)?;
+            x = x.add_inplace(&x_pos)?;
+            x.with_name(format!("pos_embed:{}", pos))
+        };
+
+        // forward all the layers
+        for l in 0..self.conf.n_layers {
+            let x_attn_orig = x.dup()?;
+
+            // attention layernorm
+            x = {
+                x = x.layer_norm_inplace(self.conf.rms_norm_eps)?;
+                x = x.mul_inplace(&self.weights.rms_att_weight[l])?;
+                x = x.add_inplace(&self.weights.rms_att_bias[l])?;
+                x.with_name(format!("attn_norm:{}:{}", l, pos))
+            };
+
+            // matmul qkv for every head, the fused qkv is split into wq, wk and wv on loading
+            let (q, k, v) = {
+                self.collect_activations(l, "attn_qkv", &x)?;
+                let q = self.weights.wq[l].matmul_vec(&x)?;
+                let k = self.weights.wk[l].matmul_vec(&x)?;
+                let v = self.weights.wv[l].matmul_vec(&x)?;
+                let q = q.add_inplace(&self.weights.bq[l])?;
+                let k = k.add_inplace(&self.weights.bk[l])?;
+                let v = v.add_inplace(&self.weights.bv[l])?;
+                (q, k, v)
+            };
+
+            x = self.forward_multi_query_attention(
+                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
+            )?;
+            x = x.add_inplace(&self.weights.bo[l])?;
+            x = x.with_name(format!("attn_out:{}:{}", l, pos));
+
+            // residual connection back into x
+            x = x.add_inplace(&x_attn_orig)?;
+            let x_ffn_orig = x.dup()?;
+
+            // ffn layernorm
+            x = {
+                x = x.layer_norm_inplace(self.conf.rms_norm_eps)?;
+                x = x.mul_inplace(&self.weights.rms_ffn_weight[l])?;
+                x = x.add_inplace(&self.weights.rms_ffn_bias[l])?;
+                x.with_name(format!("ffn_norm:{}:{}", l, pos))
+            };
+
+            // ffn
+            x = {
+                self.collect_activations(l, "ffn_up", &x)?;
+                x = self.weights.ffn_up_weight[l].matmul_vec(&x)?;
+                x = x.add_inplace(&self.weights.ffn_up_bias[l])?;
+                x = x.gelu_inplace()?;
+
+                self.collect_activations(l, "ffn_down", &x)?;
+                x = self.weights.ffn_down_weight[l].matmul_vec(&x)?;
+                x = x.add_inplace(&self.weights.ffn_down_bias[l])?;
+                x
+            };
+
+            x = x.add_inplace(&x_ffn_orig)?;
+            x = x.with_name(format!("ffn_out:{}:{}", l, pos));
+        }
+
+        // final layernorm
+        x = {
+            x = x.layer_norm_inplace(self.conf.rms_norm_eps)?;
+            x = x.mul_inplace(&self.weights.rms_final_weight)?;
+            x = x.add_inplace(self.weights.rms_final_bias.as_ref().unwrap())?;
+            x.with_name(format!("final_layernorm:{}", pos))
         };
 
         Ok(x)
@@ -485,6 +687,9 @@ impl<T: Tensor> Llama2Runner<T> {
                 // wq: (embed_dim, embed_dim) @ x (embed_dim, ) => (embed_dim, )
                 // wk: (kv_dim, embed_dim) @ x (embed_dim, ) => (kv_dim, )
                 // wv: (kv_dim, embed_dim) @ x (embed_dim, ) => (kv_dim, )
+                self.collect_activations(l, "attn_q", &x)?;
+                self.collect_activations(l, "attn_k", &x)?;
+                self.collect_activations(l, "attn_v", &x)?;
                 let q = self.weights.wq[l].matmul_vec(&x)?;
                 let k = self.weights.wk[l].matmul_vec(&x)?;
                 let v = self.weights.wv[l].matmul_vec(&x)?;
@@ -597,12 +802,19 @@ impl<T: Tensor> Llama2Runner<T> {
             self.value_cache[l].replace(v_cache.with_strider(v_cache_strider_orig)?);
 
             // final matmul to get the output of the attention
+            self.collect_activations(l, "attn_output", &x_with_attn)?;
             self.weights.wo[l].matmul_vec(&x_with_attn)?
         };
         Ok(x)
     }
 
-    fn forward_ffn(&self, mut x: T, l: usize, _pos: usize, activation: Activation) -> Result<T> {
+    fn forward_ffn(
+        &mut self,
+        mut x: T,
+        l: usize,
+        _pos: usize,
+        activation: Activation,
+    ) -> Result<T> {
         // save for residual connection
         let x_orig_ffn = x.dup()?; // (n_batch, embed_dim)
 
@@ -617,6 +829,8 @@ impl<T: Tensor> Llama2Runner<T> {
         // first calculate self.w1(x) and self.w3(x)
         // w1: (hidden_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, hidden_dim, )
         // w3: (hidden_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, hidden_dim, )
+        self.collect_activations(l, "ffn_gate", &x)?;
+        self.collect_activations(l, "ffn_up", &x)?;
         let mut h1 = self.weights.ffn_gate_weight[l].matmul_vec(&x)?;
         let h2 = self.weights.ffn_up_weight[l].matmul_vec(&x)?;
 
@@ -630,19 +844,161 @@ impl<T: Tensor> Llama2Runner<T> {
         h1 = h1.mul_inplace(&h2)?;
 
         // final matmul to get the output of the ffn
+        self.collect_activations(l, "ffn_down", &h1)?;
         x = self.weights.ffn_down_weight[l].matmul_vec(&h1)?; // (n_batch, embed_dim)
 
         // residual connection
         x = x.add_inplace(&x_orig_ffn)?;
         Ok(x)
     }
+
+    /// the ffn of the mixture of experts, the router picks the top-k experts of each token, and
+    /// the outputs of the picked experts are summed by the router weights.
+    fn forward_moe_ffn(&mut self, mut x: T, l: usize, _pos: usize) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_experts = self.conf.n_experts;
+        let n_batch = x.shape()[0];
+
+        // save for residual connection
+        let x_orig_ffn = x.dup()?; // (n_batch, embed_dim)
+
+        // ffn rmsnorm
+        x = {
+            x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
+            x = x.mul_inplace(&self.weights.rms_ffn_weight[l])?;
+            x
+        };
+
+        // gate_inp: (n_experts, embed_dim) @ x (n_batch, embed_dim) => (n_batch, n_experts)
+        self.collect_activations(l, "ffn_gate_inp", &x)?;
+        let mut router_logits = vec![0.0; n_batch * n_experts];
+        self.weights.ffn_gate_inp[l]
+            .matmul_vec(&x)?
+            .export(&mut router_logits)?;
+
+        // the tokens are routed one by one, the outputs are stacked as (n_batch, 1, embed_dim)
+        let mut x_out =
+            T::alloc(&[n_batch, 1, embed_dim], GGMLType::F32, self.device.clone())?.resize(0, 0)?;
+        let mut expert_activations = ExpertActivations::new(self.imatrix_collector.is_some());
+        for (b, logits) in router_logits.chunks(n_experts).enumerate() {
+            let mut x_token = T::alloc(&[embed_dim], GGMLType::F32, self.device.clone())?;
+            x_token.copy_rows_from(&x, &[b])?;
+
+            let mut x_token_out: Option<T> = None;
+            for (e, weight) in top_k_experts(logits, self.conf.n_experts_used) {
+                let mut h1 = self.weights.ffn_gate_exps[l][e].matmul_vec(&x_token)?;
+                let h2 = self.weights.ffn_up_exps[l][e].matmul_vec(&x_token)?;
+                h1 = h1.silu_inplace()?.mul_inplace(&h2)?;
+                expert_activations.push(e, &x_token, &h1)?;
+                let h = self.weights.ffn_down_exps[l][e]
+                    .matmul_vec(&h1)?
+                    .scale_inplace(weight)?;
+                x_token_out = Some(match x_token_out {
+                    Some(x_token_out) => x_token_out.add_inplace(&h)?,
+                    None => h,
+                });
+            }
+            let x_token_out = x_token_out.unwrap().reshape(&[1, 1, embed_dim])?;
+            x_out.concatenate(&x_token_out, 0)?;
+        }
+        x = x_out.reshape(&[n_batch, embed_dim])?;
+        self.collect_expert_activations(l, expert_activations)?;
+
+        // residual connection
+        x = x.add_inplace(&x_orig_ffn)?;
+        Ok(x)
+    }
+
+    fn collect_expert_activations(&mut self, l: usize, acts: ExpertActivations) -> Result<()> {
+        let collector = match self.imatrix_collector.as_mut() {
+            Some(collector) => collector,
+            None => return Ok(()),
+        };
+        let n_experts = self.conf.n_experts;
+        let embed_dim = self.conf.embedding_dim;
+        // ffn_down_exps: (n_experts, embed_dim, expert_hidden_dim)
+        let hidden_dim = self.weights.ffn_down_exps[l][0].shape()[1];
+        for (weight, activations, n_cols) in [
+            ("ffn_gate_exps", &acts.x, embed_dim),
+            ("ffn_up_exps", &acts.x, embed_dim),
+            ("ffn_down_exps", &acts.h, hidden_dim),
+        ] {
+            collector.collect_experts(
+                &format!("blk.{}.{}.weight", l, weight),
+                activations,
+                n_cols,
+                n_experts,
+                &acts.experts,
+            )?;
+        }
+        Ok(())
+    }
+
+    // the activations are named after the weight of the layer in GGUF, like blk.0.attn_q.weight
+    fn collect_activations(&mut self, l: usize, weight: &str, x: &T) -> Result<()> {
+        let collector = match self.imatrix_collector.as_mut() {
+            Some(collector) => collector,
+            None => return Ok(()),
+        };
+        let n_cols = *x.shape().last().unwrap();
+        let mut activations = vec![0.0; x.shape().iter().product()];
+        x.export(&mut activations)?;
+        collector.collect(
+            &format!("blk.{}.{}.weight", l, weight),
+            &activations,
+            n_cols,
+        )
+    }
+}
+
+/// the inputs of the expert matmuls of a layer, they are kept only if the imatrix is collected.
+struct ExpertActivations {
+    enabled: bool,
+    experts: Vec<usize>,
+    /// the input of ffn_gate_exps and ffn_up_exps on each routed expert
+    x: Vec<f32>,
+    /// the input of ffn_down_exps on each routed expert
+    h: Vec<f32>,
+}
+
+impl ExpertActivations {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            experts: vec![],
+            x: vec![],
+            h: vec![],
+        }
+    }
+
+    fn push<T: Tensor>(&mut self, expert: usize, x: &T, h: &T) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        for (t, buf) in [(x, &mut self.x), (h, &mut self.h)] {
+            let start = buf.len();
+            buf.resize(start + t.shape().iter().product::<usize>(), 0.0);
+            t.export(&mut buf[start..])?;
+        }
+        self.experts.push(expert);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use approx::assert_relative_eq;
+    use crabml::cpu::CpuTensor;
+    use crabml::cpu::CpuTensorBuf;
     use crabml::cpu::CpuTensorDeviceOptions;
+    use crabml::gguf::GGUFFile;
+    use crabml::gguf::GGUFFileBuilder;
     use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFReaderLoader;
+    use crabml::gguf::GGUFTensorInfo;
     use crabml_vulkan::vulkan_device::VulkanTensorDevice;
     use crabml_vulkan::vulkan_device::VulkanTensorDeviceOptions;
     use crabml_vulkan::vulkan_tensor::VulkanTensor;
@@ -686,6 +1042,20 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_generate_q8_0_from_reader() -> Result<()> {
+        let mut file = std::fs::File::open("../testdata/tinyllamas-stories-15m-q8_0.gguf").unwrap();
+        let gl = GGUFReaderLoader::new(&mut file)?;
+        let gf = gl.open()?;
+
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner.prefill_and_generate("Lily is a cute cat, ", 11)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, "3 years old. She likes to play with her");
+        Ok(())
+    }
+
     #[test]
     fn test_generate_q4_0() -> Result<()> {
         let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q4_0.gguf", false)?;
@@ -734,6 +1104,594 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_generate_bf16() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        // convert the 2-D weights into bf16, the norm weights are kept in f32 like llama.cpp
+        let bufs = gf
+            .tensor_infos()
+            .iter()
+            .map(|info| match info.dimensions().len() {
+                2 => CpuTensorBuf::from_raw_bytes(info.data(), info.typ())?
+                    .quantize(GGMLType::BF16)
+                    .map(|buf| buf.as_bytes().to_vec()),
+                _ => Ok(info.data().to_vec()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut builder = GGUFFileBuilder::new();
+        for (k, v) in gf.metadata().as_hashmap() {
+            builder.add_metadata(k.clone(), v.clone());
+        }
+        for (info, buf) in gf.tensor_infos().iter().zip(bufs.iter()) {
+            let typ = match info.dimensions().len() {
+                2 => GGMLType::BF16,
+                _ => info.typ(),
+            };
+            builder.add_tensor(GGUFTensorInfo::new(
+                info.name().to_string(),
+                info.dimensions().to_vec(),
+                typ,
+                buf,
+            ))?;
+        }
+        let mut buf = vec![];
+        builder.write(&mut buf)?;
+        let gf = GGUFFile::from_bytes(&buf)?;
+
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        assert_eq!(lm.weights.wq[0].dtype(), GGMLType::BF16);
+        assert_eq!(lm.weights.token_embed.dtype(), GGMLType::BF16);
+        assert_eq!(lm.weights.rms_att_weight[0].dtype(), GGMLType::F32);
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner.prefill_and_generate("Lily is a cat", 31)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_k_experts() {
+        let experts = top_k_experts(&[0.5, 2.0, -1.0, 2.0_f32.ln() + 0.5], 2);
+        assert_eq!(experts.iter().map(|(e, _)| *e).collect::<Vec<_>>(), vec![
+            1, 3
+        ]);
+        assert_relative_eq!(
+            experts[0].1,
+            1.0 / (1.0 + 2.0 * (-1.5_f32).exp()),
+            epsilon = 1e-6
+        );
+        assert_relative_eq!(experts[0].1 + experts[1].1, 1.0, epsilon = 1e-6);
+
+        // the expert of a smaller index wins on ties
+        assert_eq!(top_k_experts(&[0.0; 4], 2), vec![(0, 0.5), (1, 0.5)]);
+    }
+
+    #[test]
+    fn test_generate_moe() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let f32_data = |name: &str| -> Vec<f32> {
+            let data = gf.get_tensor_info(name).unwrap().data();
+            data.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        };
+        let mut seed = 42u64;
+        let mut noise = |n: usize, scale: f32| -> Vec<f32> {
+            (0..n)
+                .map(|_| {
+                    seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    ((seed >> 40) as f32 / (1 << 24) as f32 - 0.5) * scale
+                })
+                .collect()
+        };
+
+        // stack the ffn of every layer into 4 experts, every expert is the dense ffn with its
+        // own noise, and the router weights are random, so the tokens pick different experts.
+        const N_EXPERTS: usize = 4;
+        let mut builder = GGUFFileBuilder::new();
+        for (k, v) in gf.metadata().as_hashmap() {
+            builder.add_metadata(k.clone(), v.clone());
+        }
+        builder.add_metadata(
+            "llama.expert_count",
+            GGUFMetadataValue::U32(N_EXPERTS as u32),
+        );
+        builder.add_metadata("llama.expert_used_count", GGUFMetadataValue::U32(2));
+        let ffn_infos = gf
+            .tensor_infos()
+            .iter()
+            .filter(|info| {
+                ["ffn_gate.", "ffn_up.", "ffn_down."]
+                    .iter()
+                    .any(|n| info.name().contains(n))
+            })
+            .collect::<Vec<_>>();
+        let embed_dim = gf
+            .get_tensor_info("token_embd.weight")
+            .unwrap()
+            .dimensions()[0];
+        let mut experts = HashMap::new();
+        let mut gate_inps = HashMap::new();
+        for info in ffn_infos.iter() {
+            let dense = f32_data(info.name());
+            let stacked = (0..N_EXPERTS)
+                .flat_map(|_| {
+                    let noise = noise(dense.len(), 1.0);
+                    dense
+                        .iter()
+                        .zip(noise)
+                        .map(|(v, n)| v * (1.0 + n))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            experts.insert(info.name().replace(".weight", "_exps.weight"), stacked);
+            if info.name().contains("ffn_gate.") {
+                let name = info.name().replace("ffn_gate", "ffn_gate_inp");
+                gate_inps.insert(name, noise(embed_dim * N_EXPERTS, 0.5));
+            }
+        }
+        let to_bytes = |data: &[f32]| data.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let bufs: HashMap<String, Vec<u8>> = experts
+            .iter()
+            .chain(gate_inps.iter())
+            .map(|(name, data)| (name.clone(), to_bytes(data)))
+            .collect();
+        for info in gf.tensor_infos() {
+            if !ffn_infos.iter().any(|i| i.name() == info.name()) {
+                builder.add_tensor(info.clone())?;
+            }
+        }
+        for info in ffn_infos.iter() {
+            let name = info.name().replace(".weight", "_exps.weight");
+            let mut dims = info.dimensions().to_vec();
+            dims.push(N_EXPERTS);
+            builder.add_tensor(GGUFTensorInfo::new(
+                name.clone(),
+                dims,
+                GGMLType::F32,
+                &bufs[&name],
+            ))?;
+            if info.name().contains("ffn_gate.") {
+                let name = info.name().replace("ffn_gate", "ffn_gate_inp");
+                let dims = vec![embed_dim, N_EXPERTS];
+                builder.add_tensor(GGUFTensorInfo::new(
+                    name.clone(),
+                    dims,
+                    GGMLType::F32,
+                    &bufs[&name],
+                ))?;
+            }
+        }
+        let mut buf = vec![];
+        builder.write(&mut buf)?;
+        let gf_moe = GGUFFile::from_bytes(&buf)?;
+
+        let lm = CpuLlamaModelLoader::new().load(&gf_moe)?;
+        assert_eq!(lm.conf.architecture, ModelArchitecture::Mixtral);
+        assert_eq!(lm.conf.n_experts_used, 2);
+        assert!(lm.weights.ffn_gate_weight.is_empty());
+        assert_eq!(lm.weights.ffn_down_exps[0].len(), N_EXPERTS);
+        assert_eq!(lm.weights.ffn_down_exps[0][1].shape(), &[embed_dim, 768]);
+        // the experts are the views of the stacked tensor
+        assert!(!lm.weights.ffn_down_exps[0][1].is_owned());
+
+        // the reference moe ffn in plain f32 on the token embeddings: softmax over the router
+        // logits, pick the top 2 experts, and sum the experts by the renormalized weights
+        let tokens = [365, 2354, 338, 263, 6635];
+        let token_embd = f32_data("token_embd.weight");
+        let xs = tokens
+            .iter()
+            .map(|t| token_embd[t * embed_dim..(t + 1) * embed_dim].to_vec())
+            .collect::<Vec<_>>();
+        let matvec = |w: &[f32], x: &[f32]| -> Vec<f32> {
+            w.chunks(x.len())
+                .map(|row| row.iter().zip(x).map(|(a, b)| a * b).sum())
+                .collect()
+        };
+        let rms_ffn = f32_data("blk.0.ffn_norm.weight");
+        let gate_inp = &gate_inps["blk.0.ffn_gate_inp.weight"];
+        let expert = |name: &str, e: usize| {
+            let w = &experts[&format!("blk.0.{name}_exps.weight")];
+            let n = w.len() / N_EXPERTS;
+            &w[e * n..(e + 1) * n]
+        };
+        let mut picked = vec![];
+        let mut expected = vec![];
+        for x in xs.iter() {
+            let rms = (x.iter().map(|v| v * v).sum::<f32>() / embed_dim as f32
+                + lm.conf.rms_norm_eps)
+                .sqrt();
+            let xn = (0..embed_dim)
+                .map(|i| x[i] / rms * rms_ffn[i])
+                .collect::<Vec<_>>();
+            let logits = matvec(gate_inp, &xn);
+            let max = logits.iter().cloned().fold(f32::MIN, f32::max);
+            let sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>();
+            let probs = logits
+                .iter()
+                .map(|l| (l - max).exp() / sum)
+                .collect::<Vec<_>>();
+            let mut top = (0..N_EXPERTS).collect::<Vec<_>>();
+            top.sort_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+            top.truncate(2);
+            let top_sum = top.iter().map(|e| probs[*e]).sum::<f32>();
+
+            let mut out = x.clone();
+            for e in top.iter() {
+                let gate = matvec(expert("ffn_gate", *e), &xn);
+                let up = matvec(expert("ffn_up", *e), &xn);
+                let h = gate
+                    .iter()
+                    .zip(up)
+                    .map(|(g, u)| g / (1.0 + (-g).exp()) * u)
+                    .collect::<Vec<_>>();
+                let down = matvec(expert("ffn_down", *e), &h);
+                for (o, d) in out.iter_mut().zip(down) {
+                    *o += probs[*e] / top_sum * d;
+                }
+            }
+            top.sort();
+            picked.push(top);
+            expected.push(out);
+        }
+        picked.dedup();
+        assert!(picked.len() > 1, "{:?}", picked);
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_imatrix_collector();
+        let x = CpuTensor::new(
+            xs.concat(),
+            &[tokens.len(), embed_dim],
+            runner.device.clone(),
+        )?;
+        let out = runner.forward_moe_ffn(x, 0, 0)?;
+        let mut got = vec![0.0; tokens.len() * embed_dim];
+        out.export(&mut got)?;
+        // the silu on cpu looks up the f16 table, which costs a bit of precision
+        assert_relative_eq!(&got[..], &expected.concat()[..], epsilon = 5e-3);
+
+        // the activations are collected on every expert the tokens are routed to
+        let imatrix = runner
+            .take_imatrix_collector()
+            .unwrap()
+            .to_imatrix(1, "test");
+        for (name, n_cols) in [("ffn_up_exps", embed_dim), ("ffn_down_exps", 768)] {
+            let importance = imatrix.importance(&format!("blk.0.{name}.weight")).unwrap();
+            assert_eq!(importance.len(), n_cols * N_EXPERTS);
+            for (e, values) in importance.chunks(n_cols).enumerate() {
+                let used = picked.iter().any(|top| top.contains(&e));
+                assert_eq!(values.iter().any(|v| *v > 0.0), used, "{} {}", name, e);
+            }
+        }
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner.prefill_and_generate("Lily is a cat", 8)?;
+        assert!(!output.collect::<Result<String>>()?.is_empty());
+        Ok(())
+    }
+
+    const TINY_EMBED: usize = 64;
+    const TINY_HIDDEN: usize = 128;
+    const TINY_HEADS: usize = 4;
+    const TINY_HEAD_DIM: usize = TINY_EMBED / TINY_HEADS;
+    const TINY_LAYERS: usize = 2;
+    const TINY_VOCAB: usize = 512;
+    const TINY_EPS: f32 = 1e-5;
+
+    /// the deterministic random f32 weights of a tiny model, keyed by the tensor name with the
+    /// GGUF dimensions.
+    struct TinyWeights {
+        seed: u64,
+        tensors: HashMap<String, (Vec<usize>, Vec<f32>)>,
+    }
+
+    impl TinyWeights {
+        fn new() -> Self {
+            Self {
+                seed: 42,
+                tensors: HashMap::new(),
+            }
+        }
+
+        fn add(&mut self, name: impl Into<String>, dims: &[usize], scale: f32, offset: f32) {
+            let data = (0..dims.iter().product())
+                .map(|_| {
+                    self.seed = self
+                        .seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    ((self.seed >> 40) as f32 / (1 << 24) as f32 - 0.5) * scale + offset
+                })
+                .collect();
+            self.tensors.insert(name.into(), (dims.to_vec(), data));
+        }
+
+        /// the norm with the bias, shifted around 1.0 to look like a trained one
+        fn add_norm(&mut self, name: &str) {
+            self.add(format!("{name}.weight"), &[TINY_EMBED], 0.5, 1.0);
+            self.add(format!("{name}.bias"), &[TINY_EMBED], 0.2, 0.0);
+        }
+
+        fn add_linear(&mut self, name: &str, n_in: usize, n_out: usize) {
+            self.add(format!("{name}.weight"), &[n_in, n_out], 0.5, 0.0);
+            self.add(format!("{name}.bias"), &[n_out], 0.2, 0.0);
+        }
+
+        fn get(&self, name: &str) -> &[f32] {
+            &self.tensors[name].1
+        }
+
+        /// write the weights into a GGUF file of the architecture, borrowing the tokenizer of
+        /// the 260k model.
+        fn to_gguf(&self, arch: &str, metadata: &[(&str, u32)]) -> Result<Vec<u8>> {
+            let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+            let gf = gl.open()?;
+            let tokens = gf.metadata().get_string_array("tokenizer.ggml.tokens");
+            assert_eq!(tokens.unwrap().len(), TINY_VOCAB);
+
+            let mut builder = GGUFFileBuilder::new();
+            for (k, v) in gf.metadata().as_hashmap() {
+                if k.starts_with("tokenizer.") {
+                    builder.add_metadata(k.clone(), v.clone());
+                }
+            }
+            builder.add_metadata(
+                "general.architecture",
+                GGUFMetadataValue::String(arch.into()),
+            );
+            builder.add_metadata("general.name", GGUFMetadataValue::String(arch.into()));
+            let metadata = [
+                ("embedding_length", TINY_EMBED as u32),
+                ("feed_forward_length", TINY_HIDDEN as u32),
+                ("block_count", TINY_LAYERS as u32),
+                ("attention.head_count", TINY_HEADS as u32),
+                ("context_length", 32),
+            ]
+            .into_iter()
+            .chain(metadata.iter().copied());
+            for (k, v) in metadata {
+                builder.add_metadata(format!("{arch}.{k}"), GGUFMetadataValue::U32(v));
+            }
+            builder.add_metadata(
+                format!("{arch}.attention.layer_norm_epsilon"),
+                GGUFMetadataValue::F32(TINY_EPS),
+            );
+            let bufs = self
+                .tensors
+                .iter()
+                .map(|(name, (dims, data))| {
+                    let buf = data
+                        .iter()
+                        .flat_map(|v| v.to_le_bytes())
+                        .collect::<Vec<_>>();
+                    (name, dims, buf)
+                })
+                .collect::<Vec<_>>();
+            for (name, dims, buf) in bufs.iter() {
+                builder.add_tensor(GGUFTensorInfo::new(
+                    name.to_string(),
+                    dims.to_vec(),
+                    GGMLType::F32,
+                    buf,
+                ))?;
+            }
+            let mut buf = vec![];
+            builder.write(&mut buf)?;
+            Ok(buf)
+        }
+
+        // the plain f32 reference ops of the forward pass below
+
+        fn linear(&self, name: &str, x: &[f32]) -> Vec<f32> {
+            let (dims, data) = &self.tensors[&format!("{name}.weight")];
+            let bias = self.get(&format!("{name}.bias"));
+            data.chunks(dims[0])
+                .zip(bias)
+                .map(|(row, b)| row.iter().zip(x).map(|(a, b)| a * b).sum::<f32>() + b)
+                .collect()
+        }
+
+        fn layer_norm(&self, name: &str, x: &[f32]) -> Vec<f32> {
+            let mean = x.iter().sum::<f32>() / x.len() as f32;
+            let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / x.len() as f32;
+            let weight = self.get(&format!("{name}.weight"));
+            let bias = self.get(&format!("{name}.bias"));
+            (0..x.len())
+                .map(|i| (x[i] - mean) / (var + TINY_EPS).sqrt() * weight[i] + bias[i])
+                .collect()
+        }
+    }
+
+    fn ref_gelu(x: Vec<f32>) -> Vec<f32> {
+        x.into_iter()
+            .map(|v| 0.5 * v * (1.0 + (0.7978846 * (v + 0.044715 * v * v * v)).tanh()))
+            .collect()
+    }
+
+    /// the attention of q over the cached k and v, a single kv head is shared by all the heads
+    /// of q on the multi query attention
+    fn ref_attention(q: &[f32], k_cache: &[Vec<f32>], v_cache: &[Vec<f32>]) -> Vec<f32> {
+        let n_kv_heads = k_cache[0].len() / TINY_HEAD_DIM;
+        let mut out = vec![0.0; TINY_EMBED];
+        for head in 0..TINY_HEADS {
+            let qr = head * TINY_HEAD_DIM..(head + 1) * TINY_HEAD_DIM;
+            let kv_head = head * n_kv_heads / TINY_HEADS;
+            let kr = kv_head * TINY_HEAD_DIM..(kv_head + 1) * TINY_HEAD_DIM;
+            let scores = k_cache
+                .iter()
+                .map(|k| {
+                    let s = q[qr.clone()].iter().zip(&k[kr.clone()]);
+                    s.map(|(a, b)| a * b).sum::<f32>() / (TINY_HEAD_DIM as f32).sqrt()
+                })
+                .collect::<Vec<_>>();
+            let max = scores.iter().cloned().fold(f32::MIN, f32::max);
+            let exps = scores.iter().map(|s| (s - max).exp()).collect::<Vec<_>>();
+            let sum = exps.iter().sum::<f32>();
+            for (e, v) in exps.iter().zip(v_cache) {
+                for (o, v) in out[qr.clone()].iter_mut().zip(&v[kr.clone()]) {
+                    *o += e / sum * v;
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_phi2_logits() -> Result<()> {
+        const ROPE_DIM: usize = 8;
+
+        // the embeddings are shifted off zero, so the layer norm gives a different result
+        // from the rms norm.
+        let mut w = TinyWeights::new();
+        w.add("token_embd.weight", &[TINY_EMBED, TINY_VOCAB], 2.0, 0.5);
+        for l in 0..TINY_LAYERS {
+            w.add_norm(&format!("blk.{l}.attn_norm"));
+            w.add_linear(&format!("blk.{l}.attn_qkv"), TINY_EMBED, TINY_EMBED * 3);
+            w.add_linear(&format!("blk.{l}.attn_output"), TINY_EMBED, TINY_EMBED);
+            w.add_linear(&format!("blk.{l}.ffn_up"), TINY_EMBED, TINY_HIDDEN);
+            w.add_linear(&format!("blk.{l}.ffn_down"), TINY_HIDDEN, TINY_EMBED);
+        }
+        w.add_norm("output_norm");
+        w.add_linear("output", TINY_EMBED, TINY_VOCAB);
+        let buf = w.to_gguf("phi2", &[
+            ("attention.head_count_kv", TINY_HEADS as u32),
+            ("rope.dimension_count", ROPE_DIM as u32),
+        ])?;
+        let gf = GGUFFile::from_bytes(&buf)?;
+
+        // the reference forward pass in plain f32, following the HF's PhiForCausalLM
+        let rope = |x: &mut [f32], pos: usize| {
+            for head in x.chunks_mut(TINY_HEAD_DIM) {
+                for i in 0..ROPE_DIM / 2 {
+                    let theta = pos as f32 / 10000_f32.powf(2.0 * i as f32 / ROPE_DIM as f32);
+                    let (x0, x1) = (head[i], head[i + ROPE_DIM / 2]);
+                    head[i] = x0 * theta.cos() - x1 * theta.sin();
+                    head[i + ROPE_DIM / 2] = x0 * theta.sin() + x1 * theta.cos();
+                }
+            }
+        };
+        let tokens = [1, 7, 300, 42, 511];
+        let mut k_cache = vec![vec![]; TINY_LAYERS];
+        let mut v_cache = vec![vec![]; TINY_LAYERS];
+        let mut expected = vec![];
+        for (pos, token) in tokens.iter().enumerate() {
+            let embed = w.get("token_embd.weight");
+            let mut x = embed[token * TINY_EMBED..(token + 1) * TINY_EMBED].to_vec();
+            for l in 0..TINY_LAYERS {
+                let h = w.layer_norm(&format!("blk.{l}.attn_norm"), &x);
+                let qkv = w.linear(&format!("blk.{l}.attn_qkv"), &h);
+                let mut q = qkv[..TINY_EMBED].to_vec();
+                let mut k = qkv[TINY_EMBED..TINY_EMBED * 2].to_vec();
+                rope(&mut q, pos);
+                rope(&mut k, pos);
+                k_cache[l].push(k);
+                v_cache[l].push(qkv[TINY_EMBED * 2..].to_vec());
+                let attn = ref_attention(&q, &k_cache[l], &v_cache[l]);
+                let attn = w.linear(&format!("blk.{l}.attn_output"), &attn);
+
+                let up = ref_gelu(w.linear(&format!("blk.{l}.ffn_up"), &h));
+                let ffn = w.linear(&format!("blk.{l}.ffn_down"), &up);
+                x = (0..TINY_EMBED).map(|i| x[i] + attn[i] + ffn[i]).collect();
+            }
+            expected.push(w.linear("output", &w.layer_norm("output_norm", &x)));
+        }
+
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        assert_eq!(lm.conf.architecture, ModelArchitecture::Phi2);
+        let mut runner = Llama2Runner::new(&lm, 32, false)?;
+        for (pos, token) in tokens.iter().enumerate() {
+            runner.forward(&[*token], pos)?;
+            // the gelu and the softmax on cpu look up the f16 tables, which cost a bit of precision
+            assert_relative_eq!(&runner.logits[..], &expected[pos][..], epsilon = 5e-3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpt2_logits() -> Result<()> {
+        // gpt2 has no head_count_kv, starcoder shares a single kv head on every head
+        for (arch, n_kv_heads) in [("gpt2", TINY_HEADS), ("starcoder", 1)] {
+            let kv_dim = n_kv_heads * TINY_HEAD_DIM;
+            let mut w = TinyWeights::new();
+            w.add("token_embd.weight", &[TINY_EMBED, TINY_VOCAB], 2.0, 0.5);
+            w.add("position_embd.weight", &[TINY_EMBED, 32], 1.0, 0.0);
+            for l in 0..TINY_LAYERS {
+                w.add_norm(&format!("blk.{l}.attn_norm"));
+                w.add_linear(
+                    &format!("blk.{l}.attn_qkv"),
+                    TINY_EMBED,
+                    TINY_EMBED + kv_dim * 2,
+                );
+                w.add_linear(&format!("blk.{l}.attn_output"), TINY_EMBED, TINY_EMBED);
+                w.add_norm(&format!("blk.{l}.ffn_norm"));
+                w.add_linear(&format!("blk.{l}.ffn_up"), TINY_EMBED, TINY_HIDDEN);
+                w.add_linear(&format!("blk.{l}.ffn_down"), TINY_HIDDEN, TINY_EMBED);
+            }
+            w.add_norm("output_norm");
+            // the output weight is tied to the token embedding
+            let metadata = match arch {
+                "gpt2" => vec![],
+                _ => vec![("attention.head_count_kv", n_kv_heads as u32)],
+            };
+            let buf = w.to_gguf(arch, &metadata)?;
+            let gf = GGUFFile::from_bytes(&buf)?;
+
+            // the reference forward pass in plain f32, following the HF's GPT2LMHeadModel
+            let tokens = [1, 7, 300, 42, 511];
+            let mut k_cache = vec![vec![]; TINY_LAYERS];
+            let mut v_cache = vec![vec![]; TINY_LAYERS];
+            let mut expected = vec![];
+            for (pos, token) in tokens.iter().enumerate() {
+                let embed = w.get("token_embd.weight");
+                let pos_embed = w.get("position_embd.weight");
+                let mut x = (0..TINY_EMBED)
+                    .map(|i| embed[token * TINY_EMBED + i] + pos_embed[pos * TINY_EMBED + i])
+                    .collect::<Vec<_>>();
+                for l in 0..TINY_LAYERS {
+                    let h = w.layer_norm(&format!("blk.{l}.attn_norm"), &x);
+                    let qkv = w.linear(&format!("blk.{l}.attn_qkv"), &h);
+                    k_cache[l].push(qkv[TINY_EMBED..TINY_EMBED + kv_dim].to_vec());
+                    v_cache[l].push(qkv[TINY_EMBED + kv_dim..].to_vec());
+                    let attn = ref_attention(&qkv[..TINY_EMBED], &k_cache[l], &v_cache[l]);
+                    let attn = w.linear(&format!("blk.{l}.attn_output"), &attn);
+                    x = (0..TINY_EMBED).map(|i| x[i] + attn[i]).collect();
+
+                    let h = w.layer_norm(&format!("blk.{l}.ffn_norm"), &x);
+                    let up = ref_gelu(w.linear(&format!("blk.{l}.ffn_up"), &h));
+                    let ffn = w.linear(&format!("blk.{l}.ffn_down"), &up);
+                    x = (0..TINY_EMBED).map(|i| x[i] + ffn[i]).collect();
+                }
+                let x = w.layer_norm("output_norm", &x);
+                let logits = embed
+                    .chunks(TINY_EMBED)
+                    .map(|row| row.iter().zip(&x).map(|(a, b)| a * b).sum::<f32>())
+                    .collect::<Vec<_>>();
+                expected.push(logits);
+            }
+
+            let lm = CpuLlamaModelLoader::new().load(&gf)?;
+            assert_eq!(lm.conf.n_kv_heads, n_kv_heads);
+            assert_eq!(lm.weights.wk[0].shape(), &[kv_dim, TINY_EMBED]);
+            // the q, k and v are the views of the fused qkv
+            assert!(!lm.weights.wq[0].is_owned());
+            let mut runner = Llama2Runner::new(&lm, 32, false)?;
+            for (pos, token) in tokens.iter().enumerate() {
+                runner.forward(&[*token], pos)?;
+                // the logits of the tied embedding are larger, so are the errors of the f16 tables
+                assert_relative_eq!(&runner.logits[..], &expected[pos][..], epsilon = 1e-2);
+            }
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_generate_f32_gpu() -> Result<()> {
         let gl: GGUFFileLoader =
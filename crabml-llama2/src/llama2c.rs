@@ -0,0 +1,422 @@
+use std::fs::File;
+use std::sync::Arc;
+
+use crabml::bail;
+use crabml::cpu::CpuTensor;
+use crabml::cpu::CpuTensorDeviceRef;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::tokenizer::Tokenizer;
+use memmap2::Mmap;
+
+use crate::model::LlamaConfig;
+use crate::model::LlamaWeights;
+use crate::model::ModelArchitecture;
+
+// the header of a legacy llama2.c checkpoint: dim, hidden_dim, n_layers, n_heads, n_kv_heads,
+// vocab_size and seq_len, all in i32.
+const LLAMA2C_HEADER_LEN: usize = 7 * 4;
+
+/// Llama2cFileLoader maps the checkpoint of llama2.c and reads its tokenizer.bin.
+pub struct Llama2cFileLoader {
+    checkpoint: Mmap,
+    tokenizer: Vec<u8>,
+}
+
+impl Llama2cFileLoader {
+    pub fn new(checkpoint_path: &str, tokenizer_path: &str) -> Result<Self> {
+        let file = File::open(checkpoint_path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to open the file: {}", checkpoint_path),
+            cause: Some(Arc::new(err)),
+        })?;
+        let checkpoint = unsafe {
+            Mmap::map(&file).map_err(|err| Error {
+                kind: ErrorKind::IOError,
+                message: format!("failed to mmap file: {}", checkpoint_path),
+                cause: Some(Arc::new(err)),
+            })?
+        };
+        let tokenizer = std::fs::read(tokenizer_path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the file: {}", tokenizer_path),
+            cause: Some(Arc::new(err)),
+        })?;
+        Ok(Self {
+            checkpoint,
+            tokenizer,
+        })
+    }
+
+    pub fn checkpoint(&self) -> &[u8] {
+        &self.checkpoint
+    }
+
+    pub fn tokenizer(&self) -> &[u8] {
+        &self.tokenizer
+    }
+}
+
+/// parse the header of the checkpoint. the classifier weights are shared with the token
+/// embedding if vocab_size is positive, or stored after the other weights if it's negative.
+pub(crate) fn load_llama2c_config(buf: &[u8]) -> Result<(LlamaConfig, bool)> {
+    if buf.len() < LLAMA2C_HEADER_LEN {
+        bail!(
+            ErrorKind::FormatError,
+            "the llama2.c checkpoint is too short"
+        );
+    }
+    let header = buf[..LLAMA2C_HEADER_LEN]
+        .chunks_exact(4)
+        .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect::<Vec<_>>();
+    let (dim, hidden_dim, n_layers, n_heads, n_kv_heads, vocab_size, seq_len) = (
+        header[0], header[1], header[2], header[3], header[4], header[5], header[6],
+    );
+    let shared_weights = vocab_size > 0;
+    let vocab_size = vocab_size.unsigned_abs() as usize;
+    if [dim, hidden_dim, n_layers, n_heads, n_kv_heads, seq_len]
+        .iter()
+        .any(|v| *v <= 0)
+        || vocab_size == 0
+        || dim % n_heads != 0
+        || n_heads % n_kv_heads != 0
+    {
+        bail!(
+            ErrorKind::FormatError,
+            "invalid llama2.c checkpoint header {:?}",
+            header
+        );
+    }
+
+    let conf = LlamaConfig {
+        architecture: ModelArchitecture::Llama,
+        model_name: "llama2.c".to_string(),
+        chat_template: "".to_string(),
+        embedding_dim: dim as usize,
+        hidden_dim: hidden_dim as usize,
+        n_layers: n_layers as usize,
+        n_heads: n_heads as usize,
+        n_kv_heads: n_kv_heads as usize,
+        vocab_size,
+        seq_len: seq_len as usize,
+        rms_norm_eps: 1e-5,
+        rope_dim: None,
+        n_experts: 0,
+        n_experts_used: 0,
+    };
+    Ok((conf, shared_weights))
+}
+
+/// the weights are stored in F32 one after another as the order in llama2.c's run.c, the
+/// tensors are referenced from the buffer without copying.
+pub(crate) fn load_llama2c_weights<'a>(
+    buf: &'a [u8],
+    conf: &LlamaConfig,
+    shared_weights: bool,
+    device: CpuTensorDeviceRef<'a>,
+) -> Result<LlamaWeights<CpuTensor<'a>>> {
+    let dim = conf.embedding_dim;
+    let kv_dim = conf.kv_dim();
+    let hidden_dim = conf.hidden_dim;
+    let n_layers = conf.n_layers;
+    let freq_cis_len = conf.seq_len * conf.head_size() / 2;
+
+    // the header is untrusted, a huge size in it must not overflow
+    let expected_size = (|| {
+        let layer_len = dim
+            .checked_mul(dim)?
+            .checked_add(dim.checked_mul(kv_dim)?)?
+            .checked_add(dim)?
+            .checked_mul(2)?
+            .checked_add(dim.checked_mul(hidden_dim)?.checked_mul(3)?)?;
+        let classifier_len = conf.vocab_size.checked_mul(dim)?;
+        let mut len = classifier_len
+            .checked_add(n_layers.checked_mul(layer_len)?)?
+            .checked_add(dim)?
+            .checked_add(freq_cis_len.checked_mul(2)?)?;
+        if !shared_weights {
+            len = len.checked_add(classifier_len)?;
+        }
+        len.checked_mul(4)?.checked_add(LLAMA2C_HEADER_LEN)
+    })();
+    let expected_size = match expected_size {
+        Some(size) => size,
+        None => bail!(
+            ErrorKind::FormatError,
+            "invalid llama2.c checkpoint, the size of the weights overflows"
+        ),
+    };
+    if buf.len() != expected_size {
+        bail!(
+            ErrorKind::FormatError,
+            "size mismatch on the llama2.c checkpoint, expected {} bytes but got {}",
+            expected_size,
+            buf.len()
+        );
+    }
+
+    let mut r = Llama2cReader {
+        buf,
+        offset: LLAMA2C_HEADER_LEN,
+        device,
+    };
+    let token_embed = r.next(&[conf.vocab_size, dim])?;
+    let rms_att_weight = r.next_layers(n_layers, &[dim])?;
+    let wq = r.next_layers(n_layers, &[dim, dim])?;
+    let wk = r.next_layers(n_layers, &[kv_dim, dim])?;
+    let wv = r.next_layers(n_layers, &[kv_dim, dim])?;
+    let wo = r.next_layers(n_layers, &[dim, dim])?;
+    let rms_ffn_weight = r.next_layers(n_layers, &[dim])?;
+    let ffn_gate_weight = r.next_layers(n_layers, &[hidden_dim, dim])?;
+    let ffn_down_weight = r.next_layers(n_layers, &[dim, hidden_dim])?;
+    let ffn_up_weight = r.next_layers(n_layers, &[hidden_dim, dim])?;
+    let rms_final_weight = r.next(&[dim])?;
+    // the rope frequencies are computed on the fly
+    r.skip(2 * freq_cis_len);
+    let output_weight = if shared_weights {
+        None
+    } else {
+        Some(r.next(&[conf.vocab_size, dim])?)
+    };
+
+    Ok(LlamaWeights {
+        token_embed,
+        position_embed: None,
+        rms_att_weight,
+        rms_ffn_weight,
+        rms_att_bias: vec![],
+        rms_ffn_bias: vec![],
+        wq,
+        wk,
+        wv,
+        wo,
+        wqkv: vec![],
+        bq: vec![],
+        bk: vec![],
+        bv: vec![],
+        bo: vec![],
+        bqkv: vec![],
+        ffn_gate_weight,
+        ffn_down_weight,
+        ffn_up_weight,
+        ffn_down_bias: vec![],
+        ffn_up_bias: vec![],
+        ffn_gate_inp: vec![],
+        ffn_gate_exps: vec![],
+        ffn_down_exps: vec![],
+        ffn_up_exps: vec![],
+        rms_final_weight,
+        rms_final_bias: None,
+        output_weight,
+        output_bias: None,
+    })
+}
+
+struct Llama2cReader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    device: CpuTensorDeviceRef<'a>,
+}
+
+impl<'a> Llama2cReader<'a> {
+    fn next(&mut self, shape: &[usize]) -> Result<CpuTensor<'a>> {
+        let len = shape.iter().product::<usize>() * 4;
+        let data = &self.buf[self.offset..self.offset + len];
+        self.offset += len;
+        CpuTensor::from_bytes(data, GGMLType::F32, shape, self.device.clone())
+    }
+
+    fn next_layers(&mut self, n_layers: usize, shape: &[usize]) -> Result<Vec<CpuTensor<'a>>> {
+        (0..n_layers).map(|_| self.next(shape)).collect()
+    }
+
+    fn skip(&mut self, n_floats: usize) {
+        self.offset += n_floats * 4;
+    }
+}
+
+/// tokenizer.bin begins with an i32 of max_token_length, followed by the score in f32, the
+/// length in i32 and the bytes of each token. the leading spaces in the tokens are stored as
+/// is, they're turned back into `▁` for the llama tokenizer.
+pub(crate) fn load_llama2c_tokenizer(buf: &[u8], vocab_size: usize) -> Result<Tokenizer> {
+    let mut tokens = Vec::with_capacity(vocab_size);
+    let mut scores = Vec::with_capacity(vocab_size);
+    let mut offset = 4;
+    let mut read = |len: usize| -> Result<&[u8]> {
+        if offset + len > buf.len() {
+            bail!(
+                ErrorKind::FormatError,
+                "the llama2.c tokenizer is truncated"
+            );
+        }
+        let data = &buf[offset..offset + len];
+        offset += len;
+        Ok(data)
+    };
+    for _ in 0..vocab_size {
+        let b = read(4)?;
+        scores.push(f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let b = read(4)?;
+        let len = i32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        if len < 0 {
+            bail!(ErrorKind::FormatError, "invalid token length {}", len);
+        }
+        let token = String::from_utf8_lossy(read(len as usize)?);
+        // the bos and eos are exported as "\n<s>\n" and "\n</s>\n"
+        tokens.push(token.trim_matches('\n').replace(' ', "▁"));
+    }
+    Ok(Tokenizer::new_llama(tokens, scores, 1, 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGUFFile;
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::llama2::Llama2Runner;
+    use crate::model::CpuLlamaModelLoader;
+
+    /// export the GGUF model into a llama2.c checkpoint and its tokenizer.bin, the tinyllamas
+    /// are originally converted from llama2.c so the weights are kept in the same layout.
+    fn export_llama2c(
+        gf: &GGUFFile,
+        conf: &LlamaConfig,
+        shared_weights: bool,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let vocab_size = conf.vocab_size as i32;
+        let header = [
+            conf.embedding_dim as i32,
+            conf.hidden_dim as i32,
+            conf.n_layers as i32,
+            conf.n_heads as i32,
+            conf.n_kv_heads as i32,
+            if shared_weights {
+                vocab_size
+            } else {
+                -vocab_size
+            },
+            conf.seq_len as i32,
+        ];
+        let mut checkpoint = header
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let write = |checkpoint: &mut Vec<u8>, name: &str| {
+            let info = gf.get_tensor_info(name).unwrap();
+            checkpoint.extend_from_slice(info.data());
+        };
+        write(&mut checkpoint, "token_embd.weight");
+        for name in [
+            "attn_norm",
+            "attn_q",
+            "attn_k",
+            "attn_v",
+            "attn_output",
+            "ffn_norm",
+            "ffn_gate",
+            "ffn_down",
+            "ffn_up",
+        ] {
+            for layer in 0..conf.n_layers {
+                write(&mut checkpoint, &format!("blk.{}.{}.weight", layer, name));
+            }
+        }
+        write(&mut checkpoint, "output_norm.weight");
+        let freq_cis_len = conf.seq_len * conf.head_size() / 2;
+        checkpoint.extend(vec![0u8; 2 * freq_cis_len * 4]);
+        if !shared_weights {
+            write(&mut checkpoint, "output.weight");
+        }
+
+        let tokens = gf
+            .metadata()
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap();
+        let scores = gf
+            .metadata()
+            .get_f32_array("tokenizer.ggml.scores")
+            .unwrap();
+        let max_token_length = tokens.iter().map(|t| t.len()).max().unwrap() as i32;
+        let mut tokenizer = max_token_length.to_le_bytes().to_vec();
+        for (token, score) in tokens.iter().zip(scores.iter()) {
+            let token = match *token {
+                "<s>" => "\n<s>\n".to_string(),
+                "</s>" => "\n</s>\n".to_string(),
+                token => token.replace('▁', " "),
+            };
+            tokenizer.extend_from_slice(&score.to_le_bytes());
+            tokenizer.extend_from_slice(&(token.len() as i32).to_le_bytes());
+            tokenizer.extend_from_slice(token.as_bytes());
+        }
+        (checkpoint, tokenizer)
+    }
+
+    #[test]
+    fn test_load_llama2c() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let conf = CpuLlamaModelLoader::new().load(&gf)?.conf;
+
+        for shared_weights in [false, true] {
+            let (checkpoint, tokenizer) = export_llama2c(&gf, &conf, shared_weights);
+            let lm = CpuLlamaModelLoader::new().load_llama2c(&checkpoint, &tokenizer)?;
+            assert_eq!(lm.conf.vocab_size, 32000);
+            assert_eq!(lm.conf.n_layers, 6);
+            assert_eq!(lm.weights.output_weight.is_none(), shared_weights);
+            assert_eq!(lm.tokenizer.vocab()[1], "<s>");
+            assert_eq!(lm.tokenizer.vocab()[10842], "▁Captain");
+
+            // stories15M ties the classifier to the token embedding, the output is the same
+            // whether the classifier is shared or stored as a copy
+            let mut runner = Llama2Runner::new(&lm, 200, false)?;
+            let output = runner.prefill_and_generate("Lily is a cat", 31)?;
+            let s = output.collect::<Result<Vec<String>>>()?.join("");
+            assert_eq!(
+                s,
+                " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_invalid_llama2c() -> Result<()> {
+        let header = |values: [i32; 7]| {
+            values
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(load_llama2c_config(&[0; 8]).is_err());
+        assert!(load_llama2c_config(&header([288, 768, 6, 6, 6, 0, 256])).is_err());
+        assert!(load_llama2c_config(&header([288, 768, 6, 7, 7, 32000, 256])).is_err());
+        assert!(load_llama2c_config(&header([288, 768, 6, 6, 4, 32000, 256])).is_err());
+
+        let buf = header([8, 16, 1, 2, 2, -4, 4]);
+        let (conf, shared_weights) = load_llama2c_config(&buf)?;
+        assert!(!shared_weights);
+        assert_eq!(conf.vocab_size, 4);
+        let device = crabml::cpu::CpuTensorDevice::new();
+        let err = load_llama2c_weights(&buf, &conf, shared_weights, device)
+            .err()
+            .unwrap();
+        assert!(err.message.starts_with("size mismatch"), "{}", err.message);
+
+        let buf = header([i32::MAX, i32::MAX, i32::MAX, 1, 1, -i32::MAX, 1]);
+        let (conf, shared_weights) = load_llama2c_config(&buf)?;
+        let device = crabml::cpu::CpuTensorDevice::new();
+        let err = load_llama2c_weights(&buf, &conf, shared_weights, device)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert!(err.message.contains("overflows"), "{}", err.message);
+
+        assert!(load_llama2c_tokenizer(&[0; 10], 2).is_err());
+        Ok(())
+    }
+}
@@ -11,19 +11,44 @@ use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
 use crabml::gguf::GGUFFile;
+use crabml::gguf::KEY_TOKENIZER_ADD_BOS;
+use crabml::gguf::KEY_TOKENIZER_ADD_EOS;
+use crabml::gguf::KEY_TOKENIZER_ADD_SPACE_PREFIX;
+use crabml::gguf::KEY_TOKENIZER_EOT_ID;
+use crabml::gguf::KEY_TOKENIZER_HF_JSON;
+use crabml::gguf::KEY_TOKENIZER_LIST;
+use crabml::gguf::KEY_TOKENIZER_MERGES;
+use crabml::gguf::KEY_TOKENIZER_PRE;
+use crabml::gguf::KEY_TOKENIZER_SCORES;
+use crabml::gguf::KEY_TOKENIZER_TOKEN_TYPE;
+use crabml::safetensors::SafeTensorsFile;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
+use crabml::tokenizer::PreTokenizerKind;
+use crabml::tokenizer::TokenType;
 use crabml::tokenizer::Tokenizer;
 
+use crate::hf::load_hf_tokenizer;
+use crate::hf::load_hf_weights;
+use crate::hf::HfConfig;
+use crate::llama2c::load_llama2c_config;
+use crate::llama2c::load_llama2c_tokenizer;
+use crate::llama2c::load_llama2c_weights;
 use crate::sampler::Llama2SamplerRef;
 use crate::Llama2Sampler;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ModelArchitecture {
     Llama,
+    /// llama with the mixture of experts ffn, like Mixtral
+    Mixtral,
     Gemma,
     Qwen2,
     Phi2,
+    /// the learned position embeddings instead of rope, and the layer norms with bias
+    Gpt2,
+    /// gpt2 with the multi query attention
+    StarCoder,
 }
 
 #[derive(Debug, Clone)]
@@ -40,6 +65,8 @@ pub struct LlamaConfig {
     pub seq_len: usize,
     pub rms_norm_eps: f32,
     pub rope_dim: Option<usize>,
+    pub n_experts: usize,      // 0 if the ffn is not a mixture of experts
+    pub n_experts_used: usize, // the experts picked for each token
 }
 
 impl LlamaConfig {
@@ -55,10 +82,13 @@ impl LlamaConfig {
 pub struct LlamaWeights<T: Tensor> {
     // token embedding table
     pub token_embed: T, // (vocab_size, dim)
+    // learned position embedding table, only in gpt2 and starcoder
+    pub position_embed: Option<T>, // (seq_len, dim)
     // weights for rmsnorms
     pub rms_att_weight: Vec<T>, // (layer, dim) rmsnorm weights
     pub rms_ffn_weight: Vec<T>, // (layer, dim)
     pub rms_att_bias: Vec<T>,
+    pub rms_ffn_bias: Vec<T>,
     // weights for matmuls
     pub wq: Vec<T>, // (layer, embedding_dim, embedding_dim)
     pub wk: Vec<T>, // (layer, kv_dim, embedding_dim)
@@ -76,11 +106,17 @@ pub struct LlamaWeights<T: Tensor> {
     pub ffn_up_weight: Vec<T>,   // (layer, hidden_dim, embedding_dim)
     pub ffn_down_bias: Vec<T>,
     pub ffn_up_bias: Vec<T>,
+    // weights for the mixture of experts ffn, every expert is a view of the stacked expert tensor
+    pub ffn_gate_inp: Vec<T>,       // (layer, n_experts, embedding_dim)
+    pub ffn_gate_exps: Vec<Vec<T>>, // (layer, expert, hidden_dim, embedding_dim)
+    pub ffn_down_exps: Vec<Vec<T>>, // (layer, expert, embedding_dim, hidden_dim)
+    pub ffn_up_exps: Vec<Vec<T>>,   // (layer, expert, hidden_dim, embedding_dim)
     // final rmsnorm
     pub rms_final_weight: T, // (dim, )
     pub rms_final_bias: Option<T>,
     // (optional) classifier weights for the logits, on the last layer
     pub output_weight: Option<T>, // (vocab_size, dim)
+    pub output_bias: Option<T>,   // (vocab_size, ), only in phi2
 }
 
 pub trait LlamaModel {
@@ -184,8 +220,70 @@ impl CpuLlamaModelLoader {
         let device = CpuTensorDevice::with_options(self.device_options.clone());
         let metrics = device.metrics().clone();
         let conf = self.load_config(gf)?;
-        let weights = self.load_weights(gf, conf.n_layers, device.clone())?;
-        let tokenizer = self.load_tokenizer(gf)?;
+        let weights = self.load_weights(gf, &conf, device.clone())?;
+        let tokenizer = self.load_tokenizer(gf, conf.vocab_size)?;
+        let sampler = Llama2Sampler::new(self.temperature, self.probability, device.exp_cache());
+        Ok(CpuLlamaModel {
+            conf,
+            weights: Arc::new(weights),
+            device,
+            tokenizer: Arc::new(tokenizer),
+            sampler,
+            metrics,
+        })
+    }
+
+    /// load only the tokenizer of a GGUF file, the weights and the model config are skipped, so it
+    /// also works on the vocab-only GGUF files.
+    pub fn load_tokenizer_only(&self, gf: &GGUFFile) -> Result<Tokenizer> {
+        // the vocab of the embedded tokenizer.json is not padded without the embeddings
+        let vocab_size = Self::load_vocab_size(gf).unwrap_or(0);
+        self.load_tokenizer(gf, vocab_size)
+    }
+
+    /// load a Hugging Face checkpoint in safetensors, the `config_json` and `tokenizer_json` are
+    /// the contents of the `config.json` and `tokenizer.json` in the model directory.
+    pub fn load_safetensors<'a>(
+        self,
+        st: &'a SafeTensorsFile<'a>,
+        config_json: &str,
+        tokenizer_json: &str,
+    ) -> Result<CpuLlamaModel<'a>> {
+        let device = CpuTensorDevice::with_options(self.device_options.clone());
+        let metrics = device.metrics().clone();
+        let hf_conf = HfConfig::from_json(config_json)?;
+        let conf = hf_conf.to_llama_config()?;
+        let weights = load_hf_weights(st, &conf, device.clone())?;
+        let tokenizer = load_hf_tokenizer(
+            tokenizer_json,
+            conf.vocab_size,
+            hf_conf.bos_token(),
+            hf_conf.eos_token(),
+        )?
+        .with_eog_tokens(&hf_conf.eos_tokens());
+        let sampler = Llama2Sampler::new(self.temperature, self.probability, device.exp_cache());
+        Ok(CpuLlamaModel {
+            conf,
+            weights: Arc::new(weights),
+            device,
+            tokenizer: Arc::new(tokenizer),
+            sampler,
+            metrics,
+        })
+    }
+
+    /// load a legacy llama2.c checkpoint with its tokenizer.bin, the checkpoint is expected to be
+    /// mmaped and its weights are referenced without copying.
+    pub fn load_llama2c<'a>(
+        self,
+        checkpoint: &'a [u8],
+        tokenizer: &[u8],
+    ) -> Result<CpuLlamaModel<'a>> {
+        let device = CpuTensorDevice::with_options(self.device_options.clone());
+        let metrics = device.metrics().clone();
+        let (conf, shared_weights) = load_llama2c_config(checkpoint)?;
+        let weights = load_llama2c_weights(checkpoint, &conf, shared_weights, device.clone())?;
+        let tokenizer = load_llama2c_tokenizer(tokenizer, conf.vocab_size)?;
         let sampler = Llama2Sampler::new(self.temperature, self.probability, device.exp_cache());
         Ok(CpuLlamaModel {
             conf,
@@ -200,9 +298,10 @@ impl CpuLlamaModelLoader {
     fn load_weights<'a>(
         &self,
         gf: &'a GGUFFile<'a>,
-        n_layers: usize,
+        conf: &LlamaConfig,
         device: CpuTensorDeviceRef<'a>,
     ) -> Result<LlamaWeights<CpuTensor<'a>>> {
+        let n_layers = conf.n_layers;
         // [64 (dim), 512 (vocab_size)]
         let token_embed = self.load_tensor(gf, "token_embd.weight", device.clone())?;
         let mut wq = vec![];
@@ -221,9 +320,14 @@ impl CpuLlamaModelLoader {
         let mut ffn_up_weight = vec![];
         let mut ffn_up_bias = vec![];
         let mut ffn_down_bias = vec![];
+        let mut ffn_gate_inp = vec![];
+        let mut ffn_gate_exps = vec![];
+        let mut ffn_down_exps = vec![];
+        let mut ffn_up_exps = vec![];
         let mut rms_att_weight = vec![];
         let mut rms_ffn_weight = vec![];
         let mut rms_att_bias = vec![];
+        let mut rms_ffn_bias = vec![];
 
         match gf.architecture() {
             "llama" | "gemma" => {
@@ -248,22 +352,46 @@ impl CpuLlamaModelLoader {
                         &format!("blk.{}.attn_output.weight", layer),
                         device.clone(),
                     )?);
-                    // (hidden_dim:172, embedding_dim:64)
-                    ffn_gate_weight.push(self.load_tensor(
+                    // the mixture of experts replaces the ffn with the router and the experts
+                    if let Some(gate_inp) = self.load_tensor_optional(
                         gf,
-                        &format!("blk.{}.ffn_gate.weight", layer),
+                        &format!("blk.{}.ffn_gate_inp.weight", layer),
                         device.clone(),
-                    )?);
-                    ffn_down_weight.push(self.load_tensor(
-                        gf,
-                        &format!("blk.{}.ffn_down.weight", layer),
-                        device.clone(),
-                    )?);
-                    ffn_up_weight.push(self.load_tensor(
-                        gf,
-                        &format!("blk.{}.ffn_up.weight", layer),
-                        device.clone(),
-                    )?);
+                    )? {
+                        ffn_gate_inp.push(gate_inp);
+                        ffn_gate_exps.push(self.load_expert_tensors(
+                            gf,
+                            &format!("blk.{}.ffn_gate_exps.weight", layer),
+                            device.clone(),
+                        )?);
+                        ffn_down_exps.push(self.load_expert_tensors(
+                            gf,
+                            &format!("blk.{}.ffn_down_exps.weight", layer),
+                            device.clone(),
+                        )?);
+                        ffn_up_exps.push(self.load_expert_tensors(
+                            gf,
+                            &format!("blk.{}.ffn_up_exps.weight", layer),
+                            device.clone(),
+                        )?);
+                    } else {
+                        // (hidden_dim:172, embedding_dim:64)
+                        ffn_gate_weight.push(self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_gate.weight", layer),
+                            device.clone(),
+                        )?);
+                        ffn_down_weight.push(self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_down.weight", layer),
+                            device.clone(),
+                        )?);
+                        ffn_up_weight.push(self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_up.weight", layer),
+                            device.clone(),
+                        )?);
+                    }
                     rms_att_weight.push(
                         self.load_tensor(
                             gf,
@@ -413,15 +541,111 @@ impl CpuLlamaModelLoader {
                     )?);
                 }
             }
+            "gpt2" | "starcoder" => {
+                let kv_dim = conf.n_kv_heads * conf.head_size();
+                for layer in 0..n_layers {
+                    // split the fused qkv into the views of q, k and v, the k and v might be
+                    // narrower than q on the multi query attention
+                    let qkv_rows = [conf.embedding_dim, kv_dim, kv_dim];
+                    let [q, k, v]: [_; 3] = self
+                        .load_split_tensors(
+                            gf,
+                            &format!("blk.{}.attn_qkv.weight", layer),
+                            &qkv_rows,
+                            device.clone(),
+                        )?
+                        .try_into()
+                        .unwrap();
+                    wq.push(q);
+                    wk.push(k);
+                    wv.push(v);
+                    let [q, k, v]: [_; 3] = self
+                        .load_split_tensors(
+                            gf,
+                            &format!("blk.{}.attn_qkv.bias", layer),
+                            &qkv_rows,
+                            device.clone(),
+                        )?
+                        .try_into()
+                        .unwrap();
+                    bq.push(q);
+                    bk.push(k);
+                    bv.push(v);
+                    wo.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.attn_output.weight", layer),
+                        device.clone(),
+                    )?);
+                    bo.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.attn_output.bias", layer),
+                        device.clone(),
+                    )?);
+                    rms_att_weight.push(
+                        self.load_tensor(
+                            gf,
+                            &format!("blk.{}.attn_norm.weight", layer),
+                            device.clone(),
+                        )?
+                        .dequantize(GGMLType::F32)?,
+                    );
+                    rms_att_bias.push(
+                        self.load_tensor(
+                            gf,
+                            &format!("blk.{}.attn_norm.bias", layer),
+                            device.clone(),
+                        )?
+                        .dequantize(GGMLType::F32)?,
+                    );
+                    rms_ffn_weight.push(
+                        self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_norm.weight", layer),
+                            device.clone(),
+                        )?
+                        .dequantize(GGMLType::F32)?,
+                    );
+                    rms_ffn_bias.push(
+                        self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_norm.bias", layer),
+                            device.clone(),
+                        )?
+                        .dequantize(GGMLType::F32)?,
+                    );
+                    ffn_up_weight.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_up.weight", layer),
+                        device.clone(),
+                    )?);
+                    ffn_up_bias.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_up.bias", layer),
+                        device.clone(),
+                    )?);
+                    ffn_down_weight.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_down.weight", layer),
+                        device.clone(),
+                    )?);
+                    ffn_down_bias.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_down.bias", layer),
+                        device.clone(),
+                    )?);
+                }
+            }
             arch => {
                 bail!(ErrorKind::ModelError, "unsupported architecture {}", arch);
             }
         }
 
+        let position_embed =
+            self.load_tensor_optional(gf, "position_embd.weight", device.clone())?;
         let rms_final_weight = self
             .load_tensor(gf, "output_norm.weight", device.clone())?
             .dequantize(GGMLType::F32)?;
-        let rms_final_bias = if gf.architecture() == "phi2" {
+        let rms_final_bias = if ["phi2", "gpt2", "starcoder"].contains(&gf.architecture()) {
             Some(
                 self.load_tensor(gf, "output_norm.bias", device.clone())?
                     .dequantize(GGMLType::F32)?,
@@ -431,10 +655,12 @@ impl CpuLlamaModelLoader {
         };
 
         // in Gemma, the output weight is None
-        let output_weight = self.load_tensor_optional(gf, "output.weight", device)?;
+        let output_weight = self.load_tensor_optional(gf, "output.weight", device.clone())?;
+        let output_bias = self.load_tensor_optional(gf, "output.bias", device)?;
 
         Ok(LlamaWeights {
             token_embed,
+            position_embed,
             wq,
             wk,
             wv,
@@ -450,12 +676,18 @@ impl CpuLlamaModelLoader {
             ffn_up_weight,
             ffn_down_bias,
             ffn_up_bias,
+            ffn_gate_inp,
+            ffn_gate_exps,
+            ffn_down_exps,
+            ffn_up_exps,
             rms_att_weight,
             rms_ffn_weight,
             rms_att_bias,
+            rms_ffn_bias,
             rms_final_weight,
             rms_final_bias,
             output_weight,
+            output_bias,
         })
     }
 
@@ -476,6 +708,69 @@ impl CpuLlamaModelLoader {
         Ok(Some(tensor))
     }
 
+    /// split the stacked expert tensor of (n_experts, rows, cols) into the 2-D tensors of the
+    /// experts, they refer to the data of the stacked tensor without copying.
+    fn load_expert_tensors<'a>(
+        &self,
+        gf: &'a GGUFFile<'a>,
+        name: &str,
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<Vec<CpuTensor<'a>>> {
+        let info = match gf.get_tensor_info(name) {
+            None => bail!(ErrorKind::TensorNotFound, "failed to find tensor {}", name),
+            Some(info) => info.clone(),
+        };
+        let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+        if dims.len() != 3 || info.data().len() % dims[0] != 0 {
+            bail!(
+                ErrorKind::TensorError,
+                "invalid expert tensor {} of shape {:?}",
+                name,
+                dims
+            );
+        }
+        let expert_bytes = info.data().len() / dims[0];
+        info.data()
+            .chunks_exact(expert_bytes)
+            .map(|data| CpuTensor::from_bytes(data, info.typ(), &dims[1..], device.clone()))
+            .collect()
+    }
+
+    /// split the tensor into the views of the given rows on its outermost dimension, like the
+    /// fused qkv weight and bias.
+    fn load_split_tensors<'a>(
+        &self,
+        gf: &'a GGUFFile<'a>,
+        name: &str,
+        rows: &[usize],
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<Vec<CpuTensor<'a>>> {
+        let info = match gf.get_tensor_info(name) {
+            None => bail!(ErrorKind::TensorNotFound, "failed to find tensor {}", name),
+            Some(info) => info.clone(),
+        };
+        let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+        if dims[0] != rows.iter().sum::<usize>() || info.data().len() % dims[0] != 0 {
+            bail!(
+                ErrorKind::TensorError,
+                "can not split tensor {} of shape {:?} into rows {:?}",
+                name,
+                dims,
+                rows
+            );
+        }
+        let row_bytes = info.data().len() / dims[0];
+        let mut offset = 0;
+        rows.iter()
+            .map(|n| {
+                let data = &info.data()[offset * row_bytes..(offset + n) * row_bytes];
+                offset += n;
+                let shape = [&[*n], &dims[1..]].concat();
+                CpuTensor::from_bytes(data, info.typ(), &shape, device.clone())
+            })
+            .collect()
+    }
+
     pub(crate) fn load_tensor<'a>(
         &self,
         gf: &'a GGUFFile<'a>,
@@ -491,16 +786,9 @@ impl CpuLlamaModelLoader {
         }
     }
 
-    fn load_tokenizer(&self, gf: &GGUFFile) -> Result<Tokenizer> {
+    fn load_tokenizer(&self, gf: &GGUFFile, vocab_size: usize) -> Result<Tokenizer> {
         // println!("{:?}", gf.metadata().as_hashmap().keys());
         // println!("{:?}", gf.metadata().get_string("tokenizer.ggml.model"));
-        let vocab = gf
-            .metadata()
-            .get_string_array("tokenizer.ggml.tokens")
-            .unwrap()
-            .iter()
-            .map(|s| s.to_string())
-            .collect::<Vec<_>>();
         let eos_token = gf
             .metadata()
             .get_u32("tokenizer.ggml.eos_token_id")
@@ -512,9 +800,32 @@ impl CpuLlamaModelLoader {
         let tokenizer_kind = gf
             .metadata()
             .get_string("tokenizer.ggml.model")
-            .unwrap()
+            .unwrap_or_default()
             .to_string();
-        match tokenizer_kind.as_str() {
+        let has_ggml_vocab = gf.metadata().get_string_array(KEY_TOKENIZER_LIST).is_some()
+            && match tokenizer_kind.as_str() {
+                "llama" => gf.metadata().get_f32_array(KEY_TOKENIZER_SCORES).is_some(),
+                "gpt2" => gf
+                    .metadata()
+                    .get_string_array(KEY_TOKENIZER_MERGES)
+                    .is_some(),
+                _ => false,
+            };
+        // some GGUF files only ship the Hugging Face tokenizer.json
+        let tokenizer_json = gf.metadata().get_string(KEY_TOKENIZER_HF_JSON);
+        if let Some(tokenizer_json) = tokenizer_json.filter(|_| !has_ggml_vocab) {
+            let tokenizer = Tokenizer::new_hf(tokenizer_json, vocab_size, bos_token, eos_token)?;
+            return Ok(Self::with_tokenizer_metadata(gf, tokenizer));
+        }
+
+        let vocab = gf
+            .metadata()
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let tokenizer = match tokenizer_kind.as_str() {
             "llama" => {
                 // it seems that .to_vec() will raise an memory issue but it's ok with
                 // iter().cloned().collect(), strange.
@@ -526,12 +837,7 @@ impl CpuLlamaModelLoader {
                     .iter()
                     .cloned()
                     .collect::<Vec<_>>();
-                Ok(Tokenizer::new_llama(
-                    vocab,
-                    vocab_scores,
-                    bos_token,
-                    eos_token,
-                ))
+                Tokenizer::new_llama(vocab, vocab_scores, bos_token, eos_token)
             }
             "gpt2" => {
                 let merges = gf
@@ -541,12 +847,65 @@ impl CpuLlamaModelLoader {
                     .iter()
                     .map(|s| s.to_string())
                     .collect::<Vec<_>>();
-                Ok(Tokenizer::new_gpt2(vocab, merges, bos_token, eos_token))
+                // the older GGUF files without the pre-tokenizer type are split like GPT-2, and
+                // so are the unknown ones like llama.cpp does
+                let pre_tokenizer = match gf.metadata().get_string(KEY_TOKENIZER_PRE) {
+                    Some(pre) => pre.parse().unwrap_or_else(|_| {
+                        eprintln!("unknown pre-tokenizer {}, split the text like GPT-2", pre);
+                        PreTokenizerKind::Gpt2
+                    }),
+                    None => PreTokenizerKind::Gpt2,
+                };
+                // llama.cpp never prepends a space to the BPE vocabs unless it's told
+                let add_space_prefix = gf
+                    .metadata()
+                    .get_bool(KEY_TOKENIZER_ADD_SPACE_PREFIX)
+                    .is_some_and(|v| v != 0);
+                Tokenizer::new_gpt2(
+                    vocab,
+                    merges,
+                    pre_tokenizer,
+                    add_space_prefix,
+                    bos_token,
+                    eos_token,
+                )
             }
-            other => Err(error!(
-                ErrorKind::IOError,
-                "unsupported tokenizer {}", other
-            )),
+            other => bail!(ErrorKind::IOError, "unsupported tokenizer {}", other),
+        };
+        let token_types = gf
+            .metadata()
+            .get_i32_array(KEY_TOKENIZER_TOKEN_TYPE)
+            .unwrap_or_default()
+            .iter()
+            .map(|v| TokenType::from_i32(*v))
+            .collect::<Vec<_>>();
+        Ok(Self::with_tokenizer_metadata(
+            gf,
+            tokenizer.with_token_types(token_types),
+        ))
+    }
+
+    /// the eot token and whether to add the bos and eos tokens are told by the metadata.
+    fn with_tokenizer_metadata(gf: &GGUFFile, mut tokenizer: Tokenizer) -> Tokenizer {
+        if let Some(eot_token) = gf.metadata().get_u32(KEY_TOKENIZER_EOT_ID) {
+            tokenizer = tokenizer.with_eot_token(eot_token as usize);
+        }
+        if let Some(add_bos_token) = gf.metadata().get_bool(KEY_TOKENIZER_ADD_BOS) {
+            tokenizer = tokenizer.with_add_bos_token(add_bos_token != 0);
+        }
+        if let Some(add_eos_token) = gf.metadata().get_bool(KEY_TOKENIZER_ADD_EOS) {
+            tokenizer = tokenizer.with_add_eos_token(add_eos_token != 0);
+        }
+        tokenizer
+    }
+
+    /// the vocab might be only in the embedded tokenizer.json, then it's told by the embeddings.
+    fn load_vocab_size(gf: &GGUFFile) -> Option<usize> {
+        match gf.metadata().get_string_array(KEY_TOKENIZER_LIST) {
+            Some(tokens) => Some(tokens.len()),
+            None => gf
+                .get_tensor_info("token_embd.weight")
+                .map(|info| info.dimensions()[1]),
         }
     }
 
@@ -558,6 +917,8 @@ impl CpuLlamaModelLoader {
             "gemma" => (ModelArchitecture::Gemma, "gemma"),
             "qwen2" => (ModelArchitecture::Qwen2, "qwen2"),
             "phi2" => (ModelArchitecture::Phi2, "phi2"),
+            "gpt2" => (ModelArchitecture::Gpt2, "gpt2"),
+            "starcoder" => (ModelArchitecture::StarCoder, "starcoder"),
             arch => {
                 bail!(ErrorKind::ModelError, "unsupported architecture {}", arch);
             }
@@ -580,19 +941,19 @@ impl CpuLlamaModelLoader {
             .metadata()
             .get_u32(&format!("{}.feed_forward_length", prefix))
             .unwrap() as usize;
+        // gpt2 does not have the kv heads
         let n_kv_heads = gf
             .metadata()
             .get_u32(&format!("{}.attention.head_count_kv", prefix))
-            .unwrap() as usize;
+            .map_or(n_heads, |v| v as usize);
         let seq_len = gf
             .metadata()
             .get_u32(&format!("{}.context_length", prefix))
             .unwrap() as usize;
-        let vocab_size = gf
-            .metadata()
-            .get_string_array("tokenizer.ggml.tokens")
-            .unwrap()
-            .len();
+        let vocab_size = match Self::load_vocab_size(gf) {
+            Some(vocab_size) => vocab_size,
+            None => bail!(ErrorKind::ModelError, "failed to find the vocab size"),
+        };
         let chat_template = gf
             .metadata()
             .get_string("tokenizer.chat_template")
@@ -602,7 +963,7 @@ impl CpuLlamaModelLoader {
             .metadata()
             .get_u32(&format!("{}.embedding_length", prefix))
             .unwrap() as usize;
-        let rms_norm_eps = if prefix == "phi2" {
+        let rms_norm_eps = if ["phi2", "gpt2", "starcoder"].contains(&prefix) {
             gf.metadata()
                 .get_f32(&format!("{}.attention.layer_norm_epsilon", prefix))
                 .unwrap()
@@ -615,6 +976,29 @@ impl CpuLlamaModelLoader {
             .metadata()
             .get_u32(&format!("{}.rope.dimension_count", prefix))
             .map(|v| v as usize);
+        let n_experts = gf
+            .metadata()
+            .get_u32(&format!("{}.expert_count", prefix))
+            .unwrap_or(0) as usize;
+        let n_experts_used = gf
+            .metadata()
+            .get_u32(&format!("{}.expert_used_count", prefix))
+            .unwrap_or(0) as usize;
+        let architecture = match (architecture, n_experts) {
+            (ModelArchitecture::Llama, 0) => ModelArchitecture::Llama,
+            (ModelArchitecture::Llama, _) => {
+                if n_experts_used == 0 || n_experts_used > n_experts {
+                    bail!(
+                        ErrorKind::ModelError,
+                        "invalid expert_used_count {} of {} experts",
+                        n_experts_used,
+                        n_experts
+                    );
+                }
+                ModelArchitecture::Mixtral
+            }
+            (arch, _) => arch,
+        };
 
         Ok(LlamaConfig {
             architecture,
@@ -628,6 +1012,8 @@ impl CpuLlamaModelLoader {
             vocab_size,
             rms_norm_eps,
             rope_dim: n_rot,
+            n_experts,
+            n_experts_used,
             chat_template,
         })
     }
@@ -689,6 +1075,11 @@ impl<T: Tensor> GpuLlamaModel<T> {
         device: T::DeviceRef,
     ) -> Result<LlamaWeights<T>> {
         let token_embedding_table = Self::convert_cpu_tensor(&weights.token_embed, device.clone())?;
+        let position_embed = weights
+            .position_embed
+            .as_ref()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .transpose()?;
         let wq = weights
             .wq
             .iter()
@@ -764,6 +1155,14 @@ impl<T: Tensor> GpuLlamaModel<T> {
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
+        let ffn_gate_inp = weights
+            .ffn_gate_inp
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let ffn_gate_exps = Self::convert_cpu_experts(&weights.ffn_gate_exps, device.clone())?;
+        let ffn_down_exps = Self::convert_cpu_experts(&weights.ffn_down_exps, device.clone())?;
+        let ffn_up_exps = Self::convert_cpu_experts(&weights.ffn_up_exps, device.clone())?;
         let rms_att_weight = weights
             .rms_att_weight
             .iter()
@@ -779,6 +1178,11 @@ impl<T: Tensor> GpuLlamaModel<T> {
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
+        let rms_ffn_bias = weights
+            .rms_ffn_bias
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
         let rms_final_weight = Self::convert_cpu_tensor(&weights.rms_final_weight, device.clone())?;
         let rms_final_bias = weights.rms_final_bias.as_ref().map(|rms_final_bias| {
             Self::convert_cpu_tensor(rms_final_bias, device.clone()).unwrap()
@@ -787,8 +1191,13 @@ impl<T: Tensor> GpuLlamaModel<T> {
             .output_weight
             .as_ref()
             .map(|output_weight| Self::convert_cpu_tensor(output_weight, device.clone()).unwrap());
+        let output_bias = weights
+            .output_bias
+            .as_ref()
+            .map(|output_bias| Self::convert_cpu_tensor(output_bias, device.clone()).unwrap());
         let weights = LlamaWeights {
             token_embed: token_embedding_table,
+            position_embed,
             wq,
             wk,
             wv,
@@ -804,16 +1213,37 @@ impl<T: Tensor> GpuLlamaModel<T> {
             ffn_up_weight,
             ffn_down_bias,
             ffn_up_bias,
+            ffn_gate_inp,
+            ffn_gate_exps,
+            ffn_down_exps,
+            ffn_up_exps,
             rms_att_weight,
             rms_ffn_weight,
             rms_att_bias,
+            rms_ffn_bias,
             rms_final_weight,
             rms_final_bias,
             output_weight: wcls,
+            output_bias,
         };
         Ok(weights)
     }
 
+    fn convert_cpu_experts(
+        experts: &[Vec<CpuTensor>],
+        device: T::DeviceRef,
+    ) -> Result<Vec<Vec<T>>> {
+        experts
+            .iter()
+            .map(|layer| {
+                layer
+                    .iter()
+                    .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect()
+    }
+
     fn convert_cpu_tensor(tensor: &CpuTensor, device: T::DeviceRef) -> Result<T> {
         let buf = tensor.buf();
         match tensor.dtype() {
@@ -842,7 +1272,19 @@ mod tests {
     use crabml::error::Result;
     use crabml::gguf::GGMLType;
     use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFMetadataArray;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::KEY_TOKENIZER_ADD_SPACE_PREFIX;
+    use crabml::gguf::KEY_TOKENIZER_HF_JSON;
+    use crabml::gguf::KEY_TOKENIZER_LIST;
+    use crabml::gguf::KEY_TOKENIZER_MERGES;
+    use crabml::gguf::KEY_TOKENIZER_PRE;
+    use crabml::gguf::KEY_TOKENIZER_SCORES;
+    use crabml::gguf::KEY_TOKENIZER_TOKEN_TYPE;
     use crabml::tensor::Tensor;
+    use crabml::tokenizer::TokenizerKind;
+    use crabml::tokenizer::Utf8Buf;
+    use serde_json::json;
 
     use crate::model::CpuLlamaModelLoader;
 
@@ -860,4 +1302,136 @@ mod tests {
         assert_eq!(lm.weights.token_embed.dtype(), GGMLType::Q8_0);
         Ok(())
     }
+
+    #[test]
+    fn test_load_hf_json_tokenizer() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let base_gf = gl.open()?;
+        let metadata = base_gf.metadata();
+        let tokens = metadata.get_string_array("tokenizer.ggml.tokens").unwrap();
+        let scores = metadata.get_f32_array(KEY_TOKENIZER_SCORES).unwrap();
+        let vocab = tokens
+            .iter()
+            .zip(scores.iter())
+            .map(|(t, s)| json!([t, s]))
+            .collect::<Vec<_>>();
+        let tokenizer_json = json!({
+            "added_tokens": [
+                {"id": 0, "content": "<unk>", "special": true},
+                {"id": 1, "content": "<s>", "special": true},
+                {"id": 2, "content": "</s>", "special": true},
+            ],
+            "normalizer": {"type": "Sequence", "normalizers": [
+                {"type": "Prepend", "prepend": "▁"},
+                {"type": "Replace", "pattern": {"String": " "}, "content": "▁"},
+            ]},
+            "decoder": {"type": "Sequence", "decoders": [
+                {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                {"type": "ByteFallback"},
+            ]},
+            "model": {"type": "Unigram", "unk_id": 0, "byte_fallback": true, "vocab": vocab},
+        })
+        .to_string();
+
+        // the tokenizer is loaded from the tokenizer.json without the scores
+        let mut gf = gl.open()?;
+        gf.remove_metadata(KEY_TOKENIZER_SCORES)?;
+        gf.set_metadata(
+            KEY_TOKENIZER_HF_JSON,
+            GGUFMetadataValue::String(tokenizer_json.into()),
+        )?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let tk = &lm.tokenizer;
+        assert_eq!(tk.kind(), TokenizerKind::HuggingFace);
+        assert_eq!(tk.vocab().len(), 32000);
+
+        let tokens = tk.encode("Lily is a cat", true, false, false)?;
+        assert_eq!(tokens[..3], [1, 365, 2354]);
+        let mut buf = Utf8Buf::new();
+        let text = tokens
+            .iter()
+            .map(|t| tk.decode(*t, &mut buf))
+            .collect::<Result<String>>()?;
+        assert_eq!(text, " Lily is a cat");
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_unknown_pre_tokenizer() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let mut gf = gl.open()?;
+        gf.set_metadata(
+            "tokenizer.ggml.model",
+            GGUFMetadataValue::String("gpt2".into()),
+        )?;
+        gf.set_metadata(
+            KEY_TOKENIZER_MERGES,
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(vec![])),
+        )?;
+        gf.set_metadata(
+            KEY_TOKENIZER_PRE,
+            GGUFMetadataValue::String("tekken".into()),
+        )?;
+
+        // the unknown pre-tokenizer falls back to GPT-2 instead of failing the load
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        assert_eq!(lm.tokenizer.kind(), TokenizerKind::GPT2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_gpt2_add_space_prefix() -> Result<()> {
+        // a byte level vocab like llama3, where "Hello" and " Hello" are different tokens
+        // (9906 and 22691 in llama3), the byte tokens are placed in the order of the bytes
+        let bytes = (b'!'..=b'~')
+            .chain(b'\xA1'..=b'\xAC')
+            .chain(b'\xAE'..=b'\xFF');
+        let printable = bytes.collect::<Vec<_>>();
+        let mut n = 0;
+        let mut tokens = (0..=255u8)
+            .map(|b| match printable.contains(&b) {
+                true => char::from(b).to_string(),
+                false => {
+                    n += 1;
+                    char::from_u32(255 + n).unwrap().to_string()
+                }
+            })
+            .collect::<Vec<_>>();
+        tokens.extend(["He", "Hel", "Hell", "Hello", "ĠHello"].map(String::from));
+        tokens.extend((tokens.len()..32000).map(|i| format!("[PAD{}]", i)));
+        let merges = ["H e", "He l", "Hel l", "Hell o", "Ġ Hello"];
+
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let mut gf = gl.open()?;
+        gf.set_metadata(
+            "tokenizer.ggml.model",
+            GGUFMetadataValue::String("gpt2".into()),
+        )?;
+        gf.set_metadata(
+            KEY_TOKENIZER_LIST,
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(
+                tokens.iter().map(|t| t.as_str()).collect(),
+            )),
+        )?;
+        gf.set_metadata(
+            KEY_TOKENIZER_MERGES,
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(merges.to_vec())),
+        )?;
+        gf.set_metadata(
+            KEY_TOKENIZER_PRE,
+            GGUFMetadataValue::String("llama-bpe".into()),
+        )?;
+        gf.remove_metadata(KEY_TOKENIZER_TOKEN_TYPE)?;
+
+        // no space is prepended to the BPE vocab by default
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let ids = lm.tokenizer.encode("Hello Hello", false, false, false)?;
+        assert_eq!(ids, vec![259, 260]);
+
+        gf.set_metadata(KEY_TOKENIZER_ADD_SPACE_PREFIX, GGUFMetadataValue::Bool(1))?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let ids = lm.tokenizer.encode("Hello Hello", false, false, false)?;
+        assert_eq!(ids, vec![260, 260]);
+        Ok(())
+    }
 }
@@ -0,0 +1,70 @@
+#version 450
+
+layout(set = 0, binding = 0) buffer InputBuffer {
+    float bufA[];
+};
+
+layout(push_constant) uniform PushConstants {
+    uint numRows;
+    uint numDims;
+    float eps;
+} pcs;
+
+// each workgroup processes a row
+// each thread processes a chunk
+layout(local_size_x = 32, local_size_y = 1, local_size_z = 1) in;
+
+shared float[32] sketches;
+
+void main() {
+    uint rowIdx = gl_WorkGroupID.x;
+    uint rowSize = pcs.numDims;
+    uint chunkIdx = gl_LocalInvocationID.x;
+    uint chunkSize = rowSize / 32;
+
+    // calculate the sum
+    sketches[chunkIdx] = 0.0;
+    for (uint i = 0; i < chunkSize; i++) {
+        uint idx = rowIdx * rowSize + chunkIdx * chunkSize + i;
+        sketches[chunkIdx] += bufA[idx];
+    }
+    barrier();
+
+    // get the mean of the row
+    if (chunkIdx == 0) {
+        float sum = 0.0;
+        for (uint i = 0; i < 32; i++) {
+            sum += sketches[i];
+        }
+        sketches[0] = sum / rowSize;
+    }
+    barrier();
+    float mean = sketches[0];
+    barrier();
+
+    // center the row and calculate the sum of squares
+    sketches[chunkIdx] = 0.0;
+    for (uint i = 0; i < chunkSize; i++) {
+        uint idx = rowIdx * rowSize + chunkIdx * chunkSize + i;
+        bufA[idx] -= mean;
+        sketches[chunkIdx] += bufA[idx] * bufA[idx];
+    }
+    barrier();
+
+    // get the standard deviation of the row
+    if (chunkIdx == 0) {
+        float squareSum = 0.0;
+        for (uint i = 0; i < 32; i++) {
+            squareSum += sketches[i];
+        }
+        sketches[0] = sqrt(squareSum / rowSize + pcs.eps);
+    }
+    barrier();
+    float scale = 1.0 / sketches[0];
+
+    // normalize by the standard deviation
+    for (uint i = 0; i < chunkSize; i++) {
+        uint idx = rowIdx * rowSize + chunkIdx * chunkSize + i;
+        bufA[idx] *= scale;
+    }
+}
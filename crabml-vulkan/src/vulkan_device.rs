@@ -120,6 +120,9 @@ impl VulkanTensorDevice {
         mod rms_norm_shader {
             vulkano_shaders::shader! { ty: "compute", path: "./src/shaders/rms_norm.glsl" }
         }
+        mod layer_norm_shader {
+            vulkano_shaders::shader! { ty: "compute", path: "./src/shaders/layer_norm.glsl" }
+        }
         mod rope_shader {
             vulkano_shaders::shader! { ty: "compute", path: "./src/shaders/rope.glsl" }
         }
@@ -158,6 +161,10 @@ impl VulkanTensorDevice {
                 "rms_norm",
                 load_shader_entry_point!(rms_norm_shader, device.clone(), "main"),
             ),
+            (
+                "layer_norm",
+                load_shader_entry_point!(layer_norm_shader, device.clone(), "main"),
+            ),
             (
                 "rope",
                 load_shader_entry_point!(rope_shader, device.clone(), "main"),
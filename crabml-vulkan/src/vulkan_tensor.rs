@@ -272,7 +272,13 @@ impl Tensor for VulkanTensor {
     ) -> Result<Self> {
         assert!(self.shape().len() == 3 || self.shape().len() == 2);
         assert!(self.strider.is_contiguous());
-        assert!(mode == RopeMode::Llama, "TODO: only support Llama mode yet");
+        if mode != RopeMode::Llama {
+            bail!(
+                ErrorKind::NotImplemented,
+                "rope: only support Llama mode yet, got {:?}",
+                mode
+            );
+        }
 
         let (rows, n_head, m) = if self.strider.dims() == 3 {
             (
@@ -323,6 +329,33 @@ impl Tensor for VulkanTensor {
         Ok(self)
     }
 
+    fn layer_norm_inplace(self, eps: f32) -> Result<Self> {
+        assert!(self.strider.is_contiguous());
+        assert!(self.shape().last().unwrap() % 32 == 0);
+        assert!([1, 2, 3].contains(&self.shape().len()));
+
+        let (n_rows, n_cols) = match self.shape().len() {
+            3 => (self.shape()[0] * self.shape()[1], self.shape()[2]),
+            2 => (self.shape()[0], self.shape()[1]),
+            1 => (1, self.shape()[0]),
+            _ => unreachable!(),
+        };
+
+        let bufs = vec![self.buf.clone()];
+        // shares the same push constants with rms_norm
+        let pcs = RmsNormPushConstants {
+            n_rows: n_rows as u32,
+            n_cols: n_cols as u32,
+            eps,
+        };
+        // each thread block processes a row
+        let dispatches = [n_rows as u32, 1, 1];
+        self.device
+            .inner
+            .dispatch_compute("layer_norm", bufs, pcs, dispatches);
+        Ok(self)
+    }
+
     fn softmax_inplace(self, axis: usize) -> Result<Self> {
         assert!(axis == self.strider.dims() - 1);
         assert!(self.strider.is_contiguous());
@@ -639,6 +672,35 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tensor_layer_norm() -> Result<()> {
+        let d = VulkanTensorDevice::new(VulkanTensorDeviceOptions::default());
+
+        pub fn simple_layernorm(x: &mut [f32]) {
+            let mean = x.iter().sum::<f32>() / x.len() as f32;
+            let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / x.len() as f32;
+            let scale = 1.0 / (var + 1e-5).sqrt();
+            for i in x {
+                *i = (*i - mean) * scale;
+            }
+        }
+        let v1 = (0..256)
+            .map(|i| (i * 7 % 13) as f32 + 10.0)
+            .collect::<Vec<_>>();
+
+        let t1 = VulkanTensor::new(&v1, &[2, 128], d.clone())?;
+        let t1 = t1.layer_norm_inplace(1e-5)?;
+        let mut dst1 = vec![0.0; 256];
+        t1.export(&mut dst1)?;
+
+        let mut dst2 = v1.clone();
+        simple_layernorm(&mut dst2[0..128]);
+        simple_layernorm(&mut dst2[128..256]);
+
+        assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-4);
+        Ok(())
+    }
+
     #[test]
     fn test_rope() -> Result<()> {
         let d = VulkanTensorDevice::new(VulkanTensorDeviceOptions::default());
@@ -0,0 +1,75 @@
+struct Meta {
+    nBatch: u32, // number of vectors
+    nDims: u32, // length of each vector
+    eps: f32,
+    _padding: f32,
+};
+
+@group(0) @binding(0)
+var<storage, read_write> buf: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> bufM: Meta;
+
+// workgroup local to reduce the sum and the squared sum
+var<workgroup> threadSums: array<f32, 32>;
+
+// each workgroup normalize a single vector
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroupID: vec3<u32>,
+    @builtin(local_invocation_id) localID: vec3<u32>,
+) {
+    let nDims = bufM.nDims;
+    let eps = bufM.eps;
+
+    let workgroupSize: u32 = 32u;
+    let localChunkSize = nDims / workgroupSize;
+
+    // calculate each thread's chunk of the sum
+    threadSums[localID.x] = 0.0;
+    for (var i = 0u; i < localChunkSize; i += 1u) {
+        let idx = nDims * workgroupID.x + localID.x * localChunkSize + i;
+        threadSums[localID.x] += buf[idx];
+    }
+    workgroupBarrier();
+
+    // reduce the sum into the mean
+    if localID.x == 0u {
+        var sum = 0.0;
+        for (var i = 0u; i < workgroupSize; i += 1u) {
+            sum += threadSums[i];
+        }
+        threadSums[0] = sum / f32(nDims);
+    }
+    workgroupBarrier();
+    let mean = threadSums[0];
+    workgroupBarrier();
+
+    // center the vector and calculate each thread's chunk of the squared sum
+    threadSums[localID.x] = 0.0;
+    for (var i = 0u; i < localChunkSize; i += 1u) {
+        let idx = nDims * workgroupID.x + localID.x * localChunkSize + i;
+        buf[idx] -= mean;
+        threadSums[localID.x] += buf[idx] * buf[idx];
+    }
+    workgroupBarrier();
+
+    // reduce the squared sum into the variance
+    if localID.x == 0u {
+        var sum = 0.0;
+        for (var i = 0u; i < workgroupSize; i += 1u) {
+            sum += threadSums[i];
+        }
+        threadSums[0] = sum / f32(nDims);
+    }
+    workgroupBarrier();
+
+    // normalize to output
+    let scale = 1.0 / sqrt(threadSums[0] + eps);
+    for (var i = 0u; i < localChunkSize; i += 1u) {
+        let idx = nDims * workgroupID.x + localID.x * localChunkSize + i;
+        buf[idx] *= scale;
+    }
+}
@@ -77,6 +77,10 @@ impl WgpuTensorDevice {
             ("mul_inplace", include_str!("shaders/mul.wgsl")),
             ("div_inplace", include_str!("shaders/div.wgsl")),
             ("rms_norm_inplace", include_str!("shaders/rms_norm.wgsl")),
+            (
+                "layer_norm_inplace",
+                include_str!("shaders/layer_norm.wgsl"),
+            ),
             ("sgemv", include_str!("shaders/sgemv.wgsl")),
             ("rope_inplace", include_str!("shaders/rope.wgsl")),
             ("softmax_inplace", include_str!("shaders/softmax.wgsl")),
@@ -347,7 +347,13 @@ impl Tensor for WgpuTensor {
     fn rope_inplace(self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self> {
         assert!(self.shape().len() == 3 || self.shape().len() == 2);
         assert!(self.is_contiguous());
-        assert!(mode == RopeMode::Llama, "TODO: only support Llama mode yet");
+        if mode != RopeMode::Llama {
+            bail!(
+                ErrorKind::NotImplemented,
+                "rope: only support Llama mode yet, got {:?}",
+                mode
+            );
+        }
 
         let (rows, n_head, m) = if self.strider.dims() == 3 {
             (
@@ -423,6 +429,52 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn layer_norm_inplace(self, eps: f32) -> Result<Self> {
+        assert!(self.strider.dims() == 2 || self.strider.dims() == 1);
+        if !self.strider.is_contiguous() {
+            bail!(ErrorKind::TensorError, "layer_norm: not contiguous");
+        }
+        if self.shape().last().unwrap() % 32 != 0 {
+            bail!(
+                ErrorKind::TensorError,
+                "layer_norm: the last dim {} is not a multiple of 32",
+                self.shape().last().unwrap()
+            );
+        }
+        let (n_batch, n_dims) = if self.strider.dims() == 2 {
+            (self.shape()[0], self.shape()[1])
+        } else {
+            (1, self.shape()[0])
+        };
+        // shares the same meta layout with rms_norm
+        let meta = &RmsNormMeta {
+            n_batch: n_batch as u32,
+            n_dims: n_dims as u32,
+            eps,
+            _padding: 0,
+        };
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::bytes_of(meta));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self.device.encode_pipeline_command(
+            "layer_norm_inplace",
+            entries,
+            (meta.n_batch, 1, 1),
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
     fn softmax_inplace(self, axis: usize) -> Result<Self> {
         assert!(axis == self.strider.dims() - 1);
         assert!(self.is_contiguous());
@@ -744,6 +796,7 @@ mod tests {
     use std::sync::LazyLock;
 
     use approx::assert_relative_eq;
+    use crabml::error::ErrorKind;
     use crabml::error::Result;
     use crabml::gguf::GGMLType;
     use crabml::tensor::RopeMode;
@@ -876,6 +929,42 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_tensor_layer_norm() -> Result<()> {
+        pub fn simple_layernorm(x: &mut [f32]) {
+            let mean = x.iter().sum::<f32>() / x.len() as f32;
+            let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / x.len() as f32;
+            let scale = 1.0 / (var + 1e-5).sqrt();
+            for i in x {
+                *i = (*i - mean) * scale;
+            }
+        }
+
+        let v1 = (0..256)
+            .map(|i| (i * 7 % 13) as f32 + 10.0)
+            .collect::<Vec<_>>();
+
+        let t1 = WgpuTensor::new(&v1, &[2, 128], DEVICE.clone())?;
+        let t1 = t1.layer_norm_inplace(1e-5)?;
+        let mut dst1 = vec![0.0; 256];
+        t1.export(&mut dst1)?;
+
+        let mut dst2 = v1.clone();
+        simple_layernorm(&mut dst2[0..128]);
+        simple_layernorm(&mut dst2[128..256]);
+
+        assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-5);
+
+        // the shader processes a row by the blocks of 32
+        let t2 = WgpuTensor::new(&v1[..240], &[2, 120], DEVICE.clone())?;
+        let err = t2.layer_norm_inplace(1e-5).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::TensorError);
+        let t3 = WgpuTensor::new(&v1, &[64, 4], DEVICE.clone())?.transpose(&[1, 0])?;
+        let err = t3.layer_norm_inplace(1e-5).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::TensorError);
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_matmul() -> Result<()> {
         let v1 = (0..256).map(|i| i as f32).collect::<Vec<_>>();
@@ -933,6 +1022,10 @@ mod tests {
             epsilon = 1e-5
         );
 
+        let t2 = WgpuTensor::new(&v1, &[2, 16], DEVICE.clone())?;
+        let err = t2.rope_inplace(RopeMode::Neox, 1, 2).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::NotImplemented);
+
         Ok(())
     }
 